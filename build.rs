@@ -1,30 +1,74 @@
 use std::env;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let out_dir = env::var_os("OUT_DIR").unwrap();
-    let out_dir = Path::new(&out_dir);
-
-    let csi = out_dir.join("csi");
-    std::fs::create_dir_all(&csi)?;
+/// Revision of https://github.com/container-storage-interface/spec to compile against.
+/// Can be overridden with the `CSI_SPEC_REV` environment variable.
+const CSI_SPEC_REV: &str = "b01039c563108173c6743aa1410ec11fde7c24fe";
 
-    let data = reqwest::get(
-"https://raw.githubusercontent.com/container-storage-interface/spec/b01039c563108173c6743aa1410ec11fde7c24fe/csi.proto"
-    )
-    .await?
-    .error_for_status()?
-    .bytes()
-    .await?;
-    let proto = csi.join("csi.proto");
-    std::fs::write(&proto, data)?;
+fn main() -> anyhow::Result<()> {
+    let rev = env::var("CSI_SPEC_REV").unwrap_or_else(|_| CSI_SPEC_REV.into());
+    // Vendored copies are kept under proto/csi/{rev}, see proto/update-csi.sh
+    let vendored = Path::new("proto/csi").join(&rev);
+    let csi = if vendored.join("csi.proto").exists() {
+        println!(
+            "cargo:rerun-if-changed={}",
+            vendored.join("csi.proto").display()
+        );
+        vendored
+    } else {
+        fetch(&rev)?
+    };
 
     tonic_build::configure()
         .build_server(true)
         .emit_rerun_if_changed(false)
-        .compile(&[proto], &[csi])?;
+        .compile(&[csi.join("csi.proto")], &[csi])?;
+    tonic_build::configure()
+        .build_server(true)
+        .emit_rerun_if_changed(false)
+        .compile(
+            &[
+                "proto/admin.proto",
+                "proto/transfer.proto",
+                "proto/provider.proto",
+            ],
+            &["proto"],
+        )?;
 
     println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=proto/admin.proto");
+    println!("cargo:rerun-if-changed=proto/transfer.proto");
+    println!("cargo:rerun-if-changed=proto/provider.proto");
+    println!("cargo:rerun-if-env-changed=CSI_SPEC_REV");
 
     Ok(())
 }
+
+#[cfg(feature = "fetch-proto")]
+fn fetch(rev: &str) -> anyhow::Result<PathBuf> {
+    let out_dir = env::var_os("OUT_DIR").unwrap();
+    let csi = Path::new(&out_dir).join("csi");
+    std::fs::create_dir_all(&csi)?;
+
+    println!(
+        "cargo:warning=csi.proto revision {} is not vendored, downloading it",
+        rev
+    );
+    let data = reqwest::blocking::get(format!(
+        "https://raw.githubusercontent.com/container-storage-interface/spec/{}/csi.proto",
+        rev
+    ))?
+    .error_for_status()?
+    .bytes()?;
+    std::fs::write(csi.join("csi.proto"), data)?;
+    Ok(csi)
+}
+
+#[cfg(not(feature = "fetch-proto"))]
+fn fetch(rev: &str) -> anyhow::Result<PathBuf> {
+    anyhow::bail!(
+        "csi.proto revision {} is not vendored. Run `proto/update-csi.sh {}` or enable the `fetch-proto` feature",
+        rev,
+        rev
+    )
+}
@@ -0,0 +1,10 @@
+#!/bin/sh
+# Vendor csi.proto from the CSI specification repository, so that builds do not need network access.
+# Usage: proto/update-csi.sh [revision]  (defaults to CSI_SPEC_REV from build.rs)
+set -eu
+root=$(dirname "$0")/..
+rev=${1:-$(sed -n 's/^const CSI_SPEC_REV: &str = "\(.*\)";$/\1/p' "$root/build.rs")}
+dir="$root/proto/csi/$rev"
+mkdir -p "$dir"
+curl -fsSL "https://raw.githubusercontent.com/container-storage-interface/spec/$rev/csi.proto" -o "$dir/csi.proto"
+echo "Vendored $dir/csi.proto"
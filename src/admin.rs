@@ -0,0 +1,548 @@
+//! Administrative gRPC service and its command-line client (with the `admin-cli` feature).
+#[cfg(feature = "admin-cli")]
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tonic::transport::{Channel, Endpoint, Uri};
+use tracing::*;
+
+use crate::endpoint::Address;
+#[cfg(feature = "admin-cli")]
+use crate::transfer::{self, v1::base_transfer_client::BaseTransferClient};
+use crate::Overlays;
+
+pub mod v1 {
+    tonic::include_proto!("overlayfs_csi.admin.v1");
+}
+
+/// Sets the log filter of the driver (a level or `RUST_LOG`-style directives), returning the
+/// previous one
+pub type LogLevelSetter = dyn Fn(&str) -> anyhow::Result<String> + Send + Sync;
+
+pub struct AdminService {
+    overlays: Arc<Overlays>,
+    log_level: Option<Arc<LogLevelSetter>>,
+    /// Incremented at each log level change, so that a revert does not override a later change
+    log_level_generation: Arc<AtomicU64>,
+}
+impl AdminService {
+    pub fn new(overlays: Arc<Overlays>) -> Self {
+        Self {
+            overlays,
+            log_level: None,
+            log_level_generation: Default::default(),
+        }
+    }
+    /// Allow changing the log level at runtime.
+    pub fn log_level(mut self, setter: Arc<LogLevelSetter>) -> Self {
+        self.log_level = Some(setter);
+        self
+    }
+}
+#[async_trait::async_trait]
+impl v1::admin_server::Admin for AdminService {
+    async fn invalidate_family(
+        &self,
+        req: tonic::Request<v1::InvalidateFamilyRequest>,
+    ) -> tonic::Result<tonic::Response<v1::InvalidateFamilyResponse>> {
+        let req = req.into_inner();
+        if req.family.is_empty() {
+            return Err(tonic::Status::invalid_argument("Missing family"));
+        }
+        if !crate::transfer::valid_component(&req.family) {
+            return Err(tonic::Status::invalid_argument(format!(
+                "Invalid family {:?}",
+                req.family
+            )));
+        }
+        match self.overlays.invalidate_family(&req.family).await {
+            Ok(bases) => Ok(tonic::Response::new(v1::InvalidateFamilyResponse { bases })),
+            Err(e) => {
+                error!(req.family, "Failed to invalidate family: {}", e);
+                Err(tonic::Status::internal(e.to_string()))
+            }
+        }
+    }
+    async fn diagnose(
+        &self,
+        req: tonic::Request<v1::DiagnoseRequest>,
+    ) -> tonic::Result<tonic::Response<v1::DiagnoseResponse>> {
+        let req = req.into_inner();
+        if req.volume_id.is_empty() {
+            return Err(tonic::Status::invalid_argument("Missing volume id"));
+        }
+        match self.overlays.diagnose(&req.volume_id).await {
+            Ok(d) => Ok(tonic::Response::new(v1::DiagnoseResponse {
+                findings: d.findings,
+                causes: d.causes,
+            })),
+            Err(e) => {
+                error!(req.volume_id, "Failed to diagnose volume: {}", e);
+                Err(tonic::Status::internal(e.to_string()))
+            }
+        }
+    }
+    async fn cleanup_report(
+        &self,
+        _req: tonic::Request<v1::CleanupReportRequest>,
+    ) -> tonic::Result<tonic::Response<v1::CleanupReportResponse>> {
+        match self.overlays.cleanup_report().await {
+            Ok(actions) => Ok(tonic::Response::new(v1::CleanupReportResponse {
+                actions: actions
+                    .into_iter()
+                    .map(|a| v1::CleanupAction {
+                        kind: a.kind.into(),
+                        name: a.name,
+                        size_bytes: a.size_bytes,
+                        reason: a.reason,
+                    })
+                    .collect(),
+            })),
+            Err(e) => {
+                error!("Failed to report the cleanup: {}", e);
+                Err(tonic::Status::internal(e.to_string()))
+            }
+        }
+    }
+    async fn compact(
+        &self,
+        req: tonic::Request<v1::CompactRequest>,
+    ) -> tonic::Result<tonic::Response<v1::CompactResponse>> {
+        let req = req.into_inner();
+        if req.volume_id.is_empty() {
+            return Err(tonic::Status::invalid_argument("Missing volume id"));
+        }
+        match self.overlays.compact_volume(&req.volume_id).await {
+            Ok((overlays, c)) => Ok(tonic::Response::new(v1::CompactResponse {
+                overlays: overlays as u64,
+                files: c.files,
+                dirs: c.dirs,
+                whiteouts: c.whiteouts,
+                bytes: c.bytes,
+                sparse_bytes: c.sparse_bytes,
+            })),
+            Err(e) => {
+                error!(req.volume_id, "Failed to compact volume: {}", e);
+                Err(tonic::Status::internal(e.to_string()))
+            }
+        }
+    }
+    async fn backup(
+        &self,
+        req: tonic::Request<v1::BackupRequest>,
+    ) -> tonic::Result<tonic::Response<v1::BackupResponse>> {
+        let req = req.into_inner();
+        match self
+            .overlays
+            .backup(&req.family, &req.names, &req.location)
+            .await
+        {
+            Ok(bases) => Ok(tonic::Response::new(v1::BackupResponse {
+                bases: bases
+                    .into_iter()
+                    .map(|(name, size_bytes)| v1::BackedUpBase { name, size_bytes })
+                    .collect(),
+            })),
+            Err(e) => {
+                error!(req.family, req.location, "Failed to back up bases: {:#}", e);
+                Err(tonic::Status::internal(format!("{:#}", e)))
+            }
+        }
+    }
+    async fn restore(
+        &self,
+        req: tonic::Request<v1::RestoreRequest>,
+    ) -> tonic::Result<tonic::Response<v1::RestoreResponse>> {
+        let req = req.into_inner();
+        match self
+            .overlays
+            .restore_backup(&req.family, &req.names, &req.location)
+            .await
+        {
+            Ok(bases) => Ok(tonic::Response::new(v1::RestoreResponse { bases })),
+            Err(e) => {
+                error!(req.family, req.location, "Failed to restore bases: {:#}", e);
+                Err(tonic::Status::internal(format!("{:#}", e)))
+            }
+        }
+    }
+    async fn verify_base(
+        &self,
+        req: tonic::Request<v1::VerifyBaseRequest>,
+    ) -> tonic::Result<tonic::Response<v1::ManifestDiff>> {
+        let req = req.into_inner();
+        match self.overlays.verify_base(&req.family, &req.name).await {
+            Ok(diff) => Ok(tonic::Response::new(diff.into())),
+            Err(e) => {
+                error!(req.family, req.name, "Failed to verify base: {:#}", e);
+                Err(tonic::Status::failed_precondition(format!("{:#}", e)))
+            }
+        }
+    }
+    async fn diff_bases(
+        &self,
+        req: tonic::Request<v1::DiffBasesRequest>,
+    ) -> tonic::Result<tonic::Response<v1::ManifestDiff>> {
+        let req = req.into_inner();
+        self.overlays
+            .diff_bases(&req.family, &req.from, &req.to)
+            .map(|diff| tonic::Response::new(diff.into()))
+            .map_err(|e| tonic::Status::failed_precondition(format!("{:#}", e)))
+    }
+    async fn find_in_bases(
+        &self,
+        req: tonic::Request<v1::FindInBasesRequest>,
+    ) -> tonic::Result<tonic::Response<v1::FindInBasesResponse>> {
+        let req = req.into_inner();
+        let (found, unindexed) = self
+            .overlays
+            .find_in_bases(&req.family, &req.path)
+            .map_err(|e| tonic::Status::invalid_argument(format!("{:#}", e)))?;
+        Ok(tonic::Response::new(v1::FindInBasesResponse {
+            entries: found
+                .into_iter()
+                .map(|(base, entry)| v1::ManifestEntry {
+                    base,
+                    kind: entry.kind.as_str().into(),
+                    size: entry.size,
+                    sha256: entry.sha256.unwrap_or_default(),
+                })
+                .collect(),
+            unindexed,
+        }))
+    }
+    async fn set_log_level(
+        &self,
+        req: tonic::Request<v1::SetLogLevelRequest>,
+    ) -> tonic::Result<tonic::Response<v1::SetLogLevelResponse>> {
+        let req = req.into_inner();
+        let Some(setter) = &self.log_level else {
+            return Err(tonic::Status::unimplemented(
+                "The log level cannot be changed at runtime",
+            ));
+        };
+        let previous =
+            setter(&req.level).map_err(|e| tonic::Status::invalid_argument(e.to_string()))?;
+        let generation = self.log_level_generation.fetch_add(1, Ordering::SeqCst) + 1;
+        info!(req.level, previous, req.duration_s, "Changed log level");
+        if req.duration_s > 0 {
+            let setter = setter.clone();
+            let current = self.log_level_generation.clone();
+            let previous = previous.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_secs(req.duration_s)).await;
+                if current.load(Ordering::SeqCst) != generation {
+                    return;
+                }
+                match setter(&previous) {
+                    Ok(_) => info!(previous, "Reverted log level"),
+                    Err(e) => error!(previous, "Failed to revert log level: {}", e),
+                }
+            });
+        }
+        Ok(tonic::Response::new(v1::SetLogLevelResponse { previous }))
+    }
+}
+
+/// Connect to a driver.
+async fn channel(address: &Address) -> anyhow::Result<Channel> {
+    match address {
+        Address::Unix(socket) => {
+            let socket = socket.clone();
+            // The URI is ignored, the connector always uses the socket
+            Ok(Endpoint::try_from("http://[::]:50051")?
+                .connect_with_connector(tower::service_fn(move |_: Uri| {
+                    tokio::net::UnixStream::connect(socket.clone())
+                }))
+                .await?)
+        }
+        Address::Tcp(addr) => Ok(Endpoint::from_shared(format!("http://{}", addr))?
+            .connect()
+            .await?),
+        Address::Vsock { .. } => {
+            anyhow::bail!("The admin client does not support vsock endpoints")
+        }
+    }
+}
+
+/// Connect to the admin service of a driver.
+pub async fn connect(address: &Address) -> anyhow::Result<v1::admin_client::AdminClient<Channel>> {
+    Ok(v1::admin_client::AdminClient::new(channel(address).await?))
+}
+
+#[cfg(feature = "admin-cli")]
+#[derive(clap::Args)]
+pub struct AdminFlags {
+    /// Endpoint of the driver, e.g. `unix:///csi/csi.sock`, `tcp://host:port` or a socket path.
+    /// Use the driver's UNIX socket or TCP endpoint for drivers listening on vsock.
+    #[clap(long, alias = "endpoint", env = "CSI_ENDPOINT")]
+    socket: Address,
+    #[clap(subcommand)]
+    command: AdminCommand,
+}
+
+#[cfg(feature = "admin-cli")]
+#[derive(clap::Subcommand)]
+enum AdminCommand {
+    /// Mark all bases of a family as expired
+    InvalidateFamily { family: String },
+    /// Show what the driver knows about a volume, with the likely causes of problems
+    Doctor { volume_id: String },
+    /// List what the cleanup would delete, with sizes and reasons, without deleting anything
+    CleanupReport,
+    /// Reclaim space in the upper directories of a volume
+    Compact { volume_id: String },
+    /// Change the log filter of the driver, e.g. to `debug` or `info,overlayfs_csi=debug`
+    LogLevel {
+        level: String,
+        /// Revert to the previous level after this duration
+        #[clap(long)]
+        for_s: Option<u64>,
+    },
+    /// Export a base as a tar archive. Re-running the command resumes an interrupted export.
+    Export {
+        family: String,
+        name: String,
+        #[clap(long, short)]
+        output: PathBuf,
+    },
+    /// Import a tar archive, e.g. created by `export`, as a new base
+    Import {
+        family: String,
+        name: String,
+        archive: PathBuf,
+    },
+    /// Back up bases of a family, by default its valid ones, from the driver to a directory of
+    /// the node or an `http(s)://` object store prefix
+    Backup {
+        family: String,
+        names: Vec<String>,
+        #[clap(long)]
+        to: String,
+    },
+    /// Restore backed up bases into a family
+    Restore {
+        family: String,
+        #[clap(required = true)]
+        names: Vec<String>,
+        #[clap(long)]
+        from: String,
+    },
+    /// Check a base against the manifest generated at its promotion (`--base-manifests`)
+    Verify { family: String, name: String },
+    /// List the paths that differ between two bases of a family, from their manifests
+    Diff {
+        family: String,
+        from: String,
+        to: String,
+    },
+    /// List the bases of a family containing a path, from their manifests
+    Contains { family: String, path: String },
+}
+
+/// Run an administrative command against a driver.
+#[cfg(feature = "admin-cli")]
+pub async fn run(flags: AdminFlags) -> anyhow::Result<()> {
+    match flags.command {
+        AdminCommand::InvalidateFamily { family } => {
+            let mut client = connect(&flags.socket).await?;
+            let resp = client
+                .invalidate_family(v1::InvalidateFamilyRequest {
+                    family: family.clone(),
+                })
+                .await?
+                .into_inner();
+            println!("Invalidated {} bases of {}", resp.bases.len(), family);
+            for base in resp.bases {
+                // Bases promoted from volumes are named after the encoded volume id
+                match crate::encoding::decode(&base) {
+                    Ok(id) if id != base => println!("{} (volume {})", base, id),
+                    _ => println!("{}", base),
+                }
+            }
+        }
+        AdminCommand::Doctor { volume_id } => {
+            let mut client = connect(&flags.socket).await?;
+            let resp = client
+                .diagnose(v1::DiagnoseRequest { volume_id })
+                .await?
+                .into_inner();
+            for finding in resp.findings {
+                println!("{}", finding);
+            }
+            if resp.causes.is_empty() {
+                println!("\nNo problem detected");
+            } else {
+                println!("\nLikely causes:");
+                for cause in resp.causes {
+                    println!("- {}", cause);
+                }
+            }
+        }
+        AdminCommand::CleanupReport => {
+            let mut client = connect(&flags.socket).await?;
+            let resp = client
+                .cleanup_report(v1::CleanupReportRequest {})
+                .await?
+                .into_inner();
+            if resp.actions.is_empty() {
+                println!("Nothing to clean up");
+            }
+            let mut total = 0;
+            for a in &resp.actions {
+                println!(
+                    "{} {} ({} bytes): {}",
+                    a.kind, a.name, a.size_bytes, a.reason
+                );
+                total += a.size_bytes;
+            }
+            if !resp.actions.is_empty() {
+                println!("\n{} deletions, {} bytes", resp.actions.len(), total);
+            }
+        }
+        AdminCommand::Compact { volume_id } => {
+            let mut client = connect(&flags.socket).await?;
+            let resp = client
+                .compact(v1::CompactRequest { volume_id })
+                .await?
+                .into_inner();
+            println!(
+                "Compacted {} overlays: removed {} files ({} bytes), {} directories and {} \
+                 whiteouts, deallocated {} bytes of zeros",
+                resp.overlays, resp.files, resp.bytes, resp.dirs, resp.whiteouts, resp.sparse_bytes
+            );
+        }
+        AdminCommand::LogLevel { level, for_s } => {
+            let mut client = connect(&flags.socket).await?;
+            let resp = client
+                .set_log_level(v1::SetLogLevelRequest {
+                    level: level.clone(),
+                    duration_s: for_s.unwrap_or_default(),
+                })
+                .await?
+                .into_inner();
+            match for_s {
+                Some(s) => println!("Log level {} for {}s, then {}", level, s, resp.previous),
+                None => println!("Log level {} (was {})", level, resp.previous),
+            }
+        }
+        AdminCommand::Export {
+            family,
+            name,
+            output,
+        } => {
+            let mut client = BaseTransferClient::new(channel(&flags.socket).await?);
+            transfer::download(&mut client, &family, &name, &output, None, &[]).await?;
+            println!("Exported {}/{} to {:?}", family, name, output);
+        }
+        AdminCommand::Import {
+            family,
+            name,
+            archive,
+        } => {
+            let mut client = BaseTransferClient::new(channel(&flags.socket).await?);
+            let base = transfer::upload(&mut client, &family, &name, &archive).await?;
+            println!("Imported {:?} as {}/{}", archive, family, base);
+        }
+        AdminCommand::Backup { family, names, to } => {
+            let mut client = connect(&flags.socket).await?;
+            let resp = client
+                .backup(v1::BackupRequest {
+                    family: family.clone(),
+                    names,
+                    location: to.clone(),
+                })
+                .await?
+                .into_inner();
+            for base in &resp.bases {
+                println!("{}/{} ({} bytes)", family, base.name, base.size_bytes);
+            }
+            println!("Backed up {} bases to {}", resp.bases.len(), to);
+        }
+        AdminCommand::Restore {
+            family,
+            names,
+            from,
+        } => {
+            let mut client = connect(&flags.socket).await?;
+            let resp = client
+                .restore(v1::RestoreRequest {
+                    family: family.clone(),
+                    names,
+                    location: from.clone(),
+                })
+                .await?
+                .into_inner();
+            for base in resp.bases {
+                println!("Restored {}/{} from {}", family, base, from);
+            }
+        }
+        AdminCommand::Verify { family, name } => {
+            let mut client = connect(&flags.socket).await?;
+            let diff = client
+                .verify_base(v1::VerifyBaseRequest {
+                    family: family.clone(),
+                    name: name.clone(),
+                })
+                .await?
+                .into_inner();
+            let intact =
+                diff.added.is_empty() && diff.removed.is_empty() && diff.changed.is_empty();
+            print_diff(&diff);
+            anyhow::ensure!(intact, "{}/{} differs from its manifest", family, name);
+            println!("{}/{} matches its manifest", family, name);
+        }
+        AdminCommand::Diff { family, from, to } => {
+            let mut client = connect(&flags.socket).await?;
+            let diff = client
+                .diff_bases(v1::DiffBasesRequest { family, from, to })
+                .await?
+                .into_inner();
+            print_diff(&diff);
+        }
+        AdminCommand::Contains { family, path } => {
+            let mut client = connect(&flags.socket).await?;
+            let resp = client
+                .find_in_bases(v1::FindInBasesRequest {
+                    family: family.clone(),
+                    path: path.clone(),
+                })
+                .await?
+                .into_inner();
+            for e in &resp.entries {
+                match e.kind.as_str() {
+                    "file" => println!(
+                        "{}/{}: {} bytes, sha256 {}",
+                        family, e.base, e.size, e.sha256
+                    ),
+                    kind => println!("{}/{}: {}", family, e.base, kind),
+                }
+            }
+            if resp.entries.is_empty() {
+                println!("No base of {} contains {}", family, path);
+            }
+            if !resp.unindexed.is_empty() {
+                println!(
+                    "Not searched, without manifest: {}",
+                    resp.unindexed.join(", ")
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "admin-cli")]
+fn print_diff(diff: &v1::ManifestDiff) {
+    for path in &diff.added {
+        println!("+ {}", path);
+    }
+    for path in &diff.removed {
+        println!("- {}", path);
+    }
+    for path in &diff.changed {
+        println!("~ {}", path);
+    }
+}
@@ -0,0 +1,121 @@
+//! Archival of the bases deleted for their age, so that an overly aggressive `max_age_s` does not
+//! destroy hours of cache-building work. Archived bases can be restored like backups.
+//!
+//! During the cleanup, the expired bases are snapshotted with hardlinks into `{bases}/.archive`,
+//! which is cheap enough to happen under the state lock. The snapshots are then compressed and
+//! stored in the archive location, and removed.
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use time::OffsetDateTime;
+use tracing::*;
+
+use crate::backup::{self, Location};
+use crate::base::{self, Base};
+use crate::Overlays;
+
+/// Directory of the bases volume holding the snapshots waiting to be archived
+const STAGING_DIR: &str = ".archive";
+
+impl Overlays {
+    /// Snapshot a base about to be deleted, for archival.
+    pub(crate) fn stage_for_archive(&self, base: &Base) -> anyhow::Result<()> {
+        let staged = Base(
+            self.flags
+                .bases
+                .join(STAGING_DIR)
+                .join(base.family())
+                .join(base.name()),
+        );
+        if staged.0.exists() {
+            staged.remove()?;
+        }
+        crate::transfer::pin(base, &staged.0)?;
+        staged.write_meta(&base.read_meta())?;
+        Ok(())
+    }
+    /// Store the staged snapshots in the archive location, then apply its TTL and budget.
+    pub(crate) async fn archive_staged(&self) {
+        let Some(location) = &self.flags.archive_location else {
+            return;
+        };
+        // A previous cleanup may still be archiving
+        let Ok(_archiving) = self.archiving.try_lock() else {
+            return;
+        };
+        let location = match Location::parse(location) {
+            Ok(location) => location,
+            Err(e) => {
+                error!(location, "Invalid archive location: {}", e);
+                return;
+            }
+        };
+        let staging = self.flags.bases.join(STAGING_DIR);
+        let staged = base::subdirs(&staging)
+            .into_iter()
+            .flatten()
+            .flat_map(|family| base::subdirs(&family).into_iter().flatten())
+            .map(Base);
+        for base in staged {
+            let work = crate::transfer::partial_dir(
+                &self.flags.bases,
+                &format!("archive-{}-{}", base.family(), base.name()),
+            );
+            match backup::store(&base, &base.0, &work, &location, true).await {
+                Ok(size_bytes) => info!(?base, size_bytes, "Archived base"),
+                // Retried at the next cleanup
+                Err(e) => {
+                    warn!(?base, "Failed to archive base: {}", e);
+                    continue;
+                }
+            }
+            if let Err(e) = base.remove() {
+                warn!(?base, "Failed to remove archived snapshot: {}", e);
+            }
+        }
+        if let Some(dir) = location.local_dir() {
+            if let Err(e) = self.prune_archive(dir) {
+                warn!(?dir, "Failed to prune the archive: {}", e);
+            }
+        }
+    }
+    /// Delete the archives older than `archive_ttl_s`, then the oldest ones until the archive fits
+    /// in `archive_max_bytes`.
+    fn prune_archive(&self, dir: &std::path::Path) -> anyhow::Result<()> {
+        // (archival time, description, archive, size)
+        let mut archives: Vec<(SystemTime, PathBuf, PathBuf, u64)> = vec![];
+        for family in base::subdirs(dir)? {
+            for entry in std::fs::read_dir(&family)? {
+                let path = entry?.path();
+                if path.extension().map_or(true, |e| e != "json") {
+                    continue;
+                }
+                let tar = path.with_extension("tar");
+                let Ok(size) = tar.metadata().map(|m| m.len()) else {
+                    continue;
+                };
+                archives.push((path.metadata()?.modified()?, path, tar, size));
+            }
+        }
+        archives.sort_by_key(|(time, ..)| *time);
+        let mut total: u64 = archives.iter().map(|(.., size)| size).sum();
+        let now = self.clock.now();
+        for (time, description, tar, size) in archives {
+            let age_s = (now - OffsetDateTime::from(time)).whole_seconds();
+            let expired = self.flags.archive_ttl_s.map_or(false, |ttl| age_s >= ttl);
+            let over_budget = self
+                .flags
+                .archive_max_bytes
+                .map_or(false, |max| total > max);
+            if !expired && !over_budget {
+                // The next ones are younger, and the archive is within its budget
+                break;
+            }
+            info!(?tar, age_s, size, "Pruning archived base");
+            std::fs::remove_file(&description)?;
+            std::fs::remove_file(&tar)?;
+            total -= size;
+        }
+        Ok(())
+    }
+}
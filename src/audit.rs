@@ -0,0 +1,62 @@
+//! In-memory log of recent volume operations, to follow a volume's journey when debugging.
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use time::OffsetDateTime;
+
+/// Number of records kept in memory
+const AUDIT_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone)]
+pub struct AuditRecord {
+    pub time: OffsetDateTime,
+    /// Identifier of the CSI call that triggered the operation
+    pub request_id: String,
+    pub volume_id: String,
+    pub operation: &'static str,
+    /// `None` on success, otherwise the error
+    pub error: Option<String>,
+}
+
+#[derive(Default)]
+pub struct AuditLog {
+    records: Mutex<VecDeque<AuditRecord>>,
+}
+impl AuditLog {
+    pub fn record(
+        &self,
+        request_id: &str,
+        volume_id: &str,
+        operation: &'static str,
+        result: &anyhow::Result<()>,
+    ) {
+        let mut records = self.records.lock().unwrap();
+        if records.len() == AUDIT_CAPACITY {
+            records.pop_front();
+        }
+        records.push_back(AuditRecord {
+            time: OffsetDateTime::now_utc(),
+            request_id: request_id.into(),
+            volume_id: volume_id.into(),
+            operation,
+            error: result.as_ref().err().map(|e| e.to_string()),
+        });
+    }
+    /// Recent records, oldest first
+    pub fn recent(&self) -> Vec<AuditRecord> {
+        self.records.lock().unwrap().iter().cloned().collect()
+    }
+    /// Recent records, unless they are being modified (e.g. by a panicking thread)
+    pub fn try_recent(&self) -> Option<Vec<AuditRecord>> {
+        Some(self.records.try_lock().ok()?.iter().cloned().collect())
+    }
+    pub fn for_volume(&self, volume_id: &str) -> Vec<AuditRecord> {
+        self.records
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|r| r.volume_id == volume_id)
+            .cloned()
+            .collect()
+    }
+}
@@ -0,0 +1,313 @@
+//! Backups of bases to a local directory or an HTTP object store, and their restoration, e.g.
+//! when reprovisioning a node.
+//!
+//! Each base is stored as `{location}/{family}/{name}.tar`, next to `{name}.json` describing it.
+//! HTTP object stores require the `http-client` feature.
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+#[cfg(feature = "http-client")]
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::*;
+
+use crate::base::{Base, BaseMeta};
+use crate::transfer;
+use crate::Overlays;
+
+#[cfg(feature = "http-client")]
+const CHUNK_SIZE: usize = 1 << 20;
+
+/// Description of a backed up base
+#[derive(Debug, Serialize, Deserialize)]
+struct BackupMeta {
+    family: String,
+    name: String,
+    /// Creation time of the base, as a UNIX timestamp
+    created: i64,
+    size_bytes: u64,
+    /// Hex-encoded SHA-256 of the archive
+    sha256: String,
+    meta: BaseMeta,
+}
+
+/// Where backups are stored
+pub(crate) enum Location {
+    Local(PathBuf),
+    /// Prefix of the objects, which are written with `PUT` and read with `GET`
+    #[cfg(feature = "http-client")]
+    Http(String),
+}
+impl Location {
+    pub(crate) fn parse(s: &str) -> anyhow::Result<Self> {
+        anyhow::ensure!(!s.is_empty(), "Missing backup location");
+        let http = s.starts_with("http://") || s.starts_with("https://");
+        #[cfg(feature = "http-client")]
+        if http {
+            return Ok(Self::Http(s.trim_end_matches('/').into()));
+        }
+        anyhow::ensure!(
+            !http,
+            "HTTP locations require the `http-client` cargo feature"
+        );
+        let path = PathBuf::from(s.strip_prefix("file://").unwrap_or(s));
+        anyhow::ensure!(path.is_absolute(), "The backup location must be absolute");
+        Ok(Self::Local(path))
+    }
+    /// Directory of a local location
+    pub(crate) fn local_dir(&self) -> Option<&Path> {
+        match self {
+            Self::Local(dir) => Some(dir),
+            #[cfg(feature = "http-client")]
+            Self::Http(_) => None,
+        }
+    }
+    /// Store the file at `src` as `key`, replacing any previous version atomically.
+    async fn put(&self, key: &str, src: &Path) -> anyhow::Result<()> {
+        match self {
+            Self::Local(dir) => {
+                let dst = dir.join(key);
+                let tmp = dir.join(format!("{}.partial", key));
+                std::fs::create_dir_all(dst.parent().unwrap())?;
+                tokio::fs::copy(src, &tmp).await?;
+                std::fs::rename(&tmp, &dst)?;
+            }
+            #[cfg(feature = "http-client")]
+            Self::Http(prefix) => {
+                let mut file = tokio::fs::File::open(src).await?;
+                let len = file.metadata().await?.len();
+                let (mut sender, body) = hyper::Body::channel();
+                let upload = tokio::spawn(async move {
+                    let mut buf = vec![0; CHUNK_SIZE];
+                    loop {
+                        let n = file.read(&mut buf).await?;
+                        if n == 0 {
+                            return anyhow::Ok(());
+                        }
+                        sender
+                            .send_data(hyper::body::Bytes::copy_from_slice(&buf[..n]))
+                            .await?;
+                    }
+                });
+                reqwest::Client::new()
+                    .put(format!("{}/{}", prefix, key))
+                    .header(reqwest::header::CONTENT_LENGTH, len)
+                    .body(reqwest::Body::from(body))
+                    .send()
+                    .await?
+                    .error_for_status()?;
+                upload.await??;
+            }
+        }
+        Ok(())
+    }
+    /// Retrieve `key` into the file `dst`.
+    async fn get(&self, key: &str, dst: &Path) -> anyhow::Result<()> {
+        match self {
+            Self::Local(dir) => {
+                tokio::fs::copy(dir.join(key), dst).await?;
+            }
+            #[cfg(feature = "http-client")]
+            Self::Http(prefix) => {
+                let mut response = reqwest::Client::new()
+                    .get(format!("{}/{}", prefix, key))
+                    .send()
+                    .await?
+                    .error_for_status()?;
+                let mut file = tokio::fs::File::create(dst).await?;
+                while let Some(chunk) = response.chunk().await? {
+                    file.write_all(&chunk).await?;
+                }
+                file.flush().await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn sha256(path: &Path) -> anyhow::Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+/// Create the archive of a directory, optionally compressed with gzip.
+async fn archive(dir: &Path, dst: &Path, compress: bool) -> anyhow::Result<()> {
+    let status = tokio::process::Command::new("tar")
+        .args(["-c", "--sort=name", "--numeric-owner"])
+        .args(compress.then_some("-z"))
+        .arg("-f")
+        .arg(dst)
+        .arg("-C")
+        .arg(dir)
+        .arg(".")
+        .status()
+        .await?;
+    anyhow::ensure!(status.success(), "tar failed with {}", status);
+    Ok(())
+}
+
+/// Store the archive of `dir`, holding the data of `base`, and its description at `location`,
+/// using `work` as scratch directory. Returns the size of the base.
+pub(crate) async fn store(
+    base: &Base,
+    dir: &Path,
+    work: &Path,
+    location: &Location,
+    compress: bool,
+) -> anyhow::Result<u64> {
+    let (family, name) = (base.family(), base.name());
+    let result = async {
+        std::fs::create_dir_all(work)?;
+        let tar = work.join("archive.tar");
+        archive(dir, &tar, compress).await?;
+        let meta = BackupMeta {
+            family: family.clone(),
+            name: name.clone(),
+            created: base.created()?.unix_timestamp(),
+            size_bytes: base.size(),
+            sha256: {
+                let tar = tar.clone();
+                tokio::task::spawn_blocking(move || sha256(&tar)).await??
+            },
+            meta: base.read_meta(),
+        };
+        let meta_file = work.join("meta.json");
+        std::fs::write(&meta_file, serde_json::to_vec_pretty(&meta)?)?;
+        // The description goes last, so that only complete backups are restored
+        location
+            .put(&format!("{}/{}.tar", family, name), &tar)
+            .await?;
+        location
+            .put(&format!("{}/{}.json", family, name), &meta_file)
+            .await?;
+        anyhow::Ok(meta.size_bytes)
+    }
+    .await;
+    let _ = std::fs::remove_dir_all(work);
+    result
+}
+
+impl Overlays {
+    /// Back up bases of a family (by default, its valid ones), returning their names and sizes.
+    pub(crate) async fn backup(
+        &self,
+        family: &str,
+        names: &[String],
+        location: &str,
+    ) -> anyhow::Result<Vec<(String, u64)>> {
+        anyhow::ensure!(transfer::valid_component(family), "Invalid family");
+        let location = Location::parse(location)?;
+        let bases: Vec<_> = if names.is_empty() {
+            self.family_bases(family)
+                .filter(|b| self.base_valid(b))
+                .collect()
+        } else {
+            names
+                .iter()
+                .map(|name| {
+                    anyhow::ensure!(transfer::valid_component(name), "Invalid base {:?}", name);
+                    let base = Base(self.flags.bases.join(family).join(name));
+                    anyhow::ensure!(base.created().is_ok(), "No base {}/{}", family, name);
+                    Ok(base)
+                })
+                .collect::<anyhow::Result<_>>()?
+        };
+        let mut backed_up = vec![];
+        for base in bases {
+            let name = base.name();
+            let work = transfer::partial_dir(&self.flags.bases, &format!("backup-{}", name));
+            let result = async {
+                // Snapshot the base, so that it stays consistent even if it gets cleaned up
+                let pinned = transfer::pinned_dir(&self.flags.bases, &name);
+                transfer::pin(&base, &pinned)?;
+                let stored = store(&base, &pinned, &work, &location, false).await;
+                std::fs::remove_dir_all(&pinned)?;
+                stored
+            }
+            .await;
+            let size = result.map_err(|e| e.context(format!("Failed to back up {:?}", base)))?;
+            info!(?base, "Backed up base");
+            backed_up.push((name, size));
+        }
+        Ok(backed_up)
+    }
+    /// Restore backed up bases into a family, as if they had just been created.
+    pub(crate) async fn restore_backup(
+        &self,
+        family: &str,
+        names: &[String],
+        location: &str,
+    ) -> anyhow::Result<Vec<String>> {
+        anyhow::ensure!(transfer::valid_component(family), "Invalid family");
+        anyhow::ensure!(!names.is_empty(), "No base to restore");
+        let location = Location::parse(location)?;
+        let mut restored = vec![];
+        for name in names {
+            anyhow::ensure!(transfer::valid_component(name), "Invalid base {:?}", name);
+            let base = Base(self.flags.bases.join(family).join(name));
+            anyhow::ensure!(
+                !base.0.exists(),
+                "The base {}/{} already exists",
+                family,
+                name
+            );
+            let work = transfer::partial_dir(&self.flags.bases, &format!("restore-{}", name));
+            let result = async {
+                std::fs::create_dir_all(&work)?;
+                let meta_file = work.join("meta.json");
+                location
+                    .get(&format!("{}/{}.json", family, name), &meta_file)
+                    .await?;
+                let meta: BackupMeta = serde_json::from_slice(&std::fs::read(&meta_file)?)?;
+                anyhow::ensure!(
+                    meta.family == family && meta.name == *name,
+                    "The backup describes {}/{}",
+                    meta.family,
+                    meta.name
+                );
+                let tar = work.join("archive.tar");
+                location
+                    .get(&format!("{}/{}.tar", family, name), &tar)
+                    .await?;
+                let digest = {
+                    let tar = tar.clone();
+                    tokio::task::spawn_blocking(move || sha256(&tar)).await??
+                };
+                anyhow::ensure!(digest == meta.sha256, "Checksum mismatch");
+                let dir = work.join("base");
+                transfer::extract(&tar, &dir).await?;
+                anyhow::ensure!(
+                    dir.join(Base::as_base_filename()).exists(),
+                    "The archive is not a base"
+                );
+                std::fs::create_dir_all(base.0.parent().unwrap())?;
+                std::fs::rename(&dir, &base.0)?;
+                // Restart the lifecycle of the base, in the current epoch of the family
+                base.write_time(self.clock.now())?;
+                base.write_meta(&BaseMeta {
+                    volume_id: meta.meta.volume_id,
+                    epoch: self.epochs.get(family),
+                    size_bytes: Some(meta.size_bytes),
+                    generation: meta.meta.generation,
+                    max_age_s: meta.meta.max_age_s,
+                    ..Default::default()
+                })
+            }
+            .await;
+            let _ = std::fs::remove_dir_all(&work);
+            if let Err(e) = result {
+                let _ = std::fs::remove_dir_all(&base.0);
+                return Err(e.context(format!("Failed to restore {:?}", base)));
+            }
+            info!(?base, "Restored base");
+            restored.push(name.clone());
+        }
+        self.state_changed.send_replace(());
+        Ok(restored)
+    }
+}
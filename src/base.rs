@@ -0,0 +1,291 @@
+//! Bases, i.e. former volumes used as lower directories for the overlays.
+use std::io::{BufRead, BufReader};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tracing::*;
+
+/// Family used when the volume context does not specify one
+pub const DEFAULT_FAMILY: &str = "default";
+
+/// Age limit for a `max_age_s`, where 0 means that bases never age out
+pub(crate) fn age_limit(max_age_s: i64) -> i64 {
+    if max_age_s == 0 {
+        i64::MAX
+    } else {
+        max_age_s
+    }
+}
+
+/// Metadata stored next to a base, in `{family}/{id}.meta.json`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BaseMeta {
+    /// Volume from which the base was created
+    #[serde(default)]
+    pub volume_id: Option<String>,
+    /// Invalidation epoch of the family when the base was created
+    #[serde(default)]
+    pub epoch: Option<String>,
+    /// Set by an administrator to stop using the base for new volumes
+    #[serde(default)]
+    pub invalidated: bool,
+    /// Disk usage, computed once as bases are immutable
+    #[serde(default)]
+    pub size_bytes: Option<u64>,
+    /// Newer base of the family replacing this one, with the `always` promotion policy
+    #[serde(default)]
+    pub superseded_by: Option<String>,
+    /// Position of the base among the promotions of its family, starting at 1
+    #[serde(default)]
+    pub generation: Option<u64>,
+    /// Overrides `max_age_s`, from the `maxAgeS` parameter of the volume
+    #[serde(default)]
+    pub max_age_s: Option<i64>,
+    /// Container image from which the base was populated
+    #[serde(default)]
+    pub image: Option<String>,
+    /// Remote directory from which the base was populated
+    #[serde(default)]
+    pub source: Option<String>,
+}
+
+/// Name of a base promoted from the volume `volume` (already encoded), following the
+/// `base_name` template.
+pub(crate) fn render_name(
+    template: &str,
+    volume: &str,
+    family: &str,
+    generation: u64,
+    now: OffsetDateTime,
+) -> anyhow::Result<String> {
+    let timestamp = now.format(&time::format_description::parse(
+        "[year][month][day]T[hour][minute][second]Z",
+    )?)?;
+    let name = template
+        .replace("{volume}", volume)
+        .replace("{family}", family)
+        .replace("{timestamp}", &timestamp)
+        .replace("{generation}", &generation.to_string());
+    anyhow::ensure!(
+        !name.contains(['{', '}']),
+        "Unknown placeholder in the base name {:?}",
+        template
+    );
+    anyhow::ensure!(
+        !name.is_empty() && !name.starts_with('.') && !name.contains('/'),
+        "Invalid base name {:?}",
+        name
+    );
+    Ok(name)
+}
+
+/// Base for the overlays, located at `{bases}/{family}/{id}`
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+pub(crate) struct Base(pub(crate) PathBuf);
+impl Base {
+    /// Marker for volumes that can be transformed into bases.
+    /// Once transformer, the file contains the creation date.
+    pub(crate) fn as_base_filename() -> &'static str {
+        ".as_base"
+    }
+    fn as_base_file(&self) -> PathBuf {
+        self.0.join(Self::as_base_filename())
+    }
+    pub(crate) fn name(&self) -> String {
+        self.0
+            .file_name()
+            .map_or_else(Default::default, |n| n.to_string_lossy().into_owned())
+    }
+    pub(crate) fn family(&self) -> String {
+        self.0
+            .parent()
+            .and_then(Path::file_name)
+            .map_or_else(Default::default, |n| n.to_string_lossy().into_owned())
+    }
+    fn meta_file(&self) -> PathBuf {
+        self.0.with_file_name(format!("{}.meta.json", self.name()))
+    }
+    pub(crate) fn read_meta(&self) -> BaseMeta {
+        std::fs::read(self.meta_file())
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default()
+    }
+    pub(crate) fn write_meta(&self, meta: &BaseMeta) -> anyhow::Result<()> {
+        std::fs::write(self.meta_file(), serde_json::to_vec(meta)?)?;
+        Ok(())
+    }
+    /// Remove the base and its metadata from disk.
+    pub(crate) fn remove(&self) -> anyhow::Result<()> {
+        std::fs::remove_dir_all(&self.0)?;
+        let _ = std::fs::remove_file(self.meta_file());
+        self.remove_manifest();
+        Ok(())
+    }
+    pub(crate) fn write_time(&self, now: OffsetDateTime) -> anyhow::Result<()> {
+        std::fs::write(self.as_base_file(), now.format(&Rfc3339)?)?;
+        Ok(())
+    }
+    /// Disk usage of the base, cached in the metadata.
+    pub(crate) fn size(&self) -> u64 {
+        let mut meta = self.read_meta();
+        if let Some(size) = meta.size_bytes {
+            return size;
+        }
+        let size = crate::stats::disk_usage(&self.0) as u64;
+        meta.size_bytes = Some(size);
+        if let Err(e) = self.write_meta(&meta) {
+            warn!(?self, "Failed to store base size: {}", e);
+        }
+        size
+    }
+    pub(crate) fn created(&self) -> anyhow::Result<OffsetDateTime> {
+        self.read_time()
+    }
+    fn read_time(&self) -> anyhow::Result<OffsetDateTime> {
+        let data = std::fs::read_to_string(self.0.join(self.as_base_file()))?;
+        Ok(OffsetDateTime::parse(&data, &Rfc3339)?)
+    }
+    /// First time after the creation of the base in a rotation schedule
+    pub(crate) fn rotation(&self, schedule: &cron::Schedule) -> Option<OffsetDateTime> {
+        let created = self.read_time().ok()?;
+        let created =
+            chrono::TimeZone::timestamp_opt(&chrono::Utc, created.unix_timestamp(), 0).single()?;
+        let next = schedule.after(&created).next()?;
+        OffsetDateTime::from_unix_timestamp(next.timestamp()).ok()
+    }
+    /// Replace the files identical to those at the same path in `previous` by hardlinks, returning
+    /// the number of bytes saved.
+    pub(crate) fn dedup(&self, previous: &Base) -> anyhow::Result<u64> {
+        dedup_dir(&self.0, &previous.0)
+    }
+}
+
+fn dedup_dir(dir: &Path, previous: &Path) -> anyhow::Result<u64> {
+    let mut saved = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let other = previous.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            if std::fs::symlink_metadata(&other).map_or(false, |m| m.is_dir()) {
+                saved += dedup_dir(&path, &other)?;
+            }
+        } else if file_type.is_file() && identical(&path, &other)? {
+            let mut tmp = path.as_os_str().to_owned();
+            tmp.push(".dedup");
+            std::fs::hard_link(&other, &tmp)?;
+            std::fs::rename(&tmp, &path)?;
+            saved += entry.metadata()?.len();
+        }
+    }
+    Ok(saved)
+}
+
+/// Whether two regular files on the same device, not already hardlinked, have the same content,
+/// permissions and ownership.
+fn identical(a: &Path, b: &Path) -> anyhow::Result<bool> {
+    let Ok(mb) = std::fs::symlink_metadata(b) else {
+        return Ok(false);
+    };
+    let ma = std::fs::symlink_metadata(a)?;
+    if !mb.is_file()
+        || ma.len() != mb.len()
+        || ma.mode() != mb.mode()
+        || ma.uid() != mb.uid()
+        || ma.gid() != mb.gid()
+        || ma.dev() != mb.dev()
+        || ma.ino() == mb.ino()
+    {
+        return Ok(false);
+    }
+    same_content(a, b)
+}
+
+/// Compare the contents of two files.
+pub(crate) fn same_content(a: &Path, b: &Path) -> anyhow::Result<bool> {
+    let mut fa = BufReader::new(std::fs::File::open(a)?);
+    let mut fb = BufReader::new(std::fs::File::open(b)?);
+    loop {
+        let (ba, bb) = (fa.fill_buf()?, fb.fill_buf()?);
+        if ba.is_empty() || bb.is_empty() {
+            return Ok(ba.is_empty() && bb.is_empty());
+        }
+        let n = ba.len().min(bb.len());
+        if ba[..n] != bb[..n] {
+            return Ok(false);
+        }
+        fa.consume(n);
+        fb.consume(n);
+    }
+}
+
+/// Subdirectories of a directory
+pub(crate) fn subdirs(path: &Path) -> std::io::Result<impl Iterator<Item = PathBuf>> {
+    Ok(std::fs::read_dir(path)?
+        .filter_map(Result::ok)
+        .filter(|x| x.file_type().map_or(false, |t| t.is_dir()))
+        .map(|x| x.path()))
+}
+
+/// Move bases created before families were introduced, located at `{bases}/{id}`, into the
+/// default family.
+pub(crate) fn migrate_legacy_bases(bases: &Path) -> anyhow::Result<()> {
+    let default = bases.join(DEFAULT_FAMILY);
+    for dir in subdirs(bases)? {
+        if dir.join(Base::as_base_filename()).exists() {
+            std::fs::create_dir_all(&default)?;
+            let dst = default.join(dir.file_name().unwrap());
+            info!(src = ?dir, ?dst, "Moving legacy base into the default family");
+            std::fs::rename(&dir, &dst)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    fn rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = Base(dir.path().join("family").join("base"));
+        std::fs::create_dir_all(&base.0).unwrap();
+        let hourly = cron::Schedule::from_str("0 0 * * * *").unwrap();
+        // Without creation time
+        assert_eq!(base.rotation(&hourly), None);
+        // 2023-11-14T22:13:20Z
+        let created = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+        base.write_time(created).unwrap();
+        assert_eq!(base.created().unwrap(), created);
+        assert_eq!(
+            base.rotation(&hourly),
+            Some(OffsetDateTime::from_unix_timestamp(1_700_000_000 + 2800).unwrap())
+        );
+    }
+
+    #[test]
+    fn render_name() {
+        let now = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+        assert_eq!(
+            super::render_name(
+                "{family}-{volume}-{generation}-{timestamp}",
+                "v",
+                "f",
+                3,
+                now
+            )
+            .unwrap(),
+            "f-v-3-20231114T221320Z"
+        );
+        assert!(super::render_name("{unknown}", "v", "f", 1, now).is_err());
+        assert!(super::render_name(".{volume}", "v", "f", 1, now).is_err());
+    }
+}
@@ -0,0 +1,197 @@
+//! End-to-end check of an installation: on the given node, a first pod writes data into a volume
+//! of a throwaway family and marks it as a base, then a second pod verifies that its volume is an
+//! overlay on top of that base, containing the data.
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+use k8s_openapi::api::core::v1::Pod;
+use kube::api::{DeleteParams, LogParams, PostParams};
+use kube::config::{KubeConfigOptions, Kubeconfig};
+use kube::Api;
+use tracing::*;
+
+#[derive(Parser)]
+struct Flags {
+    /// Node on which the pods are scheduled
+    #[clap(long)]
+    node: String,
+    /// Kubeconfig file, by default `$KUBECONFIG`, `~/.kube/config` or the in-cluster configuration
+    #[clap(long)]
+    kubeconfig: Option<PathBuf>,
+    /// Context of the kubeconfig, by default the current one
+    #[clap(long)]
+    context: Option<String>,
+    /// Namespace of the test pods
+    #[clap(long, default_value = "default")]
+    namespace: String,
+    /// Name of the CSI driver
+    #[clap(long, default_value = "overlayfs.csi.k8s.io")]
+    driver: String,
+    /// Image of the test pods, which must provide `sh`
+    #[clap(long, default_value = "debian:bullseye-slim")]
+    image: String,
+    /// Labels of the test pods, as `key=value`, e.g. to match `--producer-selector`
+    #[clap(long = "label", value_parser = parse_label)]
+    labels: Vec<(String, String)>,
+    /// Maximal duration of each step
+    #[clap(long, default_value_t = 300)]
+    timeout_s: u64,
+}
+
+fn parse_label(s: &str) -> anyhow::Result<(String, String)> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow::anyhow!("Expected key=value, got {}", s))?;
+    Ok((key.into(), value.into()))
+}
+
+/// Test pod running `script` on a volume of `family`, mounted at `/data`
+fn pod(flags: &Flags, name: &str, family: &str, script: &str) -> anyhow::Result<Pod> {
+    let labels: BTreeMap<_, _> = flags.labels.iter().cloned().collect();
+    Ok(serde_json::from_value(serde_json::json!({
+        "metadata": {"name": name, "labels": labels},
+        "spec": {
+            "nodeName": flags.node,
+            "restartPolicy": "Never",
+            "terminationGracePeriodSeconds": 1,
+            "volumes": [{
+                "name": "data",
+                "csi": {
+                    "driver": flags.driver,
+                    // The base expires shortly after the test
+                    "volumeAttributes": {"family": family, "maxAgeS": "600"},
+                },
+            }],
+            "containers": [{
+                "name": "test",
+                "image": flags.image,
+                "command": ["sh", "-c", script],
+                "volumeMounts": [{"name": "data", "mountPath": "/data"}],
+            }],
+        },
+    }))?)
+}
+
+struct Test {
+    pods: Api<Pod>,
+    timeout: Duration,
+    created: Vec<String>,
+}
+impl Test {
+    /// Run a pod to completion, failing with its logs if it does not succeed.
+    async fn run(&mut self, pod: Pod) -> anyhow::Result<()> {
+        let name = pod.metadata.name.clone().unwrap_or_default();
+        self.pods.create(&PostParams::default(), &pod).await?;
+        self.created.push(name.clone());
+        let start = Instant::now();
+        loop {
+            let pod = self.pods.get(&name).await?;
+            let phase = pod.status.and_then(|s| s.phase).unwrap_or_default();
+            match phase.as_str() {
+                "Succeeded" => return Ok(()),
+                "Failed" => {
+                    let logs = self.pods.logs(&name, &LogParams::default()).await?;
+                    anyhow::bail!("Pod {} failed: {}", name, logs.trim());
+                }
+                _ if start.elapsed() > self.timeout => {
+                    anyhow::bail!(
+                        "Pod {} still {} after {:?}, see `kubectl describe pod {}`",
+                        name,
+                        phase,
+                        self.timeout,
+                        name
+                    );
+                }
+                _ => tokio::time::sleep(Duration::from_secs(2)).await,
+            }
+        }
+    }
+    /// Delete a pod and wait until it is gone, i.e. its volume was unpublished.
+    async fn delete(&mut self, name: &str) -> anyhow::Result<()> {
+        self.pods.delete(name, &DeleteParams::default()).await?;
+        let start = Instant::now();
+        while self.pods.get_opt(name).await?.is_some() {
+            anyhow::ensure!(
+                start.elapsed() < self.timeout,
+                "Pod {} not deleted after {:?}",
+                name,
+                self.timeout
+            );
+            tokio::time::sleep(Duration::from_secs(2)).await;
+        }
+        self.created.retain(|n| n != name);
+        Ok(())
+    }
+    async fn cleanup(&mut self) {
+        for name in std::mem::take(&mut self.created) {
+            if let Err(e) = self.pods.delete(&name, &DeleteParams::default()).await {
+                warn!(name, "Failed to delete pod: {}", e);
+            }
+        }
+    }
+}
+
+async fn client(flags: &Flags) -> anyhow::Result<kube::Client> {
+    let options = KubeConfigOptions {
+        context: flags.context.clone(),
+        ..Default::default()
+    };
+    let config = match &flags.kubeconfig {
+        Some(path) => {
+            let kubeconfig = Kubeconfig::read_from(path)?;
+            kube::Config::from_custom_kubeconfig(kubeconfig, &options).await?
+        }
+        None if flags.context.is_some() => kube::Config::from_kubeconfig(&options).await?,
+        None => kube::Config::infer().await?,
+    };
+    Ok(kube::Client::try_from(config)?)
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info")),
+        )
+        .init();
+    let flags = Flags::parse();
+    let client = client(&flags).await?;
+    let now = time::OffsetDateTime::now_utc().unix_timestamp();
+    let family = format!("smoke-test-{}", now);
+    let token = format!("{}-{}", family, std::process::id());
+    let mut test = Test {
+        pods: Api::namespaced(client, &flags.namespace),
+        timeout: Duration::from_secs(flags.timeout_s),
+        created: vec![],
+    };
+    let result = async {
+        info!(flags.node, family, "Writing data into a new volume");
+        let writer = format!("{}-write", family);
+        let script = format!("echo {} > /data/smoke && touch /data/.as_base", token);
+        test.run(pod(&flags, &writer, &family, &script)?).await?;
+        info!("Deleting the pod, promoting its volume into a base");
+        test.delete(&writer).await?;
+        info!("Mounting the base in a new volume");
+        let reader = format!("{}-read", family);
+        let script = format!(
+            "grep -q ' /data overlay ' /proc/mounts || {{ echo 'The volume is not an overlay, \
+             the base was not promoted' >&2; exit 1; }}; [ \"$(cat /data/smoke)\" = {} ] || {{ \
+             echo 'The data of the base is missing' >&2; exit 1; }}",
+            token
+        );
+        test.run(pod(&flags, &reader, &family, &script)?).await?;
+        test.delete(&reader).await
+    }
+    .await;
+    test.cleanup().await;
+    result?;
+    println!(
+        "Smoke test passed on {}: data written to a volume was promoted into a base and \
+         visible in an overlay. The base {} expires after 10 minutes.",
+        flags.node, family
+    );
+    Ok(())
+}
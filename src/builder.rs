@@ -0,0 +1,331 @@
+//! Programmatic construction of [`Overlays`], without going through command-line parsing.
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use tokio::sync::Semaphore;
+use tracing::*;
+
+use crate::clock::{AcceleratedClock, Clock, SystemClock};
+use crate::hooks::Hooks;
+use crate::mount::{self, Mounter, PropagatingMounter};
+use crate::peers::Peers;
+use crate::pods::PodApi;
+use crate::ramcache::RamCache;
+use crate::selection::BaseSelectionPolicy;
+use crate::webhook::Webhook;
+use crate::{metrics, stats, OverlayFlags, Overlays, PodUid, BASE_CLEANUP_FREQ_S};
+
+pub struct OverlaysBuilder {
+    flags: OverlayFlags,
+    bases_host: Option<PathBuf>,
+    pods: Option<Arc<dyn PodApi>>,
+    mounter: Arc<dyn Mounter>,
+    cleanup_interval: Option<Duration>,
+    clock: Arc<dyn Clock>,
+    base_selection: Option<Arc<dyn BaseSelectionPolicy>>,
+    epochs: BTreeMap<String, String>,
+}
+impl OverlaysBuilder {
+    pub fn new(
+        node: impl Into<String>,
+        namespace: impl Into<String>,
+        bases: impl Into<PathBuf>,
+    ) -> Self {
+        Self::from_flags(OverlayFlags {
+            node: node.into(),
+            namespace: namespace.into(),
+            bases: bases.into(),
+            ..Default::default()
+        })
+    }
+    pub fn from_flags(flags: OverlayFlags) -> Self {
+        Self {
+            mounter: mount::system_mounter(flags.mount_command),
+            flags,
+            bases_host: None,
+            pods: None,
+            cleanup_interval: Some(Duration::from_secs(BASE_CLEANUP_FREQ_S)),
+            clock: Arc::new(SystemClock),
+            base_selection: None,
+            epochs: Default::default(),
+        }
+    }
+    /// Directory where kubelet keeps pod volumes
+    pub fn pods_dir(mut self, pods: impl Into<PathBuf>) -> Self {
+        self.flags.pods = pods.into();
+        self
+    }
+    /// Location of the bases directory as seen from the pods directory.
+    /// By default, this is derived from the `POD_ID` environment variable.
+    pub fn bases_host(mut self, bases_host: impl Into<PathBuf>) -> Self {
+        self.bases_host = Some(bases_host.into());
+        self
+    }
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.flags.max_age_s = max_age.as_secs() as i64;
+        self
+    }
+    /// Age after which unused bases are deleted, by default the same as `max_age`
+    pub fn hard_max_age(mut self, hard_max_age: Duration) -> Self {
+        self.flags.hard_max_age_s = Some(hard_max_age.as_secs() as i64);
+        self
+    }
+    /// Size of each volume, as a Kubernetes quantity
+    pub fn size_limit(mut self, size_limit: impl Into<String>) -> Self {
+        self.flags.size_limit = size_limit.into();
+        self
+    }
+    pub fn stats_ttl(mut self, ttl: Duration) -> Self {
+        self.flags.stats_ttl_s = ttl.as_secs();
+        self
+    }
+    pub fn pod_api(mut self, pods: impl PodApi + 'static) -> Self {
+        self.pods = Some(Arc::new(pods));
+        self
+    }
+    /// Manifest used as template for the data pods instead of the built-in one
+    pub fn data_pod_template(mut self, path: impl Into<PathBuf>) -> Self {
+        self.flags.data_pod_template = Some(path.into());
+        self
+    }
+    /// Mounter used instead of the one of the system, e.g. a shared
+    /// [`MockMounter`](crate::mount::MockMounter) to inspect the mounts.
+    pub fn mounter(mut self, mounter: impl Mounter + 'static) -> Self {
+        self.mounter = Arc::new(mounter);
+        self
+    }
+    /// Source of the current time for the age of the bases, e.g. a
+    /// [`MockClock`](crate::clock::MockClock) to test expiry and rotations.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+    /// Choice of the base of new volumes, instead of the one of the `base_selection` and
+    /// `family_fallback` flags
+    pub fn base_selection(mut self, policy: impl BaseSelectionPolicy + 'static) -> Self {
+        self.base_selection = Some(Arc::new(policy));
+        self
+    }
+    /// Invalidation epochs of the families at startup, i.e. the data of the invalidation
+    /// ConfigMap (see [`invalidation::load`](crate::invalidation::load)). Without them, the bases
+    /// that recorded an epoch count as invalidated until the ConfigMap is watched.
+    pub fn epochs(mut self, epochs: BTreeMap<String, String>) -> Self {
+        self.epochs = epochs;
+        self
+    }
+    /// Make time advance `multiplier` times faster, see [`AcceleratedClock`]
+    pub fn time_multiplier(mut self, multiplier: f64) -> Self {
+        self.flags.time_multiplier = Some(multiplier);
+        self
+    }
+    /// Interval between cleanups of stale bases, or `None` to disable the background cleanup task.
+    pub fn cleanup_interval(mut self, interval: Option<Duration>) -> Self {
+        self.cleanup_interval = interval;
+        self
+    }
+    pub async fn build(mut self) -> anyhow::Result<Arc<Overlays>> {
+        let pods = self.pods.context("A pod API is required")?;
+        check_features(&self.flags)?;
+        // Fail now rather than at the first volume of a family populated from an image
+        #[cfg(feature = "oci-import")]
+        if !self.flags.family_image.is_empty()
+            || self.flags.seed.iter().any(|s| s.image().is_some())
+        {
+            crate::image::check_export_command(&self.flags.image_export_command)?;
+        }
+        if let Some(multiplier) = self.flags.time_multiplier {
+            anyhow::ensure!(multiplier > 0.0, "The time multiplier must be positive");
+            warn!(multiplier, "Simulation mode: time is accelerated");
+            self.clock = Arc::new(AcceleratedClock::new(self.clock, multiplier));
+            // Keep up with the expiries
+            self.cleanup_interval = self.cleanup_interval.map(|i| i.div_f64(multiplier));
+        }
+        // Fail on a malformed base name now rather than at the first promotion
+        crate::base::render_name(
+            &self.flags.base_name,
+            "volume",
+            "family",
+            1,
+            self.clock.now(),
+        )?;
+        let mut overlays = Overlays {
+            policy: crate::policy::Policy::from_flags(&self.flags)?,
+            base_selection: self
+                .base_selection
+                .unwrap_or_else(|| crate::selection::from_flags(&self.flags)),
+            stats: stats::StatsCache::new(Duration::from_secs(self.flags.stats_ttl_s)),
+            hooks: Hooks::new(self.flags.hooks.clone()),
+            metrics: metrics::Metrics::new(&self.flags.metrics)?,
+            peers: Peers::new(
+                self.flags.peers.clone(),
+                self.flags.node.clone(),
+                pods.clone(),
+            )?,
+            webhook: Webhook::new(
+                self.flags.webhook_url.clone(),
+                self.flags.node.clone(),
+                self.flags.webhook_retries,
+            ),
+            volume_slots: self.flags.max_volumes.map(crate::slots::Slots::new),
+            pod_creations: self.flags.max_concurrent_pod_creations.map(Semaphore::new),
+            ram_cache: self.flags.ram_cache_dir.clone().map(|dir| {
+                RamCache::new(
+                    dir,
+                    self.flags.ram_cache_max_bytes,
+                    self.flags.ram_cache_min_available_bytes,
+                )
+            }),
+            data_pod_template: crate::datapod::load(self.flags.data_pod_template.as_deref())?,
+            mounter: match (self.flags.overlay_propagation, self.flags.bind_propagation) {
+                (None, None) => self.mounter,
+                (overlay, bind) => Arc::new(PropagatingMounter {
+                    inner: self.mounter,
+                    overlay,
+                    bind,
+                }),
+            },
+            flags: self.flags,
+            pods,
+            bases_host: Default::default(),
+            lock: Default::default(),
+            data_pods: Default::default(),
+            watchdog: Default::default(),
+            state_changed: tokio::sync::watch::channel(()).0,
+            audit: Default::default(),
+            epochs: Default::default(),
+            clock: self.clock,
+            archiving: Default::default(),
+        };
+        overlays.epochs.set(self.epochs);
+        crate::base::migrate_legacy_bases(&overlays.flags.bases)?;
+        crate::propagation::check(
+            &overlays.flags.pods,
+            overlays.flags.propagation_check,
+            overlays.mounter.as_ref(),
+        )?;
+        overlays.bases_host = match self.bases_host {
+            Some(bases_host) => bases_host,
+            None => overlays.empty_dir(
+                PodUid(std::env::var("POD_ID").context("Failed to find pod ID from environment")?),
+                "bases",
+            ),
+        };
+        overlays.restore().await?;
+        let overlays = Arc::new(overlays);
+        crate::crashdump::install(&overlays);
+        crate::snapshot::spawn_on_sigusr1(&overlays);
+        if let Some(interval) = self.cleanup_interval {
+            tokio::task::spawn({
+                let overlays = overlays.clone();
+                async move {
+                    loop {
+                        if let Err(e) = overlays.cleanup().await {
+                            error!("Failed to cleanup bases: {}", e);
+                        }
+                        tokio::time::sleep(interval).await;
+                    }
+                }
+            });
+        }
+        if !overlays.flags.seed.is_empty() {
+            tokio::task::spawn({
+                let overlays = overlays.clone();
+                async move {
+                    loop {
+                        overlays.seed_bases().await;
+                        let Some(interval) = overlays.flags.seed_interval_s else {
+                            break;
+                        };
+                        tokio::time::sleep(Duration::from_secs(interval)).await;
+                    }
+                }
+            });
+        }
+        if let Some(interval) = overlays.flags.compaction_interval_s {
+            tokio::task::spawn({
+                let overlays = overlays.clone();
+                async move {
+                    loop {
+                        tokio::time::sleep(Duration::from_secs(interval)).await;
+                        overlays.compact().await;
+                    }
+                }
+            });
+        }
+        #[cfg(all(feature = "metrics", feature = "http-client"))]
+        if let Some(url) = overlays.flags.metrics.metrics_push_url.clone() {
+            let interval = Duration::from_secs(overlays.flags.metrics.metrics_push_interval_s);
+            metrics::spawn_push(overlays.clone(), url, interval);
+        }
+        if let Some(threshold) = overlays.flags.watchdog_threshold_s {
+            tokio::task::spawn({
+                let overlays = overlays.clone();
+                let threshold = Duration::from_secs(threshold);
+                async move {
+                    loop {
+                        tokio::time::sleep(threshold / 2).await;
+                        overlays.watchdog.check(&overlays.lock, threshold);
+                    }
+                }
+            });
+        }
+        if let Some(interval) = overlays.flags.orphan_pod_gc_interval_s {
+            tokio::task::spawn({
+                let overlays = overlays.clone();
+                async move {
+                    loop {
+                        tokio::time::sleep(Duration::from_secs(interval)).await;
+                        if let Err(e) = overlays.collect_orphan_pods().await {
+                            error!("Failed to collect orphan data pods: {}", e);
+                        }
+                    }
+                }
+            });
+        }
+        Ok(overlays)
+    }
+}
+
+/// Reject the flags of the optional subsystems left out of the build.
+fn check_features(flags: &OverlayFlags) -> anyhow::Result<()> {
+    let requires = |flag: &str, feature: &str, set: bool| {
+        anyhow::ensure!(
+            !set,
+            "--{} requires the `{}` cargo feature, which this build lacks",
+            flag,
+            feature
+        );
+        Ok(())
+    };
+    if !cfg!(feature = "metrics") {
+        requires("status-page", "metrics", flags.metrics.status_page)?;
+        requires(
+            "metrics-push-url",
+            "metrics",
+            flags.metrics.metrics_push_url.is_some(),
+        )?;
+    }
+    if !cfg!(feature = "http-client") {
+        requires(
+            "metrics-push-url",
+            "http-client",
+            flags.metrics.metrics_push_url.is_some(),
+        )?;
+        requires("webhook-url", "http-client", flags.webhook_url.is_some())?;
+    }
+    if !cfg!(feature = "oci-import") {
+        requires("family-image", "oci-import", !flags.family_image.is_empty())?;
+        requires(
+            "seed family=<oci-ref>",
+            "oci-import",
+            flags.seed.iter().any(|s| s.image().is_some()),
+        )?;
+    }
+    if let Some(location) = &flags.archive_location {
+        crate::backup::Location::parse(location)?;
+    }
+    Ok(())
+}
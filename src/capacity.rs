@@ -0,0 +1,48 @@
+//! Maximum number of volumes advertised to the scheduler, derived from the free space of the
+//! node, so that pods are not placed on nodes that cannot hold their volumes.
+use crate::Overlays;
+
+/// Bytes in a Kubernetes quantity, e.g. `10Gi` or `500M`
+pub(crate) fn parse_quantity(s: &str) -> anyhow::Result<u64> {
+    let digits = s.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let multiplier: u64 = match &s[digits.len()..] {
+        "" => 1,
+        "k" => 1000,
+        "M" => 1000u64.pow(2),
+        "G" => 1000u64.pow(3),
+        "T" => 1000u64.pow(4),
+        "Ki" => 1 << 10,
+        "Mi" => 1 << 20,
+        "Gi" => 1 << 30,
+        "Ti" => 1 << 40,
+        suffix => anyhow::bail!("Unsupported suffix {:?} in quantity {:?}", suffix, s),
+    };
+    let value: u64 = digits
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Invalid quantity {:?}", s))?;
+    Ok(value * multiplier)
+}
+
+impl Overlays {
+    /// Maximum number of volumes advertised for the node, 0 if unlimited. With
+    /// `capacity_reserve`, the published volumes plus as many as fit in the free space of the
+    /// bases filesystem beyond the reserve, at `size_limit` each, bounded by `max_volumes`.
+    pub async fn max_volumes_per_node(&self) -> anyhow::Result<i64> {
+        let Some(reserve) = self.flags.capacity_reserve else {
+            return Ok(0);
+        };
+        let static_max = self.flags.max_volumes.map(|m| m as i64);
+        let size_limit = parse_quantity(&self.flags.size_limit)?;
+        anyhow::ensure!(size_limit > 0, "The size limit must be positive");
+        let fs = nix::sys::statvfs::statvfs(&self.flags.bases)?;
+        let block = fs.fragment_size() as f64;
+        let total = fs.blocks() as f64 * block;
+        let available = fs.blocks_available() as f64 * block;
+        let usable = (available - reserve * total).max(0.0);
+        let published = self.lock.lock().await.volumes.len() as i64;
+        let max = published + (usable / size_limit as f64) as i64;
+        // 0 would mean unlimited
+        let max = max.max(1);
+        Ok(static_max.map_or(max, |m| m.min(max)))
+    }
+}
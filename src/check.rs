@@ -0,0 +1,183 @@
+//! Preflight checks of the environment of the driver, run without serving.
+use std::path::Path;
+
+use k8s_openapi::api::authorization::v1::{
+    ResourceAttributes, SelfSubjectAccessReview, SelfSubjectAccessReviewSpec,
+};
+use kube::api::PostParams;
+use kube::Api;
+
+use crate::mount::{self, Mounter};
+use crate::OverlayFlags;
+
+/// Verbs on pods used by the driver
+const POD_VERBS: &[&str] = &["get", "list", "create", "delete"];
+
+struct Report {
+    failures: usize,
+}
+impl Report {
+    fn check(&mut self, what: &str, result: anyhow::Result<()>, fix: &str) {
+        match result {
+            Ok(()) => println!("[ok]   {}", what),
+            Err(e) => {
+                self.failures += 1;
+                println!("[FAIL] {}: {:#}", what, e);
+                println!("       Fix: {}", fix);
+            }
+        }
+    }
+}
+
+/// Validate the environment described by the flags, printing the outcome of each check with
+/// suggested fixes. Fails if any check does.
+pub async fn run(flags: &OverlayFlags) -> anyhow::Result<()> {
+    let mut report = Report { failures: 0 };
+    report.check(
+        "overlay filesystem available",
+        overlay_available(),
+        "load the module with `modprobe overlay` (and add it to /etc/modules-load.d)",
+    );
+    report.check(
+        &format!("pods directory {:?}", flags.pods),
+        is_dir(&flags.pods),
+        "set --pods to the kubelet pods directory, mounted with bidirectional propagation",
+    );
+    report.check(
+        &format!("pods directory {:?} is a shared mount", flags.pods),
+        crate::propagation::check(
+            &flags.pods,
+            crate::propagation::PropagationCheck::Fail,
+            mount::system_mounter(flags.mount_command).as_ref(),
+        ),
+        "mount the pods directory with `mountPropagation: Bidirectional`, and make it shared on \
+         the host with `mount --make-rshared /var/lib/kubelet`",
+    );
+    report.check(
+        &format!("bases directory {:?} supports overlays", flags.bases),
+        test_overlay(&flags.bases, flags.mount_command),
+        "use a filesystem supporting d_type and trusted xattrs as upper directory, e.g. ext4 or \
+         xfs with ftype=1, on the same device as the pods directory",
+    );
+    if let Some(volumes_dir) = &flags.volumes_dir {
+        report.check(
+            &format!("volumes directory {:?} supports overlays", volumes_dir),
+            test_overlay(volumes_dir),
+            "use a filesystem supporting d_type and trusted xattrs as upper directory",
+        );
+    }
+    report.check(
+        "data pod template",
+        crate::datapod::load(flags.data_pod_template.as_deref()).map(|_| ()),
+        "fix the manifest passed to --data-pod-template",
+    );
+    match kube::Client::try_default().await {
+        Err(e) => report.check(
+            "Kubernetes client configuration",
+            Err(e.into()),
+            "run in a pod with a service account, or set KUBECONFIG",
+        ),
+        Ok(client) => {
+            let version = client.apiserver_version().await;
+            let reachable = version.is_ok();
+            report.check(
+                "Kubernetes API reachable",
+                version.map(|_| ()).map_err(Into::into),
+                "check the network access to the API server and the service account token",
+            );
+            if reachable {
+                for verb in POD_VERBS {
+                    report.check(
+                        &format!("permission to {} pods in {}", verb, flags.namespace),
+                        can(&client, verb, &flags.namespace).await,
+                        &format!(
+                            "grant `{}` on pods in {} to the service account of the driver, as \
+                             in the chart's Role",
+                            verb, flags.namespace
+                        ),
+                    );
+                }
+            }
+        }
+    }
+    anyhow::ensure!(report.failures == 0, "{} checks failed", report.failures);
+    println!("All checks passed");
+    Ok(())
+}
+
+fn overlay_available() -> anyhow::Result<()> {
+    let filesystems = std::fs::read_to_string("/proc/filesystems")?;
+    anyhow::ensure!(
+        filesystems
+            .lines()
+            .any(|l| l.split_whitespace().last() == Some("overlay")),
+        "overlay is missing from /proc/filesystems"
+    );
+    Ok(())
+}
+
+fn is_dir(path: &Path) -> anyhow::Result<()> {
+    anyhow::ensure!(std::fs::metadata(path)?.is_dir(), "Not a directory");
+    Ok(())
+}
+
+/// Mount a throwaway overlay whose upper directory is in `dir`, and write to it.
+fn test_overlay(dir: &Path, mount_command: bool) -> anyhow::Result<()> {
+    let root = dir.join(".check");
+    let _ = std::fs::remove_dir_all(&root);
+    let result = (|| {
+        for sub in ["lower", "upper", "work", "merged"] {
+            std::fs::create_dir_all(root.join(sub))?;
+        }
+        let mounter = mount::system_mounter(mount_command);
+        let merged = root.join("merged");
+        mounter.mount_overlay(
+            "overlayfs-csi-check",
+            &root.join("lower"),
+            &root.join("upper"),
+            &root.join("work"),
+            &merged,
+        )?;
+        let written = std::fs::write(merged.join("file"), "check");
+        mounter.unmount(&merged)?;
+        written?;
+        anyhow::ensure!(
+            root.join("upper/file").exists(),
+            "Write did not reach the upper directory"
+        );
+        Ok(())
+    })();
+    let _ = std::fs::remove_dir_all(&root);
+    result
+}
+
+/// Whether the driver may perform `verb` on pods in `namespace`
+async fn can(client: &kube::Client, verb: &str, namespace: &str) -> anyhow::Result<()> {
+    let review = SelfSubjectAccessReview {
+        spec: SelfSubjectAccessReviewSpec {
+            resource_attributes: Some(ResourceAttributes {
+                namespace: Some(namespace.into()),
+                verb: Some(verb.into()),
+                resource: Some("pods".into()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let api: Api<SelfSubjectAccessReview> = Api::all(client.clone());
+    let status = api
+        .create(&PostParams::default(), &review)
+        .await?
+        .status
+        .ok_or_else(|| anyhow::anyhow!("No review status"))?;
+    anyhow::ensure!(
+        status.allowed,
+        "Denied{}",
+        status
+            .reason
+            .map(|r| format!(": {}", r))
+            .unwrap_or_default()
+    );
+    Ok(())
+}
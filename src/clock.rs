@@ -0,0 +1,64 @@
+//! Source of the current time for the age of the bases, so that expiry, grace periods and
+//! rotations can be exercised without waiting.
+use std::sync::{Arc, Mutex};
+
+use time::OffsetDateTime;
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> OffsetDateTime;
+}
+
+/// Wall-clock time
+#[derive(Default, Clone, Copy)]
+pub struct SystemClock;
+impl Clock for SystemClock {
+    fn now(&self) -> OffsetDateTime {
+        OffsetDateTime::now_utc()
+    }
+}
+
+/// Clock that only moves when told to
+pub struct MockClock(Mutex<OffsetDateTime>);
+impl MockClock {
+    pub fn new(now: OffsetDateTime) -> Self {
+        Self(Mutex::new(now))
+    }
+    pub fn set(&self, now: OffsetDateTime) {
+        *self.0.lock().unwrap() = now;
+    }
+    pub fn advance(&self, by: std::time::Duration) {
+        *self.0.lock().unwrap() += by;
+    }
+}
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new(OffsetDateTime::now_utc())
+    }
+}
+impl Clock for MockClock {
+    fn now(&self) -> OffsetDateTime {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// Clock running `multiplier` times faster than another one from the time of its creation, to
+/// go through hours of base lifecycle in seconds
+pub struct AcceleratedClock {
+    inner: Arc<dyn Clock>,
+    start: OffsetDateTime,
+    multiplier: f64,
+}
+impl AcceleratedClock {
+    pub fn new(inner: Arc<dyn Clock>, multiplier: f64) -> Self {
+        Self {
+            start: inner.now(),
+            inner,
+            multiplier,
+        }
+    }
+}
+impl Clock for AcceleratedClock {
+    fn now(&self) -> OffsetDateTime {
+        self.start + (self.inner.now() - self.start) * self.multiplier
+    }
+}
@@ -0,0 +1,251 @@
+//! Reclaiming space in the upper directories of long-lived overlays.
+//!
+//! overlayfs copies a file up to the upper directory as soon as it is opened for writing, even if
+//! its content ends up unchanged. Such copies, when byte-identical to the lower file, and empty
+//! directories, are removed from the upper directory. Whiteouts hiding nothing in the lower
+//! directory are removed, and the zero-filled blocks of the remaining files are deallocated, as
+//! copy-ups of sparse files are not sparse.
+//!
+//! overlayfs does not support modifying the layers of a mounted overlay, and the kernel can keep
+//! serving cached entries for removed copies, so this is opt-in. To narrow the window for a write
+//! racing with the compaction, files changed recently or open in any process of the node are
+//! left untouched, which requires running in the host PID namespace.
+use std::collections::HashSet;
+use std::fs::Metadata;
+use std::io::Read;
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::Path;
+use std::time::Duration;
+
+use tracing::*;
+
+use crate::base;
+
+/// Extended attributes under which overlayfs keeps its metadata. Opaque or redirected directories
+/// hide or replace the lower directory, and metacopy files only hold metadata, so entries carrying
+/// them are left untouched.
+const OVERLAY_XATTRS: [&str; 3] = [
+    "trusted.overlay.opaque",
+    "trusted.overlay.redirect",
+    "trusted.overlay.metacopy",
+];
+
+/// Files modified or copied up more recently are neither removed nor made sparse, as a write
+/// racing with the comparison or the scan of a block would be lost.
+const MIN_AGE: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Default)]
+pub(crate) struct Compacted {
+    pub files: u64,
+    pub dirs: u64,
+    pub whiteouts: u64,
+    /// Bytes of the removed copy-ups
+    pub bytes: u64,
+    /// Bytes deallocated from zero-filled blocks
+    pub sparse_bytes: u64,
+}
+impl Compacted {
+    pub(crate) fn add(&mut self, other: &Self) {
+        self.files += other.files;
+        self.dirs += other.dirs;
+        self.whiteouts += other.whiteouts;
+        self.bytes += other.bytes;
+        self.sparse_bytes += other.sparse_bytes;
+    }
+}
+
+/// Compact the upper directory of an overlay with a single lower directory, sparing the files
+/// whose inode is in `open` (see [`open_inodes`]).
+pub(crate) fn compact(
+    upper: &Path,
+    lower: &Path,
+    open: &HashSet<u64>,
+) -> anyhow::Result<Compacted> {
+    let mut compacted = Compacted::default();
+    compact_dir(upper, lower, open, &mut compacted)?;
+    Ok(compacted)
+}
+
+/// Inode numbers of the files open or mapped in the processes of the node. overlayfs can report
+/// the device of the overlay rather than of the upper directory, so only inode numbers are
+/// compared, a collision merely sparing a file.
+pub(crate) fn open_inodes() -> anyhow::Result<HashSet<u64>> {
+    // Each PID namespace the process is nested in adds a field
+    let status = std::fs::read_to_string("/proc/self/status")?;
+    let levels = status
+        .lines()
+        .find_map(|l| l.strip_prefix("NSpid:"))
+        .map_or(1, |pids| pids.split_whitespace().count());
+    anyhow::ensure!(
+        levels == 1,
+        "Compaction requires the host PID namespace (hostPID) to find the open files"
+    );
+    let mut inodes = HashSet::new();
+    for process in std::fs::read_dir("/proc")?.flatten() {
+        if !process
+            .file_name()
+            .as_bytes()
+            .iter()
+            .all(u8::is_ascii_digit)
+        {
+            continue;
+        }
+        // Processes exit during the scan
+        if let Ok(fds) = std::fs::read_dir(process.path().join("fd")) {
+            for fd in fds.flatten() {
+                if let Ok(meta) = std::fs::metadata(fd.path()) {
+                    inodes.insert(meta.ino());
+                }
+            }
+        }
+        if let Ok(maps) = std::fs::read_to_string(process.path().join("maps")) {
+            // address perms offset dev inode path
+            inodes.extend(
+                maps.lines()
+                    .filter_map(|l| l.split_whitespace().nth(4)?.parse::<u64>().ok())
+                    .filter(|ino| *ino != 0),
+            );
+        }
+    }
+    Ok(inodes)
+}
+
+fn compact_dir(
+    upper: &Path,
+    lower: &Path,
+    open: &HashSet<u64>,
+    compacted: &mut Compacted,
+) -> anyhow::Result<()> {
+    for entry in std::fs::read_dir(upper)? {
+        let entry = entry?;
+        let path = entry.path();
+        let other = lower.join(entry.file_name());
+        let meta = entry.metadata()?;
+        if meta.is_file() && (recent(&meta) || open.contains(&meta.ino())) {
+            continue;
+        }
+        let Ok(lower_meta) = std::fs::symlink_metadata(&other) else {
+            if is_whiteout(&meta) {
+                std::fs::remove_file(&path)?;
+                compacted.whiteouts += 1;
+            } else if meta.is_dir() && !has_overlay_xattr(&path) {
+                compact_dir(&path, &other, open, compacted)?;
+            } else if meta.is_file() && !has_overlay_xattr(&path) {
+                make_sparse(&path, &meta, compacted);
+            }
+            continue;
+        };
+        let same_attributes = meta.mode() == lower_meta.mode()
+            && meta.uid() == lower_meta.uid()
+            && meta.gid() == lower_meta.gid();
+        if meta.is_dir() && lower_meta.is_dir() && !has_overlay_xattr(&path) {
+            compact_dir(&path, &other, open, compacted)?;
+            let empty = std::fs::read_dir(&path)?.next().is_none();
+            if empty && same_attributes && std::fs::remove_dir(&path).is_ok() {
+                compacted.dirs += 1;
+            }
+        } else if meta.is_file()
+            && lower_meta.is_file()
+            && same_attributes
+            && meta.len() == lower_meta.len()
+            && !has_overlay_xattr(&path)
+            && base::same_content(&path, &other)?
+        {
+            std::fs::remove_file(&path)?;
+            compacted.files += 1;
+            compacted.bytes += meta.len();
+        } else if meta.is_file() && !has_overlay_xattr(&path) {
+            make_sparse(&path, &meta, compacted);
+        }
+    }
+    Ok(())
+}
+
+/// Whiteouts are character devices with device number 0/0
+fn is_whiteout(meta: &Metadata) -> bool {
+    meta.file_type().is_char_device() && meta.rdev() == 0
+}
+
+/// Whether a file was modified, or copied up (which keeps the modification time of the lower
+/// file but not its change time), less than [`MIN_AGE`] ago.
+fn recent(meta: &Metadata) -> bool {
+    let changed = std::time::UNIX_EPOCH + Duration::from_secs(meta.ctime().max(0) as u64);
+    [meta.modified().ok(), Some(changed)].into_iter().any(|t| {
+        t.and_then(|t| t.elapsed().ok())
+            .map_or(true, |age| age < MIN_AGE)
+    })
+}
+
+fn make_sparse(path: &Path, meta: &Metadata, compacted: &mut Compacted) {
+    if meta.blocks() == 0 {
+        return;
+    }
+    match punch_zero_blocks(path, meta.blksize().max(512) as usize) {
+        Ok(()) => {
+            let after = std::fs::symlink_metadata(path).map_or(meta.blocks(), |m| m.blocks());
+            compacted.sparse_bytes += meta.blocks().saturating_sub(after) * 512;
+        }
+        // e.g. filesystems without hole punching
+        Err(e) => debug!(?path, "Failed to deallocate zero-filled blocks: {}", e),
+    }
+}
+
+/// Deallocate the runs of zero-filled blocks of a file, keeping its size and content.
+fn punch_zero_blocks(path: &Path, block: usize) -> anyhow::Result<()> {
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)?;
+    let punch = |file: &std::fs::File, start: u64, end: u64| {
+        nix::fcntl::fallocate(
+            file.as_raw_fd(),
+            nix::fcntl::FallocateFlags::FALLOC_FL_PUNCH_HOLE
+                | nix::fcntl::FallocateFlags::FALLOC_FL_KEEP_SIZE,
+            start as i64,
+            (end - start) as i64,
+        )
+    };
+    let mut buffer = vec![0; block];
+    let mut offset = 0;
+    let mut zeros_from = None;
+    loop {
+        let mut n = 0;
+        while n < block {
+            match file.read(&mut buffer[n..])? {
+                0 => break,
+                read => n += read,
+            }
+        }
+        if n == 0 {
+            break;
+        }
+        let zero = n == block && buffer.iter().all(|b| *b == 0);
+        match (zero, zeros_from) {
+            (true, None) => zeros_from = Some(offset),
+            (false, Some(start)) => {
+                punch(&file, start, offset)?;
+                zeros_from = None;
+            }
+            _ => {}
+        }
+        offset += n as u64;
+    }
+    if let Some(start) = zeros_from {
+        punch(&file, start, offset)?;
+    }
+    Ok(())
+}
+
+fn has_overlay_xattr(path: &Path) -> bool {
+    OVERLAY_XATTRS
+        .iter()
+        .any(|name| match crate::xattr::lgetxattr(path, name) {
+            Ok(value) => value.is_some(),
+            Err(e) => {
+                debug!(?path, name, "Failed to read extended attribute: {}", e);
+                false
+            }
+        })
+}
@@ -0,0 +1,245 @@
+//! Options passed through the CSI `volume_context` (inline volume attributes or StorageClass
+//! parameters).
+use std::collections::HashMap;
+use std::path::{Component, PathBuf};
+
+use k8s_openapi::api::core::v1::Pod;
+use serde::{Deserialize, Serialize};
+
+use crate::base::DEFAULT_FAMILY;
+
+/// Only present a subdirectory of the base as the lower directory
+const SUB_PATH_KEY: &str = "subPath";
+/// Family of bases to use, and to which the volume can be promoted
+const FAMILY_KEY: &str = "family";
+/// Alias of `family`, for StorageClasses that name their pool of bases a profile
+const PROFILE_KEY: &str = "profile";
+/// Keep the written data on a tmpfs of this size (e.g. `512m`) rather than on disk
+const TMPFS_SIZE_KEY: &str = "tmpfsSize";
+/// Never use a base nor promote the volume, which always starts empty
+const PRISTINE_KEY: &str = "pristine";
+/// Start the volume without base, but still allow promoting it, replacing the current base
+const FORCE_FRESH_KEY: &str = "forceFresh";
+/// `max_age_s` of the bases promoted from the volume, e.g. from a StorageClass parameter
+const MAX_AGE_KEY: &str = "maxAgeS";
+/// Size limit of the volume (e.g. `5Gi`), overriding the global size limit
+pub(crate) const SIZE_LIMIT_KEY: &str = "sizeLimit";
+/// Container image from which bases of the family are populated when it has none
+const IMAGE_KEY: &str = "image";
+/// Directory of the image that becomes the base, by default its whole filesystem
+const IMAGE_PATH_KEY: &str = "imagePath";
+/// How the volume is accessed, see [`Access`]
+const ACCESS_KEY: &str = "access";
+/// Pod annotation equivalent to the `pristine` key
+pub const PRISTINE_ANNOTATION: &str = "overlayfs.csi.k8s.io/pristine";
+/// Pod using the volume, set by Kubernetes when the CSIDriver has `podInfoOnMount`
+const POD_NAME_KEY: &str = "csi.storage.k8s.io/pod.name";
+const POD_NAMESPACE_KEY: &str = "csi.storage.k8s.io/pod.namespace";
+const POD_UID_KEY: &str = "csi.storage.k8s.io/pod.uid";
+
+/// Pod to which a volume is published
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PodRef {
+    pub namespace: String,
+    pub name: String,
+    pub uid: Option<String>,
+}
+
+/// Access mode of a volume
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Access {
+    /// Private writable overlay
+    #[default]
+    ReadWrite,
+    /// Read-only view of the current base, without upper directory nor data pod
+    ReadOnly,
+    /// Writable overlay promoted on unmount, replacing the current base. Only one writer per
+    /// family can be published on a node at a time.
+    Writer,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VolumeContext {
+    /// Relative path inside the base
+    pub sub_path: Option<PathBuf>,
+    pub family: String,
+    /// Size of the tmpfs holding the upper directory, in the format of the `size` mount option
+    pub tmpfs_size: Option<String>,
+    pub pod: Option<PodRef>,
+    pub pristine: bool,
+    pub force_fresh: bool,
+    pub access: Access,
+    pub max_age_s: Option<i64>,
+    /// Size limit of the data pod, as a Kubernetes quantity
+    pub size_limit: Option<String>,
+    pub image: Option<String>,
+    /// Relative path inside the image
+    pub image_path: Option<PathBuf>,
+}
+impl Default for VolumeContext {
+    fn default() -> Self {
+        Self {
+            sub_path: None,
+            family: DEFAULT_FAMILY.into(),
+            tmpfs_size: None,
+            pod: None,
+            pristine: false,
+            force_fresh: false,
+            access: Access::ReadWrite,
+            max_age_s: None,
+            size_limit: None,
+            image: None,
+            image_path: None,
+        }
+    }
+}
+impl VolumeContext {
+    pub fn parse(context: &HashMap<String, String>) -> anyhow::Result<Self> {
+        let mut parsed = Self::default();
+        let family = match (context.get(FAMILY_KEY), context.get(PROFILE_KEY)) {
+            (Some(family), Some(profile)) if family != profile => anyhow::bail!(
+                "{} {:?} and {} {:?} must be equal when both are set",
+                FAMILY_KEY,
+                family,
+                PROFILE_KEY,
+                profile
+            ),
+            (family, profile) => family.or(profile),
+        };
+        if let Some(family) = family {
+            anyhow::ensure!(
+                !family.is_empty()
+                    && !family.starts_with('.')
+                    && family
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c)),
+                "Invalid {} {:?}: only alphanumeric characters, '-', '_' and '.' are allowed",
+                FAMILY_KEY,
+                family
+            );
+            parsed.family = family.clone();
+        }
+        if let Some(sub_path) = context.get(SUB_PATH_KEY) {
+            let sub_path = PathBuf::from(sub_path);
+            anyhow::ensure!(
+                sub_path
+                    .components()
+                    .all(|c| matches!(c, Component::Normal(_) | Component::CurDir)),
+                "{} must be a relative path without '..', got {:?}",
+                SUB_PATH_KEY,
+                sub_path
+            );
+            parsed.sub_path = Some(sub_path);
+        }
+        if let Some(size) = context.get(TMPFS_SIZE_KEY) {
+            let digits = size.trim_end_matches(['k', 'm', 'g', 'K', 'M', 'G']);
+            anyhow::ensure!(
+                !digits.is_empty()
+                    && size.len() - digits.len() <= 1
+                    && digits.chars().all(|c| c.is_ascii_digit()),
+                "Invalid {} {:?}: expected a number of bytes with an optional k, m or g suffix",
+                TMPFS_SIZE_KEY,
+                size
+            );
+            parsed.tmpfs_size = Some(size.clone());
+        }
+        if let Some(pristine) = context.get(PRISTINE_KEY) {
+            parsed.pristine = parse_bool(PRISTINE_KEY, pristine)?;
+        }
+        if let Some(force_fresh) = context.get(FORCE_FRESH_KEY) {
+            parsed.force_fresh = parse_bool(FORCE_FRESH_KEY, force_fresh)?;
+        }
+        if let Some(access) = context.get(ACCESS_KEY) {
+            parsed.access = match access.as_str() {
+                "readWrite" => Access::ReadWrite,
+                "readOnly" => Access::ReadOnly,
+                "writer" => Access::Writer,
+                _ => anyhow::bail!(
+                    "Invalid {} {:?}: expected readWrite, readOnly or writer",
+                    ACCESS_KEY,
+                    access
+                ),
+            };
+        }
+        if let Some(max_age_s) = context.get(MAX_AGE_KEY) {
+            parsed.max_age_s = Some(
+                max_age_s
+                    .parse()
+                    .ok()
+                    .filter(|s: &i64| *s >= 0)
+                    .ok_or_else(|| {
+                        anyhow::anyhow!(
+                            "Invalid {} {:?}: expected a number of seconds",
+                            MAX_AGE_KEY,
+                            max_age_s
+                        )
+                    })?,
+            );
+        }
+        if let Some(size_limit) = context.get(SIZE_LIMIT_KEY) {
+            crate::capacity::parse_quantity(size_limit)
+                .map_err(|e| anyhow::anyhow!("Invalid {}: {}", SIZE_LIMIT_KEY, e))?;
+            parsed.size_limit = Some(size_limit.clone());
+        }
+        if let Some(image) = context.get(IMAGE_KEY) {
+            anyhow::ensure!(
+                cfg!(feature = "oci-import"),
+                "The {} attribute requires the `oci-import` cargo feature, which this build lacks",
+                IMAGE_KEY
+            );
+            anyhow::ensure!(
+                !image.is_empty()
+                    && image
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || "-_./:@".contains(c)),
+                "Invalid {} {:?}: expected an image reference",
+                IMAGE_KEY,
+                image
+            );
+            parsed.image = Some(image.clone());
+        }
+        if let Some(image_path) = context.get(IMAGE_PATH_KEY) {
+            let image_path = PathBuf::from(image_path.trim_start_matches('/'));
+            anyhow::ensure!(
+                image_path
+                    .components()
+                    .all(|c| matches!(c, Component::Normal(_))),
+                "{} must be a path without '.' nor '..', got {:?}",
+                IMAGE_PATH_KEY,
+                image_path
+            );
+            parsed.image_path = Some(image_path);
+        }
+        if let (Some(namespace), Some(name)) =
+            (context.get(POD_NAMESPACE_KEY), context.get(POD_NAME_KEY))
+        {
+            parsed.pod = Some(PodRef {
+                namespace: namespace.clone(),
+                name: name.clone(),
+                uid: context.get(POD_UID_KEY).cloned(),
+            });
+        }
+        Ok(parsed)
+    }
+    /// Apply the options set as annotations of the pod using the volume.
+    pub(crate) fn apply_pod_annotations(&mut self, pod: &Pod) {
+        let annotation = pod
+            .metadata
+            .annotations
+            .as_ref()
+            .and_then(|a| a.get(PRISTINE_ANNOTATION));
+        if let Some(value) = annotation {
+            match parse_bool(PRISTINE_ANNOTATION, value) {
+                Ok(pristine) => self.pristine |= pristine,
+                Err(e) => tracing::warn!("Ignoring pod annotation: {}", e),
+            }
+        }
+    }
+}
+
+fn parse_bool(key: &str, value: &str) -> anyhow::Result<bool> {
+    value
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Invalid {} {:?}: expected true or false", key, value))
+}
@@ -0,0 +1,94 @@
+//! Dump of the driver state when it panics, for post-mortem debugging without relying solely on
+//! the container logs.
+use std::sync::{Arc, Weak};
+
+use serde::Serialize;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use crate::persist::PersistedState;
+use crate::Overlays;
+
+/// Directory of the dumps, under the bases
+const CRASH_DIR: &str = ".crash";
+
+#[derive(Serialize)]
+struct CrashDump {
+    time: String,
+    panic: String,
+    /// `None` if the state was locked, e.g. by the panicking task. The last persisted state is
+    /// then in `.state.json`.
+    state: Option<PersistedState>,
+    /// Operations in flight, with their age in seconds
+    operations: Option<Vec<(String, f64)>>,
+    /// Recent volume operations, oldest first
+    events: Vec<Event>,
+}
+
+#[derive(Serialize)]
+struct Event {
+    time: String,
+    request_id: String,
+    volume_id: String,
+    operation: &'static str,
+    error: Option<String>,
+}
+
+fn format_time(time: OffsetDateTime) -> String {
+    time.format(&Rfc3339).unwrap_or_default()
+}
+
+/// Install a panic hook writing a dump to `{bases}/.crash/{time}.json`, before running the
+/// previous hook.
+pub(crate) fn install(overlays: &Arc<Overlays>) {
+    let overlays = Arc::downgrade(overlays);
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        dump(&overlays, &info.to_string());
+        previous(info);
+    }));
+}
+
+fn dump(overlays: &Weak<Overlays>, panic: &str) {
+    let Some(overlays) = overlays.upgrade() else {
+        return;
+    };
+    let now = OffsetDateTime::now_utc();
+    let dump = CrashDump {
+        time: format_time(now),
+        panic: panic.into(),
+        state: overlays
+            .lock
+            .try_lock()
+            .map(|state| PersistedState::from_state(&state)),
+        operations: overlays.watchdog.operations().map(|ops| {
+            ops.into_iter()
+                .map(|(op, age)| (op, age.as_secs_f64()))
+                .collect()
+        }),
+        events: overlays
+            .audit
+            .try_recent()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|r| Event {
+                time: format_time(r.time),
+                request_id: r.request_id,
+                volume_id: r.volume_id,
+                operation: r.operation,
+                error: r.error,
+            })
+            .collect(),
+    };
+    let dir = overlays.flags.bases.join(CRASH_DIR);
+    let path = dir.join(format!("{}.json", now.unix_timestamp_nanos()));
+    let result = std::fs::create_dir_all(&dir)
+        .map_err(anyhow::Error::from)
+        .and_then(|_| Ok(serde_json::to_vec_pretty(&dump)?))
+        .and_then(|data| Ok(std::fs::write(&path, data)?));
+    // Logging may be what panicked, so the outcome is printed directly
+    match result {
+        Ok(()) => eprintln!("Wrote crash dump to {:?}", path),
+        Err(e) => eprintln!("Failed to write crash dump to {:?}: {}", path, e),
+    }
+}
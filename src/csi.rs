@@ -0,0 +1,680 @@
+//! CSI gRPC services, which can be added to any tonic server.
+#[cfg(feature = "controller")]
+use std::collections::hash_map::{Entry, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use tokio_util::sync::CancellationToken;
+use tracing::*;
+
+use crate::context::{Access, VolumeContext};
+use crate::{
+    Cancelled, CapacityOutOfRange, NotExpandable, Overlays, QuotaExceeded, VolumeNotFound,
+    WriterBusy,
+};
+
+pub mod v1 {
+    tonic::include_proto!("csi.v1");
+}
+
+fn unimplemented() -> tonic::Status {
+    tonic::Status::unimplemented("Unimplemented")
+}
+
+/// Metadata key from which a caller-provided request ID is taken
+pub const REQUEST_ID_KEY: &str = "x-request-id";
+
+/// Identifier of a CSI call, used to correlate logs and audit records.
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+impl RequestId {
+    fn generate() -> Self {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let now = time::OffsetDateTime::now_utc().unix_timestamp_nanos();
+        Self(format!(
+            "{:x}-{:x}",
+            now,
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ))
+    }
+    fn of<T>(req: &tonic::Request<T>) -> Self {
+        req.extensions()
+            .get::<Self>()
+            .cloned()
+            .unwrap_or_else(Self::generate)
+    }
+    fn span(&self, method: &'static str) -> Span {
+        info_span!("csi", method, request_id = %self.0)
+    }
+}
+
+/// Interceptor attaching a [`RequestId`] to each call, either from the `x-request-id` metadata or
+/// freshly generated.
+pub fn request_id_interceptor(
+    mut req: tonic::Request<()>,
+) -> Result<tonic::Request<()>, tonic::Status> {
+    let id = req
+        .metadata()
+        .get(REQUEST_ID_KEY)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| RequestId(v.into()))
+        .unwrap_or_else(RequestId::generate);
+    req.extensions_mut().insert(id);
+    Ok(req)
+}
+
+/// Service that simply provides information about the CSI driver
+pub struct IdentityService {
+    name: String,
+    overlays: Option<Arc<Overlays>>,
+    controller: bool,
+}
+impl IdentityService {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            overlays: None,
+            controller: false,
+        }
+    }
+    /// Report the health of the overlays in the probe.
+    pub fn with_overlays(mut self, overlays: Arc<Overlays>) -> Self {
+        self.overlays = Some(overlays);
+        self
+    }
+    /// Advertise the controller service, which must then be served as well.
+    pub fn with_controller(mut self) -> Self {
+        self.controller = true;
+        self
+    }
+}
+#[async_trait::async_trait]
+impl v1::identity_server::Identity for IdentityService {
+    async fn get_plugin_info(
+        &self,
+        req: tonic::Request<v1::GetPluginInfoRequest>,
+    ) -> Result<tonic::Response<v1::GetPluginInfoResponse>, tonic::Status> {
+        let _span = RequestId::of(&req).span("get_plugin_info").entered();
+        info!("Returning plugin info");
+        let req = req.into_inner();
+        debug!("{:?}", req);
+        Ok(tonic::Response::new(v1::GetPluginInfoResponse {
+            name: self.name.clone(),
+            vendor_version: env!("CARGO_PKG_VERSION").into(),
+            ..Default::default()
+        }))
+    }
+    async fn get_plugin_capabilities(
+        &self,
+        _request: tonic::Request<v1::GetPluginCapabilitiesRequest>,
+    ) -> Result<tonic::Response<v1::GetPluginCapabilitiesResponse>, tonic::Status> {
+        use v1::plugin_capability::{service, Service, Type};
+        let mut capabilities = vec![];
+        if self.controller {
+            capabilities.push(v1::PluginCapability {
+                r#type: Some(Type::Service(Service {
+                    r#type: service::Type::ControllerService.into(),
+                })),
+            });
+        }
+        Ok(tonic::Response::new(v1::GetPluginCapabilitiesResponse {
+            capabilities,
+        }))
+    }
+    async fn probe(
+        &self,
+        _request: tonic::Request<v1::ProbeRequest>,
+    ) -> Result<tonic::Response<v1::ProbeResponse>, tonic::Status> {
+        if self.overlays.as_ref().map_or(false, |o| !o.healthy()) {
+            return Err(tonic::Status::failed_precondition(
+                "Stall detected by the watchdog",
+            ));
+        }
+        Ok(tonic::Response::new(v1::ProbeResponse {
+            ready: Some(true),
+        }))
+    }
+}
+/// Key of a volume in the driver. Volume ids are not necessarily unique on a node, e.g. for
+/// identically named inline volumes, so they are combined with a hash of the target path, which
+/// contains the pod UID and is known both when publishing and unpublishing.
+fn volume_key(volume_id: &str, target_path: &str) -> String {
+    format!("{}-{}", volume_id, &crate::datapod::hash(target_path)[..8])
+}
+
+/// Service that publishes and unpublishes volumes on the node
+pub struct NodeService {
+    node_id: String,
+    overlays: Arc<Overlays>,
+}
+impl NodeService {
+    pub fn new(node_id: impl Into<String>, overlays: Arc<Overlays>) -> Self {
+        Self {
+            node_id: node_id.into(),
+            overlays,
+        }
+    }
+    async fn publish(
+        &self,
+        request_id: RequestId,
+        req: v1::NodePublishVolumeRequest,
+    ) -> tonic::Result<tonic::Response<v1::NodePublishVolumeResponse>> {
+        info!(req.volume_id, ?req.target_path, "Publishing volume");
+        debug!("{:?}", req);
+        let mut context = VolumeContext::parse(&req.volume_context)
+            .map_err(|e| tonic::Status::invalid_argument(e.to_string()))?;
+        if req.readonly {
+            context.access = Access::ReadOnly;
+        }
+        // tonic drops this future when the client cancels the call. The mount runs in its own task
+        // so that it can roll back, and the guard signals the cancellation to it.
+        let cancel = CancellationToken::new();
+        let _guard = cancel.clone().drop_guard();
+        let overlays = self.overlays.clone();
+        let volume_id = req.volume_id.clone();
+        let key = volume_key(&req.volume_id, &req.target_path);
+        let _op = self
+            .overlays
+            .watchdog
+            .track(format!("publish {} ({})", key, request_id.0));
+        let result = tokio::spawn(
+            async move {
+                overlays
+                    .mount(&key, req.target_path, &context, &cancel)
+                    .await
+            }
+            .in_current_span(),
+        )
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|r| r);
+        self.overlays
+            .audit()
+            .record(&request_id.0, &volume_id, "publish", &result);
+        match result {
+            Ok(()) => Ok(tonic::Response::new(Default::default())),
+            Err(e) if e.is::<Cancelled>() => {
+                warn!(volume_id, "Publishing cancelled");
+                Err(tonic::Status::cancelled(e.to_string()))
+            }
+            Err(e) if e.is::<WriterBusy>() => {
+                warn!(volume_id, "Not publishing: {}", e);
+                Err(tonic::Status::failed_precondition(e.to_string()))
+            }
+            Err(e) if e.is::<QuotaExceeded>() => {
+                warn!(volume_id, "Not publishing: {}", e);
+                Err(tonic::Status::resource_exhausted(e.to_string()))
+            }
+            Err(e) => {
+                error!(volume_id, "Failed publishing: {}", e);
+                Err(tonic::Status::internal(e.to_string()))
+            }
+        }
+    }
+    async fn unpublish(
+        &self,
+        request_id: RequestId,
+        req: v1::NodeUnpublishVolumeRequest,
+    ) -> tonic::Result<tonic::Response<v1::NodeUnpublishVolumeResponse>> {
+        info!(
+            req.volume_id, ?req.target_path,
+            "Unpublishing volume"
+        );
+        debug!("{:?}", req);
+        let key = volume_key(&req.volume_id, &req.target_path);
+        let _op = self
+            .overlays
+            .watchdog
+            .track(format!("unpublish {} ({})", key, request_id.0));
+        let result = self.overlays.unmount(&key, req.target_path).await;
+        self.overlays
+            .audit()
+            .record(&request_id.0, &req.volume_id, "unpublish", &result);
+        match result {
+            Ok(()) => Ok(tonic::Response::new(Default::default())),
+            Err(e) => {
+                error!(req.volume_id, "Failed unpublishing: {}", e);
+                Err(tonic::Status::internal(e.to_string()))
+            }
+        }
+    }
+}
+#[async_trait::async_trait]
+impl v1::node_server::Node for NodeService {
+    async fn node_stage_volume(
+        &self,
+        _req: tonic::Request<v1::NodeStageVolumeRequest>,
+    ) -> tonic::Result<tonic::Response<v1::NodeStageVolumeResponse>> {
+        Err(unimplemented())
+    }
+    async fn node_unstage_volume(
+        &self,
+        _req: tonic::Request<v1::NodeUnstageVolumeRequest>,
+    ) -> tonic::Result<tonic::Response<v1::NodeUnstageVolumeResponse>> {
+        Err(unimplemented())
+    }
+
+    async fn node_publish_volume(
+        &self,
+        req: tonic::Request<v1::NodePublishVolumeRequest>,
+    ) -> tonic::Result<tonic::Response<v1::NodePublishVolumeResponse>> {
+        let request_id = RequestId::of(&req);
+        let span = request_id.span("node_publish_volume");
+        self.publish(request_id, req.into_inner())
+            .instrument(span)
+            .await
+    }
+    async fn node_unpublish_volume(
+        &self,
+        req: tonic::Request<v1::NodeUnpublishVolumeRequest>,
+    ) -> tonic::Result<tonic::Response<v1::NodeUnpublishVolumeResponse>> {
+        let request_id = RequestId::of(&req);
+        let span = request_id.span("node_unpublish_volume");
+        self.unpublish(request_id, req.into_inner())
+            .instrument(span)
+            .await
+    }
+    async fn node_get_volume_stats(
+        &self,
+        req: tonic::Request<v1::NodeGetVolumeStatsRequest>,
+    ) -> tonic::Result<tonic::Response<v1::NodeGetVolumeStatsResponse>> {
+        use v1::volume_usage::Unit;
+        let _span = RequestId::of(&req).span("node_get_volume_stats").entered();
+        let req = req.into_inner();
+        if req.volume_id.is_empty() || req.volume_path.is_empty() {
+            return Err(tonic::Status::invalid_argument(
+                "Missing volume ID or volume path",
+            ));
+        }
+        let key = volume_key(&req.volume_id, &req.volume_path);
+        let status = |e: anyhow::Error| {
+            if e.is::<VolumeNotFound>() {
+                tonic::Status::not_found(e.to_string())
+            } else {
+                error!(req.volume_id, "Failed getting volume statistics: {}", e);
+                tonic::Status::internal(e.to_string())
+            }
+        };
+        let condition = self
+            .overlays
+            .volume_condition(&key, &req.volume_path)
+            .await
+            .map_err(status)?;
+        if let Some(message) = condition {
+            warn!(req.volume_id, "Abnormal volume: {}", message);
+            return Ok(tonic::Response::new(v1::NodeGetVolumeStatsResponse {
+                usage: vec![],
+                volume_condition: Some(v1::VolumeCondition {
+                    abnormal: true,
+                    message,
+                }),
+            }));
+        }
+        let stats = self
+            .overlays
+            .volume_stats(&key, &req.volume_path)
+            .await
+            .map_err(status)?;
+        Ok(tonic::Response::new(v1::NodeGetVolumeStatsResponse {
+            usage: vec![
+                v1::VolumeUsage {
+                    available: stats.available_bytes,
+                    total: stats.total_bytes,
+                    used: stats.used_bytes,
+                    unit: Unit::Bytes.into(),
+                },
+                v1::VolumeUsage {
+                    available: stats.available_inodes,
+                    total: stats.total_inodes,
+                    used: stats.used_inodes,
+                    unit: Unit::Inodes.into(),
+                },
+            ],
+            volume_condition: Some(v1::VolumeCondition {
+                abnormal: false,
+                message: "Volume is mounted".into(),
+            }),
+        }))
+    }
+    async fn node_expand_volume(
+        &self,
+        req: tonic::Request<v1::NodeExpandVolumeRequest>,
+    ) -> tonic::Result<tonic::Response<v1::NodeExpandVolumeResponse>> {
+        let _span = RequestId::of(&req).span("node_expand_volume").entered();
+        let req = req.into_inner();
+        info!(req.volume_id, ?req.capacity_range, "Expanding volume");
+        if req.volume_id.is_empty() || req.volume_path.is_empty() {
+            return Err(tonic::Status::invalid_argument(
+                "Missing volume ID or volume path",
+            ));
+        }
+        let (required, limit) = req
+            .capacity_range
+            .as_ref()
+            .map_or((0, 0), |r| (r.required_bytes, r.limit_bytes));
+        if required <= 0 || limit < 0 || (limit > 0 && limit < required) {
+            return Err(tonic::Status::invalid_argument(format!(
+                "Invalid capacity range {:?}",
+                req.capacity_range
+            )));
+        }
+        let key = volume_key(&req.volume_id, &req.volume_path);
+        let limit = (limit > 0).then_some(limit as u64);
+        match self
+            .overlays
+            .expand_volume(&key, required as u64, limit)
+            .await
+        {
+            Ok(capacity_bytes) => Ok(tonic::Response::new(v1::NodeExpandVolumeResponse {
+                capacity_bytes: capacity_bytes as i64,
+            })),
+            Err(e) if e.is::<VolumeNotFound>() => Err(tonic::Status::not_found(e.to_string())),
+            Err(e) if e.is::<CapacityOutOfRange>() => {
+                warn!(req.volume_id, "Not expanding: {}", e);
+                Err(tonic::Status::out_of_range(e.to_string()))
+            }
+            Err(e) if e.is::<NotExpandable>() => {
+                warn!(req.volume_id, "Not expanding: {}", e);
+                Err(tonic::Status::failed_precondition(e.to_string()))
+            }
+            Err(e) => {
+                error!(req.volume_id, "Failed expanding: {}", e);
+                Err(tonic::Status::internal(e.to_string()))
+            }
+        }
+    }
+    async fn node_get_capabilities(
+        &self,
+        _req: tonic::Request<v1::NodeGetCapabilitiesRequest>,
+    ) -> tonic::Result<tonic::Response<v1::NodeGetCapabilitiesResponse>> {
+        use v1::node_service_capability::{rpc, Rpc, Type};
+        let capabilities = [
+            rpc::Type::GetVolumeStats,
+            rpc::Type::VolumeCondition,
+            rpc::Type::ExpandVolume,
+        ]
+        .into_iter()
+        .map(|t| v1::NodeServiceCapability {
+            r#type: Some(Type::Rpc(Rpc { r#type: t.into() })),
+        })
+        .collect();
+        Ok(tonic::Response::new(v1::NodeGetCapabilitiesResponse {
+            capabilities,
+        }))
+    }
+    async fn node_get_info(
+        &self,
+        _req: tonic::Request<v1::NodeGetInfoRequest>,
+    ) -> tonic::Result<tonic::Response<v1::NodeGetInfoResponse>> {
+        let max_volumes_per_node = match self.overlays.max_volumes_per_node().await {
+            Ok(max) => max,
+            Err(e) => {
+                warn!("Failed to compute the maximum number of volumes: {}", e);
+                0
+            }
+        };
+        Ok(tonic::Response::new(v1::NodeGetInfoResponse {
+            node_id: self.node_id.clone(),
+            max_volumes_per_node,
+            ..Default::default()
+        }))
+    }
+}
+
+/// Reason why volume capabilities are not supported, if any. Volumes are node-local
+/// filesystems, so block access and writers on several nodes are not supported.
+#[cfg(feature = "controller")]
+fn unsupported_capability(capabilities: &[v1::VolumeCapability]) -> Option<String> {
+    use v1::volume_capability::access_mode::Mode;
+    use v1::volume_capability::AccessType;
+    if capabilities.is_empty() {
+        return Some("Missing volume capabilities".into());
+    }
+    capabilities.iter().find_map(|capability| {
+        if let Some(AccessType::Block(_)) = capability.access_type {
+            return Some("Block volumes are not supported".into());
+        }
+        let mode = capability
+            .access_mode
+            .as_ref()
+            .and_then(|m| Mode::try_from(m.mode).ok())
+            .unwrap_or(Mode::Unknown);
+        match mode {
+            Mode::SingleNodeWriter
+            | Mode::SingleNodeReaderOnly
+            | Mode::SingleNodeSingleWriter
+            | Mode::SingleNodeMultiWriter
+            | Mode::MultiNodeReaderOnly => None,
+            mode => Some(format!("Access mode {:?} is not supported", mode)),
+        }
+    })
+}
+
+/// Controller service, for dynamic provisioning with external-provisioner and for deployments
+/// that run external-attacher. Volumes only exist on the node where they are published, so
+/// creating one only validates its parameters and records its capacity as its size limit, and
+/// deleting, publishing and unpublishing trivially succeed. Requires the `controller` feature.
+#[cfg(feature = "controller")]
+#[derive(Default)]
+pub struct ControllerService {
+    /// Capacity and parameters of the created volumes, by name, to tell retries from conflicting
+    /// requests. Only kept in memory, so a conflict is not detected across restarts.
+    volumes: std::sync::Mutex<HashMap<String, (i64, HashMap<String, String>)>>,
+}
+#[cfg(feature = "controller")]
+#[async_trait::async_trait]
+impl v1::controller_server::Controller for ControllerService {
+    async fn create_volume(
+        &self,
+        req: tonic::Request<v1::CreateVolumeRequest>,
+    ) -> tonic::Result<tonic::Response<v1::CreateVolumeResponse>> {
+        let _span = RequestId::of(&req).span("create_volume").entered();
+        let req = req.into_inner();
+        info!(req.name, "Creating volume");
+        debug!("{:?}", req);
+        if req.name.is_empty() {
+            return Err(tonic::Status::invalid_argument("Missing volume name"));
+        }
+        if let Some(message) = unsupported_capability(&req.volume_capabilities) {
+            return Err(tonic::Status::invalid_argument(message));
+        }
+        if req.volume_content_source.is_some() {
+            return Err(tonic::Status::invalid_argument(
+                "Volume content sources are not supported",
+            ));
+        }
+        VolumeContext::parse(&req.parameters)
+            .map_err(|e| tonic::Status::invalid_argument(e.to_string()))?;
+        let capacity_bytes = req.capacity_range.as_ref().map_or(0, |range| {
+            if range.required_bytes > 0 {
+                range.required_bytes
+            } else {
+                range.limit_bytes
+            }
+        });
+        if capacity_bytes < 0 {
+            return Err(tonic::Status::invalid_argument("Negative capacity"));
+        }
+        let volume = (capacity_bytes, req.parameters.clone());
+        match self.volumes.lock().unwrap().entry(req.name.clone()) {
+            Entry::Occupied(recorded) if *recorded.get() != volume => {
+                return Err(tonic::Status::already_exists(format!(
+                    "Volume {} already exists with a different capacity or parameters",
+                    req.name
+                )));
+            }
+            Entry::Occupied(_) => {}
+            Entry::Vacant(entry) => {
+                entry.insert(volume);
+            }
+        }
+        // The requested capacity becomes the size limit of the data pod, instead of the global one
+        let mut volume_context = req.parameters;
+        if capacity_bytes > 0 {
+            volume_context.insert(
+                crate::context::SIZE_LIMIT_KEY.into(),
+                capacity_bytes.to_string(),
+            );
+        }
+        // Kubernetes generates unique names and retries with the same one, so using it as the
+        // volume ID makes the creation idempotent, as long as the request is the same.
+        Ok(tonic::Response::new(v1::CreateVolumeResponse {
+            volume: Some(v1::Volume {
+                capacity_bytes,
+                volume_id: req.name,
+                volume_context,
+                ..Default::default()
+            }),
+        }))
+    }
+    async fn delete_volume(
+        &self,
+        req: tonic::Request<v1::DeleteVolumeRequest>,
+    ) -> tonic::Result<tonic::Response<v1::DeleteVolumeResponse>> {
+        let _span = RequestId::of(&req).span("delete_volume").entered();
+        let req = req.into_inner();
+        if req.volume_id.is_empty() {
+            return Err(tonic::Status::invalid_argument("Missing volume id"));
+        }
+        // The data is deleted with the data pod when the volume is unpublished
+        info!(req.volume_id, "Deleting volume");
+        self.volumes.lock().unwrap().remove(&req.volume_id);
+        Ok(tonic::Response::new(Default::default()))
+    }
+    async fn controller_publish_volume(
+        &self,
+        req: tonic::Request<v1::ControllerPublishVolumeRequest>,
+    ) -> tonic::Result<tonic::Response<v1::ControllerPublishVolumeResponse>> {
+        let _span = RequestId::of(&req)
+            .span("controller_publish_volume")
+            .entered();
+        let req = req.into_inner();
+        if req.volume_id.is_empty() || req.node_id.is_empty() {
+            return Err(tonic::Status::invalid_argument("Missing volume or node id"));
+        }
+        debug!(req.volume_id, req.node_id, "Publishing volume to node");
+        Ok(tonic::Response::new(Default::default()))
+    }
+    async fn controller_unpublish_volume(
+        &self,
+        req: tonic::Request<v1::ControllerUnpublishVolumeRequest>,
+    ) -> tonic::Result<tonic::Response<v1::ControllerUnpublishVolumeResponse>> {
+        let _span = RequestId::of(&req)
+            .span("controller_unpublish_volume")
+            .entered();
+        let req = req.into_inner();
+        if req.volume_id.is_empty() {
+            return Err(tonic::Status::invalid_argument("Missing volume id"));
+        }
+        debug!(req.volume_id, req.node_id, "Unpublishing volume from node");
+        Ok(tonic::Response::new(Default::default()))
+    }
+    async fn validate_volume_capabilities(
+        &self,
+        req: tonic::Request<v1::ValidateVolumeCapabilitiesRequest>,
+    ) -> tonic::Result<tonic::Response<v1::ValidateVolumeCapabilitiesResponse>> {
+        use v1::validate_volume_capabilities_response::Confirmed;
+        let _span = RequestId::of(&req)
+            .span("validate_volume_capabilities")
+            .entered();
+        let req = req.into_inner();
+        if req.volume_id.is_empty() || req.volume_capabilities.is_empty() {
+            return Err(tonic::Status::invalid_argument(
+                "Missing volume id or capabilities",
+            ));
+        }
+        if let Some(message) = unsupported_capability(&req.volume_capabilities) {
+            return Ok(tonic::Response::new(
+                v1::ValidateVolumeCapabilitiesResponse {
+                    confirmed: None,
+                    message,
+                },
+            ));
+        }
+        if let Err(e) = VolumeContext::parse(&req.parameters) {
+            return Ok(tonic::Response::new(
+                v1::ValidateVolumeCapabilitiesResponse {
+                    confirmed: None,
+                    message: e.to_string(),
+                },
+            ));
+        }
+        Ok(tonic::Response::new(
+            v1::ValidateVolumeCapabilitiesResponse {
+                confirmed: Some(Confirmed {
+                    volume_context: req.volume_context,
+                    volume_capabilities: req.volume_capabilities,
+                    parameters: req.parameters,
+                    ..Default::default()
+                }),
+                message: String::new(),
+            },
+        ))
+    }
+    async fn list_volumes(
+        &self,
+        _req: tonic::Request<v1::ListVolumesRequest>,
+    ) -> tonic::Result<tonic::Response<v1::ListVolumesResponse>> {
+        Err(unimplemented())
+    }
+    async fn get_capacity(
+        &self,
+        _req: tonic::Request<v1::GetCapacityRequest>,
+    ) -> tonic::Result<tonic::Response<v1::GetCapacityResponse>> {
+        Err(unimplemented())
+    }
+    async fn controller_get_capabilities(
+        &self,
+        _req: tonic::Request<v1::ControllerGetCapabilitiesRequest>,
+    ) -> tonic::Result<tonic::Response<v1::ControllerGetCapabilitiesResponse>> {
+        use v1::controller_service_capability::{rpc, Rpc, Type};
+        let capabilities = [
+            rpc::Type::CreateDeleteVolume,
+            rpc::Type::PublishUnpublishVolume,
+        ]
+        .into_iter()
+        .map(|t| v1::ControllerServiceCapability {
+            r#type: Some(Type::Rpc(Rpc { r#type: t.into() })),
+        })
+        .collect();
+        Ok(tonic::Response::new(
+            v1::ControllerGetCapabilitiesResponse { capabilities },
+        ))
+    }
+    async fn create_snapshot(
+        &self,
+        _req: tonic::Request<v1::CreateSnapshotRequest>,
+    ) -> tonic::Result<tonic::Response<v1::CreateSnapshotResponse>> {
+        Err(unimplemented())
+    }
+    async fn delete_snapshot(
+        &self,
+        _req: tonic::Request<v1::DeleteSnapshotRequest>,
+    ) -> tonic::Result<tonic::Response<v1::DeleteSnapshotResponse>> {
+        Err(unimplemented())
+    }
+    async fn list_snapshots(
+        &self,
+        _req: tonic::Request<v1::ListSnapshotsRequest>,
+    ) -> tonic::Result<tonic::Response<v1::ListSnapshotsResponse>> {
+        Err(unimplemented())
+    }
+    async fn controller_expand_volume(
+        &self,
+        _req: tonic::Request<v1::ControllerExpandVolumeRequest>,
+    ) -> tonic::Result<tonic::Response<v1::ControllerExpandVolumeResponse>> {
+        Err(unimplemented())
+    }
+    async fn controller_get_volume(
+        &self,
+        _req: tonic::Request<v1::ControllerGetVolumeRequest>,
+    ) -> tonic::Result<tonic::Response<v1::ControllerGetVolumeResponse>> {
+        Err(unimplemented())
+    }
+    async fn controller_modify_volume(
+        &self,
+        _req: tonic::Request<v1::ControllerModifyVolumeRequest>,
+    ) -> tonic::Result<tonic::Response<v1::ControllerModifyVolumeResponse>> {
+        Err(unimplemented())
+    }
+}
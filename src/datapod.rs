@@ -0,0 +1,83 @@
+//! Template of the data pods, whose emptyDir holds the storage of the volumes.
+use std::path::Path;
+
+use anyhow::Context;
+use k8s_openapi::api::core::v1::Pod;
+use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+use sha2::{Digest, Sha256};
+
+/// Name of the emptyDir volume of the data pods. kubelet names its directory after it.
+pub(crate) const VOLUME_NAME: &str = "volume";
+/// Label by which data pods are looked up, set to the hash of their key (the volume id, or the
+/// pod for shared data pods)
+pub(crate) const KEY_LABEL: &str = "overlayfs.csi.k8s.io/data-pod";
+/// Annotation carrying the full key, which can exceed the length allowed for label values
+pub(crate) const KEY_ANNOTATION: &str = "overlayfs.csi.k8s.io/data-pod";
+
+/// Hash of a key, usable in pod names and label values
+pub(crate) fn hash(key: &str) -> String {
+    Sha256::digest(key.as_bytes())[..10]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Load the template of the data pods, from a file or the built-in one, and check that it has the
+/// expected structure.
+pub(crate) fn load(path: Option<&Path>) -> anyhow::Result<Pod> {
+    let (template, source) = match path {
+        Some(path) => (
+            std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read the data pod template {:?}", path))?,
+            path.to_string_lossy().into_owned(),
+        ),
+        None => (
+            include_str!("../data_pod.yaml").to_owned(),
+            "built-in".into(),
+        ),
+    };
+    let pod: Pod = serde_yaml::from_str(&template)
+        .with_context(|| format!("Failed to parse the data pod template ({})", source))?;
+    validate(&pod).with_context(|| format!("Invalid data pod template ({})", source))?;
+    Ok(pod)
+}
+
+fn validate(pod: &Pod) -> anyhow::Result<()> {
+    let spec = pod.spec.as_ref().context("The template has no spec")?;
+    anyhow::ensure!(
+        !spec.containers.is_empty(),
+        "The template needs a container keeping the pod running"
+    );
+    let volume = spec
+        .volumes
+        .iter()
+        .flatten()
+        .find(|v| v.name == VOLUME_NAME)
+        .with_context(|| format!("The template needs a volume named {:?}", VOLUME_NAME))?;
+    anyhow::ensure!(
+        volume.empty_dir.is_some(),
+        "The {:?} volume must be an emptyDir",
+        VOLUME_NAME
+    );
+    if let Some(policy) = &spec.restart_policy {
+        anyhow::ensure!(
+            policy == "Always",
+            "The restart policy must be Always, so that the pod keeps its storage, got {}",
+            policy
+        );
+    }
+    Ok(())
+}
+
+/// Cap the size of the emptyDir of a data pod created from a validated template.
+pub(crate) fn set_size_limit(pod: &mut Pod, size_limit: &str) {
+    let empty_dir = pod
+        .spec
+        .iter_mut()
+        .flat_map(|spec| spec.volumes.iter_mut().flatten())
+        .find(|v| v.name == VOLUME_NAME)
+        .and_then(|v| v.empty_dir.as_mut());
+    if let Some(empty_dir) = empty_dir {
+        empty_dir.size_limit = Some(Quantity(size_limit.into()));
+    }
+}
@@ -0,0 +1,217 @@
+//! Diagnosis of a volume, e.g. stuck in publishing or unpublishing: everything the driver knows
+//! about it, and the likely causes of the usual problems.
+use std::path::Path;
+
+use k8s_openapi::api::core::v1::Pod;
+
+use crate::context::Access;
+use crate::{encoding, mountinfo, Overlays};
+
+#[derive(Debug, Default)]
+pub struct Diagnosis {
+    /// What the driver knows about the volume
+    pub findings: Vec<String>,
+    /// Likely causes of problems, most specific first
+    pub causes: Vec<String>,
+}
+
+fn size(path: &Path) -> String {
+    match std::fs::metadata(path) {
+        Ok(_) => format!("{} bytes", crate::stats::disk_usage(path)),
+        Err(e) => format!("missing ({})", e),
+    }
+}
+
+/// Short status of a data pod, and whether it is usable
+fn pod_status(pod: &Pod) -> (String, bool) {
+    let phase = pod
+        .status
+        .as_ref()
+        .and_then(|s| s.phase.clone())
+        .unwrap_or_else(|| "Unknown".into());
+    let waiting: Vec<_> = pod
+        .status
+        .iter()
+        .flat_map(|s| s.container_statuses.iter().flatten())
+        .filter_map(|c| c.state.as_ref()?.waiting.as_ref()?.reason.clone())
+        .collect();
+    let terminating = pod.metadata.deletion_timestamp.is_some();
+    let mut status = phase.clone();
+    if !waiting.is_empty() {
+        status += &format!(", waiting: {}", waiting.join(", "));
+    }
+    if terminating {
+        status += ", terminating";
+    }
+    (status, phase == "Running" && !terminating)
+}
+
+impl Overlays {
+    /// Diagnose the volumes with this id, i.e. the CSI volume id rather than the key of the
+    /// volume in the driver.
+    pub async fn diagnose(&self, volume_id: &str) -> anyhow::Result<Diagnosis> {
+        let mut d = Diagnosis::default();
+        let prefix = format!("{}-", volume_id);
+        let mapping = self.lock.lock().await;
+        let mut keys: Vec<_> = mapping
+            .volumes
+            .keys()
+            .filter(|k| k.strip_prefix(&prefix).map_or(false, |h| h.len() == 8))
+            .cloned()
+            .collect();
+        let mounts: Vec<_> = mountinfo::all()?
+            .into_iter()
+            .filter(|m| {
+                encoding::decode(&m.source)
+                    .map_or(false, |s| s.starts_with(&prefix) || s == volume_id)
+            })
+            .collect();
+        for mount in &mounts {
+            let key = encoding::decode(&mount.source)?;
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+        // The state is copied, so that it is not locked while querying the API server
+        let volumes: Vec<_> = keys
+            .iter()
+            .map(|key| {
+                let base = mapping
+                    .bases
+                    .iter()
+                    .find(|(_, v)| v.contains(key))
+                    .map(|(b, _)| b.0.clone());
+                (
+                    key,
+                    mapping.volumes.get(key).cloned(),
+                    base,
+                    mapping.layers.get(key).cloned(),
+                )
+            })
+            .collect();
+        drop(mapping);
+        if keys.is_empty() {
+            d.findings.push("No published volume and no overlay".into());
+            d.causes.push(
+                "The volume is not published on this node: check the node of the pod, and the \
+                 kubelet logs for errors before the publishing request"
+                    .into(),
+            );
+        }
+        for (key, context, base, layers) in &volumes {
+            d.findings.push(format!("Volume {}", key));
+            match context {
+                Some(context) => d.findings.push(format!("  Published: {:?}", context)),
+                None => d.findings.push("  Not in the published volumes".into()),
+            }
+            if let Some(base) = base {
+                d.findings.push(format!("  Base: {:?}", base));
+            }
+            let overlay = mounts
+                .iter()
+                .find(|m| encoding::decode(&m.source).ok().as_ref() == Some(*key));
+            match overlay {
+                Some(m) => d.findings.push(format!(
+                    "  Overlay mounted at {:?}{}",
+                    m.mount_point,
+                    if m.read_only { " (read-only)" } else { "" }
+                )),
+                None => d.findings.push("  No overlay in the mount table".into()),
+            }
+            if let Some((upper, lower)) = layers {
+                d.findings
+                    .push(format!("  Upper directory {:?}: {}", upper, size(upper)));
+                d.findings
+                    .push(format!("  Lower directory {:?}: {}", lower, size(lower)));
+                if let Some(work) = upper.parent().map(|p| p.join("workdir")) {
+                    d.findings
+                        .push(format!("  Work directory {:?}: {}", work, size(&work)));
+                }
+                if !upper.exists() {
+                    d.causes.push(format!(
+                        "{}: the upper directory is gone, e.g. because the data pod was deleted \
+                         and its emptyDir reclaimed. The volume data is lost; recreate the pod",
+                        key
+                    ));
+                }
+            }
+            let pod_key = self.data_pod_of(key);
+            match self.find_pod(&pod_key).await {
+                Ok(Some(pod)) => {
+                    let (status, usable) = pod_status(&pod);
+                    d.findings.push(format!(
+                        "  Data pod {}: {}",
+                        pod.metadata.name.clone().unwrap_or_default(),
+                        status
+                    ));
+                    if !usable && pod.metadata.deletion_timestamp.is_some() {
+                        d.causes.push(format!(
+                            "{}: the data pod is stuck terminating, e.g. on a finalizer or an \
+                             unreachable kubelet",
+                            key
+                        ));
+                    } else if !usable {
+                        d.causes.push(format!(
+                            "{}: the data pod is not running. Check its events, e.g. for \
+                             insufficient ephemeral storage on the node or image pull errors",
+                            key
+                        ));
+                    }
+                }
+                Ok(None) => {
+                    d.findings.push(format!("  No data pod ({})", pod_key));
+                    if context.is_some() && overlay.is_some() {
+                        d.causes.push(format!(
+                            "{}: the data pod was deleted while the volume is published, so its \
+                             storage is no longer accounted for",
+                            key
+                        ));
+                    }
+                }
+                Err(e) => {
+                    d.findings
+                        .push(format!("  Failed to look up the data pod: {}", e));
+                    d.causes.push(
+                        "The Kubernetes API is unreachable or denies access to the data pods, \
+                         which blocks publishing and unpublishing"
+                            .into(),
+                    );
+                }
+            }
+            match (context, overlay) {
+                (Some(c), None) if c.access != Access::ReadOnly && layers.is_some() => {
+                    d.causes.push(format!(
+                        "{}: published but not mounted, e.g. after kubelet or the node restarted. \
+                         Recreate the pod to republish the volume",
+                        key
+                    ));
+                }
+                (None, Some(_)) => d.causes.push(format!(
+                    "{}: leftover overlay not tracked by the driver, e.g. after a crash. The next \
+                     publishing request adopts it, and unpublishing removes it",
+                    key
+                )),
+                _ => {}
+            }
+        }
+        let records = self.audit.for_volume(volume_id);
+        for r in &records {
+            d.findings.push(format!(
+                "{} {} {} (request {}){}",
+                r.time,
+                r.operation,
+                r.volume_id,
+                r.request_id,
+                r.error
+                    .as_ref()
+                    .map(|e| format!(": {}", e))
+                    .unwrap_or_default()
+            ));
+        }
+        if let Some(error) = records.last().and_then(|r| r.error.as_ref()) {
+            d.causes
+                .insert(0, format!("The last operation failed: {}", error));
+        }
+        Ok(d)
+    }
+}
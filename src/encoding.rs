@@ -0,0 +1,76 @@
+//! Encoding of volume ids, which are chosen by Kubernetes or its users, into names that are safe
+//! as path components and mount sources.
+//!
+//! ASCII alphanumeric characters, `-`, `_` and non-leading `.` are kept, and all other bytes are
+//! written as `%XX`. Typical volume ids are therefore unchanged, and encoded ids can neither
+//! contain `/` nor be hidden, `.` or `..`.
+
+/// Encode a volume id into a file name.
+pub(crate) fn encode(id: &str) -> String {
+    let mut encoded = String::with_capacity(id.len());
+    for (i, b) in id.bytes().enumerate() {
+        if b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || (b == b'.' && i > 0) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    encoded
+}
+
+/// Decode a name produced by [`encode`].
+pub(crate) fn decode(encoded: &str) -> anyhow::Result<String> {
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut iter = encoded.bytes();
+    while let Some(b) = iter.next() {
+        if b == b'%' {
+            let hex: Vec<u8> = iter.by_ref().take(2).collect();
+            anyhow::ensure!(hex.len() == 2, "Truncated escape in {:?}", encoded);
+            // from_str_radix would also accept a sign
+            anyhow::ensure!(
+                hex.iter().all(u8::is_ascii_hexdigit),
+                "Invalid escape in {:?}",
+                encoded
+            );
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex)?, 16)?);
+        } else {
+            bytes.push(b);
+        }
+    }
+    Ok(String::from_utf8(bytes)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        for id in [
+            "pvc-0123",
+            "csi-a1b2_c3.d4",
+            "",
+            ".",
+            "..",
+            ".hidden",
+            "ns/name",
+            "with space%and:colon",
+            "vol\u{e9}\u{1f600}",
+        ] {
+            let encoded = encode(id);
+            assert!(!encoded.contains('/'), "{:?}", encoded);
+            assert!(!encoded.starts_with('.'), "{:?}", encoded);
+            assert_eq!(decode(&encoded).unwrap(), id);
+        }
+        // Typical ids are unchanged
+        assert_eq!(encode("pvc-0123"), "pvc-0123");
+        assert_eq!(encode("../x"), "%2E.%2Fx");
+    }
+
+    #[test]
+    fn malformed() {
+        for encoded in ["%", "a%4", "%zz", "%+1", "%-1", "%FF", "%C3"] {
+            assert!(decode(encoded).is_err(), "{:?}", encoded);
+        }
+    }
+}
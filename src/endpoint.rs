@@ -0,0 +1,146 @@
+//! Addresses on which the driver serves its gRPC services.
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Address of the driver, in the format of the `CSI_ENDPOINT` environment variable, i.e.
+/// `unix:///csi/csi.sock`, `tcp://host:port` or `vsock://cid:port` (with `any` as context
+/// identifier to listen on all of them). A bare path is interpreted as a UNIX socket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    Unix(PathBuf),
+    /// `host:port`
+    Tcp(String),
+    Vsock {
+        cid: u32,
+        port: u32,
+    },
+}
+
+impl FromStr for Address {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        anyhow::ensure!(!s.is_empty(), "Empty endpoint");
+        let Some((scheme, rest)) = s.split_once(':') else {
+            return Ok(Self::Unix(s.into()));
+        };
+        match scheme {
+            // Both `unix:///path` and `unix:/path` are in use
+            "unix" => {
+                let path = rest.strip_prefix("//").unwrap_or(rest);
+                anyhow::ensure!(!path.is_empty(), "Missing socket path in {}", s);
+                Ok(Self::Unix(path.into()))
+            }
+            "tcp" => {
+                let addr = rest
+                    .strip_prefix("//")
+                    .ok_or_else(|| anyhow::anyhow!("Expected tcp://host:port, got {}", s))?;
+                anyhow::ensure!(addr.rsplit_once(':').is_some(), "Missing port in {}", s);
+                Ok(Self::Tcp(addr.into()))
+            }
+            "vsock" => {
+                let (cid, port) = rest
+                    .strip_prefix("//")
+                    .and_then(|addr| addr.split_once(':'))
+                    .ok_or_else(|| anyhow::anyhow!("Expected vsock://cid:port, got {}", s))?;
+                let cid = match cid {
+                    "any" => crate::vsock::CID_ANY,
+                    cid => cid.parse()?,
+                };
+                Ok(Self::Vsock {
+                    cid,
+                    port: port.parse()?,
+                })
+            }
+            _ if rest.starts_with("//") => anyhow::bail!("Unsupported endpoint scheme {}", scheme),
+            _ => Ok(Self::Unix(s.into())),
+        }
+    }
+}
+
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unix(path) => write!(f, "unix://{}", path.display()),
+            Self::Tcp(addr) => write!(f, "tcp://{}", addr),
+            Self::Vsock { cid, port } => write!(f, "vsock://{}:{}", cid, port),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Address {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn unix() {
+        assert_eq!(
+            parse("unix:///csi/csi.sock"),
+            Address::Unix("/csi/csi.sock".into())
+        );
+        assert_eq!(
+            parse("unix:/csi/csi.sock"),
+            Address::Unix("/csi/csi.sock".into())
+        );
+        assert!("unix://".parse::<Address>().is_err());
+        assert_eq!(
+            parse("unix:///csi/csi.sock").to_string(),
+            "unix:///csi/csi.sock"
+        );
+    }
+
+    #[test]
+    fn bare_path() {
+        assert_eq!(
+            parse("/csi/csi.sock"),
+            Address::Unix("/csi/csi.sock".into())
+        );
+        assert_eq!(parse("csi.sock"), Address::Unix("csi.sock".into()));
+        // A colon without `//` is part of the path
+        assert_eq!(
+            parse("/run/a:b.sock"),
+            Address::Unix("/run/a:b.sock".into())
+        );
+        assert!("".parse::<Address>().is_err());
+    }
+
+    #[test]
+    fn tcp() {
+        assert_eq!(
+            parse("tcp://0.0.0.0:10000"),
+            Address::Tcp("0.0.0.0:10000".into())
+        );
+        assert_eq!(
+            parse("tcp://[::1]:10000"),
+            Address::Tcp("[::1]:10000".into())
+        );
+        assert!("tcp://localhost".parse::<Address>().is_err());
+        assert!("tcp:localhost:10000".parse::<Address>().is_err());
+    }
+
+    #[test]
+    fn vsock() {
+        assert_eq!(
+            parse("vsock://3:1024"),
+            Address::Vsock { cid: 3, port: 1024 }
+        );
+        assert_eq!(
+            parse("vsock://any:1024"),
+            Address::Vsock {
+                cid: crate::vsock::CID_ANY,
+                port: 1024
+            }
+        );
+        assert!("vsock://3".parse::<Address>().is_err());
+        assert!("vsock://host:1024".parse::<Address>().is_err());
+    }
+
+    #[test]
+    fn invalid_scheme() {
+        assert!("http://localhost:10000".parse::<Address>().is_err());
+        assert!("udp://0.0.0.0:10000".parse::<Address>().is_err());
+    }
+}
@@ -0,0 +1,121 @@
+//! Operator-provided commands run around volume and base lifecycle events.
+use std::path::Path;
+use std::time::Duration;
+
+use tracing::*;
+
+#[derive(clap::Args, Clone)]
+pub struct HookFlags {
+    /// Command run before mounting a volume. Failing aborts the mount.
+    #[clap(long)]
+    pub hook_pre_mount: Option<String>,
+    #[clap(long)]
+    pub hook_post_mount: Option<String>,
+    /// Command run before transforming a volume into a base. Failing skips the promotion.
+    #[clap(long)]
+    pub hook_pre_promotion: Option<String>,
+    #[clap(long)]
+    pub hook_post_promotion: Option<String>,
+    /// Command run after a stale base has been removed
+    #[clap(long)]
+    pub hook_post_cleanup: Option<String>,
+    #[clap(long, default_value_t = 60)]
+    pub hook_timeout_s: u64,
+}
+impl Default for HookFlags {
+    fn default() -> Self {
+        Self {
+            hook_pre_mount: None,
+            hook_post_mount: None,
+            hook_pre_promotion: None,
+            hook_post_promotion: None,
+            hook_post_cleanup: None,
+            hook_timeout_s: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum HookEvent {
+    PreMount,
+    PostMount,
+    PrePromotion,
+    PostPromotion,
+    PostCleanup,
+}
+impl HookEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            HookEvent::PreMount => "pre-mount",
+            HookEvent::PostMount => "post-mount",
+            HookEvent::PrePromotion => "pre-promotion",
+            HookEvent::PostPromotion => "post-promotion",
+            HookEvent::PostCleanup => "post-cleanup",
+        }
+    }
+}
+
+pub struct Hooks {
+    flags: HookFlags,
+}
+impl Hooks {
+    pub fn new(flags: HookFlags) -> Self {
+        Self { flags }
+    }
+    fn command(&self, event: HookEvent) -> Option<&str> {
+        match event {
+            HookEvent::PreMount => &self.flags.hook_pre_mount,
+            HookEvent::PostMount => &self.flags.hook_post_mount,
+            HookEvent::PrePromotion => &self.flags.hook_pre_promotion,
+            HookEvent::PostPromotion => &self.flags.hook_post_promotion,
+            HookEvent::PostCleanup => &self.flags.hook_post_cleanup,
+        }
+        .as_deref()
+    }
+    /// Run the hook for an event, if configured. The event is described to the command through
+    /// `OVERLAYFS_CSI_EVENT` and the provided environment variables.
+    pub async fn run(&self, event: HookEvent, env: &[(&str, &str)]) -> anyhow::Result<()> {
+        let Some(command) = self.command(event) else {
+            return Ok(());
+        };
+        debug!(event = event.name(), command, "Running hook");
+        let mut env = env.to_vec();
+        env.push(("OVERLAYFS_CSI_EVENT", event.name()));
+        run_shell(
+            command,
+            None,
+            &env,
+            Duration::from_secs(self.flags.hook_timeout_s),
+        )
+        .await
+        .map_err(|e| anyhow::anyhow!("{} hook failed: {}", event.name(), e))
+    }
+    /// Run a hook whose failure should not interrupt the operation.
+    pub async fn run_logged(&self, event: HookEvent, env: &[(&str, &str)]) {
+        if let Err(e) = self.run(event, env).await {
+            warn!("{}", e);
+        }
+    }
+}
+
+/// Run a command with `sh -c`, killing it if it exceeds the timeout or if the future is dropped.
+pub(crate) async fn run_shell(
+    command: &str,
+    dir: Option<&Path>,
+    env: &[(&str, &str)],
+    timeout: Duration,
+) -> anyhow::Result<()> {
+    let mut cmd = tokio::process::Command::new("sh");
+    cmd.arg("-c")
+        .arg(command)
+        .envs(env.iter().copied())
+        .kill_on_drop(true);
+    if let Some(dir) = dir {
+        cmd.current_dir(dir);
+    }
+    let status = tokio::time::timeout(timeout, cmd.status())
+        .await
+        .map_err(|_| anyhow::anyhow!("Command timed out after {:?}", timeout))??;
+    anyhow::ensure!(status.success(), "Command failed with {}", status);
+    Ok(())
+}
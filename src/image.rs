@@ -0,0 +1,175 @@
+//! Bases populated from container images, so that teams can build caches as ordinary images in
+//! CI. When a family has no valid base, the image of the volume (`image` attribute) or of the
+//! family (`--family-image`, else `--seed family=image`) is exported as a flattened filesystem
+//! by `--image-export-command`, and a path of it becomes the base.
+//!
+//! The population requires the `oci-import` feature, and the program run by the export command,
+//! by default `crane export` from go-containerregistry, in the driver image. Its presence is
+//! checked at startup when families are populated from images.
+use std::path::{Component, Path, PathBuf};
+#[cfg(feature = "oci-import")]
+use std::process::Stdio;
+
+#[cfg(feature = "oci-import")]
+use tracing::*;
+
+#[cfg(feature = "oci-import")]
+use crate::base;
+use crate::context::VolumeContext;
+use crate::Overlays;
+
+pub(crate) const DEFAULT_EXPORT_COMMAND: &str = "crane export \"$IMAGE\" -";
+
+/// Image populating the bases of a family, as `family=image` or `family=image#path`
+#[derive(Debug, Clone)]
+pub(crate) struct FamilyImage {
+    family: String,
+    image: String,
+    path: Option<PathBuf>,
+}
+impl std::str::FromStr for FamilyImage {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (family, image) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Expected family=image, got {}", s))?;
+        let (image, path) = match image.split_once('#') {
+            Some((image, path)) => {
+                let path = PathBuf::from(path.trim_start_matches('/'));
+                anyhow::ensure!(
+                    path.components().all(|c| matches!(c, Component::Normal(_))),
+                    "The path in the image must not contain '.' nor '..', got {:?}",
+                    path
+                );
+                (image, Some(path))
+            }
+            None => (image, None),
+        };
+        anyhow::ensure!(!image.is_empty(), "Missing image in {}", s);
+        Ok(Self {
+            family: family.into(),
+            image: image.into(),
+            path,
+        })
+    }
+}
+
+/// Check that the program run by the export command, e.g. `crane`, is installed, as it does not
+/// ship with the driver.
+#[cfg(feature = "oci-import")]
+pub(crate) fn check_export_command(command: &str) -> anyhow::Result<()> {
+    // Skip the environment assignments
+    let Some(program) = command.split_whitespace().find(|w| !w.contains('=')) else {
+        anyhow::bail!("Empty --image-export-command");
+    };
+    let found = if program.contains('/') {
+        Path::new(program).is_file()
+    } else {
+        std::env::var_os("PATH").map_or(false, |paths| {
+            std::env::split_paths(&paths).any(|dir| dir.join(program).is_file())
+        })
+    };
+    anyhow::ensure!(
+        found,
+        "{} is required to populate bases from images but was not found in PATH: install it in \
+         the driver image (e.g. crane from go-containerregistry) or set --image-export-command",
+        program
+    );
+    Ok(())
+}
+
+impl Overlays {
+    /// Image from which bases of the volume's family are populated, and path of the base in it
+    pub(crate) fn base_image<'a>(
+        &'a self,
+        context: &'a VolumeContext,
+    ) -> Option<(&'a str, &'a Path)> {
+        let (image, path) = match &context.image {
+            Some(image) => (image.as_str(), None),
+            None => {
+                let unscoped = context.family.split('@').next().unwrap_or(&context.family);
+                let matches = |family: &str| family == context.family || family == unscoped;
+                let family_image = self
+                    .flags
+                    .family_image
+                    .iter()
+                    .rev()
+                    .find(|i| matches(&i.family));
+                match family_image {
+                    Some(i) => (i.image.as_str(), i.path.as_deref()),
+                    None => {
+                        let seed = self
+                            .flags
+                            .seed
+                            .iter()
+                            .rev()
+                            .find_map(|s| s.image().filter(|_| matches(&s.family)))?;
+                        (seed, None)
+                    }
+                }
+            }
+        };
+        let path = context.image_path.as_deref().or(path);
+        Some((image, path.unwrap_or(Path::new(""))))
+    }
+    /// Create a base of the volume's family from its image, if it has one, returning its name.
+    /// `id` identifies the request.
+    #[cfg(feature = "oci-import")]
+    pub(crate) async fn populate_from_image(
+        &self,
+        id: &str,
+        context: &VolumeContext,
+    ) -> anyhow::Result<Option<String>> {
+        let Some((image, path)) = self.base_image(context) else {
+            return Ok(None);
+        };
+        let meta = base::BaseMeta {
+            image: Some(image.into()),
+            ..Default::default()
+        };
+        debug!(image, ?path, "Populating base from image");
+        let name = self
+            .populate_base(id, &context.family, image, meta, |partial| async move {
+                let rootfs = partial.join("rootfs");
+                self.export_image(image, path, &rootfs).await?;
+                let dir = rootfs.join(path);
+                anyhow::ensure!(dir.is_dir(), "{:?} is not a directory of the image", path);
+                Ok(dir)
+            })
+            .await?;
+        Ok(Some(name))
+    }
+    /// Extract `path` (everything if empty) of the filesystem of `image` into `dst`.
+    #[cfg(feature = "oci-import")]
+    async fn export_image(&self, image: &str, path: &Path, dst: &Path) -> anyhow::Result<()> {
+        std::fs::create_dir_all(dst)?;
+        let mut export = tokio::process::Command::new("sh")
+            .arg("-c")
+            .arg(&self.flags.image_export_command)
+            .env("IMAGE", image)
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let stdout: Stdio = export.stdout.take().unwrap().try_into()?;
+        let mut tar = tokio::process::Command::new("tar");
+        tar.arg("-x")
+            .arg("--numeric-owner")
+            .arg("-C")
+            .arg(dst)
+            .stdin(stdout)
+            .kill_on_drop(true);
+        if !path.as_os_str().is_empty() {
+            tar.arg("--").arg(path);
+        }
+        let mut tar = tar.spawn()?;
+        let (export, tar) = tokio::try_join!(export.wait(), tar.wait())?;
+        // As reported by the shell
+        anyhow::ensure!(
+            export.code() != Some(127),
+            "Image export command not found, see --image-export-command"
+        );
+        anyhow::ensure!(export.success(), "Image export failed with {}", export);
+        anyhow::ensure!(tar.success(), "tar failed with {}", tar);
+        Ok(())
+    }
+}
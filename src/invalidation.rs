@@ -0,0 +1,86 @@
+//! Invalidation of bases through a ConfigMap acting as a cache epoch.
+//!
+//! Each key of the ConfigMap is a family name, or `*` for all families. Changing a value
+//! invalidates all existing bases of the corresponding families for new mounts, e.g. after a
+//! toolchain upgrade. Bases record the epoch of their family when they are created.
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+
+use futures::{StreamExt, TryStreamExt};
+use k8s_openapi::api::core::v1::ConfigMap;
+use kube::runtime::watcher;
+use kube::Api;
+use tracing::*;
+
+use crate::Overlays;
+
+/// Key applying to all families
+const ALL_FAMILIES: &str = "*";
+
+#[derive(Default)]
+pub struct Epochs(RwLock<BTreeMap<String, String>>);
+impl Epochs {
+    pub fn set(&self, data: BTreeMap<String, String>) {
+        let mut epochs = self.0.write().unwrap();
+        if *epochs != data {
+            info!(?data, "Updated invalidation epochs");
+            *epochs = data;
+        }
+    }
+    /// Current epoch of a family, `None` if it was never invalidated.
+    pub fn get(&self, family: &str) -> Option<String> {
+        let epochs = self.0.read().unwrap();
+        match (epochs.get(ALL_FAMILIES), epochs.get(family)) {
+            (None, None) => None,
+            (all, family) => Some(format!(
+                "{}/{}",
+                all.map_or("", String::as_str),
+                family.map_or("", String::as_str)
+            )),
+        }
+    }
+}
+
+/// Current data of the ConfigMap, to know the epochs before the watch starts. Empty if the
+/// ConfigMap does not exist.
+pub async fn load(api: &Api<ConfigMap>, name: &str) -> anyhow::Result<BTreeMap<String, String>> {
+    Ok(api
+        .get_opt(name)
+        .await?
+        .and_then(|cm| cm.data)
+        .unwrap_or_default())
+}
+
+/// Watch the ConfigMap in the background, updating the epochs of `overlays` on changes.
+pub fn spawn_watch(api: Api<ConfigMap>, name: String, overlays: Arc<Overlays>) {
+    tokio::spawn(async move {
+        let config = watcher::Config::default().fields(&format!("metadata.name={}", name));
+        let mut stream = watcher(api, config).boxed();
+        loop {
+            match stream.try_next().await {
+                Ok(Some(watcher::Event::Applied(cm))) => {
+                    overlays.epochs.set(cm.data.unwrap_or_default());
+                }
+                Ok(Some(watcher::Event::Deleted(_))) => {
+                    overlays.epochs.set(Default::default());
+                }
+                Ok(Some(watcher::Event::Restarted(cms))) => {
+                    overlays.epochs.set(
+                        cms.into_iter()
+                            .next()
+                            .and_then(|cm| cm.data)
+                            .unwrap_or_default(),
+                    );
+                }
+                Ok(None) => {
+                    error!(name, "Invalidation ConfigMap watch ended");
+                    return;
+                }
+                Err(e) => {
+                    warn!(name, "Error watching invalidation ConfigMap: {}", e);
+                    tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+                }
+            }
+        }
+    });
+}
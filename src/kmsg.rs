@@ -0,0 +1,45 @@
+//! Kernel messages, read from `/dev/kmsg` to explain failed mounts, whose actual reason is only
+//! logged by the kernel.
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::fs::OpenOptionsExt;
+
+use nix::fcntl::OFlag;
+
+/// Follows the kernel messages logged after its creation.
+pub(crate) struct Kmsg(File);
+
+impl Kmsg {
+    pub(crate) fn follow() -> std::io::Result<Self> {
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(OFlag::O_NONBLOCK.bits())
+            .open("/dev/kmsg")?;
+        file.seek(SeekFrom::End(0))?;
+        Ok(Self(file))
+    }
+    /// Messages logged since the last call (or the creation) that contain `pattern`
+    pub(crate) fn read(&mut self, pattern: &str) -> Vec<String> {
+        let mut messages = vec![];
+        // Each read returns a single record, `{priority},{sequence},{time},{flags};{message}`
+        let mut buffer = [0; 8192];
+        loop {
+            match self.0.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => {
+                    let record = String::from_utf8_lossy(&buffer[..n]);
+                    let message = record.split_once(';').map_or("", |(_, m)| m);
+                    let message = message.lines().next().unwrap_or_default();
+                    if message.contains(pattern) {
+                        messages.push(message.to_string());
+                    }
+                }
+                // Records were overwritten before being read
+                Err(e) if e.raw_os_error() == Some(nix::libc::EPIPE) => continue,
+                // Including `WouldBlock`, once all the records were read
+                Err(_) => break,
+            }
+        }
+        messages
+    }
+}
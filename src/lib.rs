@@ -3,19 +3,76 @@ use std::hash::Hash;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use anyhow::Context;
 use clap::Parser;
-use futures::{StreamExt, TryStreamExt};
 use k8s_openapi::api::core::v1::Pod;
-use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
-use kube::api::{DeleteParams, WatchEvent, WatchParams};
-use kube::Api;
-use time::format_description::well_known::Rfc3339;
-use time::OffsetDateTime;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, Semaphore};
+use tokio_util::sync::CancellationToken;
 use tracing::*;
 
+pub mod admin;
+mod archive;
+pub mod audit;
+mod backup;
+mod base;
+mod builder;
+mod capacity;
+pub mod check;
+pub mod clock;
+mod compaction;
+pub mod context;
+mod crashdump;
+pub mod csi;
+mod datapod;
+pub mod doctor;
+mod encoding;
+pub mod endpoint;
+pub mod hooks;
+mod image;
+pub mod invalidation;
+mod kmsg;
+pub mod logfile;
+mod manifest;
+pub mod marker;
+pub mod metrics;
+pub mod mount;
+pub mod mountinfo;
+pub mod peers;
+mod persist;
+pub mod pods;
+mod policy;
+mod populate;
+pub mod propagation;
+pub mod provider;
+mod ramcache;
+pub mod repair;
+mod scripted;
+pub mod selection;
+mod slots;
+mod snapshot;
+pub mod stats;
+#[cfg(feature = "metrics")]
+mod status;
+pub mod summary;
+pub mod systemd;
+#[cfg(test)]
+mod tests;
+pub mod transfer;
+pub mod vsock;
+pub mod warm;
+mod watchdog;
+pub mod webhook;
+mod xattr;
+use base::Base;
+pub use builder::OverlaysBuilder;
+use context::{Access, VolumeContext};
+use hooks::{HookEvent, Hooks};
+use mount::Mounter;
+use pods::PodApi;
+use webhook::{BaseEvent, Webhook};
+
 const BASE_CLEANUP_FREQ_S: u64 = 30;
+/// Directory of the bases volume where promoted volumes are assembled into bases
+const PROMOTING_DIR: &str = ".promoting";
 
 #[derive(Parser)]
 pub struct OverlayFlags {
@@ -30,63 +87,517 @@ pub struct OverlayFlags {
     bases: PathBuf,
     #[clap(long, default_value = "/var/lib/kubelet/pods")]
     pods: PathBuf,
+    /// Manifest used as template for the data pods instead of the built-in one. It needs an
+    /// emptyDir volume named `volume`.
+    #[clap(long)]
+    data_pod_template: Option<PathBuf>,
+    /// Prefix of the names of the data pods, which are followed by a hash of the volume id
+    #[clap(long, default_value = "overlayfs-data")]
+    data_pod_prefix: String,
+    /// Age after which bases are not used anymore for new volumes. With 0, bases never age out,
+    /// and are only replaced by promotions and evicted by `bases_max_bytes`.
     #[clap(long)]
     max_age_s: i64,
+    /// Age after which unused bases are deleted. In between, bases can still be inspected or
+    /// exported. Defaults to `max_age_s`.
+    #[clap(long)]
+    hard_max_age_s: Option<i64>,
     /// Size per volume
     #[clap(long)]
     size_limit: String,
+    /// How long volume statistics are cached before being recomputed
+    #[clap(long, default_value_t = 60)]
+    stats_ttl_s: u64,
+    /// Shell command run in volumes created from scratch before they are published, e.g. to
+    /// pre-populate a cache. The volume id is available as `$VOLUME_ID`.
+    #[clap(long)]
+    init_command: Option<String>,
+    #[clap(long, default_value_t = 600)]
+    init_timeout_s: u64,
+    #[clap(flatten)]
+    pub hooks: hooks::HookFlags,
+    #[clap(flatten)]
+    pub peers: peers::PeerFlags,
+    #[clap(flatten)]
+    pub metrics: metrics::MetricsFlags,
+    /// URL to which base promotions, expiries and deletions are POSTed as JSON
+    #[clap(long)]
+    webhook_url: Option<String>,
+    #[clap(long, default_value_t = 5)]
+    webhook_retries: u32,
+    /// ConfigMap (in the driver namespace) whose values act as invalidation epochs per family
+    #[clap(long)]
+    pub invalidation_configmap: Option<String>,
+    /// Prefix of a ConfigMap (in the driver namespace, suffixed with the node name) in which a
+    /// summary of the node state is published, for inspection with kubectl
+    #[clap(long)]
+    pub state_configmap: Option<String>,
+    /// Label the node with `warm.overlayfs.csi.k8s.io/{family}=true` for each family with a
+    /// valid base, so that workloads can prefer the nodes where their cache is warm
+    #[clap(long)]
+    pub warm_labels: bool,
+    /// Maximum total size of the bases. Unused bases are evicted to stay under it, starting with
+    /// the least important families and the oldest bases.
+    #[clap(long)]
+    bases_max_bytes: Option<u64>,
+    /// Instead of only deleting the bases that expired (past `hard_max_age_s`), store them
+    /// compressed in this directory or `http(s)://` object store prefix, from which they can be
+    /// restored like backups
+    #[clap(long)]
+    archive_location: Option<String>,
+    /// Maximum total size of the archives in a local `archive_location`, deleting the oldest ones
+    #[clap(long)]
+    archive_max_bytes: Option<u64>,
+    /// Age after which archives in a local `archive_location` are deleted
+    #[clap(long)]
+    archive_ttl_s: Option<i64>,
+    /// Importance of a family for eviction, as `family=weight` (default weight: 1)
+    #[clap(long, value_parser = parse_family_weight)]
+    family_weight: Vec<(String, f64)>,
+    /// Rotation schedule of a family, as `family=cron expression` (with seconds, e.g.
+    /// `default=0 0 2 * * *` for every night at 02:00 UTC), or `*=...` for all families. Bases
+    /// stop being used at the first scheduled time after their creation, in addition to
+    /// `max_age_s`.
+    #[clap(long, value_parser = parse_family_rotation)]
+    family_rotation: Vec<(String, cron::Schedule)>,
+    /// `max_age_s` of a family, as `family=seconds`, e.g. `tests=3600`. With
+    /// `namespace_isolation`, applies to the family in all namespaces. Overridden by the
+    /// `maxAgeS` parameter of the volumes producing the bases.
+    #[clap(long, value_parser = parse_family_max_age)]
+    family_max_age_s: Vec<(String, i64)>,
+    /// Size limit of the volumes of a family, as `family=quantity`, e.g. `builds=50Gi`,
+    /// overriding `size_limit`. Overridden by the `sizeLimit` parameter of the volumes.
+    #[clap(long, value_parser = parse_family_size_limit)]
+    family_size_limit: Vec<(String, String)>,
+    /// YAML file of retention rules (maximum age, number of generations, bases kept, weights and
+    /// budget), applied on top of those of the flags. See the README.
+    #[clap(long)]
+    retention_policy: Option<PathBuf>,
+    /// Maximum number of simultaneously published volumes
+    #[clap(long)]
+    max_volumes: Option<usize>,
+    /// Advertise to the scheduler a maximum number of volumes derived from the free space of the
+    /// bases filesystem, at `size_limit` per volume, keeping this fraction of its capacity in
+    /// reserve, e.g. `0.1`. Bounded by `max_volumes`.
+    #[clap(long)]
+    capacity_reserve: Option<f64>,
+    /// Maximum number of data pods being created at once, to spare the API server when many
+    /// volumes are published simultaneously. Further creations are queued.
+    #[clap(long)]
+    max_concurrent_pod_creations: Option<usize>,
+    /// How long publishing waits for a slot when `max_volumes` is reached, before failing
+    #[clap(long, default_value_t = 0)]
+    volume_queue_timeout_s: u64,
+    /// Serve the requests queued for a slot in turn per namespace or per pod owner (e.g. a Job),
+    /// rather than in arrival order. Requires `podInfoOnMount` on the CSIDriver.
+    #[clap(long, value_enum)]
+    fair_queuing: Option<FairQueuing>,
+    /// Directory, e.g. on a fast local device, holding the volume data (upper and work
+    /// directories) instead of the data pods' emptyDir. Size limits are not enforced there, and
+    /// volumes are copied rather than moved when promoted into bases on another device.
+    #[clap(long)]
+    volumes_dir: Option<PathBuf>,
+    /// Memory-backed directory (tmpfs or zram) where copies of the bases in use are kept, to
+    /// serve as faster lower directories
+    #[clap(long)]
+    ram_cache_dir: Option<PathBuf>,
+    /// Maximal total size of the bases copied into `ram_cache_dir`
+    #[clap(long, default_value_t = 1 << 30)]
+    ram_cache_max_bytes: u64,
+    /// Unused bases are evicted from `ram_cache_dir` when less memory than this is available
+    #[clap(long, default_value_t = 1 << 30)]
+    ram_cache_min_available_bytes: u64,
+    /// When promoting a volume, replace the files identical to the previous base of the family by
+    /// hardlinks. Deduplicated files take the modification time of the previous generation.
+    #[clap(long)]
+    dedup_bases: bool,
+    /// When promoting a volume, store the paths, sizes and hashes of the files of the base, to
+    /// check its integrity, compare generations and search bases with the admin CLI
+    #[clap(long)]
+    base_manifests: bool,
+    /// Name of the bases promoted from volumes, within the directory of their family. Supports
+    /// the placeholders `{volume}` (volume id), `{family}`, `{timestamp}` (UTC, e.g.
+    /// `20240131T120000Z`) and `{generation}` (incremented at each promotion in the family),
+    /// e.g. `{family}-{generation}`. A numeric suffix is appended if the name is already taken.
+    #[clap(long, default_value = "{volume}")]
+    base_name: String,
+    /// Scope the families by the namespace of the pod using the volume, so that volumes only use
+    /// and produce the bases of their namespace. Requires `podInfoOnMount` on the CSIDriver.
+    #[clap(long)]
+    namespace_isolation: bool,
+    /// Interval at which byte-identical copy-ups and empty directories are removed from the upper
+    /// directories of the overlays. Disabled if unset.
+    #[clap(long)]
+    pub compaction_interval_s: Option<u64>,
+    /// Interval at which the data pods of this node that do not back any published volume, e.g.
+    /// after a failed unpublish, are deleted. Disabled if unset.
+    #[clap(long)]
+    pub orphan_pod_gc_interval_s: Option<u64>,
+    /// Threshold beyond which holding the state lock or serving a CSI call is considered a stall.
+    /// Stalls are logged with diagnostic state, and the driver then reports itself unhealthy, so
+    /// that it gets restarted. Should exceed `volume_queue_timeout_s`, as queued publishing calls
+    /// are in flight. Disabled if unset.
+    #[clap(long)]
+    pub watchdog_threshold_s: Option<u64>,
+    /// Minimal age of the data pods deleted as orphans, so that volumes being published are not
+    /// affected
+    #[clap(long, default_value_t = 3600)]
+    orphan_pod_min_age_s: i64,
+    /// Only log what the cleanup and the orphan data pod collection would delete, without
+    /// deleting anything, e.g. to review new retention settings before enforcing them
+    #[clap(long)]
+    pub cleanup_dry_run: bool,
+    /// When set, unpublishing waits up to this long for the data pod to be gone and its emptyDir
+    /// to be reclaimed. Stalled deletions are counted in `overlayfs_csi_pod_deletion_stalls_total`.
+    #[clap(long)]
+    pod_deletion_timeout_s: Option<u64>,
+    /// Allocate the storage of all the volumes of a pod from a single data pod, in distinct
+    /// subdirectories of its emptyDir, rather than from one data pod per volume. The size limit
+    /// then applies to these volumes together. Requires `podInfoOnMount` on the CSIDriver.
+    #[clap(long)]
+    shared_data_pods: bool,
+    /// When volumes are promoted into bases
+    #[clap(long, value_enum, default_value_t = PromotionPolicy::WhenMissing)]
+    promotion_policy: PromotionPolicy,
+    /// What to recreate, before retrying once, when an overlay cannot be mounted because of
+    /// stale overlayfs state in its directories (as reported by the kernel), e.g. after a hard
+    /// reboot
+    #[clap(long, value_enum, default_value_t = repair::OverlayRepair::Workdir)]
+    overlay_repair: repair::OverlayRepair,
+    /// Marker by which a volume can be promoted: a file at its root (`{name}` or `file:{name}`)
+    /// or an extended attribute of its root (`xattr:{name}`, e.g.
+    /// `xattr:trusted.overlayfs-csi.as-base`), which the workload does not see as a file and
+    /// cannot create by accident
+    #[clap(long, default_value = ".as_base")]
+    promotion_marker: marker::Marker,
+    /// What to do at startup when the pods directory is not a shared mount, which hides the
+    /// volumes mounted by a containerized driver from the pods
+    #[clap(long, value_enum, default_value_t = propagation::PropagationCheck::Warn)]
+    propagation_check: propagation::PropagationCheck,
+    /// Propagation type of the overlays mounted at the volume targets, instead of the one
+    /// inherited from the pods directory
+    #[clap(long, value_enum)]
+    overlay_propagation: Option<mount::MountPropagation>,
+    /// Propagation type of the bind mounts at the volume targets (volumes created from scratch
+    /// and read-only views)
+    #[clap(long, value_enum)]
+    bind_propagation: Option<mount::MountPropagation>,
+    /// Shell out to the `mount` and `umount` binaries instead of calling `mount(2)` directly
+    #[clap(long)]
+    mount_command: bool,
+    /// Only promote the volumes of pods matching this selector on their labels and annotations,
+    /// e.g. `role=cache-builder`. Requires `podInfoOnMount` on the CSIDriver.
+    #[clap(long)]
+    producer_selector: Option<pods::Selector>,
+    /// Simulation mode for tests: make time advance this many times faster for the age of the
+    /// bases and the cleanup interval, e.g. `3600` for an hour per second
+    #[clap(long)]
+    time_multiplier: Option<f64>,
+    /// Base of the new volumes among the valid bases of their family
+    #[clap(long, value_enum, default_value_t = selection::BaseSelection::Newest)]
+    base_selection: selection::BaseSelection,
+    /// Family whose bases are used when a family has no valid base, as `family=fallback`, e.g.
+    /// `feature-x=main`. Repeat for several fallbacks, tried in order. With
+    /// `namespace_isolation`, applies to the family in all namespaces.
+    #[clap(long, value_parser = parse_family_fallback)]
+    family_fallback: Vec<(String, String)>,
+    /// Command ranking the valid bases of a family for a new volume, e.g.
+    /// `lua /etc/overlayfs-csi/select.lua`, given them and the volume context as JSON on its
+    /// standard input. See the README.
+    #[clap(long)]
+    base_selection_script: Option<String>,
+    /// Time after which the `base_selection_script` is killed and the built-in selection applies
+    #[clap(long, default_value_t = 1000)]
+    base_selection_script_timeout_ms: u64,
+    /// Limit of the data segment (`RLIMIT_DATA`) of the `base_selection_script`
+    #[clap(long, default_value_t = 256 << 20)]
+    base_selection_script_max_bytes: u64,
+    /// Endpoint of a `BaseProvider` gRPC service (`proto/provider.proto`) asked for a base when
+    /// neither the node nor its peers have a valid one, e.g. `http://artifacts:7576`
+    #[clap(long)]
+    base_provider: Option<String>,
+    /// Maximal time spent waiting for the base provider before creating the volume from scratch
+    #[clap(long, default_value_t = 600)]
+    base_provider_timeout_s: u64,
+    /// Container image from which the bases of a family are populated when none is available,
+    /// as `family=image` or `family=image#path` to only use a directory of the image, e.g.
+    /// `deps=ghcr.io/org/deps-cache:main#/cache`. Overridden by the `image` volume attribute.
+    #[clap(long)]
+    family_image: Vec<image::FamilyImage>,
+    /// Command writing the flattened filesystem of the image `$IMAGE` to its standard output, as
+    /// a tar archive
+    #[clap(long, default_value = image::DEFAULT_EXPORT_COMMAND)]
+    image_export_command: String,
+    /// Maximal time spent populating a base from an image before creating the volume from scratch
+    #[clap(long, default_value_t = 600)]
+    image_timeout_s: u64,
+    /// Remote directory from which the bases of a family are populated when none is available,
+    /// as `family=source` with an `rsync://` or `http(s)://` (directory listing, or tarball if
+    /// ending with `.tar`, `.tar.gz`, `.tgz`, `.tar.xz` or `.tar.zst`) URL, an `sftp://` URL or
+    /// `[user@]host:path` (over SSH), e.g. `datasets=rsync://fileserver/datasets`
+    #[clap(long)]
+    family_source: Vec<populate::FamilySource>,
+    /// Maximal time spent copying a base from its remote source before creating the volume from
+    /// scratch
+    #[clap(long, default_value_t = 3600)]
+    source_timeout_s: u64,
+    /// Family populated at startup if it has no valid base, rather than by its first volume, from
+    /// the peers, the base provider, `family_image` or `family_source`, as `family`, or from an
+    /// image or an http(s):// URL (directory listing or tarball) as `family=<oci-ref>` or
+    /// `family=<https-url>`, e.g. `deps=registry.example.com/caches/deps:latest`
+    #[clap(long)]
+    seed: Vec<populate::Seed>,
+    /// Interval at which the families of `seed` are populated again once their bases expired
+    #[clap(long)]
+    seed_interval_s: Option<u64>,
 }
-/// Base for the overlays
-#[derive(Debug, Clone, Hash, Eq, PartialEq)]
-struct Base(PathBuf);
-impl Base {
-    /// Marker for volumes that can be transformed into bases.
-    /// Once transformer, the file contains the creation date.
-    fn as_base_filename() -> &'static str {
-        ".as_base"
-    }
-    fn as_base_file(&self) -> PathBuf {
-        self.0.join(Self::as_base_filename())
-    }
-    fn write_time(&self) -> anyhow::Result<()> {
-        std::fs::write(
-            self.as_base_file(),
-            OffsetDateTime::now_utc().format(&Rfc3339)?,
-        )?;
-        Ok(())
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PromotionPolicy {
+    /// Only when the family has no valid base
+    WhenMissing,
+    /// At every unmount of an eligible volume, replacing the current base. The previous
+    /// generation is kept until the overlays using it are unpublished.
+    Always,
+}
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FairQueuing {
+    /// Namespace of the pod
+    Namespace,
+    /// Controller of the pod, e.g. a Job or ReplicaSet, or the pod itself
+    Owner,
+}
+fn parse_family_weight(s: &str) -> anyhow::Result<(String, f64)> {
+    let (family, weight) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow::anyhow!("Expected family=weight, got {}", s))?;
+    Ok((family.into(), weight.parse()?))
+}
+fn parse_family_rotation(s: &str) -> anyhow::Result<(String, cron::Schedule)> {
+    let (family, schedule) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow::anyhow!("Expected family=schedule, got {}", s))?;
+    Ok((family.into(), schedule.parse()?))
+}
+fn parse_family_fallback(s: &str) -> anyhow::Result<(String, String)> {
+    let (family, fallback) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow::anyhow!("Expected family=fallback, got {}", s))?;
+    Ok((family.into(), fallback.into()))
+}
+fn parse_family_size_limit(s: &str) -> anyhow::Result<(String, String)> {
+    let (family, size_limit) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow::anyhow!("Expected family=quantity, got {}", s))?;
+    capacity::parse_quantity(size_limit)?;
+    Ok((family.into(), size_limit.into()))
+}
+fn parse_family_max_age(s: &str) -> anyhow::Result<(String, i64)> {
+    let (family, max_age_s) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow::anyhow!("Expected family=seconds, got {}", s))?;
+    Ok((family.into(), max_age_s.parse()?))
+}
+impl Default for OverlayFlags {
+    fn default() -> Self {
+        Self {
+            name: Default::default(),
+            node: Default::default(),
+            namespace: Default::default(),
+            bases: Default::default(),
+            pods: "/var/lib/kubelet/pods".into(),
+            data_pod_template: None,
+            data_pod_prefix: "overlayfs-data".into(),
+            max_age_s: 86400,
+            hard_max_age_s: None,
+            size_limit: "10Gi".into(),
+            stats_ttl_s: 60,
+            init_command: None,
+            init_timeout_s: 600,
+            hooks: Default::default(),
+            peers: Default::default(),
+            metrics: Default::default(),
+            webhook_url: None,
+            webhook_retries: 5,
+            invalidation_configmap: None,
+            state_configmap: None,
+            warm_labels: false,
+            bases_max_bytes: None,
+            archive_location: None,
+            archive_max_bytes: None,
+            archive_ttl_s: None,
+            family_weight: vec![],
+            family_rotation: vec![],
+            family_max_age_s: vec![],
+            family_size_limit: vec![],
+            retention_policy: None,
+            max_volumes: None,
+            capacity_reserve: None,
+            max_concurrent_pod_creations: None,
+            volume_queue_timeout_s: 0,
+            fair_queuing: None,
+            volumes_dir: None,
+            ram_cache_dir: None,
+            ram_cache_max_bytes: 1 << 30,
+            ram_cache_min_available_bytes: 1 << 30,
+            dedup_bases: false,
+            base_manifests: false,
+            base_name: "{volume}".into(),
+            namespace_isolation: false,
+            compaction_interval_s: None,
+            orphan_pod_gc_interval_s: None,
+            watchdog_threshold_s: None,
+            orphan_pod_min_age_s: 3600,
+            cleanup_dry_run: false,
+            pod_deletion_timeout_s: None,
+            shared_data_pods: false,
+            promotion_policy: PromotionPolicy::WhenMissing,
+            overlay_repair: repair::OverlayRepair::Workdir,
+            promotion_marker: Default::default(),
+            propagation_check: propagation::PropagationCheck::Warn,
+            overlay_propagation: None,
+            bind_propagation: None,
+            mount_command: false,
+            producer_selector: None,
+            time_multiplier: None,
+            base_selection: selection::BaseSelection::Newest,
+            family_fallback: vec![],
+            base_selection_script: None,
+            base_selection_script_timeout_ms: 1000,
+            base_selection_script_max_bytes: 256 << 20,
+            base_provider: None,
+            base_provider_timeout_s: 600,
+            family_image: vec![],
+            image_export_command: image::DEFAULT_EXPORT_COMMAND.into(),
+            image_timeout_s: 600,
+            family_source: vec![],
+            source_timeout_s: 3600,
+            seed: vec![],
+            seed_interval_s: None,
+        }
     }
-    fn read_time(&self) -> anyhow::Result<OffsetDateTime> {
-        let data = std::fs::read_to_string(self.0.join(self.as_base_file()))?;
-        Ok(OffsetDateTime::parse(&data, &Rfc3339)?)
+}
+/// Error returned when an operation was cancelled by the caller, after rolling back.
+#[derive(Debug)]
+pub struct Cancelled;
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Operation cancelled")
     }
-    /// Check if a base is younger than `max_age_s`.
-    fn valid(&self, max_age_s: i64) -> bool {
-        let Ok(dt) = self.read_time() else {
-            return false;
-        };
-        let age = OffsetDateTime::now_utc() - dt;
-        if age.is_negative() {
-            warn!(?self, "Base in the future");
-            false
-        } else if age.whole_seconds() < max_age_s {
-            true
-        } else {
-            debug!(s = age.whole_seconds(), max_age_s, ?self, "Too old base");
-            false
-        }
+}
+impl std::error::Error for Cancelled {}
+
+/// Error returned when the maximum number of volumes is reached.
+#[derive(Debug)]
+pub struct QuotaExceeded;
+impl std::fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Maximum number of volumes on the node reached")
+    }
+}
+impl std::error::Error for QuotaExceeded {}
+
+/// Error returned when a writer of the family is already published on the node.
+#[derive(Debug)]
+pub struct WriterBusy(String);
+impl std::fmt::Display for WriterBusy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Family {} already has a writer on the node", self.0)
+    }
+}
+impl std::error::Error for WriterBusy {}
+
+/// Error returned when a volume is not published on the node.
+#[derive(Debug)]
+pub struct VolumeNotFound(String);
+impl std::fmt::Display for VolumeNotFound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Volume {} is not published on the node", self.0)
+    }
+}
+impl std::error::Error for VolumeNotFound {}
+
+/// Error returned when a volume cannot be resized to the requested capacity.
+#[derive(Debug)]
+pub struct CapacityOutOfRange(String);
+impl std::fmt::Display for CapacityOutOfRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl std::error::Error for CapacityOutOfRange {}
+
+/// Error returned when the storage of a volume cannot be resized at all.
+#[derive(Debug)]
+pub struct NotExpandable(String);
+impl std::fmt::Display for NotExpandable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
     }
 }
+impl std::error::Error for NotExpandable {}
+
+/// Deletion made by the cleanup, or that it would make in a dry run
+#[derive(Debug, Clone)]
+pub struct CleanupAction {
+    /// `base` or `pod`
+    pub kind: &'static str,
+    /// Path of the base, or name of the data pod
+    pub name: String,
+    pub size_bytes: u64,
+    pub reason: String,
+}
+
+/// State of the published volumes, protected by the `Overlays` lock
+#[derive(Debug, Default)]
+struct State {
+    /// Volumes using each base
+    bases: HashMap<Base, HashSet<String>>,
+    /// Context of the published volumes
+    volumes: HashMap<String, VolumeContext>,
+    /// Slots held by the published volumes, when their number is limited
+    slots: HashMap<String, slots::Slot>,
+    /// Upper and lower directories of the overlays
+    layers: HashMap<String, (PathBuf, PathBuf)>,
+    /// Volumes whose pod matches the producer selector
+    producers: HashSet<String>,
+}
+
 pub struct Overlays {
-    // {workdir}/bases/{id}
+    // {workdir}/bases/{family}/{id}
     //          /volumes/{id}/upper
     //                       /work
     flags: OverlayFlags,
-    pods: Api<Pod>,
+    pods: Arc<dyn PodApi>,
+    /// Validated template of the data pods
+    data_pod_template: Pod,
+    mounter: Arc<dyn Mounter>,
     // To avoid spurious cross-device errors when we move volumes into bases, we retrieve the path
     // where the `bases` volume is present on the host, which should be on the same device as the
     // `pods` folder.
     bases_host: PathBuf,
-    lock: Mutex<HashMap<Base, HashSet<String> /* volumes */>>,
+    lock: watchdog::TrackedMutex<State>,
+    /// Serializes the creation and deletion of shared data pods
+    data_pods: Mutex<()>,
+    stats: stats::StatsCache,
+    audit: audit::AuditLog,
+    hooks: Hooks,
+    peers: peers::Peers,
+    metrics: metrics::Metrics,
+    webhook: Webhook,
+    epochs: invalidation::Epochs,
+    volume_slots: Option<slots::Slots>,
+    pod_creations: Option<Semaphore>,
+    watchdog: watchdog::Watchdog,
+    /// Notified when the volumes or bases change
+    state_changed: tokio::sync::watch::Sender<()>,
+    ram_cache: Option<ramcache::RamCache>,
+    /// Time used for the age of the bases
+    clock: Arc<dyn clock::Clock>,
+    policy: policy::Policy,
+    base_selection: Arc<dyn selection::BaseSelectionPolicy>,
+    /// Held while storing expired bases in the archive location
+    archiving: Mutex<()>,
 }
 struct PodUid(String);
 impl AsRef<Path> for PodUid {
@@ -95,31 +606,18 @@ impl AsRef<Path> for PodUid {
     }
 }
 impl Overlays {
-    pub async fn from_flags(flags: OverlayFlags, pods: Api<Pod>) -> anyhow::Result<Arc<Self>> {
-        let mut overlays = Self {
-            flags,
-            pods,
-            bases_host: Default::default(),
-            lock: Default::default(),
-        };
-        overlays.bases_host = overlays.empty_dir(
-            PodUid(std::env::var("POD_ID").context("Failed to find pod ID from environment")?),
-            "bases",
-        );
-        let overlays = Arc::new(overlays);
-        // Cleanup thread
-        tokio::task::spawn({
-            let overlays = overlays.clone();
-            async move {
-                loop {
-                    if let Err(e) = overlays.cleanup().await {
-                        error!("Failed to cleanup bases: {}", e);
-                    }
-                    tokio::time::sleep(std::time::Duration::from_secs(BASE_CLEANUP_FREQ_S)).await;
-                }
-            }
-        });
-        Ok(overlays)
+    pub async fn from_flags(
+        flags: OverlayFlags,
+        pods: impl PodApi + 'static,
+    ) -> anyhow::Result<Arc<Self>> {
+        OverlaysBuilder::from_flags(flags)
+            .pod_api(pods)
+            .build()
+            .await
+    }
+    /// Whether no stall was detected by the watchdog
+    pub fn healthy(&self) -> bool {
+        self.watchdog.healthy()
     }
     fn empty_dir(&self, pod_uid: PodUid, volume: &str) -> PathBuf {
         self.flags
@@ -129,167 +627,1841 @@ impl Overlays {
             .join("kubernetes.io~empty-dir")
             .join(volume)
     }
-    fn volume_dir(&self, pod_uid: PodUid) -> PathBuf {
-        self.empty_dir(pod_uid, "volume")
+    fn volume_dir(&self, id: &str, data_pod: &str, pod_uid: PodUid) -> PathBuf {
+        match &self.flags.volumes_dir {
+            Some(volumes_dir) => volumes_dir.join(encoding::encode(id)),
+            // Volumes sharing a data pod each get a subdirectory
+            None if data_pod != id => self
+                .empty_dir(pod_uid, datapod::VOLUME_NAME)
+                .join(encoding::encode(id)),
+            None => self.empty_dir(pod_uid, datapod::VOLUME_NAME),
+        }
+    }
+    /// Retrieve the data directory of a published volume
+    async fn find_volume_dir(&self, id: &str) -> anyhow::Result<PathBuf> {
+        if let Some(volumes_dir) = &self.flags.volumes_dir {
+            return Ok(volumes_dir.join(encoding::encode(id)));
+        }
+        // Get the volume path from the pod
+        let data_pod = self.data_pod_of(id);
+        let pod = self
+            .find_pod(&data_pod)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Data pod of volume {} not found", id))?;
+        Ok(self.volume_dir(id, &data_pod, PodUid(pod.metadata.uid.unwrap())))
     }
-    async fn base_host(&self, id: &str) -> anyhow::Result<Base> {
-        Ok(Base(self.bases_host.join(id)))
+    /// File recording the data pod of a volume that shares it with other volumes
+    fn data_pod_record(&self, id: &str) -> PathBuf {
+        self.flags
+            .bases
+            .join(".data-pods")
+            .join(encoding::encode(id))
+    }
+    /// Data pod that should hold the storage of a new volume
+    fn data_pod_name(&self, id: &str, context: &VolumeContext) -> String {
+        match context.pod.as_ref().and_then(|p| p.uid.as_ref()) {
+            Some(uid) if self.flags.shared_data_pods => format!("data-{}", uid),
+            _ => id.into(),
+        }
+    }
+    /// Data pod holding the storage of a published volume
+    fn data_pod_of(&self, id: &str) -> String {
+        std::fs::read_to_string(self.data_pod_record(id)).unwrap_or_else(|_| id.into())
+    }
+    /// Context with the family scoped to the namespace of the pod with `namespace_isolation`, as
+    /// `{family}@{namespace}`. Families cannot contain `@`, so that scoped families never clash.
+    fn isolate(&self, context: &VolumeContext) -> anyhow::Result<VolumeContext> {
+        let mut context = context.clone();
+        if self.flags.namespace_isolation {
+            let Some(pod) = &context.pod else {
+                anyhow::bail!("Namespace isolation requires podInfoOnMount on the CSIDriver");
+            };
+            context.family = format!("{}@{}", context.family, pod.namespace);
+        }
+        Ok(context)
+    }
+    /// Location of a new base promoted from a volume, and its generation in the family
+    async fn base_host(&self, family: &str, id: &str) -> anyhow::Result<(Base, u64)> {
+        let family_dir = self.bases_host.join(family);
+        std::fs::create_dir_all(&family_dir)?;
+        let generation = self
+            .family_bases(family)
+            .filter_map(|b| b.read_meta().generation)
+            .max()
+            .map_or(1, |g| g + 1);
+        let name = base::render_name(
+            &self.flags.base_name,
+            &encoding::encode(id),
+            family,
+            generation,
+            self.clock.now(),
+        )?;
+        // Never reuse the name of an existing base, e.g. a previous promotion of the same volume
+        let mut unique = name.clone();
+        for n in 2.. {
+            if !family_dir.join(&unique).exists() {
+                break;
+            }
+            unique = format!("{}-{}", name, n);
+        }
+        Ok((Base(family_dir.join(unique)), generation))
     }
+    /// All bases, across families
     fn bases(&self) -> anyhow::Result<impl Iterator<Item = Base>> {
-        Ok(std::fs::read_dir(&self.flags.bases)?
-            .filter_map(Result::ok)
-            .filter(|x| x.file_type().map_or(false, |t| t.is_dir()))
-            .map(|x| Base(x.path().to_owned())))
+        Ok(base::subdirs(&self.flags.bases)?
+            // Skip the directories used for transfers
+            .filter(|family| {
+                !family
+                    .file_name()
+                    .map_or(true, |n| n.to_string_lossy().starts_with('.'))
+            })
+            .flat_map(|family| base::subdirs(&family).into_iter().flatten())
+            .map(Base))
     }
-    fn find_valid_base(&self) -> anyhow::Result<Option<Base>> {
-        Ok(self.bases()?.find(|base| base.valid(self.flags.max_age_s)))
+    fn family_bases(&self, family: &str) -> impl Iterator<Item = Base> {
+        base::subdirs(&self.flags.bases.join(family))
+            .into_iter()
+            .flatten()
+            .map(Base)
     }
-    async fn delete_pod(&self, id: &str) -> anyhow::Result<()> {
-        info!(id, "Deleting pod");
-        self.pods.delete(id, &DeleteParams::background()).await?;
-        Ok(())
+    /// Check whether a base can be used for new volumes
+    fn base_valid(&self, base: &Base) -> bool {
+        if let Some(reason) = self.retention(base).retired {
+            debug!(?base, reason, "Base retired");
+            return false;
+        }
+        let now = self.clock.now();
+        if self.base_rotation(base).map_or(false, |t| t <= now) {
+            debug!(?base, "Base rotated out");
+            return false;
+        }
+        let meta = base.read_meta();
+        if meta.invalidated {
+            debug!(?base, "Base invalidated by an administrator");
+            return false;
+        }
+        if let Some(id) = &meta.superseded_by {
+            debug!(?base, id, "Base superseded");
+            return false;
+        }
+        let epoch = self.epochs.get(&base.family());
+        if meta.epoch != epoch {
+            debug!(?base, ?epoch, "Invalidated base");
+            return false;
+        }
+        true
     }
-    async fn watch_pod(&self, id: &str) -> anyhow::Result<()> {
-        let mut watch = self
-            .pods
-            .watch(
-                &WatchParams::default().fields(&format!("metadata.name={}", id)),
-                "0",
-            )
+    /// Time at which the base is rotated out according to the schedule of its family
+    fn base_rotation(&self, base: &Base) -> Option<time::OffsetDateTime> {
+        let family = base.family();
+        let (_, schedule) = self
+            .flags
+            .family_rotation
+            .iter()
+            .find(|(f, _)| *f == family)
+            .or_else(|| self.flags.family_rotation.iter().find(|(f, _)| f == "*"))?;
+        base.rotation(schedule)
+    }
+    /// `max_age_s` of a base: set by the volume that produced it, or else by its family
+    fn base_max_age_s(&self, base: &Base) -> i64 {
+        base.read_meta()
+            .max_age_s
+            .unwrap_or_else(|| self.policy.family_max_age_s(&base.family()))
+    }
+    /// What the retention policy says about a base
+    fn retention(&self, base: &Base) -> policy::Verdict {
+        self.policy.evaluate(&self.retention_facts(base))
+    }
+    fn retention_facts(&self, base: &Base) -> policy::Facts {
+        let created = base.created().ok();
+        let age_s = created.map(|c| (self.clock.now() - c).whole_seconds());
+        if age_s.map_or(false, |a| a < 0) {
+            warn!(?base, "Base in the future");
+        }
+        policy::Facts {
+            family: base.family(),
+            age_s,
+            max_age_s: base.read_meta().max_age_s,
+            newer: self
+                .family_bases(&base.family())
+                .filter(|b| b.created().ok() > created)
+                .count(),
+        }
+    }
+    /// Check whether a base that is not valid anymore can be deleted
+    fn base_deletable(&self, base: &Base) -> bool {
+        let facts = self.retention_facts(base);
+        if self.policy.pinned(&facts) {
+            return false;
+        }
+        base.read_meta().superseded_by.is_some() || self.policy.evaluate(&facts).deletable.is_some()
+    }
+    /// Stop using the other valid bases of a family after a promotion. They are deleted as soon as
+    /// they are not used anymore.
+    fn supersede(&self, family: &str, name: &str) {
+        for base in self
+            .family_bases(family)
+            .filter(|b| b.name() != name && self.base_valid(b))
+        {
+            info!(?base, name, "Superseding base");
+            let mut meta = base.read_meta();
+            meta.superseded_by = Some(name.into());
+            if let Err(e) = base.write_meta(&meta) {
+                warn!(?base, "Failed to supersede base: {}", e);
+            }
+        }
+    }
+    /// Mark all bases of a family as expired, returning their names. They are left on disk until
+    /// the next cleanup.
+    pub async fn invalidate_family(&self, family: &str) -> anyhow::Result<Vec<String>> {
+        anyhow::ensure!(transfer::valid_component(family), "Invalid family");
+        let _mapping = self.lock.lock().await;
+        let mut invalidated = vec![];
+        for base in self.family_bases(family) {
+            let mut meta = base.read_meta();
+            if !meta.invalidated {
+                meta.invalidated = true;
+                base.write_meta(&meta)?;
+                warn!(?base, "Invalidated base");
+                invalidated.push(base.name());
+            }
+        }
+        Ok(invalidated)
+    }
+    /// Kubernetes name of a data pod. Volume ids can collide with other pods or exceed the length
+    /// of names, hence the hash.
+    fn pod_name(&self, key: &str) -> String {
+        format!("{}-{}", self.flags.data_pod_prefix, datapod::hash(key))
+    }
+    /// Look a data pod up by its label
+    async fn find_pod(&self, key: &str) -> anyhow::Result<Option<Pod>> {
+        let selector = format!("{}={}", datapod::KEY_LABEL, datapod::hash(key));
+        Ok(self
+            .metrics
+            .time_kube("list", self.pods.list(&selector))
             .await?
-            .boxed();
-        while let Some(status) = watch.try_next().await? {
-            if let WatchEvent::Modified(pod) = status {
-                if pod
-                    .status
-                    .and_then(|status| status.phase)
-                    .map_or(false, |phase| phase == "Running")
+            .into_iter()
+            .find(|pod| {
+                pod.metadata
+                    .annotations
+                    .as_ref()
+                    .and_then(|a| a.get(datapod::KEY_ANNOTATION))
+                    .map_or(false, |k| k == key)
+            }))
+    }
+    async fn delete_pod(&self, key: &str) -> anyhow::Result<()> {
+        match self.find_pod(key).await? {
+            Some(pod) => {
+                let name = pod.metadata.name.unwrap_or_default();
+                info!(key, name, "Deleting pod");
+                self.metrics
+                    .time_kube("delete", self.pods.delete(&name))
+                    .await?;
+                if let (Some(timeout), Some(uid)) =
+                    (self.flags.pod_deletion_timeout_s, pod.metadata.uid)
                 {
-                    info!(id, uid = pod.metadata.uid.unwrap(), "Pod was created");
-                    return Ok(());
+                    let timeout = std::time::Duration::from_secs(timeout);
+                    let wait = self.wait_pod_gone(&name, PodUid(uid));
+                    if tokio::time::timeout(timeout, wait).await.is_err() {
+                        let finalizers = pod.metadata.finalizers.unwrap_or_default();
+                        warn!(name, ?finalizers, ?timeout, "Pod deletion stalled");
+                        self.metrics.record_pod_deletion_stall();
+                    }
                 }
+                Ok(())
+            }
+            None => {
+                warn!(key, "Data pod not found, not deleting it");
+                Ok(())
             }
         }
-        Ok(())
     }
-    async fn create_pod(&self, id: &str) -> anyhow::Result<PodUid> {
-        info!(id, "Creating pod to allocate storage");
-        let mut pod: Pod = serde_yaml::from_str(include_str!("../data_pod.yaml"))?;
-        pod.metadata.name = Some(id.into());
+    /// Wait until the pod object is gone and kubelet removed its emptyDir.
+    async fn wait_pod_gone(&self, name: &str, uid: PodUid) {
+        let empty_dir = self.empty_dir(uid, datapod::VOLUME_NAME);
+        loop {
+            match self.metrics.time_kube("get", self.pods.exists(name)).await {
+                Ok(false) if !empty_dir.exists() => {
+                    debug!(name, "Pod deleted");
+                    return;
+                }
+                Ok(_) => {}
+                Err(e) => warn!(name, "Failed to check pod deletion: {}", e),
+            }
+            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        }
+    }
+    /// Size limit of the data pod of a volume
+    fn size_limit<'a>(&'a self, context: &'a VolumeContext) -> &'a str {
+        let unscoped = context.family.split('@').next().unwrap_or(&context.family);
+        let family_size_limit = self
+            .flags
+            .family_size_limit
+            .iter()
+            .rev()
+            .find(|(f, _)| *f == context.family || f == unscoped)
+            .map(|(_, size_limit)| size_limit.as_str());
+        context
+            .size_limit
+            .as_deref()
+            .or(family_size_limit)
+            .unwrap_or(&self.flags.size_limit)
+    }
+    /// Create the data pod of a volume, or reuse it if it is shared with other volumes.
+    async fn create_pod(&self, id: &str, name: &str, size_limit: &str) -> anyhow::Result<PodUid> {
+        if name == id {
+            return self.create_data_pod(id, size_limit).await;
+        }
+        let _guard = self.data_pods.lock().await;
+        let record = self.data_pod_record(id);
+        std::fs::create_dir_all(record.parent().unwrap())?;
+        std::fs::write(&record, name)?;
+        let result = match self.find_pod(name).await {
+            Ok(Some(pod)) if pod.metadata.deletion_timestamp.is_some() => {
+                Err(anyhow::anyhow!("Data pod {} is terminating", name))
+            }
+            Ok(Some(pod)) => {
+                info!(id, name, "Reusing data pod");
+                Ok(self.wait_pod_running(pod).await)
+            }
+            Ok(None) => self.create_data_pod(name, size_limit).await,
+            Err(e) => Err(e),
+        };
+        if result.is_err() {
+            let _ = std::fs::remove_file(&record);
+        }
+        result
+    }
+    /// Delete the data pod of a volume, unless other volumes still use it.
+    async fn release_pod(&self, id: &str) -> anyhow::Result<()> {
+        let name = self.data_pod_of(id);
+        if name == id {
+            return self.delete_pod(id).await;
+        }
+        let _guard = self.data_pods.lock().await;
+        let record = self.data_pod_record(id);
+        let _ = std::fs::remove_file(&record);
+        let used = std::fs::read_dir(record.parent().unwrap())?
+            .filter_map(Result::ok)
+            .any(|e| std::fs::read_to_string(e.path()).map_or(false, |n| n == name));
+        if used {
+            info!(id, name, "Data pod still used by other volumes");
+            return Ok(());
+        }
+        self.delete_pod(&name).await
+    }
+    async fn create_data_pod(&self, key: &str, size_limit: &str) -> anyhow::Result<PodUid> {
+        let _permit = match &self.pod_creations {
+            Some(semaphore) => {
+                let _queued = self.metrics.queue_pod_creation();
+                Some(semaphore.acquire().await?)
+            }
+            None => None,
+        };
+        let name = self.pod_name(key);
+        info!(key, name, "Creating pod to allocate storage");
+        let mut pod = self.data_pod_template.clone();
+        pod.metadata.name = Some(name.clone());
         pod.metadata.namespace = Some(self.flags.namespace.clone());
-        let spec = pod.spec.as_mut().unwrap();
-        spec.volumes.as_mut().unwrap()[0]
-            .empty_dir
-            .as_mut()
-            .unwrap()
-            .size_limit = Some(Quantity(self.flags.size_limit.clone()));
-        spec.node_name = Some(self.flags.node.clone());
-        let pod = self.pods.create(&Default::default(), &pod).await?;
+        pod.metadata
+            .labels
+            .get_or_insert_with(Default::default)
+            .insert(datapod::KEY_LABEL.into(), datapod::hash(key));
+        pod.metadata
+            .annotations
+            .get_or_insert_with(Default::default)
+            .insert(datapod::KEY_ANNOTATION.into(), key.into());
+        datapod::set_size_limit(&mut pod, size_limit);
+        pod.spec.as_mut().unwrap().node_name = Some(self.flags.node.clone());
+        let pod = match self
+            .metrics
+            .time_kube("create", self.pods.create(&pod))
+            .await
+        {
+            Ok(pod) => pod,
+            // A retried publish, e.g. after a restart of the driver, finds the pod it created
+            Err(e) if pods::already_exists(&e) => self.existing_data_pod(key, &name).await?,
+            Err(e) => return Err(e),
+        };
+        Ok(self.wait_pod_running(pod).await)
+    }
+    /// Data pod of the volume left by an earlier attempt to publish it
+    async fn existing_data_pod(&self, key: &str, name: &str) -> anyhow::Result<Pod> {
+        let pod = self.metrics.time_kube("get", self.pods.get(name)).await?;
+        let owner = pod
+            .metadata
+            .annotations
+            .as_ref()
+            .and_then(|a| a.get(datapod::KEY_ANNOTATION));
+        anyhow::ensure!(
+            owner.map(String::as_str) == Some(key),
+            "The pod {} already exists for another volume ({:?})",
+            name,
+            owner
+        );
+        anyhow::ensure!(
+            pod.metadata.deletion_timestamp.is_none(),
+            "The pod {} of a previous attempt is terminating",
+            name
+        );
+        info!(key, name, "Reusing the data pod of a previous attempt");
+        Ok(pod)
+    }
+    async fn wait_pod_running(&self, pod: Pod) -> PodUid {
+        let name = pod.metadata.name.unwrap_or_default();
         let uid = pod.metadata.uid.unwrap();
-        info!(id, uid, "Waiting for pod to get created");
+        let running = pod
+            .status
+            .and_then(|status| status.phase)
+            .map_or(false, |phase| phase == "Running");
+        if running {
+            return PodUid(uid);
+        }
+        info!(name, uid, "Waiting for pod to get created");
         loop {
-            match self.watch_pod(id).await {
+            match self
+                .metrics
+                .time_kube("wait_running", self.pods.wait_running(&name))
+                .await
+            {
                 Ok(()) => {
-                    return Ok(PodUid(uid));
+                    return PodUid(uid);
                 }
                 Err(e) => {
                     error!(
-                        id,
+                        name,
                         "Watching for pod creation failed ({}), restarting watch", e
                     );
                 }
             }
         }
     }
-    pub async fn mount(&self, id: &str, mountpoint: impl AsRef<Path>) -> anyhow::Result<()> {
-        let mountpoint = mountpoint.as_ref();
-        let pod_uid = self.create_pod(id).await?;
-        let volume_dir = self.volume_dir(pod_uid);
+    /// Undo the work of a `mount` that did not complete.
+    async fn rollback_mount(&self, id: &str) {
+        if let Err(e) = self.release_pod(id).await {
+            warn!(id, "Failed to delete pod while rolling back: {}", e);
+        }
+    }
+    /// Wait for a free volume slot, if the number of volumes is limited.
+    async fn acquire_volume_slot(
+        &self,
+        id: &str,
+        context: &VolumeContext,
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<Option<slots::Slot>> {
+        let Some(slots) = &self.volume_slots else {
+            return Ok(None);
+        };
+        if let Some(slot) = slots.try_acquire() {
+            return Ok(Some(slot));
+        }
+        let timeout = std::time::Duration::from_secs(self.flags.volume_queue_timeout_s);
+        let tenant = self.queue_tenant(id, context).await;
+        info!(
+            id,
+            ?timeout,
+            tenant,
+            "Maximum number of volumes reached, queuing"
+        );
+        tokio::select! {
+            slot = tokio::time::timeout(timeout, slots.acquire(&tenant)) => match slot {
+                Ok(slot) => Ok(Some(slot?)),
+                Err(_) => Err(QuotaExceeded.into()),
+            },
+            _ = cancel.cancelled() => Err(Cancelled.into()),
+        }
+    }
+    /// Queue in which a request waits for a volume slot, according to `fair_queuing`
+    async fn queue_tenant(&self, id: &str, context: &VolumeContext) -> String {
+        let Some(fair_queuing) = self.flags.fair_queuing else {
+            return String::new();
+        };
+        let Some(pod_ref) = &context.pod else {
+            warn!(id, "Pod unknown, queuing with the other unknown pods");
+            return String::new();
+        };
+        match fair_queuing {
+            FairQueuing::Namespace => pod_ref.namespace.clone(),
+            FairQueuing::Owner => {
+                let owner = self.get_pod(id, context).await.and_then(|pod| {
+                    pod.metadata
+                        .owner_references?
+                        .into_iter()
+                        .find(|o| o.controller == Some(true))
+                });
+                match owner {
+                    Some(o) => format!("{}/{}/{}", pod_ref.namespace, o.kind, o.name),
+                    None => format!("{}/Pod/{}", pod_ref.namespace, pod_ref.name),
+                }
+            }
+        }
+    }
+    /// Mount a volume. If `cancel` is triggered before the mount is performed, partial work (i.e.
+    /// the data pod) is rolled back and [`Cancelled`] is returned.
+    pub async fn mount(
+        &self,
+        id: &str,
+        mountpoint: impl AsRef<Path>,
+        context: &VolumeContext,
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<()> {
+        let mount = self.mount_volume(id, mountpoint.as_ref(), context, cancel);
+        let result = self.metrics.time_operation("publish", mount).await;
+        self.record_volume_metrics().await;
+        result
+    }
+    async fn mount_volume(
+        &self,
+        id: &str,
+        mountpoint: &Path,
+        context: &VolumeContext,
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<()> {
+        let context = &self.isolate(context)?;
+        if self.lock.lock().await.volumes.contains_key(id) {
+            info!(id, "Volume already published");
+            return Ok(());
+        }
+        let mountpoint_str = mountpoint.to_string_lossy().into_owned();
+        self.hooks
+            .run(
+                HookEvent::PreMount,
+                &[("VOLUME_ID", id), ("MOUNTPOINT", mountpoint_str.as_str())],
+            )
+            .await?;
+        if let Some(info) = mountinfo::find(self.mounter.mounts()?, mountpoint) {
+            if context.access == Access::ReadOnly {
+                info!(id, ?info, "Replacing leftover read-only view");
+                self.mounter.unmount(mountpoint)?;
+            } else {
+                return self.adopt(id, mountpoint, context, info).await;
+            }
+        }
+        if context.access == Access::ReadOnly {
+            return self.mount_read_only(id, mountpoint, context).await;
+        }
+        let slot = self.acquire_volume_slot(id, context, cancel).await?;
+        let data_pod = self.data_pod_name(id, context);
+        let pod_uid = tokio::select! {
+            pod_uid = self.create_pod(id, &data_pod, self.size_limit(context)) => pod_uid?,
+            _ = cancel.cancelled() => {
+                warn!(id, "Cancelled while creating pod, rolling back");
+                self.rollback_mount(id).await;
+                return Err(Cancelled.into());
+            }
+        };
+        let volume_dir = self.volume_dir(id, &data_pod, pod_uid);
+        let pod = self.get_pod(id, context).await;
+        let mut context = context.clone();
+        if let Some(pod) = &pod {
+            context.apply_pod_annotations(pod);
+        }
+        let context = &context;
+        let fresh = context.pristine || context.force_fresh;
+        if fresh {
+            info!(id, context.pristine, context.force_fresh, "Not using bases");
+        }
+        let producer = !context.pristine && self.is_producer(id, pod.as_ref());
+        let remote = self.peers.enabled()
+            || self.flags.base_provider.is_some()
+            || self.base_image(context).is_some()
+            || self.has_family_source(&context.family);
+        if !fresh && remote && !self.has_valid_base(&context.family) {
+            tokio::select! {
+                _ = self.fetch_base(id, context) => {},
+                _ = cancel.cancelled() => {
+                    warn!(id, "Cancelled while fetching base, rolling back");
+                    self.rollback_mount(id).await;
+                    return Err(Cancelled.into());
+                }
+            }
+        }
 
-        let mut mapping = self.lock.lock().await;
+        let mut mapping = tokio::select! {
+            mapping = self.lock.lock() => mapping,
+            _ = cancel.cancelled() => {
+                warn!(id, "Cancelled while waiting for lock, rolling back");
+                self.rollback_mount(id).await;
+                return Err(Cancelled.into());
+            }
+        };
+        if context.access == Access::Writer {
+            let busy = mapping
+                .volumes
+                .values()
+                .any(|c| c.access == Access::Writer && c.family == context.family);
+            if busy {
+                drop(mapping);
+                self.rollback_mount(id).await;
+                return Err(WriterBusy(context.family.clone()).into());
+            }
+        }
+        // From here on, failures must release the data pod
+        let base = match self.mount_layers(id, mountpoint, context, &volume_dir, fresh) {
+            Ok(base) => base,
+            Err(e) => {
+                drop(mapping);
+                self.rollback_mount(id).await;
+                return Err(e);
+            }
+        };
+        if let Some((base, lower)) = base {
+            let upper = volume_dir.join("upper");
+            let base_str = base.0.to_string_lossy().into_owned();
+            mapping
+                .bases
+                .entry(base)
+                .or_default()
+                .insert(id.to_string());
+            mapping.volumes.insert(id.into(), context.clone());
+            mapping.slots.extend(slot.map(|s| (id.to_string(), s)));
+            mapping.layers.insert(id.into(), (upper.clone(), lower));
+            if producer {
+                mapping.producers.insert(id.into());
+            }
+            self.stats.register(id, upper);
+            debug!(?mapping);
+            self.persist(&mapping);
+            drop(mapping);
+            self.metrics.record_publish_source("base");
+            self.hooks
+                .run_logged(
+                    HookEvent::PostMount,
+                    &[
+                        ("VOLUME_ID", id),
+                        ("MOUNTPOINT", mountpoint_str.as_str()),
+                        ("BASE", base_str.as_str()),
+                    ],
+                )
+                .await;
+            return Ok(());
+        }
+        mapping.volumes.insert(id.into(), context.clone());
+        mapping.slots.extend(slot.map(|s| (id.to_string(), s)));
+        if producer {
+            mapping.producers.insert(id.into());
+        }
+        self.stats.register(id, volume_dir.clone());
+        debug!(?mapping);
+        self.persist(&mapping);
+        drop(mapping);
+
+        // Pristine volumes stay empty
+        let init_command = self
+            .flags
+            .init_command
+            .as_ref()
+            .filter(|_| !context.pristine);
+        if let Some(command) = init_command {
+            if let Err(e) = self.init_volume(id, &volume_dir, command, cancel).await {
+                let mut mapping = self.lock.lock().await;
+                mapping.volumes.remove(id);
+                mapping.slots.remove(id);
+                mapping.producers.remove(id);
+                self.persist(&mapping);
+                drop(mapping);
+                if let Err(e) = self.mounter.unmount(mountpoint) {
+                    warn!(id, "Failed to unmount while rolling back: {}", e);
+                }
+                self.unmount_tmpfs(id, context, &volume_dir);
+                self.stats.forget(id);
+                self.rollback_mount(id).await;
+                return Err(e);
+            }
+        }
+        self.hooks
+            .run_logged(
+                HookEvent::PostMount,
+                &[("VOLUME_ID", id), ("MOUNTPOINT", mountpoint_str.as_str())],
+            )
+            .await;
+        self.metrics.record_publish_source("scratch");
+        Ok(())
+    }
+    /// Mount a volume, with its data in `volume_dir`: an overlay on a valid base, returning the
+    /// base and the lower directory, or else a bind mount of the data directory. Undoes its own
+    /// mounts on failure.
+    fn mount_layers(
+        &self,
+        id: &str,
+        mountpoint: &Path,
+        context: &VolumeContext,
+        volume_dir: &Path,
+        fresh: bool,
+    ) -> anyhow::Result<Option<(Base, PathBuf)>> {
         std::fs::create_dir_all(mountpoint)?;
-        if let Some(base) = self.find_valid_base()? {
+        if let Some(size) = &context.tmpfs_size {
+            info!(id, size, ?volume_dir, "Mounting tmpfs for the volume data");
+            std::fs::create_dir_all(volume_dir)?;
+            self.mounter.mount_tmpfs(size, volume_dir)?;
+        }
+        let result = self.mount_upper(id, mountpoint, context, volume_dir, fresh);
+        if result.is_err() {
+            self.unmount_tmpfs(id, context, volume_dir);
+        }
+        result
+    }
+    fn mount_upper(
+        &self,
+        id: &str,
+        mountpoint: &Path,
+        context: &VolumeContext,
+        volume_dir: &Path,
+        fresh: bool,
+    ) -> anyhow::Result<Option<(Base, PathBuf)>> {
+        let valid_base = (!fresh).then(|| self.select_base(context)).flatten();
+        let base = valid_base.and_then(|base| {
+            let dir = self
+                .ram_cache
+                .as_ref()
+                .and_then(|cache| cache.get(&base))
+                .unwrap_or_else(|| base.0.clone());
+            let lower = match &context.sub_path {
+                Some(sub_path) => dir.join(sub_path),
+                None => dir,
+            };
+            if lower.is_dir() {
+                Some((base, lower))
+            } else {
+                warn!(
+                    id,
+                    ?base,
+                    ?lower,
+                    "Base does not contain the requested sub path"
+                );
+                None
+            }
+        });
+        if let Some((base, lower)) = base {
             // A base is available, we create an overlay
-            info!(id, ?mountpoint, ?base, "Creating overlay",);
+            info!(id, ?mountpoint, ?base, ?lower, "Creating overlay",);
             let upper = volume_dir.join("upper");
             let workdir = volume_dir.join("workdir");
             for d in [&upper, &workdir] {
                 std::fs::create_dir_all(d)?;
             }
-            duct::cmd!(
-                "mount",
-                "-t",
-                "overlay",
-                id,
-                "-o",
-                format!(
-                    "lowerdir={},upperdir={},workdir={}",
-                    base.0.as_os_str().to_str().unwrap(),
-                    upper.as_os_str().to_str().unwrap(),
-                    workdir.as_os_str().to_str().unwrap()
-                ),
-                mountpoint
+            let mount = || {
+                self.mounter.mount_overlay(
+                    &encoding::encode(id),
+                    &lower,
+                    &upper,
+                    &workdir,
+                    mountpoint,
+                )
+            };
+            let mut result = mount();
+            if let Err(e) = &result {
+                if self.repair_overlay(id, e, &upper, &workdir) {
+                    result = mount();
+                }
+            }
+            result?;
+            return Ok(Some((base, lower)));
+        }
+        // If no base is available, we create a volume with a bind mount
+        warn!(id, "Could not find a base, creating a volume from scratch");
+        std::fs::create_dir_all(volume_dir)?;
+        self.mounter.mount_bind(volume_dir, mountpoint)?;
+        Ok(None)
+    }
+    /// Unmount the tmpfs holding the data of a volume, if any, while rolling back.
+    fn unmount_tmpfs(&self, id: &str, context: &VolumeContext, volume_dir: &Path) {
+        if context.tmpfs_size.is_some() {
+            if let Err(e) = self.mounter.unmount(volume_dir) {
+                warn!(
+                    id,
+                    ?volume_dir,
+                    "Failed to unmount tmpfs while rolling back: {}",
+                    e
+                );
+            }
+        }
+    }
+    /// Rebuild the state of a volume whose mount was made before the driver crashed while
+    /// publishing, provided that it is one of ours.
+    async fn adopt(
+        &self,
+        id: &str,
+        mountpoint: &Path,
+        context: &VolumeContext,
+        info: mountinfo::MountInfo,
+    ) -> anyhow::Result<()> {
+        if info.fs_type == "overlay" && info.source == encoding::encode(id) {
+            let (Some(lower), Some(upper)) = (info.option("lowerdir"), info.option("upperdir"))
+            else {
+                anyhow::bail!("Overlay at {:?} without layers", mountpoint);
+            };
+            let (lower, upper) = (PathBuf::from(lower), PathBuf::from(upper));
+            let base = self.bases()?.find(|b| self.is_lower_of(&lower, b));
+            info!(id, ?mountpoint, ?base, ?upper, "Adopting leftover overlay");
+            let producer = self.is_producer(id, self.get_pod(id, context).await.as_ref());
+            let mut mapping = self.lock.lock().await;
+            match base {
+                Some(base) => {
+                    mapping
+                        .bases
+                        .entry(base)
+                        .or_default()
+                        .insert(id.to_string());
+                }
+                None => warn!(id, ?lower, "Base of the leftover overlay not found"),
+            }
+            mapping.volumes.insert(id.into(), context.clone());
+            mapping.layers.insert(id.into(), (upper.clone(), lower));
+            if producer {
+                mapping.producers.insert(id.into());
+            }
+            self.stats.register(id, upper);
+            self.persist(&mapping);
+            return Ok(());
+        }
+        // Bind mounts are recognized by the data directory they expose
+        let volume_dir = self.find_volume_dir(id).await?;
+        let root = info.root.to_string_lossy();
+        let ours = match &self.flags.volumes_dir {
+            Some(_) => root.ends_with(&encoding::encode(id)),
+            None => volume_dir
+                .strip_prefix(&self.flags.pods)
+                .map_or(false, |rel| root.ends_with(&*rel.to_string_lossy())),
+        };
+        anyhow::ensure!(
+            ours,
+            "{:?} is already mounted ({} from {}), but not by this volume",
+            mountpoint,
+            info.fs_type,
+            info.source
+        );
+        info!(id, ?mountpoint, ?volume_dir, "Adopting leftover bind mount");
+        let producer = self.is_producer(id, self.get_pod(id, context).await.as_ref());
+        let mut mapping = self.lock.lock().await;
+        mapping.volumes.insert(id.into(), context.clone());
+        if producer {
+            mapping.producers.insert(id.into());
+        }
+        self.stats.register(id, volume_dir);
+        self.persist(&mapping);
+        Ok(())
+    }
+    /// Bind the current base of the family read-only, or an empty directory if there is none.
+    /// Such volumes only pin the base, without data pod nor upper directory.
+    async fn mount_read_only(
+        &self,
+        id: &str,
+        mountpoint: &Path,
+        context: &VolumeContext,
+    ) -> anyhow::Result<()> {
+        let mut mapping = self.lock.lock().await;
+        let base = self.select_base(context).map(|base| {
+            let dir = self
+                .ram_cache
+                .as_ref()
+                .and_then(|cache| cache.get(&base))
+                .unwrap_or_else(|| base.0.clone());
+            let lower = match &context.sub_path {
+                Some(sub_path) => dir.join(sub_path),
+                None => dir,
+            };
+            (base, lower)
+        });
+        let base = base.filter(|(_, lower)| lower.is_dir());
+        let source = match &base {
+            Some((_, lower)) => lower.clone(),
+            None => {
+                warn!(id, ?base, "No base available, mounting an empty directory");
+                let empty = self.flags.bases.join(".empty");
+                std::fs::create_dir_all(&empty)?;
+                empty
+            }
+        };
+        info!(id, ?mountpoint, ?source, "Creating read-only view");
+        std::fs::create_dir_all(mountpoint)?;
+        self.mounter.mount_bind_read_only(&source, mountpoint)?;
+        if let Some((base, _)) = base {
+            mapping
+                .bases
+                .entry(base)
+                .or_default()
+                .insert(id.to_string());
+        }
+        mapping.volumes.insert(id.into(), context.clone());
+        debug!(?mapping);
+        self.persist(&mapping);
+        drop(mapping);
+        let mountpoint_str = mountpoint.to_string_lossy().into_owned();
+        self.hooks
+            .run_logged(
+                HookEvent::PostMount,
+                &[("VOLUME_ID", id), ("MOUNTPOINT", mountpoint_str.as_str())],
             )
-            .run()?;
-            mapping.entry(base).or_default().insert(id.to_string());
-        } else {
-            // If no base is available, we create a volume with a bind mount
-            warn!(id, "Could not find a base, creating a volume from scratch");
-            std::fs::create_dir_all(mountpoint)?;
-            std::fs::create_dir_all(&volume_dir)?;
-            duct::cmd!("mount", "--bind", volume_dir, mountpoint).run()?;
+            .await;
+        Ok(())
+    }
+    /// Save the state of the published volumes for the next driver process. Failures are not
+    /// fatal, the next process then relies on the mount table.
+    fn persist(&self, state: &State) {
+        if let Err(e) = persist::PersistedState::from_state(state).save(&self.flags.bases) {
+            warn!("Failed to persist the state: {}", e);
+        }
+        self.state_changed.send_replace(());
+    }
+    /// Restore the state saved by the previous driver process. Its volumes stay mounted, as the
+    /// mounts are propagated to the host.
+    pub(crate) async fn restore(&self) -> anyhow::Result<()> {
+        let persisted = match persist::PersistedState::load(&self.flags.bases) {
+            Ok(persisted) => persisted,
+            Err(e) => {
+                warn!("Failed to load the persisted state: {}", e);
+                None
+            }
+        };
+        let mut restored = persisted.map_or_else(State::default, |p| p.into_state());
+        // Overlays missing from the state, e.g. if it was lost, must still pin their base
+        if let Err(e) = self.recover_overlays(&mut restored) {
+            warn!("Failed to recover overlays from the mount table: {}", e);
+        }
+        if restored.volumes.is_empty() {
+            return Ok(());
+        }
+        let mut mapping = self.lock.lock().await;
+        for (id, context) in &restored.volumes {
+            let data_dir = match restored.layers.get(id) {
+                Some((upper, _)) => Some(upper.clone()),
+                None if context.access == Access::ReadOnly => None,
+                None => match self.find_volume_dir(id).await {
+                    Ok(dir) => Some(dir),
+                    Err(e) => {
+                        warn!(id, "Failed to find the data of a restored volume: {}", e);
+                        None
+                    }
+                },
+            };
+            if let Some(data_dir) = data_dir {
+                self.stats.register(id, data_dir);
+            }
+            if let Some(slots) = &self.volume_slots {
+                match slots.try_acquire() {
+                    Some(slot) => {
+                        mapping.slots.insert(id.clone(), slot);
+                    }
+                    None => warn!(id, "No slot left for a restored volume"),
+                }
+            }
         }
+        info!(volumes = restored.volumes.len(), "Restored state");
+        mapping.bases = restored.bases;
+        mapping.volumes = restored.volumes;
+        mapping.layers = restored.layers;
+        mapping.producers = restored.producers;
         debug!(?mapping);
+        self.persist(&mapping);
+        Ok(())
+    }
+    /// Add to the state the overlays of the driver found in the mount table, i.e. whose source is
+    /// an encoded volume key and whose lower directory is in a base. Their pod is unknown, hence
+    /// they are not promoted.
+    fn recover_overlays(&self, state: &mut State) -> anyhow::Result<()> {
+        let bases: Vec<_> = self.bases()?.collect();
+        for info in self.mounter.mounts()? {
+            if info.fs_type != "overlay" {
+                continue;
+            }
+            let Ok(id) = encoding::decode(&info.source) else {
+                continue;
+            };
+            let (Some(lower), Some(upper)) = (info.option("lowerdir"), info.option("upperdir"))
+            else {
+                continue;
+            };
+            let (lower, upper) = (PathBuf::from(lower), PathBuf::from(upper));
+            if state.volumes.contains_key(&id) {
+                continue;
+            }
+            let Some(base) = bases.iter().find(|b| self.is_lower_of(&lower, b)) else {
+                continue;
+            };
+            warn!(id, mount_point = ?info.mount_point, ?base, "Recovered overlay missing from the state");
+            state
+                .bases
+                .entry(base.clone())
+                .or_default()
+                .insert(id.clone());
+            state.layers.insert(id.clone(), (upper, lower));
+            state.volumes.insert(
+                id.clone(),
+                VolumeContext {
+                    family: base.family(),
+                    ..Default::default()
+                },
+            );
+        }
         Ok(())
     }
+    /// Whether a lower directory is the base, a subdirectory of it, or its copy in the RAM cache
+    fn is_lower_of(&self, lower: &Path, base: &Base) -> bool {
+        lower.starts_with(&base.0)
+            || self
+                .ram_cache
+                .as_ref()
+                .map_or(false, |cache| lower.starts_with(cache.path(base)))
+    }
+    /// Pod to which the volume is published, if known
+    async fn get_pod(&self, id: &str, context: &VolumeContext) -> Option<Pod> {
+        let pod = context.pod.as_ref()?;
+        match self
+            .metrics
+            .time_kube("get", self.pods.get_in(&pod.namespace, &pod.name))
+            .await
+        {
+            Ok(p) => Some(p),
+            Err(e) => {
+                warn!(id, ?pod, "Failed to get pod: {}", e);
+                None
+            }
+        }
+    }
+    /// Whether the volume may be promoted into a base, according to the producer selector
+    fn is_producer(&self, id: &str, pod: Option<&Pod>) -> bool {
+        let Some(selector) = &self.flags.producer_selector else {
+            return true;
+        };
+        let Some(pod) = pod else {
+            warn!(id, "Pod unknown, the volume will not be promoted");
+            return false;
+        };
+        let producer = selector.matches(pod);
+        debug!(id, producer, "Checked producer selector");
+        producer
+    }
+    /// Try to fetch a base of the family from a peer, else to obtain one from the base provider,
+    /// else to populate one from the image or the remote source of the family, within the
+    /// configured time budgets.
+    /// Failures are not fatal, as the volume can still be created from scratch.
+    async fn fetch_base(&self, id: &str, context: &VolumeContext) {
+        let family = &context.family;
+        if self.peers.enabled() {
+            self.fetch_base_from_peers(id, family).await;
+        }
+        if self.flags.base_provider.is_some() && !self.has_valid_base(family) {
+            let timeout = std::time::Duration::from_secs(self.flags.base_provider_timeout_s);
+            match tokio::time::timeout(timeout, self.provide_base(id, family)).await {
+                Ok(Ok(Some(name))) => debug!(id, family, name, "Base obtained from the provider"),
+                Ok(Ok(None)) => debug!(id, family, "No base available from the provider"),
+                Ok(Err(e)) => warn!(id, family, "Failed to obtain base from the provider: {}", e),
+                Err(_) => {
+                    warn!(id, family, "Timed out waiting for the base provider");
+                    let _ = std::fs::remove_dir_all(transfer::partial_dir(&self.flags.bases, id));
+                }
+            }
+        }
+        #[cfg(feature = "oci-import")]
+        if self.base_image(context).is_some() && !self.has_valid_base(family) {
+            let timeout = std::time::Duration::from_secs(self.flags.image_timeout_s);
+            match tokio::time::timeout(timeout, self.populate_from_image(id, context)).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => warn!(id, family, "Failed to populate base from image: {}", e),
+                Err(_) => {
+                    warn!(id, family, "Timed out populating base from image");
+                    let _ = std::fs::remove_dir_all(transfer::partial_dir(&self.flags.bases, id));
+                }
+            }
+        }
+        if self.has_family_source(family) && !self.has_valid_base(family) {
+            let timeout = std::time::Duration::from_secs(self.flags.source_timeout_s);
+            match tokio::time::timeout(timeout, self.populate_from_source(id, family)).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => warn!(id, family, "Failed to populate base from source: {}", e),
+                Err(_) => {
+                    warn!(id, family, "Timed out populating base from source");
+                    let _ = std::fs::remove_dir_all(transfer::partial_dir(&self.flags.bases, id));
+                }
+            }
+        }
+    }
+    async fn fetch_base_from_peers(&self, id: &str, family: &str) {
+        // Most recent local base with a manifest, even expired, whose unchanged files are reused
+        let reference = self
+            .family_bases(family)
+            .filter(Base::has_manifest)
+            .filter_map(|b| Some((b.created().ok()?, b)))
+            .max_by_key(|(created, _)| *created)
+            .map(|(_, b)| b);
+        let fetch = self.peers.fetch(
+            &self.flags.bases,
+            family,
+            id,
+            base::age_limit(self.policy.family_max_age_s(family)),
+            self.clock.now(),
+            reference.as_ref(),
+        );
+        match tokio::time::timeout(self.peers.timeout(), fetch).await {
+            Ok(Ok(Some(name))) => {
+                let base = Base(self.flags.bases.join(family).join(name));
+                let meta = base::BaseMeta {
+                    epoch: self.epochs.get(family),
+                    ..Default::default()
+                };
+                if let Err(e) = base.write_meta(&meta) {
+                    warn!(?base, "Failed to write metadata of fetched base: {}", e);
+                }
+            }
+            Ok(Ok(None)) => debug!(id, family, "No base available from peers"),
+            Ok(Err(e)) => warn!(id, family, "Failed to fetch base from peers: {}", e),
+            Err(_) => {
+                warn!(id, family, "Timed out fetching base from peers");
+                let _ = std::fs::remove_dir_all(transfer::partial_dir(&self.flags.bases, id));
+            }
+        }
+    }
+    /// Hardlink the files of a freshly promoted base that are identical in the most recent other
+    /// base of the family.
+    async fn dedup_base(&self, family: &str, base: &Base) {
+        // Hardlinks cannot cross mounts, hence the container paths for both bases
+        let Some(previous) = self
+            .family_bases(family)
+            .filter(|b| b != base)
+            .filter_map(|b| Some((b.created().ok()?, b)))
+            .max_by_key(|(created, _)| *created)
+            .map(|(_, b)| b)
+        else {
+            return;
+        };
+        info!(?base, ?previous, "Deduplicating base");
+        let result = {
+            let (base, previous) = (base.clone(), previous.clone());
+            tokio::task::spawn_blocking(move || base.dedup(&previous)).await
+        };
+        match result {
+            Ok(Ok(saved)) => info!(?base, saved, "Deduplicated base"),
+            Ok(Err(e)) => warn!(?base, "Failed to deduplicate base: {}", e),
+            Err(e) => warn!(?base, "Failed to deduplicate base: {}", e),
+        }
+    }
+    /// Run the initialization command in a volume created from scratch.
+    async fn init_volume(
+        &self,
+        id: &str,
+        volume_dir: &Path,
+        command: &str,
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<()> {
+        info!(id, command, "Initializing volume");
+        tokio::select! {
+            r = hooks::run_shell(
+                command,
+                Some(volume_dir),
+                &[("VOLUME_ID", id)],
+                std::time::Duration::from_secs(self.flags.init_timeout_s),
+            ) => {
+                r.map_err(|e| anyhow::anyhow!("Failed to initialize volume: {}", e))?;
+                info!(id, "Initialized volume");
+                Ok(())
+            }
+            _ = cancel.cancelled() => {
+                warn!(id, "Cancelled while initializing volume, rolling back");
+                Err(Cancelled.into())
+            }
+        }
+    }
+    /// Compact the upper directories of the overlays of a volume, i.e. the CSI volume id rather
+    /// than the key of the volume in the driver, returning the number of overlays and the space
+    /// reclaimed.
+    pub(crate) async fn compact_volume(
+        &self,
+        volume_id: &str,
+    ) -> anyhow::Result<(usize, compaction::Compacted)> {
+        let prefix = format!("{}-", volume_id);
+        let layers: Vec<_> = self
+            .lock
+            .lock()
+            .await
+            .layers
+            .iter()
+            .filter(|(k, _)| k.strip_prefix(&prefix).map_or(false, |h| h.len() == 8))
+            .map(|(k, l)| (k.clone(), l.clone()))
+            .collect();
+        anyhow::ensure!(!layers.is_empty(), "No overlay for volume {}", volume_id);
+        let open = Arc::new(tokio::task::spawn_blocking(compaction::open_inodes).await??);
+        let mut total = compaction::Compacted::default();
+        for (id, (upper, lower)) in &layers {
+            let (upper, lower, open) = (upper.clone(), lower.clone(), open.clone());
+            let compacted =
+                tokio::task::spawn_blocking(move || compaction::compact(&upper, &lower, &open))
+                    .await??;
+            info!(id, ?compacted, "Compacted upper directory");
+            total.add(&compacted);
+        }
+        Ok((layers.len(), total))
+    }
+    /// Remove redundant copy-ups from the upper directories of the overlays.
+    pub async fn compact(&self) {
+        let layers: Vec<_> = self.lock.lock().await.layers.clone().into_iter().collect();
+        if layers.is_empty() {
+            return;
+        }
+        let open = tokio::task::spawn_blocking(compaction::open_inodes)
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|r| r);
+        let open = match open {
+            Ok(open) => Arc::new(open),
+            Err(e) => {
+                warn!("Not compacting upper directories: {}", e);
+                return;
+            }
+        };
+        for (id, (upper, lower)) in layers {
+            let open = open.clone();
+            let result =
+                tokio::task::spawn_blocking(move || compaction::compact(&upper, &lower, &open))
+                    .await
+                    .map_err(anyhow::Error::from)
+                    .and_then(|r| r);
+            match result {
+                Ok(compacted) => info!(id, ?compacted, "Compacted upper directory"),
+                // The volume might have been unpublished in the meantime
+                Err(e) => warn!(id, "Failed to compact upper directory: {}", e),
+            }
+        }
+    }
+    /// Delete the data pods of the node that do not back any published volume.
+    pub async fn collect_orphan_pods(&self) -> anyhow::Result<()> {
+        self.orphan_pods(self.flags.cleanup_dry_run).await?;
+        Ok(())
+    }
+    /// Delete the orphan data pods, or only report them in a dry run.
+    async fn orphan_pods(&self, dry_run: bool) -> anyhow::Result<Vec<CleanupAction>> {
+        let mut actions = vec![];
+        let pods = self
+            .metrics
+            .time_kube("list", self.pods.list(datapod::KEY_LABEL))
+            .await?;
+        // Volumes published before a restart are unknown, but their mounts still reference the
+        // data directories.
+        let mounts = self.mounter.mounts()?;
+        let referenced = |dir: &str| {
+            mounts.iter().any(|m| {
+                m.root.to_string_lossy().contains(dir)
+                    || m.source.contains(dir)
+                    || m.super_options.contains(dir)
+            })
+        };
+        let mut used: HashSet<String> = {
+            let mapping = self.lock.lock().await;
+            mapping
+                .volumes
+                .keys()
+                .map(|id| self.data_pod_of(id))
+                .collect()
+        };
+        if let Ok(records) = std::fs::read_dir(self.flags.bases.join(".data-pods")) {
+            used.extend(
+                records
+                    .filter_map(Result::ok)
+                    .filter_map(|e| std::fs::read_to_string(e.path()).ok()),
+            );
+        }
+        let now = self.clock.now().unix_timestamp();
+        for pod in pods {
+            if pod.spec.as_ref().and_then(|s| s.node_name.as_deref()) != Some(&self.flags.node) {
+                continue;
+            }
+            let meta = pod.metadata;
+            let (Some(name), Some(uid)) = (meta.name, meta.uid) else {
+                continue;
+            };
+            let key = meta
+                .annotations
+                .and_then(|mut a| a.remove(datapod::KEY_ANNOTATION))
+                .unwrap_or_default();
+            let age_s = meta.creation_timestamp.map_or(0, |t| now - t.0.timestamp());
+            let volume_dir = self.flags.volumes_dir.as_ref().map(|d| {
+                d.join(encoding::encode(&key))
+                    .to_string_lossy()
+                    .into_owned()
+            });
+            let mounted = referenced(&format!("{}/volumes/", uid))
+                || volume_dir.map_or(false, |d| referenced(&d));
+            if age_s < self.flags.orphan_pod_min_age_s
+                || meta.deletion_timestamp.is_some()
+                || used.contains(&key)
+                || mounted
+            {
+                continue;
+            }
+            let empty_dir = self.empty_dir(PodUid(uid), datapod::VOLUME_NAME);
+            let size_bytes = stats::disk_usage(&empty_dir).max(0) as u64;
+            actions.push(CleanupAction {
+                kind: "pod",
+                name: name.clone(),
+                size_bytes,
+                reason: format!("orphan data pod of {:?}, created {}s ago", key, age_s),
+            });
+            if dry_run {
+                info!(name, key, age_s, size_bytes, "Would delete orphan data pod");
+                continue;
+            }
+            warn!(name, key, age_s, "Deleting orphan data pod");
+            if let Err(e) = self
+                .metrics
+                .time_kube("delete", self.pods.delete(&name))
+                .await
+            {
+                warn!(name, "Failed to delete orphan data pod: {}", e);
+            }
+        }
+        Ok(actions)
+    }
     pub async fn cleanup(&self) -> anyhow::Result<()> {
+        self.metrics
+            .time_operation("cleanup", self.clean_up_bases())
+            .await
+    }
+    async fn clean_up_bases(&self) -> anyhow::Result<()> {
+        let dry_run = self.flags.cleanup_dry_run;
         let mut mapping = self.lock.lock().await;
-        debug!("Cleaning up bases");
-        for base in self.bases()?.filter(|b| !b.valid(self.flags.max_age_s)) {
+        debug!(dry_run, "Cleaning up bases");
+        let mut actions = vec![];
+        for base in self.bases()?.filter(|b| !self.base_valid(b)) {
+            self.webhook.notify(BaseEvent::Expired, &base.0, None);
+            if !self.base_deletable(&base) {
+                continue;
+            }
             // We only clean up bases not tied to a volume.
             // The base might not be in the mapping if it has never been associated with a volume.
-            if mapping.entry(base.clone()).or_default().is_empty() {
-                warn!(?base, "Cleaning up");
-                std::fs::remove_dir_all(&base.0)?;
-                mapping.remove(&base);
+            if !mapping.bases.entry(base.clone()).or_default().is_empty() {
+                continue;
+            }
+            let archived = self.archived_on_expiry(&base);
+            if archived && !dry_run {
+                if let Err(e) = self.stage_for_archive(&base) {
+                    // Keep the base rather than losing it
+                    error!(?base, "Failed to stage base for archival: {}", e);
+                    continue;
+                }
+            }
+            let reason = if archived {
+                "expired, archived"
+            } else {
+                "expired"
+            };
+            self.clean_up_base(&mut mapping, &base, reason.into(), dry_run, &mut actions)
+                .await?;
+        }
+        if let Some(max_bytes) = self.policy.max_bytes() {
+            self.enforce_budget(&mut mapping, max_bytes, dry_run, &mut actions)
+                .await?;
+        }
+        if let Some(cache) = &self.ram_cache {
+            cache.shrink(|base| mapping.bases.get(base).map_or(true, |v| v.is_empty()));
+        }
+        self.record_base_metrics(&mapping)?;
+        drop(mapping);
+        self.state_changed.send_replace(());
+        if !dry_run {
+            self.archive_staged().await;
+        }
+        Ok(())
+    }
+    /// Whether an expired base is archived rather than only deleted. Replaced bases are not.
+    fn archived_on_expiry(&self, base: &Base) -> bool {
+        self.flags.archive_location.is_some() && base.read_meta().superseded_by.is_none()
+    }
+    fn record_base_metrics(&self, state: &State) -> anyhow::Result<()> {
+        let now = self.clock.now();
+        self.metrics.record_volumes(state.volumes.len());
+        self.metrics.reset_bases();
+        for base in self.bases()? {
+            let max_age_s = self.base_max_age_s(&base);
+            let mut ttl_s = match base.created() {
+                Ok(_) if max_age_s == 0 => f64::INFINITY,
+                Ok(created) => (max_age_s - (now - created).whole_seconds()) as f64,
+                Err(_) => 0.0,
+            };
+            if let Some(rotation) = self.base_rotation(&base) {
+                ttl_s = ttl_s.min((rotation - now).whole_seconds() as f64);
             }
+            let age_s = base.created().map_or(0.0, |c| (now - c).as_seconds_f64());
+            let base_state = self.base_state(state, &base);
+            self.metrics
+                .record_base(&base.family(), &base.name(), ttl_s, age_s, base_state);
         }
         Ok(())
     }
-    pub async fn unmount(&self, id: &str, mountpoint: impl AsRef<Path>) -> anyhow::Result<()> {
+    async fn record_volume_metrics(&self) {
+        let volumes = self.lock.lock().await.volumes.len();
+        self.metrics.record_volumes(volumes);
+    }
+    fn base_state(&self, state: &State, base: &Base) -> metrics::BaseState {
+        if self.base_valid(base) {
+            metrics::BaseState::Valid
+        } else if state.bases.get(base).map_or(false, |v| !v.is_empty()) {
+            metrics::BaseState::Pinned
+        } else if !self.base_deletable(base) {
+            metrics::BaseState::Stale
+        } else {
+            metrics::BaseState::Expired
+        }
+    }
+    /// What the cleanup would delete, without deleting anything: the expired bases, the bases
+    /// evicted to stay within `bases_max_bytes`, and the orphan data pods.
+    pub async fn cleanup_report(&self) -> anyhow::Result<Vec<CleanupAction>> {
+        let mut actions = vec![];
+        {
+            let mut mapping = self.lock.lock().await;
+            for base in self.bases()?.filter(|b| !self.base_valid(b)) {
+                if self.base_deletable(&base)
+                    && mapping.bases.get(&base).map_or(true, |v| v.is_empty())
+                {
+                    let reason = if self.archived_on_expiry(&base) {
+                        "expired, archived"
+                    } else {
+                        "expired"
+                    };
+                    self.clean_up_base(&mut mapping, &base, reason.into(), true, &mut actions)
+                        .await?;
+                }
+            }
+            if let Some(max_bytes) = self.policy.max_bytes() {
+                self.enforce_budget(&mut mapping, max_bytes, true, &mut actions)
+                    .await?;
+            }
+        }
+        actions.extend(self.orphan_pods(true).await?);
+        Ok(actions)
+    }
+    /// Remove a base, or only record it in a dry run.
+    async fn clean_up_base(
+        &self,
+        state: &mut State,
+        base: &Base,
+        reason: String,
+        dry_run: bool,
+        actions: &mut Vec<CleanupAction>,
+    ) -> anyhow::Result<()> {
+        let size_bytes = base.size();
+        if dry_run {
+            info!(?base, size_bytes, reason, "Would clean up");
+        } else {
+            warn!(?base, size_bytes, reason, "Cleaning up");
+            self.remove_base(state, base).await?;
+        }
+        actions.push(CleanupAction {
+            kind: "base",
+            name: base.0.to_string_lossy().into_owned(),
+            size_bytes,
+            reason,
+        });
+        Ok(())
+    }
+    async fn remove_base(&self, state: &mut State, base: &Base) -> anyhow::Result<()> {
+        if let Some(cache) = &self.ram_cache {
+            cache.evict(base);
+        }
+        base.remove()?;
+        self.webhook.notify(BaseEvent::Deleted, &base.0, None);
+        let base_str = base.0.to_string_lossy().into_owned();
+        self.hooks
+            .run_logged(HookEvent::PostCleanup, &[("BASE", base_str.as_str())])
+            .await;
+        state.bases.remove(base);
+        Ok(())
+    }
+    /// Evict unused bases until their total size is under the budget, starting with the least
+    /// important families and, within them, the oldest bases.
+    async fn enforce_budget(
+        &self,
+        state: &mut State,
+        max_bytes: u64,
+        dry_run: bool,
+        actions: &mut Vec<CleanupAction>,
+    ) -> anyhow::Result<()> {
+        // In a dry run, the bases already planned for deletion are still there
+        let planned: HashSet<_> = actions.iter().map(|a| a.name.clone()).collect();
+        let mut bases: Vec<_> = self
+            .bases()?
+            .filter(|base| !planned.contains(base.0.to_string_lossy().as_ref()))
+            .map(|base| {
+                let size = base.size();
+                (base, size)
+            })
+            .collect();
+        let mut total: u64 = bases.iter().map(|(_, size)| size).sum();
+        if total <= max_bytes {
+            return Ok(());
+        }
+        bases.sort_by(|(a, _), (b, _)| {
+            self.policy
+                .weight(&a.family())
+                .total_cmp(&self.policy.weight(&b.family()))
+                .then_with(|| a.created().ok().cmp(&b.created().ok()))
+        });
+        for (base, size) in bases {
+            if total <= max_bytes {
+                break;
+            }
+            if state.bases.get(&base).map_or(false, |v| !v.is_empty())
+                || self.policy.pinned(&self.retention_facts(&base))
+            {
+                continue;
+            }
+            let reason = format!("over budget ({} of {} bytes)", total, max_bytes);
+            self.clean_up_base(state, &base, reason, dry_run, actions)
+                .await?;
+            total -= size;
+        }
+        if total > max_bytes {
+            warn!(total, max_bytes, "Bases in use exceed the budget");
+        }
+        Ok(())
+    }
+    /// Log of recent operations
+    pub fn audit(&self) -> &audit::AuditLog {
+        &self.audit
+    }
+    /// Usage statistics for a published volume, served from a short-lived cache. The capacity is
+    /// capped to the size limit of the volume.
+    pub async fn volume_stats(
+        &self,
+        id: &str,
+        mountpoint: impl AsRef<Path>,
+    ) -> anyhow::Result<stats::VolumeStats> {
+        let size_limit = match self.lock.lock().await.volumes.get(id) {
+            Some(context) => self.size_limit(context).to_owned(),
+            None => return Err(VolumeNotFound(id.into()).into()),
+        };
+        let mut stats = self.stats.get(id, mountpoint.as_ref())?;
+        if let Ok(limit) = capacity::parse_quantity(&size_limit) {
+            let limit = limit as i64;
+            if limit < stats.total_bytes {
+                stats.total_bytes = limit;
+                stats.available_bytes =
+                    stats.available_bytes.min((limit - stats.used_bytes).max(0));
+            }
+        }
+        Ok(stats)
+    }
+    /// Raise the size limit of a published volume to `required_bytes`, returning its capacity.
+    /// Only volumes kept in `volumes_dir` can grow: the size limit of the emptyDir of a data pod
+    /// is immutable, and read-only views have no storage. No volume can shrink.
+    pub async fn expand_volume(
+        &self,
+        id: &str,
+        required_bytes: u64,
+        limit_bytes: Option<u64>,
+    ) -> anyhow::Result<u64> {
         let mut mapping = self.lock.lock().await;
+        let Some(context) = mapping.volumes.get(id) else {
+            return Err(VolumeNotFound(id.into()).into());
+        };
+        let current = capacity::parse_quantity(self.size_limit(context))?;
+        if limit_bytes.map_or(false, |limit| current > limit) {
+            return Err(CapacityOutOfRange(format!(
+                "The volume already has a capacity of {} bytes, above the limit of {:?}",
+                current, limit_bytes
+            ))
+            .into());
+        }
+        if required_bytes < current {
+            return Err(CapacityOutOfRange(format!(
+                "Volumes cannot shrink, from {} to {} bytes",
+                current, required_bytes
+            ))
+            .into());
+        }
+        if required_bytes == current {
+            return Ok(current);
+        }
+        if context.access == Access::ReadOnly {
+            return Err(NotExpandable("Read-only views have no storage to expand".into()).into());
+        }
+        if self.flags.volumes_dir.is_none() {
+            return Err(NotExpandable(format!(
+                "The capacity of the volume is capped to {} bytes by its data pod",
+                current
+            ))
+            .into());
+        }
+        info!(id, current, required_bytes, "Expanding volume");
+        mapping.volumes.get_mut(id).unwrap().size_limit = Some(required_bytes.to_string());
+        self.persist(&mapping);
+        Ok(required_bytes)
+    }
+    /// Problem with a published volume, if any, e.g. its mount disappeared from under the pod.
+    pub async fn volume_condition(
+        &self,
+        id: &str,
+        mountpoint: impl AsRef<Path>,
+    ) -> anyhow::Result<Option<String>> {
         let mountpoint = mountpoint.as_ref();
-        let is_overlay = mapping.values().flatten().any(|v| v == id);
-        let no_valid_base = self.find_valid_base().map_or(true, |o| o.is_none());
-        info!(id, ?mountpoint, is_overlay, no_valid_base, "Unmounting");
+        if !self.lock.lock().await.volumes.contains_key(id) {
+            return Err(VolumeNotFound(id.into()).into());
+        }
+        Ok(match mountinfo::find(self.mounter.mounts()?, mountpoint) {
+            Some(_) => None,
+            None => Some(format!("{:?} is not mounted", mountpoint)),
+        })
+    }
+    pub async fn unmount(&self, id: &str, mountpoint: impl AsRef<Path>) -> anyhow::Result<()> {
+        let unmount = self.unmount_volume(id, mountpoint.as_ref());
+        let result = self.metrics.time_operation("unpublish", unmount).await;
+        self.record_volume_metrics().await;
+        result
+    }
+    async fn unmount_volume(&self, id: &str, mountpoint: &Path) -> anyhow::Result<()> {
+        let mut mapping = self.lock.lock().await;
+        // The mapping is empty after a restart, so the mount table is authoritative
+        let mount = mountinfo::find(self.mounter.mounts()?, mountpoint);
+        let known = mapping.volumes.contains_key(id);
+        if !known && mount.is_none() {
+            info!(id, ?mountpoint, "Volume already unpublished");
+            remove_mountpoint(mountpoint);
+            return Ok(());
+        }
+        let is_overlay = match &mount {
+            Some(mount) => mount.fs_type == "overlay",
+            None => mapping.bases.values().flatten().any(|v| v == id),
+        };
+        // The context is unknown if the volume was published before a restart
+        let mut context = mapping.volumes.get(id).cloned().unwrap_or_default();
+        if !known && !is_overlay && mount.as_ref().map_or(false, |m| m.read_only) {
+            context.access = Access::ReadOnly;
+        }
+        if context.access == Access::ReadOnly {
+            info!(id, ?mountpoint, "Unmounting read-only view");
+            mapping.volumes.remove(id);
+            for volumes in mapping.bases.values_mut() {
+                volumes.remove(id);
+            }
+            self.persist(&mapping);
+            if mount.is_some() {
+                self.mounter.unmount(mountpoint)?;
+            }
+            remove_mountpoint(mountpoint);
+            return Ok(());
+        }
+        let family = context.family;
+        let tmpfs = context.tmpfs_size.is_some();
+        let force_fresh = context.force_fresh;
+        let writer = context.access == Access::Writer;
+        // Without selector, all volumes are producers. Otherwise, volumes published before a
+        // restart are not promoted. Pristine volumes are never promoted.
+        let producer = (mapping.producers.remove(id) || self.flags.producer_selector.is_none())
+            && !context.pristine;
+        let no_valid_base = !self.has_valid_base(&family);
+        info!(
+            id,
+            ?mountpoint,
+            is_overlay,
+            family,
+            no_valid_base,
+            tmpfs,
+            producer,
+            force_fresh,
+            writer,
+            "Unmounting"
+        );
         // If this can be used as a base and we need one, transform it
         // TODO: We could also do that a bit before the previous base has expired.
-        if !is_overlay && no_valid_base {
-            // Get the volume path from the pod
-            let pod: Pod = self.pods.get(id).await?;
-            let volume_dir = self.volume_dir(PodUid(pod.metadata.uid.unwrap()));
-            let as_base = volume_dir.join(Base::as_base_filename());
-            if as_base.exists() {
-                let base = self.base_host(id).await?;
-                info!(id, ?mountpoint, src=?volume_dir, dst=?base.0, "Transforming volume into base");
-                std::fs::rename(volume_dir, &base.0)?;
-                base.write_time()?;
-            } else {
-                warn!(
-                    id,
-                    "Not transforming into base as {:?} does not exist", as_base
-                );
-            }
+        // Volumes kept in memory are never promoted. Fresh volumes and writers replace the current
+        // base.
+        let replace = force_fresh || writer;
+        let promote = producer
+            && !tmpfs
+            && (replace
+                || match self.flags.promotion_policy {
+                    PromotionPolicy::WhenMissing => !is_overlay && no_valid_base,
+                    PromotionPolicy::Always => true,
+                });
+        // Copying a volume and looking its data pod up take a while, so other volumes are
+        // published and unpublished meanwhile. The volume stays in the mapping until it is
+        // unmounted, which keeps its base in use.
+        drop(mapping);
+        if promote {
+            self.promote(
+                id,
+                mountpoint,
+                &family,
+                is_overlay,
+                replace,
+                context.max_age_s,
+            )
+            .await?;
         }
+        let shared_data_pod = self.flags.volumes_dir.is_none() && self.data_pod_of(id) != id;
+        let volume_dir = if tmpfs || shared_data_pod {
+            Some(self.find_volume_dir(id).await?)
+        } else {
+            None
+        };
+
+        let mut mapping = self.lock.lock().await;
         // Update the mapping so that the base can be cleaned up if necessary.
-        for volumes in mapping.values_mut() {
+        mapping.volumes.remove(id);
+        for volumes in mapping.bases.values_mut() {
             volumes.remove(id);
         }
-        duct::cmd!("umount", "-f", mountpoint).unchecked().run()?;
+        mapping.slots.remove(id);
+        mapping.layers.remove(id);
+        self.stats.forget(id);
+        // The mountpoint might be gone already, e.g. after a retried call or a reboot
+        if mount.is_some() {
+            self.mounter.unmount(mountpoint)?;
+        } else {
+            info!(id, ?mountpoint, "Volume no longer mounted");
+        }
+        remove_mountpoint(mountpoint);
+        if let Some(volume_dir) = volume_dir.as_ref().filter(|_| tmpfs) {
+            self.mounter.unmount(volume_dir)?;
+        }
+        if let Some(volumes_dir) = &self.flags.volumes_dir {
+            // Unless it was promoted, the volume data is still there
+            let volume_dir = volumes_dir.join(encoding::encode(id));
+            if volume_dir.exists() {
+                std::fs::remove_dir_all(volume_dir)?;
+            }
+        } else if let Some(volume_dir) = volume_dir.filter(|_| shared_data_pod) {
+            // The data pod outlives the volume if other volumes still use it
+            if volume_dir.exists() {
+                std::fs::remove_dir_all(volume_dir)?;
+            }
+        }
         debug!(?mapping);
+        self.persist(&mapping);
         drop(mapping);
         // Kubernetes will clean up the pod storage
-        self.delete_pod(id).await?;
+        self.release_pod(id).await?;
+        Ok(())
+    }
+    /// Transform the data of a volume being unpublished into a new base of its family, if it has
+    /// the promotion marker. The base is assembled under [`PROMOTING_DIR`] without holding the
+    /// lock, and only moved into its family once complete, so that it is neither used nor cleaned
+    /// up while partial.
+    async fn promote(
+        &self,
+        id: &str,
+        mountpoint: &Path,
+        family: &str,
+        is_overlay: bool,
+        replace: bool,
+        max_age_s: Option<i64>,
+    ) -> anyhow::Result<()> {
+        // Overlays only hold the changes to their base, so their merged view is copied. As
+        // the base has the marker, the volume must have written it again to be eligible.
+        let (volume_dir, marker_root) = if is_overlay {
+            let upper = self.find_volume_dir(id).await?.join("upper");
+            (mountpoint.to_owned(), upper)
+        } else {
+            let volume_dir = self.find_volume_dir(id).await?;
+            (volume_dir.clone(), volume_dir)
+        };
+        let marker = &self.flags.promotion_marker;
+        if !marker.present(&marker_root) {
+            warn!(
+                id,
+                "Not transforming into base as the {} is missing from {:?}", marker, marker_root
+            );
+            return Ok(());
+        }
+        // Requested by the workload, e.g. because its output is partial
+        let requested_max_age_s = marker.requested_max_age_s(&marker_root);
+        if let Some(s) = requested_max_age_s {
+            info!(id, s, "Maximum age of the base requested by the volume");
+        }
+        let (base, generation) = self.base_host(family, id).await?;
+        let volume_dir_str = volume_dir.to_string_lossy().to_string();
+        let base_str = base.0.to_string_lossy().to_string();
+        let env = [
+            ("VOLUME_ID", id),
+            ("VOLUME_DIR", volume_dir_str.as_str()),
+            ("BASE", base_str.as_str()),
+        ];
+        if let Err(e) = self.hooks.run(HookEvent::PrePromotion, &env).await {
+            warn!(id, "Not transforming into base: {}", e);
+            return Ok(());
+        }
+        // The copy is made through the host path, on the same device as the volumes, and the
+        // deduplication through the container path, on the same mount as the other bases.
+        let staging = Path::new(PROMOTING_DIR).join(encoding::encode(id));
+        let staging_dir = self.bases_host.join(&staging);
+        if staging_dir.exists() {
+            // Left by an interrupted promotion
+            std::fs::remove_dir_all(&staging_dir)?;
+        }
+        std::fs::create_dir_all(&staging_dir)?;
+        let staged_host = Base(self.bases_host.join(&staging).join(base.name()));
+        let staged = Base(self.flags.bases.join(&staging).join(base.name()));
+        info!(id, ?mountpoint, src=?volume_dir, dst=?base.0, "Transforming volume into base");
+        let staged_result = if is_overlay {
+            duct::cmd!("cp", "-a", &volume_dir, &staged_host.0)
+                .run()
+                .map(drop)
+                .map_err(Into::into)
+        } else {
+            move_dir(&volume_dir, &staged_host.0)
+        };
+        if let Err(e) = staged_result {
+            let _ = std::fs::remove_dir_all(&staging_dir);
+            return Err(e);
+        }
+        if self.flags.dedup_bases {
+            self.dedup_base(family, &staged).await;
+        }
+        if self.flags.base_manifests {
+            self.index_base(&staged).await;
+        }
+
+        let mapping = self.lock.lock().await;
+        // Another volume of the family might have been promoted meanwhile
+        let (base, generation) = if base.0.exists() {
+            let (renamed, generation) = self.base_host(family, id).await?;
+            warn!(id, ?base, ?renamed, "Base name taken during the promotion");
+            (renamed, generation)
+        } else {
+            (base, generation)
+        };
+        std::fs::rename(&staged_host.0, &base.0)?;
+        if staged_host.has_manifest() {
+            std::fs::rename(staged_host.manifest_file(), base.manifest_file())?;
+        }
+        base.write_time(self.clock.now())?;
+        base.write_meta(&base::BaseMeta {
+            volume_id: Some(id.into()),
+            epoch: self.epochs.get(family),
+            generation: Some(generation),
+            max_age_s: requested_max_age_s.or(max_age_s),
+            ..Default::default()
+        })?;
+        if self.flags.promotion_policy == PromotionPolicy::Always || replace {
+            self.supersede(family, &base.name());
+        }
+        drop(mapping);
+        let _ = std::fs::remove_dir(&staging_dir);
+        self.webhook.notify(BaseEvent::Promoted, &base.0, Some(id));
+        self.hooks.run_logged(HookEvent::PostPromotion, &env).await;
         Ok(())
     }
 }
+
+/// Remove the target directory of an unpublished volume, as required by the CSI spec. Failures are
+/// not fatal, as the volume itself is already unpublished.
+fn remove_mountpoint(mountpoint: &Path) {
+    match std::fs::remove_dir(mountpoint) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            warn!(?mountpoint, "Failed to remove mountpoint: {}", e);
+        }
+        _ => {}
+    }
+}
+
+/// Move a directory, falling back to copying it if the destination is on another device.
+fn move_dir(src: &Path, dst: &Path) -> anyhow::Result<()> {
+    match std::fs::rename(src, dst) {
+        Err(e) if e.raw_os_error() == Some(nix::libc::EXDEV) => {
+            info!(?src, ?dst, "Cross-device move, copying");
+            duct::cmd!("cp", "-a", src, dst).run()?;
+            std::fs::remove_dir_all(src)?;
+            Ok(())
+        }
+        r => Ok(r?),
+    }
+}
@@ -0,0 +1,122 @@
+//! Log file, reopened on `SIGHUP` for logrotate and optionally rotated by size, so that
+//! long-running drivers logging to the node do not fill its disk.
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use tracing::*;
+
+#[derive(clap::Args, Clone)]
+pub struct LogFileFlags {
+    /// Also write the logs to this file, without colors. It is reopened on SIGHUP, so that it
+    /// can be rotated by logrotate.
+    #[clap(long)]
+    pub log_file: Option<PathBuf>,
+    /// Rotate the log file once it exceeds this size, renaming it to `{log_file}.1`, the
+    /// previous `{log_file}.1` to `{log_file}.2`, and so on
+    #[clap(long)]
+    pub log_file_max_bytes: Option<u64>,
+    /// Number of rotated log files kept
+    #[clap(long, default_value_t = 5)]
+    pub log_file_keep: usize,
+}
+
+struct Inner {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_bytes: Option<u64>,
+    keep: usize,
+}
+impl Inner {
+    fn open(path: &Path) -> std::io::Result<(File, u64)> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok((file, size))
+    }
+    fn reopen(&mut self) -> std::io::Result<()> {
+        (self.file, self.size) = Self::open(&self.path)?;
+        Ok(())
+    }
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+    fn rotate(&mut self) -> std::io::Result<()> {
+        if self.keep == 0 {
+            self.file.set_len(0)?;
+            self.size = 0;
+            return Ok(());
+        }
+        for n in (1..self.keep).rev() {
+            let from = self.rotated(n);
+            if from.exists() {
+                std::fs::rename(&from, self.rotated(n + 1))?;
+            }
+        }
+        std::fs::rename(&self.path, self.rotated(1))?;
+        self.reopen()
+    }
+}
+
+#[derive(Clone)]
+pub struct LogFile(Arc<Mutex<Inner>>);
+impl LogFile {
+    pub fn open(flags: &LogFileFlags, path: &Path) -> std::io::Result<Self> {
+        let (file, size) = Inner::open(path)?;
+        Ok(Self(Arc::new(Mutex::new(Inner {
+            path: path.into(),
+            file,
+            size,
+            max_bytes: flags.log_file_max_bytes,
+            keep: flags.log_file_keep,
+        }))))
+    }
+    /// Reopen the file on each `SIGHUP`, in the background.
+    pub fn spawn_reopen_on_sighup(self) {
+        use tokio::signal::unix::{signal, SignalKind};
+        tokio::spawn(async move {
+            let mut sighup = match signal(SignalKind::hangup()) {
+                Ok(sighup) => sighup,
+                Err(e) => {
+                    error!("Failed to install the SIGHUP handler: {}", e);
+                    return;
+                }
+            };
+            while sighup.recv().await.is_some() {
+                let result = self.0.lock().unwrap().reopen();
+                match result {
+                    Ok(()) => info!("Reopened the log file"),
+                    Err(e) => error!("Failed to reopen the log file: {}", e),
+                }
+            }
+        });
+    }
+}
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut inner = self.0.lock().unwrap();
+        if inner.max_bytes.map_or(false, |max| {
+            inner.size > 0 && inner.size + buf.len() as u64 > max
+        }) {
+            // Logging the failure would recurse into this writer
+            if let Err(e) = inner.rotate() {
+                eprintln!("Failed to rotate the log file: {}", e);
+            }
+        }
+        let written = inner.file.write(buf)?;
+        inner.size += written as u64;
+        Ok(written)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.lock().unwrap().file.flush()
+    }
+}
+impl<'a> tracing_subscriber::fmt::MakeWriter<'a> for LogFile {
+    type Writer = LogFile;
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
@@ -1,192 +1,364 @@
-use std::path::PathBuf;
+use std::os::fd::FromRawFd;
 use std::sync::Arc;
+use std::time::Duration;
 
-use clap::Parser;
-use k8s_openapi::api::core::v1::Pod;
+use clap::{CommandFactory, Parser};
+use k8s_openapi::api::core::v1::{ConfigMap, Node, Pod};
 use kube::Api;
-use tokio::net::UnixListener;
+use overlayfs_csi::admin::{self, AdminService};
+#[cfg(feature = "controller")]
+use overlayfs_csi::csi::ControllerService;
+use overlayfs_csi::csi::{request_id_interceptor, v1, IdentityService, NodeService};
+use overlayfs_csi::endpoint::Address;
+use overlayfs_csi::transfer::{self, TransferService};
+use overlayfs_csi::vsock::VsockIncoming;
+use overlayfs_csi::OverlaysBuilder;
+use tokio::net::{TcpListener, UnixListener};
 use tokio_stream::wrappers::UnixListenerStream;
+use tonic::codec::CompressionEncoding;
+use tonic::service::interceptor::InterceptedService;
+use tonic::transport::server::TcpIncoming;
 use tracing::*;
-use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::filter::{EnvFilter, LevelFilter};
 use tracing_subscriber::prelude::*;
-
-pub mod v1 {
-    tonic::include_proto!("csi.v1");
-}
+use tracing_subscriber::reload;
 
 #[derive(Parser)]
+#[clap(args_conflicts_with_subcommands = true)]
 struct Flags {
+    #[clap(subcommand)]
+    command: Option<Command>,
+    /// Without subcommand, the driver is served
+    #[clap(flatten)]
+    serve: Option<ServeFlags>,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Administrative operations on a running driver
+    #[cfg(feature = "admin-cli")]
+    Admin(overlayfs_csi::admin::AdminFlags),
+    /// Check the environment (kernel, filesystems, Kubernetes API access and permissions) with
+    /// the same flags as the driver, without serving
+    Check(overlayfs_csi::OverlayFlags),
+}
+
+#[derive(clap::Args)]
+struct ServeFlags {
     #[clap(flatten)]
     overlay: overlayfs_csi::OverlayFlags,
-    #[clap(long, alias = "endpoint")]
-    socket: PathBuf,
+    /// Endpoint on which the driver listens, e.g. `unix:///csi/csi.sock`, `tcp://0.0.0.0:10000`,
+    /// `vsock://any:10000` or a socket path
+    #[clap(long, alias = "endpoint", env = "CSI_ENDPOINT")]
+    socket: Address,
+    /// Log at the debug level. Ignored if the `RUST_LOG` environment variable is set, e.g. to
+    /// `info,overlayfs_csi=debug`.
     #[clap(long, short)]
     debug: bool,
+    /// Address on which Prometheus metrics are served, e.g. `0.0.0.0:9090`
+    #[clap(long)]
+    metrics_addr: Option<std::net::SocketAddr>,
+    /// Also serve a controller service, for dynamic provisioning with external-provisioner
+    /// (CreateVolume records the requested capacity as the size limit of the volume) and for
+    /// clusters that run external-attacher (ControllerPublishVolume trivially succeeds)
+    #[clap(long)]
+    controller_service: bool,
+    #[clap(flatten)]
+    log_file: overlayfs_csi::logfile::LogFileFlags,
+    #[clap(flatten)]
+    grpc: GrpcFlags,
 }
 
-fn unimplemented() -> tonic::Status {
-    tonic::Status::unimplemented("Unimplemented")
+/// Tuning of the gRPC server. Defaults are tonic's.
+#[derive(clap::Args)]
+struct GrpcFlags {
+    /// Maximum size of decoded and encoded messages, in bytes
+    #[clap(long)]
+    grpc_max_message_size: Option<usize>,
+    /// Maximum number of concurrent requests per connection
+    #[clap(long)]
+    grpc_concurrency_per_connection: Option<usize>,
+    /// Interval between HTTP/2 keepalive pings, in seconds
+    #[clap(long)]
+    grpc_keepalive_interval_s: Option<u64>,
+    /// Timeout for HTTP/2 keepalive ping acknowledgements, in seconds
+    #[clap(long)]
+    grpc_keepalive_timeout_s: Option<u64>,
+    /// TCP keepalive, in seconds (only applies to TCP endpoints)
+    #[clap(long)]
+    grpc_tcp_keepalive_s: Option<u64>,
+    /// Accept and send gzip-compressed messages
+    #[clap(long)]
+    grpc_gzip: bool,
 }
 
-/// Service that simply provides information about the CSI driver
-struct IdentityService {
-    name: String,
+/// Apply the message limits and compression settings to a generated service, and add the
+/// request ID interceptor.
+macro_rules! configure_service {
+    ($service:expr, $flags:expr) => {{
+        let mut service = $service;
+        if let Some(size) = $flags.grpc_max_message_size {
+            service = service
+                .max_decoding_message_size(size)
+                .max_encoding_message_size(size);
+        }
+        if $flags.grpc_gzip {
+            service = service
+                .accept_compressed(CompressionEncoding::Gzip)
+                .send_compressed(CompressionEncoding::Gzip);
+        }
+        InterceptedService::new(service, request_id_interceptor)
+    }};
 }
-#[async_trait::async_trait]
-impl v1::identity_server::Identity for IdentityService {
-    async fn get_plugin_info(
-        &self,
-        req: tonic::Request<v1::GetPluginInfoRequest>,
-    ) -> Result<tonic::Response<v1::GetPluginInfoResponse>, tonic::Status> {
-        info!("Returning plugin info");
-        let req = req.into_inner();
-        debug!("{:?}", req);
-        Ok(tonic::Response::new(v1::GetPluginInfoResponse {
-            name: self.name.clone(),
-            vendor_version: env!("CARGO_PKG_VERSION").into(),
-            ..Default::default()
-        }))
-    }
-    async fn get_plugin_capabilities(
-        &self,
-        _request: tonic::Request<v1::GetPluginCapabilitiesRequest>,
-    ) -> Result<tonic::Response<v1::GetPluginCapabilitiesResponse>, tonic::Status> {
-        Ok(tonic::Response::new(Default::default()))
-    }
-    async fn probe(
-        &self,
-        _request: tonic::Request<v1::ProbeRequest>,
-    ) -> Result<tonic::Response<v1::ProbeResponse>, tonic::Status> {
-        Ok(tonic::Response::new(v1::ProbeResponse {
-            ready: Some(true),
-        }))
+
+async fn main_impl(
+    args: ServeFlags,
+    log_level: Arc<overlayfs_csi::admin::LogLevelSetter>,
+) -> anyhow::Result<()> {
+    info!("Connecting to Kubernetes API");
+    let kube_client = kube::Client::try_default().await?;
+    let version = kube_client.apiserver_version().await?;
+    info!(version = %version.git_version, "Connected to Kubernetes API");
+    let pods: Api<Pod> = Api::namespaced(kube_client.clone(), &args.overlay.namespace);
+    let configmaps: Api<ConfigMap> = Api::namespaced(kube_client.clone(), &args.overlay.namespace);
+    let nodes: Api<Node> = Api::all(kube_client);
+    let warm_labels = args.overlay.warm_labels;
+    let state_configmap = args
+        .overlay
+        .state_configmap
+        .as_ref()
+        .map(|prefix| format!("{}-{}", prefix, args.overlay.node));
+    let identity_name = args.overlay.name.clone();
+    let node_id = args.overlay.node.clone();
+    let invalidation_configmap = args.overlay.invalidation_configmap.clone();
+    let peer_listen = args.overlay.peers.peer_listen;
+    let mut overlays = OverlaysBuilder::from_flags(args.overlay).pod_api(pods);
+    // Restoring the volumes and the first cleanup need the epochs of the families
+    if let Some(name) = &invalidation_configmap {
+        overlays = overlays.epochs(overlayfs_csi::invalidation::load(&configmaps, name).await?);
     }
-}
-struct NodeService {
-    node_id: String,
-    overlays: Arc<overlayfs_csi::Overlays>,
-}
-#[async_trait::async_trait]
-impl v1::node_server::Node for NodeService {
-    async fn node_stage_volume(
-        &self,
-        _req: tonic::Request<v1::NodeStageVolumeRequest>,
-    ) -> tonic::Result<tonic::Response<v1::NodeStageVolumeResponse>> {
-        Err(unimplemented())
+    let overlays = overlays.build().await?;
+    if let Some(name) = invalidation_configmap {
+        overlayfs_csi::invalidation::spawn_watch(configmaps.clone(), name, overlays.clone());
     }
-    async fn node_unstage_volume(
-        &self,
-        _req: tonic::Request<v1::NodeUnstageVolumeRequest>,
-    ) -> tonic::Result<tonic::Response<v1::NodeUnstageVolumeResponse>> {
-        Err(unimplemented())
+    if let Some(name) = state_configmap {
+        overlayfs_csi::summary::spawn_publisher(configmaps, name, overlays.clone());
     }
-
-    async fn node_publish_volume(
-        &self,
-        req: tonic::Request<v1::NodePublishVolumeRequest>,
-    ) -> tonic::Result<tonic::Response<v1::NodePublishVolumeResponse>> {
-        let req = req.into_inner();
-        info!(req.volume_id, ?req.target_path, "Publishing volume");
-        debug!("{:?}", req);
-        match self.overlays.mount(&req.volume_id, req.target_path).await {
-            Ok(()) => Ok(tonic::Response::new(Default::default())),
-            Err(e) => {
-                error!(req.volume_id, "Failed publishing: {}", e);
-                Err(tonic::Status::internal(e.to_string()))
-            }
-        }
+    if warm_labels {
+        overlayfs_csi::warm::spawn_labeler(nodes, node_id.clone(), overlays.clone());
     }
-    async fn node_unpublish_volume(
-        &self,
-        req: tonic::Request<v1::NodeUnpublishVolumeRequest>,
-    ) -> tonic::Result<tonic::Response<v1::NodeUnpublishVolumeResponse>> {
-        let req = req.into_inner();
-        info!(
-            req.volume_id, ?req.target_path,
-            "Unpublishing volume"
+    if let Some(addr) = args.metrics_addr {
+        #[cfg(feature = "metrics")]
+        overlayfs_csi::metrics::spawn_server(overlays.clone(), addr);
+        #[cfg(not(feature = "metrics"))]
+        anyhow::bail!(
+            "--metrics-addr {} requires the `metrics` cargo feature, which this build lacks",
+            addr
         );
-        debug!("{:?}", req);
-        match self.overlays.unmount(&req.volume_id, req.target_path).await {
-            Ok(()) => Ok(tonic::Response::new(Default::default())),
-            Err(e) => {
-                error!(req.volume_id, "Failed unpublishing: {}", e);
-                Err(tonic::Status::internal(e.to_string()))
-            }
-        }
     }
-    async fn node_get_volume_stats(
-        &self,
-        _req: tonic::Request<v1::NodeGetVolumeStatsRequest>,
-    ) -> tonic::Result<tonic::Response<v1::NodeGetVolumeStatsResponse>> {
-        Err(unimplemented())
+    if let Some(addr) = peer_listen {
+        overlayfs_csi::peers::spawn_server(overlays.clone(), addr);
     }
-    async fn node_expand_volume(
-        &self,
-        _req: tonic::Request<v1::NodeExpandVolumeRequest>,
-    ) -> tonic::Result<tonic::Response<v1::NodeExpandVolumeResponse>> {
-        Err(unimplemented())
+    let mut identity_service = IdentityService::new(identity_name).with_overlays(overlays.clone());
+    if args.controller_service {
+        anyhow::ensure!(
+            cfg!(feature = "controller"),
+            "--controller-service requires the `controller` cargo feature, which this build lacks"
+        );
+        identity_service = identity_service.with_controller();
     }
-    async fn node_get_capabilities(
-        &self,
-        _req: tonic::Request<v1::NodeGetCapabilitiesRequest>,
-    ) -> tonic::Result<tonic::Response<v1::NodeGetCapabilitiesResponse>> {
-        Ok(tonic::Response::new(Default::default()))
+    // The admin and transfer services are not authenticated: Backup and Restore take arbitrary
+    // paths and URLs, and Import creates bases. They are only served on UNIX sockets, whose
+    // access is restricted by the file permissions, and neither to the network nor to the peers
+    // of a vsock.
+    let local = matches!(args.socket, Address::Unix(_));
+    if !local {
+        info!(endpoint = %args.socket, "Admin and transfer services are not served");
     }
-    async fn node_get_info(
-        &self,
-        _req: tonic::Request<v1::NodeGetInfoRequest>,
-    ) -> tonic::Result<tonic::Response<v1::NodeGetInfoResponse>> {
-        Ok(tonic::Response::new(v1::NodeGetInfoResponse {
-            node_id: self.node_id.clone(),
-            ..Default::default()
-        }))
-    }
-}
-
-async fn main_impl(args: Flags) -> anyhow::Result<()> {
-    info!("Connecting to Kubernetes API");
-    let kube_client = kube::Client::try_default().await?;
-    let pods: Api<Pod> = Api::namespaced(kube_client, &args.overlay.namespace);
-    let identity_service = IdentityService {
-        name: args.overlay.name.clone(),
-    };
-    let node_service = NodeService {
-        node_id: args.overlay.node.clone(),
-        overlays: overlayfs_csi::Overlays::from_flags(args.overlay, pods).await?,
-    };
+    let admin_service = AdminService::new(overlays.clone()).log_level(log_level);
+    let transfer_service = TransferService::new(overlays.clone());
+    let node_service = NodeService::new(node_id, overlays);
 
-    info!("Connecting to socket {:?}", args.socket);
-    let _ = std::fs::remove_file(&args.socket);
-    let uds = UnixListener::bind(&args.socket)?;
-    let uds_stream = UnixListenerStream::new(uds);
-
-    info!("Started server on socket {:?}", args.socket);
     let layer = tower::ServiceBuilder::new().into_inner();
 
-    let mut builder = tonic::transport::Server::builder().layer(layer);
-    builder
-        .add_service(v1::node_server::NodeServer::new(node_service))
-        .add_service(v1::identity_server::IdentityServer::new(identity_service))
-        .serve_with_incoming(uds_stream)
-        .await?;
+    let grpc = &args.grpc;
+    let mut builder = tonic::transport::Server::builder()
+        .http2_keepalive_interval(grpc.grpc_keepalive_interval_s.map(Duration::from_secs))
+        .http2_keepalive_timeout(grpc.grpc_keepalive_timeout_s.map(Duration::from_secs))
+        .tcp_keepalive(grpc.grpc_tcp_keepalive_s.map(Duration::from_secs));
+    if let Some(limit) = grpc.grpc_concurrency_per_connection {
+        builder = builder.concurrency_limit_per_connection(limit);
+    }
+    let mut builder = builder.layer(layer);
+    let router = builder
+        .add_service(configure_service!(
+            v1::node_server::NodeServer::new(node_service),
+            grpc
+        ))
+        .add_service(configure_service!(
+            v1::identity_server::IdentityServer::new(identity_service),
+            grpc
+        ))
+        .add_optional_service(local.then(|| {
+            configure_service!(
+                admin::v1::admin_server::AdminServer::new(admin_service),
+                grpc
+            )
+        }))
+        .add_optional_service(local.then(|| {
+            configure_service!(
+                transfer::v1::base_transfer_server::BaseTransferServer::new(transfer_service),
+                grpc
+            )
+        }));
+    #[cfg(feature = "controller")]
+    let router = router.add_optional_service(args.controller_service.then(|| {
+        configure_service!(
+            v1::controller_server::ControllerServer::new(ControllerService::default()),
+            grpc
+        )
+    }));
+    // With socket activation, systemd passes a socket bound to the endpoint
+    let listen_fd = overlayfs_csi::systemd::listen_fd()?;
+    match &args.socket {
+        Address::Unix(socket) => {
+            let uds = match listen_fd {
+                Some(fd) => {
+                    info!(fd, "Using socket passed by systemd");
+                    // SAFETY: systemd passes the socket as this descriptor, which nothing else owns
+                    let uds = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+                    uds.set_nonblocking(true)?;
+                    UnixListener::from_std(uds)?
+                }
+                None => {
+                    info!("Connecting to socket {:?}", socket);
+                    let _ = std::fs::remove_file(socket);
+                    UnixListener::bind(socket)?
+                }
+            };
+            let uds_stream = UnixListenerStream::new(uds);
+            info!("Started server on socket {:?}", socket);
+            overlayfs_csi::systemd::notify("READY=1");
+            router
+                .serve_with_incoming_shutdown(uds_stream, shutdown_signal())
+                .await?;
+        }
+        Address::Tcp(addr) => {
+            let listener = match listen_fd {
+                Some(fd) => {
+                    info!(fd, "Using socket passed by systemd");
+                    // SAFETY: systemd passes the socket as this descriptor, which nothing else owns
+                    let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+                    listener.set_nonblocking(true)?;
+                    TcpListener::from_std(listener)?
+                }
+                None => TcpListener::bind(addr).await?,
+            };
+            let incoming = TcpIncoming::from_listener(
+                listener,
+                true,
+                grpc.grpc_tcp_keepalive_s.map(Duration::from_secs),
+            )
+            .map_err(|e| anyhow::anyhow!(e))?;
+            info!("Started server on {}", args.socket);
+            overlayfs_csi::systemd::notify("READY=1");
+            router
+                .serve_with_incoming_shutdown(incoming, shutdown_signal())
+                .await?;
+        }
+        Address::Vsock { cid, port } => {
+            anyhow::ensure!(
+                listen_fd.is_none(),
+                "Socket activation is not supported for vsock endpoints"
+            );
+            let incoming = VsockIncoming::bind(*cid, *port)?;
+            info!("Started server on {}", args.socket);
+            overlayfs_csi::systemd::notify("READY=1");
+            router
+                .serve_with_incoming_shutdown(incoming, shutdown_signal())
+                .await?;
+        }
+    }
+    info!("Server stopped");
 
     Ok(())
 }
 
+/// Resolves on SIGTERM, so that in-flight requests complete before the driver is replaced, e.g.
+/// during an upgrade.
+async fn shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    match signal(SignalKind::terminate()) {
+        Ok(mut sigterm) => {
+            sigterm.recv().await;
+            info!("Received SIGTERM, draining requests");
+            overlayfs_csi::systemd::notify("STOPPING=1");
+        }
+        Err(e) => {
+            error!("Failed to install the SIGTERM handler: {}", e);
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() {
     let args = Flags::parse();
-    tracing_subscriber::registry()
-        .with(Some(tracing_subscriber::fmt::layer().with_filter(
-            if args.debug {
+    let debug = args.serve.as_ref().map_or(false, |s| s.debug);
+    let filter = EnvFilter::builder()
+        .with_default_directive(
+            if debug {
                 LevelFilter::DEBUG
             } else {
                 LevelFilter::INFO
-            },
-        )))
+            }
+            .into(),
+        )
+        .from_env_lossy();
+    let log_file = args.serve.as_ref().and_then(|s| {
+        let path = s.log_file.log_file.as_ref()?;
+        match overlayfs_csi::logfile::LogFile::open(&s.log_file, path) {
+            Ok(log_file) => Some(log_file),
+            Err(e) => {
+                eprintln!("Failed to open the log file {:?}: {}", path, e);
+                std::process::exit(1);
+            }
+        }
+    });
+    let (filter, filter_handle) = reload::Layer::new(filter);
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(Some(tracing_subscriber::fmt::layer()))
+        .with(log_file.clone().map(|log_file| {
+            tracing_subscriber::fmt::layer()
+                .with_ansi(false)
+                .with_writer(log_file)
+        }))
         .init();
+    if let Some(log_file) = log_file {
+        log_file.spawn_reopen_on_sighup();
+    }
+    let log_level: Arc<overlayfs_csi::admin::LogLevelSetter> = Arc::new(move |level: &str| {
+        let filter = EnvFilter::try_new(level)?;
+        let previous = filter_handle.with_current(ToString::to_string)?;
+        filter_handle.reload(filter)?;
+        Ok(previous)
+    });
 
-    if let Err(e) = main_impl(args).await {
+    let result = match (args.command, args.serve) {
+        #[cfg(feature = "admin-cli")]
+        (Some(Command::Admin(flags)), _) => admin::run(flags).await,
+        (Some(Command::Check(flags)), _) => overlayfs_csi::check::run(&flags).await,
+        (None, Some(serve)) => main_impl(serve, log_level).await,
+        // None of the serve flags were given, e.g. no arguments nor `CSI_ENDPOINT`
+        (None, None) => Flags::command()
+            .error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "the endpoint (--endpoint or CSI_ENDPOINT) is required without subcommand",
+            )
+            .exit(),
+    };
+    if let Err(e) = result {
         error!("{:#?}", e);
         std::process::exit(1);
     }
@@ -0,0 +1,294 @@
+//! Manifests of the bases: the paths, sizes and hashes of their files, generated at promotion
+//! (`--base-manifests`) and stored next to the base in `{family}/{name}.manifest.json`. They are
+//! used to check the integrity of bases, to compare generations, and to find files in the bases of
+//! a family without walking their directories.
+use std::collections::BTreeMap;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tracing::*;
+
+use crate::base::Base;
+use crate::Overlays;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Kind {
+    File,
+    Dir,
+    Symlink,
+    Other,
+}
+impl Kind {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Kind::File => "file",
+            Kind::Dir => "dir",
+            Kind::Symlink => "symlink",
+            Kind::Other => "other",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Entry {
+    pub kind: Kind,
+    /// Permission bits
+    pub mode: u32,
+    #[serde(default)]
+    pub uid: u32,
+    #[serde(default)]
+    pub gid: u32,
+    pub size: u64,
+    /// Of the content of regular files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// Of symbolic links
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+}
+
+/// Entries of a base by path relative to its root, excluding the marker of the base
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct Manifest {
+    pub entries: BTreeMap<String, Entry>,
+}
+impl Manifest {
+    /// Walk a directory, hashing its regular files.
+    pub(crate) fn build(root: &Path) -> anyhow::Result<Self> {
+        let mut manifest = Self::default();
+        manifest.add_dir(root, Path::new(""))?;
+        manifest.entries.remove(Base::as_base_filename());
+        Ok(manifest)
+    }
+    fn add_dir(&mut self, root: &Path, relative: &Path) -> anyhow::Result<()> {
+        for entry in std::fs::read_dir(root.join(relative))? {
+            let entry = entry?;
+            let path = relative.join(entry.file_name());
+            let metadata = entry.metadata()?;
+            let file_type = metadata.file_type();
+            let (kind, sha256, target) = if file_type.is_dir() {
+                (Kind::Dir, None, None)
+            } else if file_type.is_file() {
+                let sha256 = crate::backup::sha256(&entry.path())?;
+                (Kind::File, Some(sha256), None)
+            } else if file_type.is_symlink() {
+                let target = std::fs::read_link(entry.path())?;
+                (
+                    Kind::Symlink,
+                    None,
+                    Some(target.to_string_lossy().into_owned()),
+                )
+            } else {
+                (Kind::Other, None, None)
+            };
+            self.entries.insert(
+                path.to_string_lossy().into_owned(),
+                Entry {
+                    kind,
+                    mode: metadata.permissions().mode() & 0o7777,
+                    uid: metadata.uid(),
+                    gid: metadata.gid(),
+                    size: if file_type.is_file() {
+                        metadata.size()
+                    } else {
+                        0
+                    },
+                    sha256,
+                    target,
+                },
+            );
+            if file_type.is_dir() {
+                self.add_dir(root, &path)?;
+            }
+        }
+        Ok(())
+    }
+    /// Differences from `self` to `other`
+    pub(crate) fn diff(&self, other: &Manifest) -> Diff {
+        let mut diff = Diff::default();
+        for (path, entry) in &self.entries {
+            match other.entries.get(path) {
+                None => diff.removed.push(path.clone()),
+                Some(e) if e != entry => diff.changed.push(path.clone()),
+                Some(_) => {}
+            }
+        }
+        diff.added = other
+            .entries
+            .keys()
+            .filter(|path| !self.entries.contains_key(*path))
+            .cloned()
+            .collect();
+        diff
+    }
+    /// Regular files of `other` identical in `self`
+    pub(crate) fn identical_files(&self, other: &Manifest) -> Vec<String> {
+        other
+            .entries
+            .iter()
+            .filter(|(path, entry)| {
+                entry.kind == Kind::File && self.entries.get(*path) == Some(*entry)
+            })
+            .map(|(path, _)| path.clone())
+            .collect()
+    }
+}
+
+/// Path below `root`, from a path of a manifest, checking that it does not escape `root`, e.g.
+/// through a symbolic link.
+pub(crate) fn beneath(root: &Path, path: &str) -> anyhow::Result<PathBuf> {
+    let relative = Path::new(path);
+    anyhow::ensure!(
+        relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_))),
+        "Invalid path {:?}",
+        path
+    );
+    let mut resolved = root.to_owned();
+    for (i, component) in relative.components().enumerate() {
+        if i > 0 {
+            anyhow::ensure!(
+                std::fs::symlink_metadata(&resolved)?.is_dir(),
+                "{:?} is not a directory",
+                resolved
+            );
+        }
+        resolved.push(component);
+    }
+    Ok(resolved)
+}
+
+/// Hardlink the regular files at `paths` from `src` into `dst`, where their directories exist,
+/// returning the number of bytes linked. Files already in `dst` are left untouched.
+pub(crate) fn link_files(src: &Path, dst: &Path, paths: &[String]) -> anyhow::Result<u64> {
+    let mut linked = 0;
+    for path in paths {
+        let (from, to) = (beneath(src, path)?, beneath(dst, path)?);
+        if to.symlink_metadata().is_ok() {
+            continue;
+        }
+        let metadata = from.symlink_metadata()?;
+        anyhow::ensure!(metadata.is_file(), "{:?} is not a regular file", from);
+        std::fs::hard_link(&from, &to)?;
+        linked += metadata.len();
+    }
+    Ok(linked)
+}
+
+/// Paths that differ between two manifests, in lexicographic order
+#[derive(Debug, Default)]
+pub(crate) struct Diff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+}
+impl From<Diff> for crate::admin::v1::ManifestDiff {
+    fn from(diff: Diff) -> Self {
+        Self {
+            added: diff.added,
+            removed: diff.removed,
+            changed: diff.changed,
+        }
+    }
+}
+
+impl Base {
+    pub(crate) fn manifest_file(&self) -> PathBuf {
+        self.0
+            .with_file_name(format!("{}.manifest.json", self.name()))
+    }
+    pub(crate) fn read_manifest(&self) -> anyhow::Result<Manifest> {
+        let data = std::fs::read(self.manifest_file())
+            .map_err(|e| anyhow::anyhow!("No manifest for {:?}: {}", self, e))?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+    pub(crate) fn has_manifest(&self) -> bool {
+        self.manifest_file().exists()
+    }
+    pub(crate) fn store_manifest(&self, manifest: &Manifest) -> anyhow::Result<()> {
+        std::fs::write(self.manifest_file(), serde_json::to_vec(manifest)?)?;
+        Ok(())
+    }
+    /// Generate and store the manifest of the base.
+    pub(crate) fn write_manifest(&self) -> anyhow::Result<()> {
+        self.store_manifest(&Manifest::build(&self.0)?)
+    }
+    pub(crate) fn remove_manifest(&self) {
+        let _ = std::fs::remove_file(self.manifest_file());
+    }
+}
+
+impl Overlays {
+    /// Base of a family by name, if it exists
+    fn named_base(&self, family: &str, name: &str) -> anyhow::Result<Base> {
+        anyhow::ensure!(
+            crate::transfer::valid_component(family) && crate::transfer::valid_component(name),
+            "Invalid base {}/{}",
+            family,
+            name
+        );
+        let base = Base(self.flags.bases.join(family).join(name));
+        anyhow::ensure!(base.0.is_dir(), "No base {}/{}", family, name);
+        Ok(base)
+    }
+    /// Generate the manifest of a freshly promoted base, logging failures.
+    pub(crate) async fn index_base(&self, base: &Base) {
+        let result = {
+            let base = base.clone();
+            tokio::task::spawn_blocking(move || base.write_manifest()).await
+        };
+        match result {
+            Ok(Ok(())) => debug!(?base, "Generated manifest"),
+            Ok(Err(e)) => warn!(?base, "Failed to generate manifest: {}", e),
+            Err(e) => warn!(?base, "Failed to generate manifest: {}", e),
+        }
+    }
+    /// Compare a base to its manifest, returning the differences, i.e. what was added, removed or
+    /// changed since the promotion.
+    pub(crate) async fn verify_base(&self, family: &str, name: &str) -> anyhow::Result<Diff> {
+        let base = self.named_base(family, name)?;
+        let expected = base.read_manifest()?;
+        let actual = tokio::task::spawn_blocking(move || Manifest::build(&base.0)).await??;
+        Ok(expected.diff(&actual))
+    }
+    /// Differences between the manifests of two bases of a family
+    pub(crate) fn diff_bases(&self, family: &str, from: &str, to: &str) -> anyhow::Result<Diff> {
+        let from = self.named_base(family, from)?.read_manifest()?;
+        let to = self.named_base(family, to)?.read_manifest()?;
+        Ok(from.diff(&to))
+    }
+    /// Entries at `path` in the bases of a family that have a manifest, by base name, most recent
+    /// first. Returns as well the names of the bases without manifest.
+    pub(crate) fn find_in_bases(
+        &self,
+        family: &str,
+        path: &str,
+    ) -> anyhow::Result<(Vec<(String, Entry)>, Vec<String>)> {
+        anyhow::ensure!(
+            crate::transfer::valid_component(family),
+            "Invalid family {}",
+            family
+        );
+        let path = path.trim_matches('/');
+        let mut bases: Vec<_> = self
+            .family_bases(family)
+            .map(|base| (base.created().ok(), base))
+            .collect();
+        bases.sort_by_key(|(created, _)| std::cmp::Reverse(*created));
+        let (mut found, mut unindexed) = (vec![], vec![]);
+        for (_, base) in bases {
+            match base.read_manifest() {
+                Ok(mut manifest) => {
+                    if let Some(entry) = manifest.entries.remove(path) {
+                        found.push((base.name(), entry));
+                    }
+                }
+                Err(_) => unindexed.push(base.name()),
+            }
+        }
+        Ok((found, unindexed))
+    }
+}
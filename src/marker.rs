@@ -0,0 +1,112 @@
+//! Marker by which a volume indicates that it can be promoted into a base: a file at its root, or
+//! an extended attribute of its root, which stays out of the directory seen by the workload.
+//!
+//! The value of the marker (content of the file or of the attribute) can request a maximum age
+//! for the resulting base, e.g. `1h` for a job that knows that its output is partial.
+use std::path::Path;
+
+use tracing::*;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Marker {
+    File(String),
+    Xattr(String),
+}
+impl Default for Marker {
+    fn default() -> Self {
+        Self::File(crate::base::Base::as_base_filename().into())
+    }
+}
+impl std::str::FromStr for Marker {
+    type Err = anyhow::Error;
+    /// `xattr:{name}`, e.g. `xattr:trusted.overlayfs-csi.as-base`, or `file:{name}` or `{name}`
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let marker = match s.split_once(':') {
+            Some(("xattr", name)) => {
+                anyhow::ensure!(
+                    name.contains('.') && !name.contains('\0'),
+                    "Expected a namespaced attribute name, e.g. user.as-base, got {:?}",
+                    name
+                );
+                Self::Xattr(name.into())
+            }
+            Some(("file", name)) => Self::File(name.into()),
+            _ => Self::File(s.into()),
+        };
+        if let Self::File(name) = &marker {
+            anyhow::ensure!(
+                !name.is_empty() && !name.contains(['/', '\0']) && name != "." && name != "..",
+                "Invalid marker file name {:?}",
+                name
+            );
+        }
+        Ok(marker)
+    }
+}
+impl std::fmt::Display for Marker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::File(name) => write!(f, "file {}", name),
+            Self::Xattr(name) => write!(f, "extended attribute {}", name),
+        }
+    }
+}
+impl Marker {
+    /// Whether the marker is set on the directory `root`
+    pub(crate) fn present(&self, root: &Path) -> bool {
+        match self {
+            Self::File(name) => root.join(name).exists(),
+            Self::Xattr(name) => match crate::xattr::lgetxattr(root, name) {
+                Ok(value) => value.is_some(),
+                Err(e) => {
+                    debug!(?root, name, "Failed to read extended attribute: {}", e);
+                    false
+                }
+            },
+        }
+    }
+    /// Value of the marker on `root`, trimmed, if present and not empty
+    fn value(&self, root: &Path) -> Option<String> {
+        let value = match self {
+            Self::File(name) => std::fs::read(root.join(name)).ok()?,
+            Self::Xattr(name) => crate::xattr::lgetxattr(root, name).ok()??,
+        };
+        let value = String::from_utf8_lossy(&value).trim().to_owned();
+        (!value.is_empty()).then_some(value)
+    }
+    /// Maximum age requested by the workload for the base, in seconds
+    pub(crate) fn requested_max_age_s(&self, root: &Path) -> Option<i64> {
+        let value = self.value(root)?;
+        match parse_duration_s(&value) {
+            Ok(s) => Some(s),
+            Err(e) => {
+                warn!(
+                    ?root,
+                    value, "Ignoring the value of the promotion marker: {}", e
+                );
+                None
+            }
+        }
+    }
+}
+
+/// Duration in seconds, as a number of seconds or with a unit among `s`, `m`, `h` and `d`
+fn parse_duration_s(s: &str) -> anyhow::Result<i64> {
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let unit = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => anyhow::bail!("Expected a duration, e.g. 3600, 90m or 1h, got {:?}", s),
+    };
+    let duration = number
+        .parse::<i64>()?
+        .checked_mul(unit)
+        .ok_or_else(|| anyhow::anyhow!("Duration {:?} out of range", s))?;
+    anyhow::ensure!(duration > 0, "The duration must be positive");
+    Ok(duration)
+}
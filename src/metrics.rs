@@ -0,0 +1,454 @@
+//! Prometheus metrics, served in the text format at `/metrics`.
+//!
+//! Without the `metrics` feature, nothing is recorded nor served.
+#[cfg(feature = "metrics")]
+use std::collections::HashMap;
+#[cfg(feature = "metrics")]
+use std::convert::Infallible;
+use std::future::Future;
+#[cfg(feature = "metrics")]
+use std::net::SocketAddr;
+#[cfg(feature = "metrics")]
+use std::sync::Arc;
+#[cfg(feature = "metrics")]
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "metrics")]
+use hyper::{Body, Request, Response, StatusCode};
+#[cfg(feature = "metrics")]
+use prometheus::{
+    Encoder, GaugeVec, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    Opts, Registry, TextEncoder,
+};
+#[cfg(feature = "metrics")]
+use tracing::*;
+
+#[cfg(feature = "metrics")]
+use crate::Overlays;
+
+/// State of a base, as reported in `overlayfs_csi_base_state`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum BaseState {
+    /// Can be used for new volumes
+    Valid,
+    /// Not valid anymore, but kept as volumes still use it
+    Pinned,
+    /// Not valid anymore, and kept until its hard expiry
+    Stale,
+    /// Not valid anymore, and about to be cleaned up
+    Expired,
+}
+impl BaseState {
+    #[cfg(feature = "metrics")]
+    const ALL: [Self; 4] = [Self::Valid, Self::Pinned, Self::Stale, Self::Expired];
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Self::Valid => "valid",
+            Self::Pinned => "pinned",
+            Self::Stale => "stale",
+            Self::Expired => "expired",
+        }
+    }
+}
+
+/// Default buckets of the duration histograms, in seconds
+#[cfg(feature = "metrics")]
+const DEFAULT_BUCKETS: [f64; 12] = [
+    0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0,
+];
+
+#[derive(clap::Args, Clone)]
+pub struct MetricsFlags {
+    /// Boundaries of the buckets of the duration histograms, in seconds, e.g.
+    /// `0.1,1,10,60,600`
+    #[clap(long, value_delimiter = ',')]
+    pub metrics_buckets: Vec<f64>,
+    /// Constant label added to all metrics, as `name=value`, e.g. `cluster=prod`
+    #[clap(long, value_parser = parse_label)]
+    pub metrics_label: Vec<(String, String)>,
+    /// Prometheus Pushgateway to which the metrics are pushed, e.g. `http://pushgateway:9091`,
+    /// for nodes that cannot be scraped. They are grouped by job `overlayfs-csi` and node.
+    #[clap(long)]
+    pub metrics_push_url: Option<String>,
+    #[clap(long, default_value_t = 60)]
+    pub metrics_push_interval_s: u64,
+    /// Serve an HTML page showing the bases and volumes at `/status` on the metrics address
+    #[clap(long)]
+    pub status_page: bool,
+}
+impl Default for MetricsFlags {
+    fn default() -> Self {
+        Self {
+            metrics_buckets: vec![],
+            metrics_label: vec![],
+            metrics_push_url: None,
+            metrics_push_interval_s: 60,
+            status_page: false,
+        }
+    }
+}
+fn parse_label(s: &str) -> anyhow::Result<(String, String)> {
+    let (name, value) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow::anyhow!("Expected name=value, got {}", s))?;
+    Ok((name.into(), value.into()))
+}
+
+#[cfg(feature = "metrics")]
+pub struct Metrics {
+    registry: Registry,
+    base_ttl: GaugeVec,
+    base_age: GaugeVec,
+    base_state: GaugeVec,
+    bases: IntGauge,
+    volumes: IntGauge,
+    publish_sources: IntCounterVec,
+    operations: HistogramVec,
+    pod_deletion_stalls: IntCounter,
+    pod_creations_queued: IntGauge,
+    pod_creation_queue_seconds: Histogram,
+    kube_requests: HistogramVec,
+}
+#[cfg(feature = "metrics")]
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new(&Default::default()).unwrap()
+    }
+}
+#[cfg(feature = "metrics")]
+impl Metrics {
+    pub fn new(flags: &MetricsFlags) -> anyhow::Result<Self> {
+        let labels: HashMap<_, _> = flags.metrics_label.iter().cloned().collect();
+        let registry = Registry::new_custom(
+            Some("overlayfs_csi".into()),
+            (!labels.is_empty()).then_some(labels),
+        )?;
+        let buckets = if flags.metrics_buckets.is_empty() {
+            DEFAULT_BUCKETS.to_vec()
+        } else {
+            flags.metrics_buckets.clone()
+        };
+        let base_ttl = GaugeVec::new(
+            Opts::new(
+                "base_ttl_seconds",
+                "Time until the base expires, negative once expired, +Inf if it never does",
+            ),
+            &["family", "base"],
+        )
+        .unwrap();
+        let base_age = GaugeVec::new(
+            Opts::new("base_age_seconds", "Time since the creation of the base"),
+            &["family", "base"],
+        )
+        .unwrap();
+        let base_state = GaugeVec::new(
+            Opts::new(
+                "base_state",
+                "1 for the current state of the base (valid, pinned, stale or expired)",
+            ),
+            &["family", "base", "state"],
+        )
+        .unwrap();
+        let bases = IntGauge::new("bases", "Bases on the node, in any state").unwrap();
+        let volumes = IntGauge::new("volumes", "Volumes published on the node").unwrap();
+        let publish_sources = IntCounterVec::new(
+            Opts::new(
+                "publish_sources_total",
+                "Read-write volumes published on top of a base (`base`) or from scratch (`scratch`)",
+            ),
+            &["source"],
+        )
+        .unwrap();
+        let operations = HistogramVec::new(
+            HistogramOpts::new(
+                "operation_duration_seconds",
+                "Duration of the publish, unpublish and cleanup operations, by result",
+            )
+            .buckets(buckets.clone()),
+            &["operation", "result"],
+        )?;
+        let pod_deletion_stalls = IntCounter::new(
+            "pod_deletion_stalls_total",
+            "Data pods that were not gone within the deletion timeout",
+        )
+        .unwrap();
+        let pod_creations_queued = IntGauge::new(
+            "pod_creations_queued",
+            "Data pod creations waiting for `max_concurrent_pod_creations`",
+        )
+        .unwrap();
+        let pod_creation_queue_seconds = Histogram::with_opts(
+            HistogramOpts::new(
+                "pod_creation_queue_seconds",
+                "Time data pod creations spent waiting for `max_concurrent_pod_creations`",
+            )
+            .buckets(buckets.clone()),
+        )?;
+        let kube_requests = HistogramVec::new(
+            HistogramOpts::new(
+                "kube_request_duration_seconds",
+                "Duration of the requests to the Kubernetes API, by operation and result",
+            )
+            .buckets(buckets),
+            &["operation", "result"],
+        )?;
+        registry.register(Box::new(base_ttl.clone())).unwrap();
+        registry.register(Box::new(base_age.clone())).unwrap();
+        registry.register(Box::new(base_state.clone())).unwrap();
+        registry.register(Box::new(bases.clone())).unwrap();
+        registry.register(Box::new(volumes.clone())).unwrap();
+        registry
+            .register(Box::new(publish_sources.clone()))
+            .unwrap();
+        registry.register(Box::new(operations.clone())).unwrap();
+        registry
+            .register(Box::new(pod_deletion_stalls.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(pod_creations_queued.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(pod_creation_queue_seconds.clone()))
+            .unwrap();
+        registry.register(Box::new(kube_requests.clone())).unwrap();
+        Ok(Self {
+            registry,
+            base_ttl,
+            base_age,
+            base_state,
+            bases,
+            volumes,
+            publish_sources,
+            operations,
+            pod_deletion_stalls,
+            pod_creations_queued,
+            pod_creation_queue_seconds,
+            kube_requests,
+        })
+    }
+    /// Forget all bases, before recording the current ones.
+    pub(crate) fn reset_bases(&self) {
+        self.base_ttl.reset();
+        self.base_age.reset();
+        self.base_state.reset();
+        self.bases.set(0);
+    }
+    pub(crate) fn record_base(
+        &self,
+        family: &str,
+        base: &str,
+        ttl_s: f64,
+        age_s: f64,
+        state: BaseState,
+    ) {
+        self.bases.inc();
+        self.base_ttl.with_label_values(&[family, base]).set(ttl_s);
+        self.base_age.with_label_values(&[family, base]).set(age_s);
+        for s in BaseState::ALL {
+            let value = if s == state { 1.0 } else { 0.0 };
+            self.base_state
+                .with_label_values(&[family, base, s.as_str()])
+                .set(value);
+        }
+    }
+    pub(crate) fn record_volumes(&self, count: usize) {
+        self.volumes.set(count as i64);
+    }
+    /// Count a read-write volume published on top of a base (`base`) or from scratch (`scratch`).
+    pub(crate) fn record_publish_source(&self, source: &'static str) {
+        self.publish_sources.with_label_values(&[source]).inc();
+    }
+    pub(crate) fn record_pod_deletion_stall(&self) {
+        self.pod_deletion_stalls.inc();
+    }
+    /// Count a queued data pod creation until the returned guard is dropped.
+    pub(crate) fn queue_pod_creation(&self) -> QueuedPodCreation<'_> {
+        self.pod_creations_queued.inc();
+        QueuedPodCreation {
+            metrics: self,
+            since: Instant::now(),
+        }
+    }
+    /// Run a request to the Kubernetes API, recording its duration and result.
+    pub(crate) async fn time_kube<T>(
+        &self,
+        operation: &'static str,
+        request: impl Future<Output = anyhow::Result<T>>,
+    ) -> anyhow::Result<T> {
+        let start = Instant::now();
+        let result = request.await;
+        self.kube_requests
+            .with_label_values(&[operation, if result.is_ok() { "ok" } else { "error" }])
+            .observe(start.elapsed().as_secs_f64());
+        result
+    }
+    /// Run a publish, unpublish or cleanup operation, recording its duration and result.
+    pub(crate) async fn time_operation<T>(
+        &self,
+        operation: &'static str,
+        run: impl Future<Output = anyhow::Result<T>>,
+    ) -> anyhow::Result<T> {
+        let start = Instant::now();
+        let result = run.await;
+        self.operations
+            .with_label_values(&[operation, if result.is_ok() { "ok" } else { "error" }])
+            .observe(start.elapsed().as_secs_f64());
+        result
+    }
+    fn encode(&self) -> anyhow::Result<Vec<u8>> {
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(buffer)
+    }
+}
+
+#[cfg(feature = "metrics")]
+pub(crate) struct QueuedPodCreation<'a> {
+    metrics: &'a Metrics,
+    since: Instant,
+}
+#[cfg(feature = "metrics")]
+impl Drop for QueuedPodCreation<'_> {
+    fn drop(&mut self) {
+        self.metrics.pod_creations_queued.dec();
+        self.metrics
+            .pod_creation_queue_seconds
+            .observe(self.since.elapsed().as_secs_f64());
+    }
+}
+
+/// Serve the metrics in the background.
+#[cfg(feature = "metrics")]
+pub fn spawn_server(overlays: Arc<Overlays>, addr: SocketAddr) {
+    tokio::spawn(async move {
+        let make_service = hyper::service::make_service_fn(move |_| {
+            let overlays = overlays.clone();
+            async move {
+                Ok::<_, Infallible>(hyper::service::service_fn(move |req| {
+                    handle(overlays.clone(), req)
+                }))
+            }
+        });
+        info!(?addr, "Serving metrics");
+        if let Err(e) = hyper::Server::bind(&addr).serve(make_service).await {
+            error!("Failed to serve metrics: {}", e);
+        }
+    });
+}
+
+/// Push the metrics to a Pushgateway in the background, replacing the previous push of the node.
+#[cfg(all(feature = "metrics", feature = "http-client"))]
+pub(crate) fn spawn_push(overlays: Arc<Overlays>, url: String, interval: Duration) {
+    let url = format!(
+        "{}/metrics/job/overlayfs-csi/node/{}",
+        url.trim_end_matches('/'),
+        overlays.flags.node
+    );
+    tokio::spawn(async move {
+        let client = reqwest::Client::new();
+        info!(url, ?interval, "Pushing metrics");
+        loop {
+            let result = async {
+                let body = overlays.metrics.encode()?;
+                client
+                    .put(&url)
+                    .header(
+                        reqwest::header::CONTENT_TYPE,
+                        TextEncoder::new().format_type(),
+                    )
+                    .body(body)
+                    .send()
+                    .await?
+                    .error_for_status()?;
+                anyhow::Ok(())
+            }
+            .await;
+            if let Err(e) = result {
+                warn!(url, "Failed to push metrics: {}", e);
+            }
+            tokio::time::sleep(interval).await;
+        }
+    });
+}
+
+#[cfg(feature = "metrics")]
+async fn handle(overlays: Arc<Overlays>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let mut response = Response::default();
+    if req.uri().path() == "/healthz" {
+        if !overlays.healthy() {
+            *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+        }
+        return Ok(response);
+    }
+    if req.uri().path() == "/status" && overlays.flags.metrics.status_page {
+        match crate::status::render(&overlays).await {
+            Ok(html) => {
+                response.headers_mut().insert(
+                    hyper::header::CONTENT_TYPE,
+                    hyper::header::HeaderValue::from_static("text/html; charset=utf-8"),
+                );
+                *response.body_mut() = html.into();
+            }
+            Err(e) => {
+                error!("Failed to render the status page: {}", e);
+                *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            }
+        }
+        return Ok(response);
+    }
+    if req.uri().path() != "/metrics" {
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(response);
+    }
+    match overlays.metrics.encode() {
+        Ok(buffer) => *response.body_mut() = buffer.into(),
+        Err(e) => {
+            error!("Failed to encode metrics: {}", e);
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        }
+    }
+    Ok(response)
+}
+
+#[cfg(not(feature = "metrics"))]
+#[derive(Default)]
+pub struct Metrics;
+#[cfg(not(feature = "metrics"))]
+impl Metrics {
+    pub fn new(_flags: &MetricsFlags) -> anyhow::Result<Self> {
+        Ok(Self)
+    }
+    pub(crate) fn reset_bases(&self) {}
+    pub(crate) fn record_base(
+        &self,
+        _family: &str,
+        _base: &str,
+        _ttl_s: f64,
+        _age_s: f64,
+        _state: BaseState,
+    ) {
+    }
+    pub(crate) fn record_volumes(&self, _count: usize) {}
+    pub(crate) fn record_publish_source(&self, _source: &'static str) {}
+    pub(crate) fn record_pod_deletion_stall(&self) {}
+    pub(crate) fn queue_pod_creation(&self) -> QueuedPodCreation {
+        QueuedPodCreation
+    }
+    pub(crate) async fn time_kube<T>(
+        &self,
+        _operation: &'static str,
+        request: impl Future<Output = anyhow::Result<T>>,
+    ) -> anyhow::Result<T> {
+        request.await
+    }
+    pub(crate) async fn time_operation<T>(
+        &self,
+        _operation: &'static str,
+        run: impl Future<Output = anyhow::Result<T>>,
+    ) -> anyhow::Result<T> {
+        run.await
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+pub(crate) struct QueuedPodCreation;
@@ -0,0 +1,520 @@
+//! Mount operations, behind a trait so that the overlay logic can be driven without root.
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use nix::errno::Errno;
+use nix::mount::{MntFlags, MsFlags};
+
+use crate::mountinfo::{self, MountInfo, Propagation};
+
+/// Propagation type set on a mount, see `mount(8)`
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MountPropagation {
+    Private,
+    Rslave,
+    Rshared,
+}
+impl MountPropagation {
+    fn option(self) -> &'static str {
+        match self {
+            Self::Private => "--make-private",
+            Self::Rslave => "--make-rslave",
+            Self::Rshared => "--make-rshared",
+        }
+    }
+    fn flags(self) -> MsFlags {
+        match self {
+            Self::Private => MsFlags::MS_PRIVATE,
+            Self::Rslave => MsFlags::MS_SLAVE | MsFlags::MS_REC,
+            Self::Rshared => MsFlags::MS_SHARED | MsFlags::MS_REC,
+        }
+    }
+}
+
+/// Kernel messages of overlayfs revealing leftover state in the upper or work directory, e.g. after
+/// a hard reboot, rather than a problem with the mount itself
+const STALE_STATE_MESSAGES: &[&str] = &[
+    // `work/incompat/volatile` left by a volatile mount that was not cleanly unmounted
+    "incompat feature",
+    "failed to verify upper root origin",
+    "failed to verify index dir",
+    "failed to verify origin",
+];
+
+/// Failure to mount an overlay, with the messages logged by overlayfs in the meantime
+#[derive(Debug)]
+pub struct OverlayMountError {
+    pub error: String,
+    pub kernel: Vec<String>,
+}
+impl OverlayMountError {
+    /// Whether the mount failed due to stale overlay metadata in the upper or work directory
+    pub fn stale_state(&self) -> bool {
+        self.kernel
+            .iter()
+            .any(|m| STALE_STATE_MESSAGES.iter().any(|s| m.contains(s)))
+    }
+}
+impl std::fmt::Display for OverlayMountError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.error)?;
+        if !self.kernel.is_empty() {
+            write!(f, " (kernel: {})", self.kernel.join("; "))?;
+        }
+        Ok(())
+    }
+}
+impl std::error::Error for OverlayMountError {}
+
+pub trait Mounter: Send + Sync {
+    /// Mount an overlay filesystem with a single lower directory. Failures should be reported as
+    /// [`OverlayMountError`], so that stale state can be repaired.
+    fn mount_overlay(
+        &self,
+        source: &str,
+        lower: &Path,
+        upper: &Path,
+        work: &Path,
+        target: &Path,
+    ) -> anyhow::Result<()>;
+    fn mount_bind(&self, source: &Path, target: &Path) -> anyhow::Result<()>;
+    fn mount_bind_read_only(&self, source: &Path, target: &Path) -> anyhow::Result<()>;
+    /// Mount a tmpfs capped to `size` (in the format of the `size` mount option).
+    fn mount_tmpfs(&self, size: &str, target: &Path) -> anyhow::Result<()>;
+    /// Forcefully unmount, ignoring errors if nothing is mounted.
+    fn unmount(&self, target: &Path) -> anyhow::Result<()>;
+    /// Change the propagation type of a mount.
+    fn set_propagation(&self, target: &Path, propagation: MountPropagation) -> anyhow::Result<()>;
+    /// Current mounts, in mount order, to recognize the mounts made earlier, e.g. before a
+    /// restart. By default, those of `/proc/self/mountinfo`.
+    fn mounts(&self) -> anyhow::Result<Vec<MountInfo>> {
+        mountinfo::all()
+    }
+}
+
+impl<M: Mounter + ?Sized> Mounter for Arc<M> {
+    fn mount_overlay(
+        &self,
+        source: &str,
+        lower: &Path,
+        upper: &Path,
+        work: &Path,
+        target: &Path,
+    ) -> anyhow::Result<()> {
+        (**self).mount_overlay(source, lower, upper, work, target)
+    }
+    fn mount_bind(&self, source: &Path, target: &Path) -> anyhow::Result<()> {
+        (**self).mount_bind(source, target)
+    }
+    fn mount_bind_read_only(&self, source: &Path, target: &Path) -> anyhow::Result<()> {
+        (**self).mount_bind_read_only(source, target)
+    }
+    fn mount_tmpfs(&self, size: &str, target: &Path) -> anyhow::Result<()> {
+        (**self).mount_tmpfs(size, target)
+    }
+    fn unmount(&self, target: &Path) -> anyhow::Result<()> {
+        (**self).unmount(target)
+    }
+    fn set_propagation(&self, target: &Path, propagation: MountPropagation) -> anyhow::Result<()> {
+        (**self).set_propagation(target, propagation)
+    }
+    fn mounts(&self) -> anyhow::Result<Vec<MountInfo>> {
+        (**self).mounts()
+    }
+}
+
+/// Mounter of the node: [`SyscallMounter`], or [`CommandMounter`] with `mount_command`
+pub(crate) fn system_mounter(mount_command: bool) -> Arc<dyn Mounter> {
+    if mount_command {
+        Arc::new(CommandMounter)
+    } else {
+        Arc::new(SyscallMounter)
+    }
+}
+
+/// Calls `mount(2)` and `umount2(2)` directly, without depending on binaries of the image.
+#[derive(Default)]
+pub struct SyscallMounter;
+impl SyscallMounter {
+    fn mount(
+        source: &str,
+        target: &Path,
+        fs_type: Option<&str>,
+        flags: MsFlags,
+        options: Option<&str>,
+    ) -> anyhow::Result<()> {
+        nix::mount::mount(Some(source), target, fs_type, flags, options).map_err(|e| {
+            anyhow::anyhow!(
+                "Failed to mount {} ({}) on {:?}: {}",
+                source,
+                fs_type.unwrap_or("bind"),
+                target,
+                e
+            )
+        })
+    }
+}
+impl Mounter for SyscallMounter {
+    fn mount_overlay(
+        &self,
+        source: &str,
+        lower: &Path,
+        upper: &Path,
+        work: &Path,
+        target: &Path,
+    ) -> anyhow::Result<()> {
+        let mut kmsg = crate::kmsg::Kmsg::follow()
+            .map_err(|e| tracing::debug!("Cannot read kernel messages: {}", e))
+            .ok();
+        let options = format!(
+            "lowerdir={},upperdir={},workdir={}",
+            lower.to_string_lossy(),
+            upper.to_string_lossy(),
+            work.to_string_lossy()
+        );
+        Self::mount(
+            source,
+            target,
+            Some("overlay"),
+            MsFlags::empty(),
+            Some(&options),
+        )
+        .map_err(|e| {
+            OverlayMountError {
+                error: e.to_string(),
+                kernel: kmsg
+                    .as_mut()
+                    .map(|k| k.read("overlayfs"))
+                    .unwrap_or_default(),
+            }
+            .into()
+        })
+    }
+    fn mount_bind(&self, source: &Path, target: &Path) -> anyhow::Result<()> {
+        Self::mount(
+            &source.to_string_lossy(),
+            target,
+            None,
+            MsFlags::MS_BIND,
+            None,
+        )
+    }
+    fn mount_bind_read_only(&self, source: &Path, target: &Path) -> anyhow::Result<()> {
+        self.mount_bind(source, target)?;
+        // The read-only flag is only applied to bind mounts when remounting
+        let flags = MsFlags::MS_REMOUNT | MsFlags::MS_BIND | MsFlags::MS_RDONLY;
+        if let Err(e) = Self::mount(&source.to_string_lossy(), target, None, flags, None) {
+            self.unmount(target)?;
+            return Err(e);
+        }
+        Ok(())
+    }
+    fn mount_tmpfs(&self, size: &str, target: &Path) -> anyhow::Result<()> {
+        let options = format!("size={}", size);
+        Self::mount(
+            "tmpfs",
+            target,
+            Some("tmpfs"),
+            MsFlags::empty(),
+            Some(&options),
+        )
+    }
+    fn unmount(&self, target: &Path) -> anyhow::Result<()> {
+        match nix::mount::umount2(target, MntFlags::MNT_FORCE) {
+            // Not a mount point, or already gone
+            Ok(()) | Err(Errno::EINVAL) | Err(Errno::ENOENT) => Ok(()),
+            Err(e) => Err(anyhow::anyhow!("Failed to unmount {:?}: {}", target, e)),
+        }
+    }
+    fn set_propagation(&self, target: &Path, propagation: MountPropagation) -> anyhow::Result<()> {
+        nix::mount::mount::<str, _, str, str>(None, target, None, propagation.flags(), None)
+            .map_err(|e| {
+                anyhow::anyhow!(
+                    "Failed to set the propagation of {:?} to {:?}: {}",
+                    target,
+                    propagation,
+                    e
+                )
+            })
+    }
+}
+
+/// Shells out to the `mount` and `umount` binaries.
+#[derive(Default)]
+pub struct CommandMounter;
+impl Mounter for CommandMounter {
+    fn mount_overlay(
+        &self,
+        source: &str,
+        lower: &Path,
+        upper: &Path,
+        work: &Path,
+        target: &Path,
+    ) -> anyhow::Result<()> {
+        // The reason of a failure, e.g. an upper directory on an unsupported filesystem, is only
+        // logged by the kernel
+        let mut kmsg = crate::kmsg::Kmsg::follow()
+            .map_err(|e| tracing::debug!("Cannot read kernel messages: {}", e))
+            .ok();
+        let result = duct::cmd!(
+            "mount",
+            "-t",
+            "overlay",
+            source,
+            "-o",
+            format!(
+                "lowerdir={},upperdir={},workdir={}",
+                lower.as_os_str().to_str().unwrap(),
+                upper.as_os_str().to_str().unwrap(),
+                work.as_os_str().to_str().unwrap()
+            ),
+            target
+        )
+        .stderr_to_stdout()
+        .stdout_capture()
+        .unchecked()
+        .run()?;
+        if result.status.success() {
+            return Ok(());
+        }
+        Err(OverlayMountError {
+            error: format!(
+                "mount failed ({}): {}",
+                result.status,
+                String::from_utf8_lossy(&result.stdout).trim()
+            ),
+            kernel: kmsg
+                .as_mut()
+                .map(|k| k.read("overlayfs"))
+                .unwrap_or_default(),
+        }
+        .into())
+    }
+    fn mount_bind(&self, source: &Path, target: &Path) -> anyhow::Result<()> {
+        duct::cmd!("mount", "--bind", source, target).run()?;
+        Ok(())
+    }
+    fn mount_bind_read_only(&self, source: &Path, target: &Path) -> anyhow::Result<()> {
+        self.mount_bind(source, target)?;
+        // The read-only flag is only applied to bind mounts when remounting
+        if let Err(e) = duct::cmd!("mount", "-o", "remount,bind,ro", target).run() {
+            self.unmount(target)?;
+            return Err(e.into());
+        }
+        Ok(())
+    }
+    fn mount_tmpfs(&self, size: &str, target: &Path) -> anyhow::Result<()> {
+        duct::cmd!(
+            "mount",
+            "-t",
+            "tmpfs",
+            "tmpfs",
+            "-o",
+            format!("size={}", size),
+            target
+        )
+        .run()?;
+        Ok(())
+    }
+    fn unmount(&self, target: &Path) -> anyhow::Result<()> {
+        duct::cmd!("umount", "-f", target).unchecked().run()?;
+        Ok(())
+    }
+    fn set_propagation(&self, target: &Path, propagation: MountPropagation) -> anyhow::Result<()> {
+        duct::cmd!("mount", propagation.option(), target).run()?;
+        Ok(())
+    }
+}
+
+/// Sets the propagation type of the overlays and bind mounts made by another mounter, e.g. for
+/// nested containers that need to see or hide the mounts made below the volumes.
+pub struct PropagatingMounter {
+    pub inner: Arc<dyn Mounter>,
+    pub overlay: Option<MountPropagation>,
+    pub bind: Option<MountPropagation>,
+}
+impl PropagatingMounter {
+    /// Set the propagation of a new mount, unmounting it on failure.
+    fn propagate(
+        &self,
+        target: &Path,
+        propagation: Option<MountPropagation>,
+    ) -> anyhow::Result<()> {
+        let Some(propagation) = propagation else {
+            return Ok(());
+        };
+        if let Err(e) = self.inner.set_propagation(target, propagation) {
+            self.inner.unmount(target)?;
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+impl Mounter for PropagatingMounter {
+    fn mount_overlay(
+        &self,
+        source: &str,
+        lower: &Path,
+        upper: &Path,
+        work: &Path,
+        target: &Path,
+    ) -> anyhow::Result<()> {
+        self.inner
+            .mount_overlay(source, lower, upper, work, target)?;
+        self.propagate(target, self.overlay)
+    }
+    fn mount_bind(&self, source: &Path, target: &Path) -> anyhow::Result<()> {
+        self.inner.mount_bind(source, target)?;
+        self.propagate(target, self.bind)
+    }
+    fn mount_bind_read_only(&self, source: &Path, target: &Path) -> anyhow::Result<()> {
+        self.inner.mount_bind_read_only(source, target)?;
+        self.propagate(target, self.bind)
+    }
+    fn mount_tmpfs(&self, size: &str, target: &Path) -> anyhow::Result<()> {
+        self.inner.mount_tmpfs(size, target)
+    }
+    fn unmount(&self, target: &Path) -> anyhow::Result<()> {
+        self.inner.unmount(target)
+    }
+    fn set_propagation(&self, target: &Path, propagation: MountPropagation) -> anyhow::Result<()> {
+        self.inner.set_propagation(target, propagation)
+    }
+    fn mounts(&self) -> anyhow::Result<Vec<MountInfo>> {
+        self.inner.mounts()
+    }
+}
+
+/// Operation performed by a [`MockMounter`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MountCall {
+    Overlay {
+        lower: PathBuf,
+        upper: PathBuf,
+        work: PathBuf,
+        target: PathBuf,
+    },
+    Bind {
+        source: PathBuf,
+        target: PathBuf,
+        read_only: bool,
+    },
+    Tmpfs {
+        size: String,
+        target: PathBuf,
+    },
+    Unmount(PathBuf),
+    Propagation(PathBuf, MountPropagation),
+}
+
+/// Mounter that only records the operations and the resulting mount table, to drive the overlay
+/// logic without root. Bind mounts appear in the table with the `bind` type.
+#[derive(Default)]
+pub struct MockMounter {
+    calls: Mutex<Vec<MountCall>>,
+    mounts: Mutex<Vec<MountInfo>>,
+}
+impl MockMounter {
+    /// Operations performed so far, in order
+    pub fn calls(&self) -> Vec<MountCall> {
+        self.calls.lock().unwrap().clone()
+    }
+    fn record(&self, call: MountCall) -> anyhow::Result<()> {
+        self.calls.lock().unwrap().push(call);
+        Ok(())
+    }
+    /// Record a mount operation and add its result to the mount table
+    fn mount(
+        &self,
+        call: MountCall,
+        target: &Path,
+        read_only: bool,
+        fs_type: &str,
+        source: &str,
+        options: String,
+    ) -> anyhow::Result<()> {
+        self.mounts.lock().unwrap().push(MountInfo {
+            root: "/".into(),
+            mount_point: target.into(),
+            read_only,
+            fs_type: fs_type.into(),
+            source: source.into(),
+            super_options: options,
+            propagation: Propagation::Private,
+        });
+        self.record(call)
+    }
+}
+impl Mounter for MockMounter {
+    fn mount_overlay(
+        &self,
+        source: &str,
+        lower: &Path,
+        upper: &Path,
+        work: &Path,
+        target: &Path,
+    ) -> anyhow::Result<()> {
+        let options = format!(
+            "lowerdir={},upperdir={},workdir={}",
+            lower.to_string_lossy(),
+            upper.to_string_lossy(),
+            work.to_string_lossy()
+        );
+        let call = MountCall::Overlay {
+            lower: lower.into(),
+            upper: upper.into(),
+            work: work.into(),
+            target: target.into(),
+        };
+        self.mount(call, target, false, "overlay", source, options)
+    }
+    fn mount_bind(&self, source: &Path, target: &Path) -> anyhow::Result<()> {
+        let call = MountCall::Bind {
+            source: source.into(),
+            target: target.into(),
+            read_only: false,
+        };
+        let source = source.to_string_lossy();
+        self.mount(call, target, false, "bind", &source, String::new())
+    }
+    fn mount_bind_read_only(&self, source: &Path, target: &Path) -> anyhow::Result<()> {
+        let call = MountCall::Bind {
+            source: source.into(),
+            target: target.into(),
+            read_only: true,
+        };
+        let source = source.to_string_lossy();
+        self.mount(call, target, true, "bind", &source, String::new())
+    }
+    fn mount_tmpfs(&self, size: &str, target: &Path) -> anyhow::Result<()> {
+        let call = MountCall::Tmpfs {
+            size: size.into(),
+            target: target.into(),
+        };
+        let options = format!("size={}", size);
+        self.mount(call, target, false, "tmpfs", "tmpfs", options)
+    }
+    fn unmount(&self, target: &Path) -> anyhow::Result<()> {
+        let mut mounts = self.mounts.lock().unwrap();
+        if let Some(i) = mounts.iter().rposition(|m| m.mount_point == target) {
+            mounts.remove(i);
+        }
+        drop(mounts);
+        self.record(MountCall::Unmount(target.into()))
+    }
+    fn set_propagation(&self, target: &Path, propagation: MountPropagation) -> anyhow::Result<()> {
+        let mut mounts = self.mounts.lock().unwrap();
+        if let Some(mount) = mounts.iter_mut().rev().find(|m| m.mount_point == target) {
+            mount.propagation = match propagation {
+                MountPropagation::Private => Propagation::Private,
+                MountPropagation::Rslave => Propagation::Slave,
+                MountPropagation::Rshared => Propagation::Shared,
+            };
+        }
+        drop(mounts);
+        self.record(MountCall::Propagation(target.into(), propagation))
+    }
+    fn mounts(&self) -> anyhow::Result<Vec<MountInfo>> {
+        Ok(self.mounts.lock().unwrap().clone())
+    }
+}
@@ -0,0 +1,106 @@
+//! Parsing of `/proc/self/mountinfo`, to recognize the mounts made by the driver.
+use std::path::{Path, PathBuf};
+
+/// Propagation type of a mount, from its optional fields
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Propagation {
+    /// Mounts and unmounts propagate to and from its peer group
+    Shared,
+    /// Only receives the mounts and unmounts of its master
+    Slave,
+    Private,
+}
+
+#[derive(Debug, Clone)]
+pub struct MountInfo {
+    /// Path of the mounted directory inside its filesystem
+    pub root: PathBuf,
+    pub mount_point: PathBuf,
+    pub read_only: bool,
+    pub fs_type: String,
+    pub source: String,
+    /// Filesystem-specific options, e.g. the layers of an overlay
+    pub super_options: String,
+    pub propagation: Propagation,
+}
+impl MountInfo {
+    /// Value of a filesystem-specific option, e.g. `upperdir`
+    pub fn option(&self, key: &str) -> Option<&str> {
+        self.super_options
+            .split(',')
+            .find_map(|o| o.strip_prefix(key)?.strip_prefix('='))
+    }
+}
+
+/// Undo the octal escapes of whitespace and backslashes.
+fn unescape(s: &str) -> String {
+    let mut out = Vec::with_capacity(s.len());
+    let bytes = s.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes.get(i + 1..i + 4).and_then(|o| {
+            let o = std::str::from_utf8(o).ok()?;
+            u8::from_str_radix(o, 8).ok()
+        });
+        match escaped {
+            Some(b) if bytes[i] == b'\\' => {
+                out.push(b);
+                i += 4;
+            }
+            _ => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn parse_line(line: &str) -> Option<MountInfo> {
+    // The optional fields are terminated by a single hyphen
+    let (left, right) = line.split_once(" - ")?;
+    let left: Vec<_> = left.split(' ').collect();
+    let optional = left.get(6..).unwrap_or_default();
+    let propagation = if optional.iter().any(|f| f.starts_with("shared:")) {
+        Propagation::Shared
+    } else if optional.iter().any(|f| f.starts_with("master:")) {
+        Propagation::Slave
+    } else {
+        Propagation::Private
+    };
+    let mut right = right.split(' ');
+    Some(MountInfo {
+        root: unescape(left.get(3)?).into(),
+        mount_point: unescape(left.get(4)?).into(),
+        read_only: left.get(5)?.split(',').any(|o| o == "ro"),
+        fs_type: right.next()?.into(),
+        source: unescape(right.next()?),
+        super_options: unescape(right.next().unwrap_or_default()),
+        propagation,
+    })
+}
+
+/// All mounts, in mount order
+pub(crate) fn all() -> anyhow::Result<Vec<MountInfo>> {
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo")?;
+    Ok(mountinfo.lines().filter_map(parse_line).collect())
+}
+
+/// Topmost mount containing a path
+pub(crate) fn containing(path: &Path) -> anyhow::Result<Option<MountInfo>> {
+    // Later mounts hide earlier ones at the same mount point
+    Ok(all()?
+        .into_iter()
+        .filter(|m| path.starts_with(&m.mount_point))
+        .enumerate()
+        .max_by_key(|(i, m)| (m.mount_point.components().count(), *i))
+        .map(|(_, m)| m))
+}
+
+/// Topmost of `mounts` at a mount point, if any
+pub(crate) fn find(mounts: Vec<MountInfo>, mount_point: &Path) -> Option<MountInfo> {
+    mounts
+        .into_iter()
+        .filter(|m| m.mount_point == mount_point)
+        .last()
+}
@@ -0,0 +1,327 @@
+//! Exchange of bases between the drivers of different nodes.
+//!
+//! Drivers started with `--peer-listen` serve the read-only methods of the
+//! [`BaseTransfer`](crate::transfer) gRPC service over TCP, to the peers presenting the token.
+//! When no valid base exists locally, a driver fetches the most recent one from its peers, i.e.
+//! the driver pods matching a label selector in the driver namespace. If both have
+//! manifests, only the files missing or changed relative to the newest local base of the family
+//! are transferred, the others being hardlinked from it.
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tonic::codegen::InterceptedService;
+use tonic::transport::Channel;
+use tracing::*;
+
+use crate::base::Base;
+use crate::manifest::{self, Manifest};
+use crate::pods::PodApi;
+use crate::transfer::v1::base_transfer_client::BaseTransferClient;
+use crate::transfer::v1::base_transfer_server::BaseTransferServer;
+use crate::transfer::{self, v1, TransferService};
+use crate::Overlays;
+
+/// Interrupted downloads are resumed this many times
+const FETCH_ATTEMPTS: usize = 3;
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct PeerFlags {
+    /// Label selector of the driver pods from which missing bases are fetched, e.g.
+    /// `app=overlayfs.csi.k8s.io`. Disabled if unset.
+    #[clap(long)]
+    pub peer_selector: Option<String>,
+    /// Port on which the drivers serve their bases
+    #[clap(long, default_value_t = 7575)]
+    pub peer_port: u16,
+    /// Maximal time spent fetching a base before creating the volume from scratch
+    #[clap(long, default_value_t = 60)]
+    pub peer_fetch_timeout_s: u64,
+    /// Bases larger than this are not fetched
+    #[clap(long)]
+    pub peer_fetch_max_bytes: Option<u64>,
+    /// Address on which the local bases are served to peers, e.g. `0.0.0.0:7575`
+    #[clap(long)]
+    pub peer_listen: Option<SocketAddr>,
+    /// File containing a token that peers must present as bearer token, both when serving and
+    /// fetching bases. Required with `--peer-listen`.
+    #[clap(long)]
+    pub peer_token_file: Option<PathBuf>,
+}
+impl Default for PeerFlags {
+    fn default() -> Self {
+        Self {
+            peer_selector: None,
+            peer_port: 7575,
+            peer_fetch_timeout_s: 60,
+            peer_fetch_max_bytes: None,
+            peer_listen: None,
+            peer_token_file: None,
+        }
+    }
+}
+
+pub(crate) struct Peers {
+    flags: PeerFlags,
+    node: String,
+    pods: Arc<dyn PodApi>,
+    token: Option<String>,
+}
+impl Peers {
+    pub(crate) fn new(
+        flags: PeerFlags,
+        node: String,
+        pods: Arc<dyn PodApi>,
+    ) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            flags.peer_listen.is_none() || flags.peer_token_file.is_some(),
+            "--peer-listen requires --peer-token-file, so that only peers can download bases"
+        );
+        let token = match &flags.peer_token_file {
+            Some(path) => Some(std::fs::read_to_string(path)?.trim().to_string()),
+            None => None,
+        };
+        Ok(Self {
+            flags,
+            node,
+            pods,
+            token,
+        })
+    }
+    pub(crate) fn enabled(&self) -> bool {
+        self.flags.peer_selector.is_some()
+    }
+    pub(crate) fn timeout(&self) -> Duration {
+        Duration::from_secs(self.flags.peer_fetch_timeout_s)
+    }
+    /// Endpoints of the running drivers on other nodes
+    async fn urls(&self) -> anyhow::Result<Vec<String>> {
+        let Some(selector) = &self.flags.peer_selector else {
+            return Ok(vec![]);
+        };
+        Ok(self
+            .pods
+            .list(selector)
+            .await?
+            .into_iter()
+            .filter(|pod| {
+                pod.spec.as_ref().and_then(|s| s.node_name.as_deref()) != Some(self.node.as_str())
+            })
+            .filter_map(|pod| pod.status?.pod_ip)
+            .map(|ip| format!("http://{}:{}", ip, self.flags.peer_port))
+            .collect())
+    }
+    /// Most recent base of the family among the peers, younger than `max_age_s` at `now`
+    async fn find(
+        &self,
+        family: &str,
+        max_age_s: i64,
+        now: time::OffsetDateTime,
+    ) -> anyhow::Result<Option<(BaseTransferClient<Channel>, v1::BaseInfo)>> {
+        let now = now.unix_timestamp();
+        let mut best: Option<(BaseTransferClient<Channel>, v1::BaseInfo)> = None;
+        for url in self.urls().await? {
+            let req = v1::ListBasesRequest {
+                family: family.into(),
+            };
+            let (client, bases) = match async {
+                let mut client = BaseTransferClient::connect(url.clone()).await?;
+                let bases = client
+                    .list_bases(transfer::request(req, self.token.as_deref()))
+                    .await?
+                    .into_inner()
+                    .bases;
+                anyhow::Ok((client, bases))
+            }
+            .await
+            {
+                Ok(r) => r,
+                Err(e) => {
+                    warn!(url, "Failed to list bases of peer: {}", e);
+                    continue;
+                }
+            };
+            for base in bases {
+                if !transfer::valid_component(&base.name) {
+                    warn!(url, base.name, "Ignoring invalid base name");
+                    continue;
+                }
+                let fits = self
+                    .flags
+                    .peer_fetch_max_bytes
+                    .map_or(true, |max| base.size_bytes <= max);
+                if fits
+                    && now - base.created < max_age_s
+                    && best
+                        .as_ref()
+                        .map_or(true, |(_, b)| base.created > b.created)
+                {
+                    best = Some((client.clone(), base));
+                }
+            }
+        }
+        Ok(best)
+    }
+    /// Download the most recent base of the family from a peer into `{bases}/{family}`,
+    /// returning its name if one was found. `id` identifies the download. The files identical in
+    /// `reference`, a local base with a manifest, are not transferred.
+    pub(crate) async fn fetch(
+        &self,
+        bases: &Path,
+        family: &str,
+        id: &str,
+        max_age_s: i64,
+        now: time::OffsetDateTime,
+        reference: Option<&Base>,
+    ) -> anyhow::Result<Option<String>> {
+        let Some((mut client, base)) = self.find(family, max_age_s, now).await? else {
+            return Ok(None);
+        };
+        info!(?base, family, "Fetching base from peer");
+        let partial = transfer::partial_dir(bases, id);
+        if partial.exists() {
+            std::fs::remove_dir_all(&partial)?;
+        }
+        std::fs::create_dir_all(&partial)?;
+        let manifest = self.manifest(&mut client, family, &base.name).await;
+        let reused = match (reference, &manifest) {
+            (Some(reference), Some(manifest)) => reuse(reference, manifest, &partial),
+            _ => vec![],
+        };
+        let result = self
+            .download(&mut client, family, &base.name, &partial, &reused)
+            .await;
+        let result = match result {
+            Ok(()) => {
+                let dst = Base(bases.join(family).join(&base.name));
+                std::fs::create_dir_all(bases.join(family))?;
+                match std::fs::rename(partial.join("base"), &dst.0) {
+                    Ok(()) => {
+                        if let Some(manifest) = &manifest {
+                            if let Err(e) = dst.store_manifest(manifest) {
+                                warn!(?dst, "Failed to store manifest of fetched base: {}", e);
+                            }
+                        }
+                    }
+                    // Most likely fetched concurrently for another volume
+                    Err(e) => warn!(?dst, "Failed to move fetched base: {}", e),
+                }
+                info!(?dst, "Fetched base from peer");
+                Ok(Some(base.name))
+            }
+            Err(e) => Err(e),
+        };
+        std::fs::remove_dir_all(&partial)?;
+        result
+    }
+    /// Manifest of a base of a peer, if it has one
+    async fn manifest(
+        &self,
+        client: &mut BaseTransferClient<Channel>,
+        family: &str,
+        name: &str,
+    ) -> Option<Manifest> {
+        match transfer::download_manifest(client, family, name, self.token.as_deref()).await {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                warn!(family, name, "Failed to download manifest: {}", e);
+                None
+            }
+        }
+    }
+    /// Download the archive, resuming after interruptions, and extract it into `{partial}/base`.
+    /// The files at `reused` are hardlinked from `{partial}/reference` instead.
+    async fn download(
+        &self,
+        client: &mut BaseTransferClient<Channel>,
+        family: &str,
+        name: &str,
+        partial: &Path,
+        reused: &[String],
+    ) -> anyhow::Result<()> {
+        let archive = partial.join("archive.tar");
+        let token = self.token.as_deref();
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            match transfer::download(client, family, name, &archive, token, reused).await {
+                Ok(()) => break,
+                Err(e) if attempt < FETCH_ATTEMPTS => {
+                    warn!(attempt, name, "Transfer interrupted, resuming: {}", e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        let dst = partial.join("base");
+        transfer::extract(&archive, &dst).await?;
+        if !reused.is_empty() {
+            let src = partial.join("reference");
+            let reused = reused.to_vec();
+            let linked =
+                tokio::task::spawn_blocking(move || manifest::link_files(&src, &dst, &reused))
+                    .await??;
+            info!(family, name, linked, "Reused files of the local base");
+        }
+        Ok(())
+    }
+}
+
+/// Regular files of the base described by `manifest` that are identical in `reference`, after
+/// snapshotting the latter into `{partial}/reference`. Empty if that is not possible.
+fn reuse(reference: &Base, manifest: &Manifest, partial: &Path) -> Vec<String> {
+    let result = (|| -> anyhow::Result<Vec<String>> {
+        let local = reference.read_manifest()?;
+        let reused = local.identical_files(manifest);
+        // Leave room for the encoding and the other fields of the request
+        let size: usize = reused.iter().map(|path| path.len() + 8).sum();
+        anyhow::ensure!(
+            size < transfer::MAX_REQUEST_BYTES / 2,
+            "Too many identical files to list"
+        );
+        if !reused.is_empty() {
+            transfer::pin(reference, &partial.join("reference"))?;
+        }
+        Ok(reused)
+    })();
+    result.unwrap_or_else(|e| {
+        warn!(?reference, "Fetching the whole base: {}", e);
+        vec![]
+    })
+}
+
+/// Serve the local bases to peers in the background. Peers can only list and download bases,
+/// and must present the token.
+pub fn spawn_server(overlays: Arc<Overlays>, addr: SocketAddr) {
+    let Some(token) = overlays.peers.token.clone() else {
+        error!(?addr, "Not serving bases to peers without token");
+        return;
+    };
+    let service = InterceptedService::new(
+        BaseTransferServer::new(TransferService::new(overlays).without_import())
+            .max_decoding_message_size(transfer::MAX_REQUEST_BYTES),
+        move |req: tonic::Request<()>| {
+            let authorized = req
+                .metadata()
+                .get("authorization")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.strip_prefix("Bearer "))
+                .map_or(false, |t| transfer::token_matches(t, &token));
+            if authorized {
+                Ok(req)
+            } else {
+                Err(tonic::Status::unauthenticated("Invalid token"))
+            }
+        },
+    );
+    tokio::spawn(async move {
+        info!(?addr, "Serving bases to peers");
+        if let Err(e) = tonic::transport::Server::builder()
+            .add_service(service)
+            .serve(addr)
+            .await
+        {
+            error!("Failed to serve bases to peers: {}", e);
+        }
+    });
+}
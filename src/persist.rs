@@ -0,0 +1,89 @@
+//! State of the published volumes, persisted in the bases volume so that a restarted (e.g.
+//! upgraded) driver can keep serving the volumes published by its predecessor.
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::base::Base;
+use crate::context::VolumeContext;
+use crate::State;
+
+const STATE_FILE: &str = ".state.json";
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct PersistedState {
+    pub volumes: HashMap<String, PersistedVolume>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct PersistedVolume {
+    pub context: VolumeContext,
+    /// Base of the overlay
+    #[serde(default)]
+    pub base: Option<PathBuf>,
+    /// Upper and lower directories of the overlay
+    #[serde(default)]
+    pub layers: Option<(PathBuf, PathBuf)>,
+    #[serde(default)]
+    pub producer: bool,
+}
+
+impl PersistedState {
+    pub(crate) fn from_state(state: &State) -> Self {
+        let volumes = state
+            .volumes
+            .iter()
+            .map(|(id, context)| {
+                let base = state
+                    .bases
+                    .iter()
+                    .find(|(_, volumes)| volumes.contains(id))
+                    .map(|(base, _)| base.0.clone());
+                let volume = PersistedVolume {
+                    context: context.clone(),
+                    base,
+                    layers: state.layers.get(id).cloned(),
+                    producer: state.producers.contains(id),
+                };
+                (id.clone(), volume)
+            })
+            .collect();
+        Self { volumes }
+    }
+    /// Restore the state, except for the volume slots.
+    pub(crate) fn into_state(self) -> State {
+        let mut state = State::default();
+        for (id, volume) in self.volumes {
+            if let Some(base) = volume.base {
+                state
+                    .bases
+                    .entry(Base(base))
+                    .or_default()
+                    .insert(id.clone());
+            }
+            if let Some(layers) = volume.layers {
+                state.layers.insert(id.clone(), layers);
+            }
+            if volume.producer {
+                state.producers.insert(id.clone());
+            }
+            state.volumes.insert(id, volume.context);
+        }
+        state
+    }
+    pub(crate) fn load(bases: &Path) -> anyhow::Result<Option<Self>> {
+        match std::fs::read(bases.join(STATE_FILE)) {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+    /// Atomically replace the state file.
+    pub(crate) fn save(&self, bases: &Path) -> anyhow::Result<()> {
+        let tmp = bases.join(format!("{}.tmp", STATE_FILE));
+        std::fs::write(&tmp, serde_json::to_vec(self)?)?;
+        std::fs::rename(&tmp, bases.join(STATE_FILE))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,251 @@
+//! Access to the Kubernetes pods API, behind a trait so that it can be replaced outside a cluster.
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use futures::{StreamExt, TryStreamExt};
+use k8s_openapi::api::core::v1::Pod;
+use kube::api::{DeleteParams, ListParams, WatchEvent, WatchParams};
+use kube::Api;
+use tracing::*;
+
+/// Whether creating a pod failed because a pod of the same name exists
+pub(crate) fn already_exists(e: &anyhow::Error) -> bool {
+    matches!(e.downcast_ref(), Some(kube::Error::Api(e)) if e.code == 409)
+}
+
+#[async_trait::async_trait]
+pub trait PodApi: Send + Sync {
+    async fn create(&self, pod: &Pod) -> anyhow::Result<Pod>;
+    async fn get(&self, name: &str) -> anyhow::Result<Pod>;
+    /// Get a pod outside the driver namespace
+    async fn get_in(&self, namespace: &str, name: &str) -> anyhow::Result<Pod>;
+    /// Delete a pod, succeeding if it does not exist
+    async fn delete(&self, name: &str) -> anyhow::Result<()>;
+    /// Pods matching a label selector
+    async fn list(&self, selector: &str) -> anyhow::Result<Vec<Pod>>;
+    /// Whether a pod exists, including while it is terminating
+    async fn exists(&self, name: &str) -> anyhow::Result<bool>;
+    /// Wait until the pod is running. Returns without error if the watch ends before that.
+    async fn wait_running(&self, name: &str) -> anyhow::Result<()>;
+}
+
+#[async_trait::async_trait]
+impl<P: PodApi + ?Sized> PodApi for Arc<P> {
+    async fn create(&self, pod: &Pod) -> anyhow::Result<Pod> {
+        (**self).create(pod).await
+    }
+    async fn get(&self, name: &str) -> anyhow::Result<Pod> {
+        (**self).get(name).await
+    }
+    async fn get_in(&self, namespace: &str, name: &str) -> anyhow::Result<Pod> {
+        (**self).get_in(namespace, name).await
+    }
+    async fn delete(&self, name: &str) -> anyhow::Result<()> {
+        (**self).delete(name).await
+    }
+    async fn list(&self, selector: &str) -> anyhow::Result<Vec<Pod>> {
+        (**self).list(selector).await
+    }
+    async fn exists(&self, name: &str) -> anyhow::Result<bool> {
+        (**self).exists(name).await
+    }
+    async fn wait_running(&self, name: &str) -> anyhow::Result<()> {
+        (**self).wait_running(name).await
+    }
+}
+
+#[async_trait::async_trait]
+impl PodApi for Api<Pod> {
+    async fn create(&self, pod: &Pod) -> anyhow::Result<Pod> {
+        Ok(Api::create(self, &Default::default(), pod).await?)
+    }
+    async fn get(&self, name: &str) -> anyhow::Result<Pod> {
+        Ok(Api::get(self, name).await?)
+    }
+    async fn get_in(&self, namespace: &str, name: &str) -> anyhow::Result<Pod> {
+        let api: Api<Pod> = Api::namespaced(self.clone().into_client(), namespace);
+        Ok(api.get(name).await?)
+    }
+    async fn delete(&self, name: &str) -> anyhow::Result<()> {
+        match Api::delete(self, name, &DeleteParams::background()).await {
+            Err(kube::Error::Api(e)) if e.code == 404 => {
+                debug!(name, "Pod already deleted");
+                Ok(())
+            }
+            result => {
+                result?;
+                Ok(())
+            }
+        }
+    }
+    async fn list(&self, selector: &str) -> anyhow::Result<Vec<Pod>> {
+        Ok(Api::list(self, &ListParams::default().labels(selector))
+            .await?
+            .items)
+    }
+    async fn exists(&self, name: &str) -> anyhow::Result<bool> {
+        Ok(Api::get_opt(self, name).await?.is_some())
+    }
+    async fn wait_running(&self, name: &str) -> anyhow::Result<()> {
+        let mut watch = self
+            .watch(
+                &WatchParams::default().fields(&format!("metadata.name={}", name)),
+                "0",
+            )
+            .await?
+            .boxed();
+        while let Some(status) = watch.try_next().await? {
+            if let WatchEvent::Modified(pod) = status {
+                if pod
+                    .status
+                    .and_then(|status| status.phase)
+                    .map_or(false, |phase| phase == "Running")
+                {
+                    info!(name, uid = pod.metadata.uid.unwrap(), "Pod was created");
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Pods API keeping the pods in memory, where created pods are running right away, to drive the
+/// overlay logic outside a cluster
+#[derive(Default)]
+pub struct MockPodApi(Mutex<Vec<Pod>>);
+impl MockPodApi {
+    /// Pods that currently exist, in order of creation
+    pub fn pods(&self) -> Vec<Pod> {
+        self.0.lock().unwrap().clone()
+    }
+    /// Add a pod as is, e.g. one of the workloads, which are looked up with `get_in`
+    pub fn insert(&self, pod: Pod) {
+        self.0.lock().unwrap().push(pod);
+    }
+    fn find(&self, namespace: Option<&str>, name: &str) -> Option<Pod> {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|pod| {
+                pod.metadata.name.as_deref() == Some(name)
+                    && namespace.map_or(true, |ns| pod.metadata.namespace.as_deref() == Some(ns))
+            })
+            .cloned()
+    }
+}
+/// Error of the API server with a status code
+fn api_error(code: u16, reason: &str, message: String) -> anyhow::Error {
+    kube::Error::Api(kube::core::ErrorResponse {
+        status: "Failure".into(),
+        message,
+        reason: reason.into(),
+        code,
+    })
+    .into()
+}
+#[async_trait::async_trait]
+impl PodApi for MockPodApi {
+    async fn create(&self, pod: &Pod) -> anyhow::Result<Pod> {
+        let name = pod.metadata.name.clone().unwrap_or_default();
+        let mut pods = self.0.lock().unwrap();
+        if pods.iter().any(|p| p.metadata.name == pod.metadata.name) {
+            return Err(api_error(
+                409,
+                "AlreadyExists",
+                format!("pods {:?} already exists", name),
+            ));
+        }
+        let mut pod = pod.clone();
+        pod.metadata.uid = Some(format!("uid-{}", name));
+        pod.status = Some(k8s_openapi::api::core::v1::PodStatus {
+            phase: Some("Running".into()),
+            ..Default::default()
+        });
+        pods.push(pod.clone());
+        Ok(pod)
+    }
+    async fn get(&self, name: &str) -> anyhow::Result<Pod> {
+        self.find(None, name)
+            .ok_or_else(|| api_error(404, "NotFound", format!("pods {:?} not found", name)))
+    }
+    async fn get_in(&self, namespace: &str, name: &str) -> anyhow::Result<Pod> {
+        self.find(Some(namespace), name)
+            .ok_or_else(|| api_error(404, "NotFound", format!("pods {:?} not found", name)))
+    }
+    async fn delete(&self, name: &str) -> anyhow::Result<()> {
+        self.0
+            .lock()
+            .unwrap()
+            .retain(|pod| pod.metadata.name.as_deref() != Some(name));
+        Ok(())
+    }
+    async fn list(&self, selector: &str) -> anyhow::Result<Vec<Pod>> {
+        let selector: Selector = selector.parse()?;
+        Ok(self
+            .pods()
+            .into_iter()
+            .filter(|pod| selector.matches(pod))
+            .collect())
+    }
+    async fn exists(&self, name: &str) -> anyhow::Result<bool> {
+        Ok(self.find(None, name).is_some())
+    }
+    async fn wait_running(&self, _name: &str) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Selector on the labels and annotations of a pod, made of comma-separated `key=value`,
+/// `key!=value` or `key` requirements. A requirement is met by either a label or an annotation.
+#[derive(Debug, Clone)]
+pub struct Selector(Vec<Requirement>);
+#[derive(Debug, Clone)]
+enum Requirement {
+    Exists(String),
+    Equals(String, String),
+    NotEquals(String, String),
+}
+impl std::str::FromStr for Selector {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let requirements = s
+            .split(',')
+            .map(str::trim)
+            .map(|r| {
+                let requirement = if let Some((key, value)) = r.split_once("!=") {
+                    Requirement::NotEquals(key.trim().into(), value.trim().into())
+                } else if let Some((key, value)) = r.split_once('=') {
+                    Requirement::Equals(key.trim().into(), value.trim().into())
+                } else {
+                    Requirement::Exists(r.into())
+                };
+                match &requirement {
+                    Requirement::Exists(key)
+                    | Requirement::Equals(key, _)
+                    | Requirement::NotEquals(key, _) => {
+                        anyhow::ensure!(!key.is_empty(), "Empty key in selector {:?}", s)
+                    }
+                }
+                Ok(requirement)
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self(requirements))
+    }
+}
+impl Selector {
+    pub fn matches(&self, pod: &Pod) -> bool {
+        let empty = BTreeMap::new();
+        let maps = [
+            pod.metadata.labels.as_ref().unwrap_or(&empty),
+            pod.metadata.annotations.as_ref().unwrap_or(&empty),
+        ];
+        let get = |key: &str| maps.iter().filter_map(move |m| m.get(key));
+        self.0.iter().all(|r| match r {
+            Requirement::Exists(key) => get(key).next().is_some(),
+            Requirement::Equals(key, value) => get(key).any(|v| v == value),
+            Requirement::NotEquals(key, value) => get(key).all(|v| v != value),
+        })
+    }
+}
@@ -0,0 +1,407 @@
+//! Retention policy, deciding which bases are used for new volumes and which are deleted.
+//!
+//! The policy is a list of rules: those derived from the flags (`--max-age-s`,
+//! `--family-max-age-s`, `--hard-max-age-s`, `--bases-max-bytes`, `--family-weight`), followed by
+//! those of the `--retention-policy` file, e.g.
+//!
+//! ```yaml
+//! - maxAge: { family: tests, seconds: 3600, hardSeconds: 86400 }
+//! - maxGenerations: { family: datasets, count: 3 }
+//! - keepNewest: { family: datasets, count: 1 }
+//! - weight: { family: tests, weight: 0.5 }
+//! - maxBytes: 100000000000
+//! ```
+//!
+//! For each kind of rule, the last one of the family applies, else the last one of the family
+//! without its namespace (with `namespace_isolation`), else the last one without family.
+use std::path::Path;
+
+use anyhow::Context;
+use serde::Deserialize;
+
+use crate::base::age_limit;
+use crate::OverlayFlags;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub(crate) enum Rule {
+    /// Bases older than `seconds` (never if 0) are not used for new volumes, and are deleted
+    /// once older than `hard_seconds` (by default `seconds`) and unused
+    #[serde(rename_all = "camelCase")]
+    MaxAge {
+        #[serde(default)]
+        family: Option<String>,
+        seconds: i64,
+        #[serde(default)]
+        hard_seconds: Option<i64>,
+    },
+    /// Only the `count` most recent bases of the family are used, the older ones are deleted once
+    /// unused
+    #[serde(rename_all = "camelCase")]
+    MaxGenerations {
+        #[serde(default)]
+        family: Option<String>,
+        count: usize,
+    },
+    /// The `count` most recent bases of the family are never deleted, even once expired or to
+    /// stay within the budget. They are still not used for new volumes once expired.
+    #[serde(rename_all = "camelCase")]
+    KeepNewest {
+        #[serde(default)]
+        family: Option<String>,
+        count: usize,
+    },
+    /// Importance of the family when evicting bases to stay within the budget (default: 1)
+    #[serde(rename_all = "camelCase")]
+    Weight {
+        #[serde(default)]
+        family: Option<String>,
+        weight: f64,
+    },
+    /// Maximum total size of the bases
+    MaxBytes(u64),
+}
+impl Rule {
+    fn family(&self) -> Option<&str> {
+        match self {
+            Self::MaxAge { family, .. }
+            | Self::MaxGenerations { family, .. }
+            | Self::KeepNewest { family, .. }
+            | Self::Weight { family, .. } => family.as_deref(),
+            Self::MaxBytes(_) => None,
+        }
+    }
+}
+
+/// What is known about a base when applying the policy
+pub(crate) struct Facts {
+    pub family: String,
+    /// `None` if the creation time is unknown
+    pub age_s: Option<i64>,
+    /// Set by the volume that produced the base, overriding the `max_age` rules
+    pub max_age_s: Option<i64>,
+    /// Number of more recent bases in the family
+    pub newer: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Verdict {
+    /// Why the base is not used for new volumes, if it is not
+    pub retired: Option<String>,
+    /// Why the base can be deleted once unused, if it is not used for new volumes (for this or
+    /// another reason, e.g. an invalidation)
+    pub deletable: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Policy {
+    rules: Vec<Rule>,
+}
+impl Policy {
+    /// Rules of the flags, followed by those of the `retention_policy` file.
+    pub(crate) fn from_flags(flags: &OverlayFlags) -> anyhow::Result<Self> {
+        let mut rules = vec![Rule::MaxAge {
+            family: None,
+            seconds: flags.max_age_s,
+            hard_seconds: flags.hard_max_age_s,
+        }];
+        rules.extend(
+            flags
+                .family_max_age_s
+                .iter()
+                .map(|(family, seconds)| Rule::MaxAge {
+                    family: Some(family.clone()),
+                    seconds: *seconds,
+                    hard_seconds: flags.hard_max_age_s,
+                }),
+        );
+        rules.extend(flags.bases_max_bytes.map(Rule::MaxBytes));
+        rules.extend(
+            flags
+                .family_weight
+                .iter()
+                .map(|(family, weight)| Rule::Weight {
+                    family: Some(family.clone()),
+                    weight: *weight,
+                }),
+        );
+        if let Some(path) = &flags.retention_policy {
+            rules.extend(load(path)?);
+        }
+        Ok(Self { rules })
+    }
+    /// Most specific rule of a kind for a family
+    fn find<T>(&self, family: &str, f: impl Fn(&Rule) -> Option<T>) -> Option<T> {
+        let unscoped = family.split('@').next().unwrap_or(family);
+        [Some(family), Some(unscoped), None]
+            .into_iter()
+            .find_map(|candidate| {
+                self.rules
+                    .iter()
+                    .rev()
+                    .filter(|r| r.family() == candidate)
+                    .find_map(&f)
+            })
+    }
+    /// Soft and hard maximum ages of a family
+    fn max_age_s(&self, family: &str) -> (i64, Option<i64>) {
+        self.find(family, |r| match r {
+            Rule::MaxAge {
+                seconds,
+                hard_seconds,
+                ..
+            } => Some((*seconds, *hard_seconds)),
+            _ => None,
+        })
+        .unwrap_or((0, None))
+    }
+    /// Age after which the bases of a family are not used anymore, 0 for never
+    pub(crate) fn family_max_age_s(&self, family: &str) -> i64 {
+        self.max_age_s(family).0
+    }
+    pub(crate) fn weight(&self, family: &str) -> f64 {
+        self.find(family, |r| match r {
+            Rule::Weight { weight, .. } => Some(*weight),
+            _ => None,
+        })
+        .unwrap_or(1.0)
+    }
+    pub(crate) fn max_bytes(&self) -> Option<u64> {
+        self.rules.iter().rev().find_map(|r| match r {
+            Rule::MaxBytes(bytes) => Some(*bytes),
+            _ => None,
+        })
+    }
+    /// Whether the base must be kept, whatever the other rules say
+    pub(crate) fn pinned(&self, facts: &Facts) -> bool {
+        self.find(&facts.family, |r| match r {
+            Rule::KeepNewest { count, .. } => Some(*count),
+            _ => None,
+        })
+        .map_or(false, |count| facts.newer < count)
+    }
+    pub(crate) fn evaluate(&self, facts: &Facts) -> Verdict {
+        let Some(age_s) = facts.age_s else {
+            let reason = Some("unknown creation time".to_owned());
+            return Verdict {
+                retired: reason.clone(),
+                deletable: reason,
+            };
+        };
+        let (family_max_age_s, hard_max_age_s) = self.max_age_s(&facts.family);
+        let max_age_s = facts.max_age_s.unwrap_or(family_max_age_s);
+        let hard_max_age_s = hard_max_age_s.map_or(max_age_s, |h| h.max(max_age_s));
+        let max_generations = self.find(&facts.family, |r| match r {
+            Rule::MaxGenerations { count, .. } => Some(*count),
+            _ => None,
+        });
+        let generations = max_generations
+            .filter(|count| facts.newer >= *count)
+            .map(|_| format!("{} newer generations", facts.newer));
+        let mut verdict = Verdict::default();
+        if age_s < 0 {
+            verdict.retired = Some("created in the future".into());
+            verdict.deletable = verdict.retired.clone();
+        } else {
+            verdict.retired = generations.clone().or_else(|| {
+                (age_s >= age_limit(max_age_s)).then(|| format!("older than {}s", max_age_s))
+            });
+            verdict.deletable = generations.or_else(|| {
+                (age_s >= hard_max_age_s).then(|| format!("older than {}s", hard_max_age_s))
+            });
+        }
+        if self.pinned(facts) {
+            verdict.deletable = None;
+        }
+        verdict
+    }
+}
+
+fn load(path: &Path) -> anyhow::Result<Vec<Rule>> {
+    let data = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read the retention policy {:?}", path))?;
+    serde_yaml::from_str(&data)
+        .with_context(|| format!("Failed to parse the retention policy {:?}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn facts(family: &str, age_s: Option<i64>, newer: usize) -> Facts {
+        Facts {
+            family: family.into(),
+            age_s,
+            max_age_s: None,
+            newer,
+        }
+    }
+
+    fn max_age(family: Option<&str>, seconds: i64, hard_seconds: Option<i64>) -> Rule {
+        Rule::MaxAge {
+            family: family.map(Into::into),
+            seconds,
+            hard_seconds,
+        }
+    }
+
+    #[test]
+    fn max_age_with_grace_period() {
+        let policy = Policy {
+            rules: vec![max_age(None, 3600, Some(7200))],
+        };
+        assert_eq!(
+            policy.evaluate(&facts("f", Some(3599), 0)),
+            Verdict::default()
+        );
+        let verdict = policy.evaluate(&facts("f", Some(3600), 0));
+        assert!(verdict.retired.is_some());
+        assert!(verdict.deletable.is_none());
+        let verdict = policy.evaluate(&facts("f", Some(7200), 0));
+        assert!(verdict.retired.is_some());
+        assert!(verdict.deletable.is_some());
+    }
+
+    #[test]
+    fn hard_max_age_defaults_to_max_age() {
+        let policy = Policy {
+            rules: vec![max_age(None, 3600, None)],
+        };
+        let verdict = policy.evaluate(&facts("f", Some(3600), 0));
+        assert!(verdict.retired.is_some());
+        assert!(verdict.deletable.is_some());
+    }
+
+    #[test]
+    fn zero_max_age_never_retires() {
+        let policy = Policy {
+            rules: vec![max_age(None, 0, None)],
+        };
+        let verdict = policy.evaluate(&facts("f", Some(100 * 365 * 86400), 0));
+        assert!(verdict.retired.is_none());
+    }
+
+    #[test]
+    fn most_specific_family_rule_applies() {
+        let policy = Policy {
+            rules: vec![
+                max_age(None, 3600, None),
+                max_age(Some("tests"), 60, None),
+                max_age(Some("tests@team-a"), 600, None),
+            ],
+        };
+        assert!(policy
+            .evaluate(&facts("tests", Some(60), 0))
+            .retired
+            .is_some());
+        assert!(policy
+            .evaluate(&facts("other", Some(60), 0))
+            .retired
+            .is_none());
+        // Scoped families fall back to the rules of their unscoped family
+        assert!(policy
+            .evaluate(&facts("tests@team-b", Some(60), 0))
+            .retired
+            .is_some());
+        assert!(policy
+            .evaluate(&facts("tests@team-a", Some(60), 0))
+            .retired
+            .is_none());
+    }
+
+    #[test]
+    fn max_age_of_the_volume_overrides_the_rules() {
+        let policy = Policy {
+            rules: vec![max_age(None, 3600, None)],
+        };
+        let facts = Facts {
+            max_age_s: Some(60),
+            ..facts("f", Some(60), 0)
+        };
+        assert!(policy.evaluate(&facts).retired.is_some());
+    }
+
+    #[test]
+    fn negative_age() {
+        let policy = Policy {
+            rules: vec![max_age(None, 3600, None)],
+        };
+        let verdict = policy.evaluate(&facts("f", Some(-1), 0));
+        assert_eq!(verdict.retired.as_deref(), Some("created in the future"));
+        assert_eq!(verdict.deletable.as_deref(), Some("created in the future"));
+    }
+
+    #[test]
+    fn unknown_creation_time() {
+        let policy = Policy {
+            rules: vec![max_age(None, 3600, None)],
+        };
+        let verdict = policy.evaluate(&facts("f", None, 0));
+        assert!(verdict.retired.is_some());
+        assert!(verdict.deletable.is_some());
+    }
+
+    #[test]
+    fn max_generations() {
+        let policy = Policy {
+            rules: vec![
+                max_age(None, 3600, None),
+                Rule::MaxGenerations {
+                    family: None,
+                    count: 2,
+                },
+            ],
+        };
+        assert_eq!(policy.evaluate(&facts("f", Some(0), 1)), Verdict::default());
+        let verdict = policy.evaluate(&facts("f", Some(0), 2));
+        assert_eq!(verdict.retired.as_deref(), Some("2 newer generations"));
+        assert_eq!(verdict.deletable.as_deref(), Some("2 newer generations"));
+    }
+
+    #[test]
+    fn keep_newest() {
+        let policy = Policy {
+            rules: vec![
+                max_age(None, 3600, None),
+                Rule::KeepNewest {
+                    family: Some("f".into()),
+                    count: 1,
+                },
+            ],
+        };
+        // Expired, but the newest of its family
+        let newest = facts("f", Some(7200), 0);
+        assert!(policy.pinned(&newest));
+        let verdict = policy.evaluate(&newest);
+        assert!(verdict.retired.is_some());
+        assert!(verdict.deletable.is_none());
+        let older = facts("f", Some(7200), 1);
+        assert!(!policy.pinned(&older));
+        assert!(policy.evaluate(&older).deletable.is_some());
+        // Also when created in the future
+        assert!(policy
+            .evaluate(&facts("f", Some(-1), 0))
+            .deletable
+            .is_none());
+        // Other families are not pinned
+        assert!(policy
+            .evaluate(&facts("g", Some(7200), 0))
+            .deletable
+            .is_some());
+    }
+
+    #[test]
+    fn parse_policy_file() {
+        let rules: Vec<Rule> = serde_yaml::from_str(
+            "- maxAge: { family: tests, seconds: 3600, hardSeconds: 86400 }\n\
+             - maxGenerations: { family: datasets, count: 3 }\n\
+             - keepNewest: { count: 1 }\n\
+             - maxBytes: 1000\n",
+        )
+        .unwrap();
+        let policy = Policy { rules };
+        assert_eq!(policy.max_age_s("tests"), (3600, Some(86400)));
+        assert_eq!(policy.max_bytes(), Some(1000));
+        assert!(policy.pinned(&facts("any", Some(0), 0)));
+    }
+}
@@ -0,0 +1,339 @@
+//! Bases populated from outside of the volumes, when a family has none: from a remote copy
+//! (`--family-source`, e.g. to seed dataset caches from a fileserver) or from a container image
+//! (see [`image`](crate::image)).
+//!
+//! Sources are copied with `rsync` (`rsync://` URLs, `[user@]host:path` and `sftp://` URLs, over
+//! SSH) or `wget` (`http(s)://` directory listings and tarballs), which must be available in the
+//! driver image.
+//!
+//! The families given to `--seed` are populated ahead of their volumes, at startup and every
+//! `--seed-interval-s`, so that the first volumes of a fresh node do not start cold, optionally
+//! from an image reference or URL given with the family.
+use std::future::Future;
+use std::path::{Path, PathBuf};
+
+use tracing::*;
+
+use crate::base::{self, Base};
+use crate::context::VolumeContext;
+use crate::transfer;
+use crate::Overlays;
+
+/// Extensions of the URLs downloaded and extracted as a whole rather than as directory listings
+const TARBALL_EXTENSIONS: &[&str] = &[".tar", ".tar.gz", ".tgz", ".tar.xz", ".tar.zst"];
+
+/// Remote directory from which the bases of a family are populated, as `family=source`
+#[derive(Debug, Clone)]
+pub(crate) struct FamilySource {
+    family: String,
+    source: Source,
+}
+impl std::str::FromStr for FamilySource {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (family, source) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Expected family=source, got {}", s))?;
+        Ok(Self {
+            family: family.into(),
+            source: source.parse()?,
+        })
+    }
+}
+
+/// Family populated at startup, as `family`, from the usual sources of its bases, or as
+/// `family=<oci-ref>` or `family=<http(s)-url>`, from this image or remote directory or tarball,
+/// which then also serves as the image or source of the family
+#[derive(Debug, Clone)]
+pub(crate) struct Seed {
+    pub(crate) family: String,
+    source: Option<SeedSource>,
+}
+#[derive(Debug, Clone)]
+enum SeedSource {
+    Image(String),
+    Url(Source),
+}
+impl std::str::FromStr for Seed {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (family, source) = match s.split_once('=') {
+            Some((family, source)) => (family, Some(source)),
+            None => (s, None),
+        };
+        anyhow::ensure!(
+            transfer::valid_component(family),
+            "Invalid family {:?}",
+            family
+        );
+        let source = match source {
+            None => None,
+            Some(url) if url.starts_with("http://") || url.starts_with("https://") => {
+                Some(SeedSource::Url(url.parse()?))
+            }
+            Some(image) => {
+                anyhow::ensure!(
+                    !image.is_empty()
+                        && !image.starts_with('-')
+                        && image.contains(|c: char| c.is_ascii_alphanumeric())
+                        && image
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || "-_./:@".contains(c)),
+                    "Expected an image reference or an http(s):// URL, got {:?}",
+                    image
+                );
+                Some(SeedSource::Image(image.into()))
+            }
+        };
+        Ok(Self {
+            family: family.into(),
+            source,
+        })
+    }
+}
+impl Seed {
+    pub(crate) fn image(&self) -> Option<&str> {
+        match &self.source {
+            Some(SeedSource::Image(image)) => Some(image),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Source {
+    /// Source argument of `rsync`
+    Rsync(String),
+    /// URL of a directory listing
+    Http(String),
+    /// URL of a tar archive, optionally compressed
+    Tarball(String),
+}
+impl std::str::FromStr for Source {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            !s.is_empty() && !s.starts_with('-'),
+            "Invalid source {:?}",
+            s
+        );
+        if s.starts_with("http://") || s.starts_with("https://") {
+            let path = s.split(['?', '#']).next().unwrap_or(s);
+            if TARBALL_EXTENSIONS.iter().any(|e| path.ends_with(e)) {
+                return Ok(Self::Tarball(s.into()));
+            }
+            return Ok(Self::Http(format!("{}/", s.trim_end_matches('/'))));
+        }
+        if let Some(rest) = s.strip_prefix("sftp://") {
+            let (host, path) = rest
+                .split_once('/')
+                .ok_or_else(|| anyhow::anyhow!("Expected sftp://[user@]host/path, got {}", s))?;
+            return Ok(Self::Rsync(format!(
+                "{}:/{}/",
+                host,
+                path.trim_end_matches('/')
+            )));
+        }
+        anyhow::ensure!(
+            s.starts_with("rsync://") || s.contains(':'),
+            "Expected an rsync://, sftp://, http(s):// URL or [user@]host:path, got {}",
+            s
+        );
+        // Copy the content of the directory rather than the directory itself
+        Ok(Self::Rsync(format!("{}/", s.trim_end_matches('/'))))
+    }
+}
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Rsync(source) | Self::Http(source) | Self::Tarball(source) => {
+                write!(f, "{}", source)
+            }
+        }
+    }
+}
+impl Source {
+    /// Copy the remote directory, or extract the remote archive, into `dst`.
+    async fn copy(&self, dst: &Path) -> anyhow::Result<()> {
+        std::fs::create_dir_all(dst)?;
+        let mut command = match self {
+            Self::Rsync(source) => {
+                let mut command = tokio::process::Command::new("rsync");
+                command.arg("-a").arg("--").arg(source).arg(dst);
+                command
+            }
+            Self::Http(url) => {
+                // Drop the host and the directories of the URL from the local paths
+                let depth = url.split('/').skip(3).filter(|c| !c.is_empty()).count();
+                let mut command = tokio::process::Command::new("wget");
+                command
+                    .args([
+                        "--quiet",
+                        "--recursive",
+                        "--no-parent",
+                        "--no-host-directories",
+                    ])
+                    .arg(format!("--cut-dirs={}", depth))
+                    .args(["--reject", "index.html*"])
+                    .arg("--directory-prefix")
+                    .arg(dst)
+                    .arg(url);
+                command
+            }
+            Self::Tarball(url) => {
+                // tar detects the compression of the archive
+                let archive = dst.with_file_name("archive");
+                Self::download(url, &archive).await?;
+                return transfer::extract(&archive, dst).await;
+            }
+        };
+        let status = command.kill_on_drop(true).status().await?;
+        anyhow::ensure!(
+            status.success(),
+            "Copy from {} failed with {}",
+            self,
+            status
+        );
+        Ok(())
+    }
+    async fn download(url: &str, dst: &Path) -> anyhow::Result<()> {
+        std::fs::create_dir_all(dst.parent().unwrap())?;
+        let status = tokio::process::Command::new("wget")
+            .arg("--quiet")
+            .arg("--output-document")
+            .arg(dst)
+            .arg(url)
+            .kill_on_drop(true)
+            .status()
+            .await?;
+        anyhow::ensure!(
+            status.success(),
+            "Download of {} failed with {}",
+            url,
+            status
+        );
+        Ok(())
+    }
+}
+
+impl Overlays {
+    /// Create a base of the family named after `origin`, filled by `fill`, which is given a
+    /// scratch directory and returns the directory to turn into the base. `id` identifies the
+    /// request. Returns the name of the base.
+    pub(crate) async fn populate_base<F, Fut>(
+        &self,
+        id: &str,
+        family: &str,
+        origin: &str,
+        meta: base::BaseMeta,
+        fill: F,
+    ) -> anyhow::Result<String>
+    where
+        F: FnOnce(PathBuf) -> Fut,
+        Fut: Future<Output = anyhow::Result<PathBuf>>,
+    {
+        let now = self.clock.now();
+        // Expired bases of the same origin may still be in use
+        let name = format!(
+            "{}-{}",
+            crate::encoding::encode(origin),
+            now.unix_timestamp()
+        );
+        let base = Base(self.flags.bases.join(family).join(&name));
+        anyhow::ensure!(!base.0.exists(), "The base {:?} already exists", base);
+        info!(origin, ?base, "Populating base");
+        let partial = transfer::partial_dir(&self.flags.bases, id);
+        if partial.exists() {
+            std::fs::remove_dir_all(&partial)?;
+        }
+        let result = async {
+            let populated = Base(fill(partial.clone()).await?);
+            populated.write_time(now)?;
+            std::fs::create_dir_all(base.0.parent().unwrap())?;
+            std::fs::rename(&populated.0, &base.0)?;
+            base.write_meta(&base::BaseMeta {
+                epoch: self.epochs.get(family),
+                ..meta
+            })
+        }
+        .await;
+        let _ = std::fs::remove_dir_all(&partial);
+        result?;
+        info!(?base, "Populated base");
+        self.state_changed.send_replace(());
+        Ok(name)
+    }
+    /// Remote source of the bases of a family, from `--family-source`, else from `--seed`
+    fn family_source(&self, family: &str) -> Option<&Source> {
+        let unscoped = family.split('@').next().unwrap_or(family);
+        let seeds = self
+            .flags
+            .seed
+            .iter()
+            .rev()
+            .filter_map(|s| match &s.source {
+                Some(SeedSource::Url(source)) => Some((&s.family, source)),
+                _ => None,
+            });
+        self.flags
+            .family_source
+            .iter()
+            .rev()
+            .map(|s| (&s.family, &s.source))
+            .chain(seeds)
+            .find(|(f, _)| *f == family || *f == unscoped)
+            .map(|(_, source)| source)
+    }
+    pub(crate) fn has_family_source(&self, family: &str) -> bool {
+        self.family_source(family).is_some()
+    }
+    /// Create a base of the family from its remote source, if it has one, returning its name.
+    /// `id` identifies the request.
+    pub(crate) async fn populate_from_source(
+        &self,
+        id: &str,
+        family: &str,
+    ) -> anyhow::Result<Option<String>> {
+        let Some(source) = self.family_source(family) else {
+            return Ok(None);
+        };
+        let meta = base::BaseMeta {
+            source: Some(source.to_string()),
+            ..Default::default()
+        };
+        let origin = source.to_string();
+        let origin = origin.trim_end_matches('/');
+        let origin = TARBALL_EXTENSIONS
+            .iter()
+            .find_map(|e| origin.strip_suffix(e))
+            .unwrap_or(origin);
+        let name = self
+            .populate_base(id, family, origin, meta, |partial| async move {
+                let dir = partial.join("base");
+                source.copy(&dir).await?;
+                Ok(dir)
+            })
+            .await?;
+        Ok(Some(name))
+    }
+    /// Populate the families of `--seed` that have no valid base, in the same way as for a
+    /// volume: from the peers, the base provider, the image or the remote source of the family.
+    pub(crate) async fn seed_bases(&self) {
+        for seed in &self.flags.seed {
+            let family = &seed.family;
+            if self.has_valid_base(family) {
+                debug!(family, "Not seeding, the family has a valid base");
+                continue;
+            }
+            info!(family, "Seeding base");
+            let context = VolumeContext {
+                family: family.clone(),
+                ..Default::default()
+            };
+            self.fetch_base(&format!("seed-{}", family), &context).await;
+            if !self.has_valid_base(family) {
+                warn!(family, "Could not seed a base for the family");
+            }
+        }
+    }
+}
@@ -0,0 +1,60 @@
+//! Mount propagation of the pods directory. The driver mounts the volumes from its own mount
+//! namespace when containerized: unless the pods directory is a shared mount, kubelet and the
+//! pods never see them, and the volumes silently appear empty.
+use std::path::Path;
+
+use tracing::*;
+
+use crate::mount::{MountPropagation, Mounter};
+use crate::mountinfo::{self, Propagation};
+
+/// What to do when the pods directory is not a shared mount
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PropagationCheck {
+    /// Only log a warning
+    Warn,
+    /// Refuse to start
+    Fail,
+    /// Remount it as shared (rshared), which is only effective when the driver runs in the mount
+    /// namespace of the host
+    Fix,
+}
+
+/// Ensure that the mounts made in `pods` propagate outside the mount namespace of the driver.
+pub(crate) fn check(
+    pods: &Path,
+    action: PropagationCheck,
+    mounter: &dyn Mounter,
+) -> anyhow::Result<()> {
+    let mount = mountinfo::containing(pods)?
+        .ok_or_else(|| anyhow::anyhow!("No mount contains {:?}", pods))?;
+    if mount.propagation == Propagation::Shared {
+        debug!(?pods, mount_point = ?mount.mount_point, "Pods directory is shared");
+        return Ok(());
+    }
+    let problem = format!(
+        "The pods directory {:?} is on the mount {:?} with {:?} propagation, so the volumes \
+         mounted by the driver are invisible to kubelet and the pods. Mount it into the driver \
+         container with `mountPropagation: Bidirectional`, and make it shared on the host, e.g. \
+         with `mount --make-rshared /var/lib/kubelet`",
+        pods, mount.mount_point, mount.propagation
+    );
+    match action {
+        PropagationCheck::Warn => {
+            warn!("{}", problem);
+            Ok(())
+        }
+        PropagationCheck::Fail => anyhow::bail!(problem),
+        PropagationCheck::Fix => {
+            warn!(mount_point = ?mount.mount_point, ?mount.propagation, "Remounting pods directory as shared");
+            mounter.set_propagation(&mount.mount_point, MountPropagation::Rshared)?;
+            let propagation = mountinfo::containing(pods)?.map(|m| m.propagation);
+            anyhow::ensure!(
+                propagation == Some(Propagation::Shared),
+                "{} (remounting as shared failed)",
+                problem
+            );
+            Ok(())
+        }
+    }
+}
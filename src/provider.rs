@@ -0,0 +1,127 @@
+//! Client of an external [`BaseProvider`](v1::base_provider_client::BaseProviderClient), which
+//! materializes bases on demand (e.g. from an artifact store or a build farm) when neither the
+//! node nor its peers have a valid base for a family. See `proto/provider.proto`.
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+use tokio::io::AsyncWriteExt;
+use tracing::*;
+
+use crate::base::{self, Base};
+use crate::transfer;
+use crate::Overlays;
+
+pub mod v1 {
+    tonic::include_proto!("overlayfs_csi.provider.v1");
+}
+use v1::base_provider_client::BaseProviderClient;
+use v1::provide_response::Message;
+
+impl Overlays {
+    /// Ask the base provider for a base of the family, returning its name if it provided one.
+    /// `id` identifies the request.
+    pub(crate) async fn provide_base(
+        &self,
+        id: &str,
+        family: &str,
+    ) -> anyhow::Result<Option<String>> {
+        let Some(url) = &self.flags.base_provider else {
+            return Ok(None);
+        };
+        let mut client = BaseProviderClient::connect(url.clone()).await?;
+        let req = v1::ProvideRequest {
+            family: family.into(),
+            node: self.flags.node.clone(),
+            volume_id: id.into(),
+        };
+        let mut stream = match client.provide(req).await {
+            Ok(response) => response.into_inner(),
+            Err(status) if status.code() == tonic::Code::NotFound => return Ok(None),
+            Err(status) => return Err(status.into()),
+        };
+        let header = match stream.message().await? {
+            None => return Ok(None),
+            Some(v1::ProvideResponse {
+                message: Some(Message::Header(header)),
+            }) => header,
+            Some(_) => anyhow::bail!("The first message must be the header"),
+        };
+        anyhow::ensure!(
+            transfer::valid_component(&header.name),
+            "Invalid base name {:?}",
+            header.name
+        );
+        let base = Base(self.flags.bases.join(family).join(&header.name));
+        anyhow::ensure!(!base.0.exists(), "The base {:?} already exists", base);
+        info!(?base, "Receiving base from the provider");
+        let partial = transfer::partial_dir(&self.flags.bases, id);
+        if partial.exists() {
+            std::fs::remove_dir_all(&partial)?;
+        }
+        let result = async {
+            receive(&mut stream, &partial).await?;
+            let received = Base(partial.join("base"));
+            if received.created().is_err() {
+                let created = match header.created {
+                    0 => self.clock.now(),
+                    t => OffsetDateTime::from_unix_timestamp(t)?,
+                };
+                received.write_time(created)?;
+            }
+            std::fs::create_dir_all(base.0.parent().unwrap())?;
+            std::fs::rename(&received.0, &base.0)?;
+            base.write_meta(&base::BaseMeta {
+                epoch: self.epochs.get(family),
+                max_age_s: (header.max_age_s != 0).then_some(header.max_age_s),
+                ..Default::default()
+            })
+        }
+        .await;
+        let _ = std::fs::remove_dir_all(&partial);
+        result?;
+        info!(?base, "Received base from the provider");
+        self.state_changed.send_replace(());
+        Ok(Some(header.name))
+    }
+}
+
+/// Write the streamed archive to `{partial}/archive.tar`, verify its checksum, and extract it
+/// into `{partial}/base`.
+async fn receive(
+    stream: &mut tonic::Streaming<v1::ProvideResponse>,
+    partial: &Path,
+) -> anyhow::Result<()> {
+    std::fs::create_dir_all(partial)?;
+    let archive = partial.join("archive.tar");
+    let mut file = tokio::fs::File::create(&archive).await?;
+    let mut hasher = Sha256::new();
+    let mut position = 0u64;
+    loop {
+        let Some(v1::ProvideResponse {
+            message: Some(Message::Chunk(chunk)),
+        }) = stream.message().await?
+        else {
+            anyhow::bail!("Expected a chunk at offset {}", position);
+        };
+        anyhow::ensure!(
+            chunk.offset == position,
+            "Unexpected chunk at offset {}, expected {}",
+            chunk.offset,
+            position
+        );
+        if !chunk.sha256.is_empty() {
+            anyhow::ensure!(
+                hasher.finalize().as_slice() == chunk.sha256.as_slice(),
+                "Checksum mismatch"
+            );
+            break;
+        }
+        hasher.update(&chunk.data);
+        file.write_all(&chunk.data).await?;
+        position += chunk.data.len() as u64;
+    }
+    file.flush().await?;
+    drop(file);
+    transfer::extract(&archive, &partial.join("base")).await
+}
@@ -0,0 +1,129 @@
+//! Copies of bases on a memory-backed filesystem (tmpfs or zram), used as lower directories to
+//! speed up cold reads.
+//!
+//! A base is copied in the background the first time it is used, and subsequent overlays use the
+//! copy once it is complete. Copies of unused bases are evicted when the node runs low on memory.
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use tracing::*;
+
+use crate::base::Base;
+
+#[derive(Debug)]
+struct Entry {
+    size: u64,
+    ready: bool,
+}
+
+pub(crate) struct RamCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    min_available_bytes: u64,
+    entries: Arc<Mutex<HashMap<Base, Entry>>>,
+}
+impl RamCache {
+    pub(crate) fn new(dir: PathBuf, max_bytes: u64, min_available_bytes: u64) -> Self {
+        Self {
+            dir,
+            max_bytes,
+            min_available_bytes,
+            entries: Default::default(),
+        }
+    }
+    /// Location of the copy of a base
+    pub(crate) fn path(&self, base: &Base) -> PathBuf {
+        self.dir.join(base.family()).join(base.name())
+    }
+    /// Return the cached copy of the base if it is complete, otherwise start copying it if it
+    /// fits in the cache.
+    pub(crate) fn get(&self, base: &Base) -> Option<PathBuf> {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.get(base) {
+            return entry.ready.then(|| self.path(base));
+        }
+        let size = base.size();
+        let used: u64 = entries.values().map(|e| e.size).sum();
+        if used + size > self.max_bytes {
+            debug!(?base, size, used, "Base does not fit in the RAM cache");
+            return None;
+        }
+        if memory_available().map_or(false, |a| a < self.min_available_bytes + size) {
+            debug!(?base, size, "Not enough memory available to cache base");
+            return None;
+        }
+        entries.insert(base.clone(), Entry { size, ready: false });
+        let entries = self.entries.clone();
+        let (base, dst) = (base.clone(), self.path(base));
+        tokio::task::spawn_blocking(move || match copy(&base.0, &dst) {
+            Ok(()) => {
+                info!(?base, ?dst, size, "Cached base in RAM");
+                if let Some(entry) = entries.lock().unwrap().get_mut(&base) {
+                    entry.ready = true;
+                }
+            }
+            Err(e) => {
+                warn!(?base, "Failed to cache base in RAM: {}", e);
+                let _ = std::fs::remove_dir_all(&dst);
+                entries.lock().unwrap().remove(&base);
+            }
+        });
+        None
+    }
+    /// Remove the cached copy of a base, which must not be in use.
+    pub(crate) fn evict(&self, base: &Base) {
+        let mut entries = self.entries.lock().unwrap();
+        // Copies in progress are left alone
+        if entries.get(base).map_or(false, |e| e.ready) {
+            info!(?base, "Evicting base from the RAM cache");
+            if let Err(e) = std::fs::remove_dir_all(self.path(base)) {
+                warn!(?base, "Failed to evict base from the RAM cache: {}", e);
+            }
+            entries.remove(base);
+        }
+    }
+    /// Evict unused bases, largest first, while the node is under memory pressure.
+    pub(crate) fn shrink(&self, unused: impl Fn(&Base) -> bool) {
+        let mut candidates: Vec<_> = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(b, e)| e.ready && unused(b))
+            .map(|(b, e)| (b.clone(), e.size))
+            .collect();
+        candidates.sort_by_key(|(_, size)| std::cmp::Reverse(*size));
+        for (base, _) in candidates {
+            match memory_available() {
+                Some(available) if available < self.min_available_bytes => self.evict(&base),
+                _ => break,
+            }
+        }
+    }
+}
+
+/// Copy to a temporary directory first, so that partial copies are never used.
+fn copy(src: &Path, dst: &Path) -> anyhow::Result<()> {
+    let mut tmp = dst.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    std::fs::create_dir_all(dst.parent().unwrap())?;
+    // Leftovers from before a restart
+    for d in [tmp.as_path(), dst] {
+        if d.exists() {
+            std::fs::remove_dir_all(d)?;
+        }
+    }
+    duct::cmd!("cp", "-a", src, &tmp).run()?;
+    std::fs::rename(&tmp, dst)?;
+    Ok(())
+}
+
+/// `MemAvailable` from `/proc/meminfo`, in bytes
+fn memory_available() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|l| l.starts_with("MemAvailable:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
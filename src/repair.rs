@@ -0,0 +1,58 @@
+//! Repair of the upper and work directories of an overlay whose mount fails because of leftover
+//! overlayfs state, e.g. the `incompat` marker of an interrupted volatile mount or stale origin
+//! metadata after a hard reboot.
+use std::path::Path;
+
+use tracing::*;
+
+use crate::mount::OverlayMountError;
+use crate::Overlays;
+
+/// What is recreated when an overlay cannot be mounted because of stale state
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverlayRepair {
+    /// Fail the mount
+    Off,
+    /// Recreate the work directory, which only holds transient state
+    Workdir,
+    /// Recreate the work directory and wipe the upper directory, losing the changes made to the
+    /// volume
+    Upper,
+}
+
+impl Overlays {
+    /// Prepare the directories of the overlay `id` for a second mount attempt after `error`,
+    /// according to `--overlay-repair`. Returns whether the mount should be retried.
+    pub(crate) fn repair_overlay(
+        &self,
+        id: &str,
+        error: &anyhow::Error,
+        upper: &Path,
+        workdir: &Path,
+    ) -> bool {
+        let Some(error) = error.downcast_ref::<OverlayMountError>() else {
+            return false;
+        };
+        if !error.stale_state() || self.flags.overlay_repair == OverlayRepair::Off {
+            return false;
+        }
+        warn!(
+            id,
+            repair = ?self.flags.overlay_repair,
+            "Stale overlay state, recreating the directories: {}",
+            error
+        );
+        let mut dirs = vec![workdir];
+        if self.flags.overlay_repair == OverlayRepair::Upper {
+            dirs.push(upper);
+        }
+        for dir in dirs {
+            let result = std::fs::remove_dir_all(dir).and_then(|()| std::fs::create_dir(dir));
+            if let Err(e) = result {
+                error!(id, ?dir, "Failed to recreate directory: {}", e);
+                return false;
+            }
+        }
+        true
+    }
+}
@@ -0,0 +1,151 @@
+//! Base selection delegated to an operator-provided script, for rules that the flags and the
+//! retention policy cannot express, without rebuilding the driver.
+//!
+//! The script is run with `sh -c` (e.g. `lua /etc/overlayfs-csi/select.lua` or
+//! `wasmtime run /etc/overlayfs-csi/select.wasm`) and receives on its standard input
+//!
+//! ```json
+//! {"family": "tests", "volume": {...}, "bases": [{"name": "...", "created": 1700000000, "meta": {...}}]}
+//! ```
+//!
+//! with the valid bases of the family, most recent first, and the context of the volume. It prints
+//! the names of the bases it accepts, in order of preference, e.g. `["b", "a"]`, or `[]` to create
+//! the volume from scratch. As selections happen while publishing volumes, the script runs within
+//! a strict time and memory budget; if it exceeds it or fails, the built-in selection applies.
+use std::io::{Read, Write};
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use tracing::*;
+
+use crate::base::BaseMeta;
+use crate::context::VolumeContext;
+use crate::selection::{BaseCatalog, BaseSelectionPolicy, Candidate};
+
+/// Maximum size of the output of the script
+const MAX_OUTPUT_BYTES: u64 = 1 << 20;
+
+#[derive(Serialize)]
+struct Input<'a> {
+    family: &'a str,
+    volume: &'a VolumeContext,
+    bases: Vec<InputBase>,
+}
+
+#[derive(Serialize)]
+struct InputBase {
+    name: String,
+    /// Creation time, as a UNIX timestamp
+    created: i64,
+    meta: BaseMeta,
+}
+
+pub(crate) struct ScriptedSelection {
+    pub command: String,
+    pub timeout: Duration,
+    /// Limit of the data segment of the script (`RLIMIT_DATA`), in bytes
+    pub max_memory_bytes: u64,
+    /// Used when the script fails
+    pub fallback: Arc<dyn BaseSelectionPolicy>,
+}
+impl ScriptedSelection {
+    /// Names of the accepted bases, in order of preference
+    fn rank(&self, input: &Input) -> anyhow::Result<Vec<String>> {
+        let input = serde_json::to_vec(input)?;
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(&self.command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit());
+        let max_memory_bytes = self.max_memory_bytes;
+        // SAFETY: setrlimit is async-signal-safe, and nothing is allocated between the fork and
+        // the exec.
+        unsafe {
+            command.pre_exec(move || {
+                let limit = nix::libc::rlimit {
+                    rlim_cur: max_memory_bytes,
+                    rlim_max: max_memory_bytes,
+                };
+                if nix::libc::setrlimit(nix::libc::RLIMIT_DATA, &limit) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let mut child = command.spawn()?;
+        // Write and read from other threads, so that neither pipe blocks the script
+        let mut stdin = child.stdin.take().unwrap();
+        let writer = std::thread::spawn(move || stdin.write_all(&input));
+        let stdout = child.stdout.take().unwrap();
+        let reader = std::thread::spawn(move || {
+            let mut output = vec![];
+            stdout
+                .take(MAX_OUTPUT_BYTES)
+                .read_to_end(&mut output)
+                .map(|_| output)
+        });
+        let deadline = Instant::now() + self.timeout;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                let _ = child.kill();
+                let _ = child.wait();
+                anyhow::bail!("Timed out after {:?}", self.timeout);
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        };
+        // The script may exit without reading its input
+        let _ = writer.join();
+        anyhow::ensure!(status.success(), "Failed with {}", status);
+        let output = reader
+            .join()
+            .map_err(|_| anyhow::anyhow!("Failed to read the output"))??;
+        Ok(serde_json::from_slice(&output)?)
+    }
+}
+impl BaseSelectionPolicy for ScriptedSelection {
+    fn select(&self, family: &str, catalog: &BaseCatalog) -> Option<Candidate> {
+        let mut candidates = catalog.valid(family);
+        if candidates.is_empty() {
+            return None;
+        }
+        let input = Input {
+            family,
+            volume: catalog.volume(),
+            bases: candidates
+                .iter()
+                .map(|c| InputBase {
+                    name: c.name(),
+                    created: c.created().unix_timestamp(),
+                    meta: c.meta(),
+                })
+                .collect(),
+        };
+        let start = Instant::now();
+        match self.rank(&input) {
+            Ok(ranking) => {
+                debug!(family, ?ranking, elapsed = ?start.elapsed(), "Ranked bases with the script");
+                ranking.iter().find_map(|name| {
+                    let i = candidates.iter().position(|c| c.name() == *name)?;
+                    Some(candidates.swap_remove(i))
+                })
+            }
+            Err(e) => {
+                warn!(
+                    family,
+                    command = self.command,
+                    "Base selection script failed, using the built-in selection: {}",
+                    e
+                );
+                self.fallback.select(family, catalog)
+            }
+        }
+    }
+}
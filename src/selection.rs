@@ -0,0 +1,196 @@
+//! Choice of the base of a new volume among the valid bases, behind a trait so that embedders can
+//! bring their own logic. Closures `Fn(&str, &BaseCatalog) -> Option<Candidate>` are policies.
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use time::OffsetDateTime;
+
+use crate::base::Base;
+use crate::context::VolumeContext;
+use crate::scripted::ScriptedSelection;
+use crate::Overlays;
+
+/// A valid base
+#[derive(Debug)]
+pub struct Candidate {
+    base: Base,
+    created: OffsetDateTime,
+}
+impl Candidate {
+    pub fn path(&self) -> &Path {
+        &self.base.0
+    }
+    pub fn family(&self) -> String {
+        self.base.family()
+    }
+    pub fn name(&self) -> String {
+        self.base.name()
+    }
+    pub fn created(&self) -> OffsetDateTime {
+        self.created
+    }
+    /// Disk usage of the base, computed at the first call and then cached in its metadata
+    pub fn size_bytes(&self) -> u64 {
+        self.base.size()
+    }
+    pub(crate) fn meta(&self) -> crate::base::BaseMeta {
+        self.base.read_meta()
+    }
+}
+
+/// View of the bases offered to the policies
+pub struct BaseCatalog<'a> {
+    overlays: &'a Overlays,
+    volume: &'a VolumeContext,
+}
+impl BaseCatalog<'_> {
+    /// Context of the volume requesting a base
+    pub fn volume(&self) -> &VolumeContext {
+        self.volume
+    }
+    /// Bases of a family that can be used for new volumes, most recent first
+    pub fn valid(&self, family: &str) -> Vec<Candidate> {
+        if !crate::transfer::valid_component(family) {
+            return vec![];
+        }
+        let mut candidates: Vec<_> = self
+            .overlays
+            .family_bases(family)
+            .filter(|base| self.overlays.base_valid(base))
+            .filter_map(|base| {
+                let created = base.created().ok()?;
+                Some(Candidate { base, created })
+            })
+            .collect();
+        candidates.sort_by_key(|c| std::cmp::Reverse(c.created));
+        candidates
+    }
+}
+
+pub trait BaseSelectionPolicy: Send + Sync {
+    /// Base of a new volume of `family`, among those of `catalog`, or `None` to start from an
+    /// empty volume.
+    fn select(&self, family: &str, catalog: &BaseCatalog) -> Option<Candidate>;
+}
+impl<F> BaseSelectionPolicy for F
+where
+    F: Fn(&str, &BaseCatalog) -> Option<Candidate> + Send + Sync,
+{
+    fn select(&self, family: &str, catalog: &BaseCatalog) -> Option<Candidate> {
+        self(family, catalog)
+    }
+}
+
+/// Built-in policies, selected with `--base-selection`
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BaseSelection {
+    /// Most recent valid base of the family
+    Newest,
+    /// Largest valid base of the family, e.g. the most complete cache
+    Largest,
+}
+
+/// Most recent valid base of the family
+pub struct NewestValid;
+impl BaseSelectionPolicy for NewestValid {
+    fn select(&self, family: &str, catalog: &BaseCatalog) -> Option<Candidate> {
+        catalog.valid(family).into_iter().next()
+    }
+}
+
+/// Largest valid base of the family, the most recent one among equals
+pub struct Largest;
+impl BaseSelectionPolicy for Largest {
+    fn select(&self, family: &str, catalog: &BaseCatalog) -> Option<Candidate> {
+        // `max_by_key` returns the last maximum
+        catalog
+            .valid(family)
+            .into_iter()
+            .rev()
+            .max_by_key(Candidate::size_bytes)
+    }
+}
+
+/// Falls back to the bases of other families when the family has none, e.g. to start the volumes
+/// of a feature branch from the cache of the main branch.
+pub struct FamilyMatch {
+    pub inner: Arc<dyn BaseSelectionPolicy>,
+    /// Families tried in order after a family. With `namespace_isolation`, the fallbacks of a
+    /// family without namespace apply in all namespaces.
+    pub fallbacks: HashMap<String, Vec<String>>,
+}
+impl FamilyMatch {
+    fn fallbacks(&self, family: &str) -> Vec<String> {
+        if let Some(fallbacks) = self.fallbacks.get(family) {
+            return fallbacks.clone();
+        }
+        let Some((unscoped, namespace)) = family.split_once('@') else {
+            return vec![];
+        };
+        self.fallbacks
+            .get(unscoped)
+            .map_or_else(Vec::new, |fallbacks| {
+                fallbacks
+                    .iter()
+                    .map(|f| format!("{}@{}", f, namespace))
+                    .collect()
+            })
+    }
+}
+impl BaseSelectionPolicy for FamilyMatch {
+    fn select(&self, family: &str, catalog: &BaseCatalog) -> Option<Candidate> {
+        std::iter::once(family.to_owned())
+            .chain(self.fallbacks(family))
+            .find_map(|family| self.inner.select(&family, catalog))
+    }
+}
+
+/// Policy of the `base_selection`, `base_selection_script` and `family_fallback` flags
+pub(crate) fn from_flags(flags: &crate::OverlayFlags) -> Arc<dyn BaseSelectionPolicy> {
+    let mut policy: Arc<dyn BaseSelectionPolicy> = match flags.base_selection {
+        BaseSelection::Newest => Arc::new(NewestValid),
+        BaseSelection::Largest => Arc::new(Largest),
+    };
+    if let Some(command) = &flags.base_selection_script {
+        policy = Arc::new(ScriptedSelection {
+            command: command.clone(),
+            timeout: Duration::from_millis(flags.base_selection_script_timeout_ms),
+            max_memory_bytes: flags.base_selection_script_max_bytes,
+            fallback: policy,
+        });
+    }
+    if flags.family_fallback.is_empty() {
+        return policy;
+    }
+    let mut fallbacks: HashMap<String, Vec<String>> = HashMap::new();
+    for (family, fallback) in &flags.family_fallback {
+        fallbacks
+            .entry(family.clone())
+            .or_default()
+            .push(fallback.clone());
+    }
+    Arc::new(FamilyMatch {
+        inner: policy,
+        fallbacks,
+    })
+}
+
+impl Overlays {
+    /// Base of a new volume, according to the selection policy
+    pub(crate) fn select_base(&self, volume: &VolumeContext) -> Option<Base> {
+        let catalog = BaseCatalog {
+            overlays: self,
+            volume,
+        };
+        // Candidates only come from the catalog, hence are valid bases
+        self.base_selection
+            .select(&volume.family, &catalog)
+            .map(|candidate| candidate.base)
+    }
+    /// Whether the family has a base usable for new volumes, ignoring the fallbacks
+    pub(crate) fn has_valid_base(&self, family: &str) -> bool {
+        self.family_bases(family).any(|base| self.base_valid(&base))
+    }
+}
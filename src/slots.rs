@@ -0,0 +1,87 @@
+//! Volume slots, limiting the number of simultaneously published volumes.
+//!
+//! Contrary to a semaphore, waiters are queued per tenant and served in a round-robin fashion, so
+//! that a tenant publishing many volumes at once does not starve the others.
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::oneshot;
+
+#[derive(Default)]
+struct Inner {
+    available: usize,
+    /// Waiters of each tenant, in the order in which the tenants are served
+    queues: VecDeque<(String, VecDeque<oneshot::Sender<Slot>>)>,
+}
+
+pub(crate) struct Slots(Arc<Mutex<Inner>>);
+
+/// A slot, returned to the pool (or handed to the next waiter) when dropped
+pub(crate) struct Slot(Option<Arc<Mutex<Inner>>>);
+
+impl Slots {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self(Arc::new(Mutex::new(Inner {
+            available: capacity,
+            ..Default::default()
+        })))
+    }
+    /// Take a slot if one is free and nobody is queued.
+    pub(crate) fn try_acquire(&self) -> Option<Slot> {
+        let mut inner = self.0.lock().unwrap();
+        if inner.available > 0 && inner.queues.is_empty() {
+            inner.available -= 1;
+            Some(Slot(Some(self.0.clone())))
+        } else {
+            None
+        }
+    }
+    /// Wait for a slot, behind the other waiters of the same tenant.
+    ///
+    /// If the future is dropped, e.g. on timeout, the waiter leaves the queue, and a slot that was
+    /// handed to it in the meantime is passed on.
+    pub(crate) async fn acquire(&self, tenant: &str) -> anyhow::Result<Slot> {
+        let receiver = {
+            let mut inner = self.0.lock().unwrap();
+            if inner.available > 0 && inner.queues.is_empty() {
+                inner.available -= 1;
+                return Ok(Slot(Some(self.0.clone())));
+            }
+            let (sender, receiver) = oneshot::channel();
+            match inner.queues.iter_mut().find(|(t, _)| t == tenant) {
+                Some((_, waiters)) => waiters.push_back(sender),
+                None => inner
+                    .queues
+                    .push_back((tenant.to_string(), VecDeque::from([sender]))),
+            }
+            receiver
+        };
+        Ok(receiver.await?)
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let Some(pool) = self.0.take() else {
+            return;
+        };
+        let mut inner = pool.lock().unwrap();
+        while let Some((tenant, mut waiters)) = inner.queues.pop_front() {
+            let Some(waiter) = waiters.pop_front() else {
+                continue;
+            };
+            let sent = waiter.send(Slot(Some(pool.clone())));
+            if !waiters.is_empty() {
+                inner.queues.push_back((tenant, waiters));
+            }
+            match sent {
+                Ok(()) => return,
+                // The waiter is gone. The slot is disarmed, as releasing it here would deadlock.
+                Err(mut slot) => {
+                    slot.0 = None;
+                }
+            }
+        }
+        inner.available += 1;
+    }
+}
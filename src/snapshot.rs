@@ -0,0 +1,105 @@
+//! Dump of the driver state on `SIGUSR1`, for debugging a running driver even when the admin API
+//! is unreachable.
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Serialize;
+use time::format_description::well_known::Rfc3339;
+use tracing::*;
+
+use crate::base::BaseMeta;
+use crate::persist::PersistedState;
+use crate::Overlays;
+
+/// Directory of the snapshots, under the bases
+const SNAPSHOT_DIR: &str = ".snapshots";
+/// How long to wait for the state lock before dumping without the state
+const LOCK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize)]
+struct Snapshot {
+    time: String,
+    /// Command line of the driver
+    args: Vec<String>,
+    /// `None` if the state stayed locked, e.g. by a stalled operation
+    state: Option<PersistedState>,
+    bases: Vec<BaseSnapshot>,
+    /// Operations in flight, with their age in seconds
+    operations: Option<Vec<(String, f64)>>,
+}
+
+#[derive(Serialize)]
+struct BaseSnapshot {
+    family: String,
+    name: String,
+    created: Option<String>,
+    state: Option<&'static str>,
+    meta: BaseMeta,
+}
+
+/// Write a snapshot to `{bases}/.snapshots/{time}.json` at every `SIGUSR1`.
+pub(crate) fn spawn_on_sigusr1(overlays: &Arc<Overlays>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let overlays = Arc::downgrade(overlays);
+    tokio::spawn(async move {
+        let mut sigusr1 = match signal(SignalKind::user_defined1()) {
+            Ok(sigusr1) => sigusr1,
+            Err(e) => {
+                warn!("Failed to listen for SIGUSR1: {}", e);
+                return;
+            }
+        };
+        while sigusr1.recv().await.is_some() {
+            let Some(overlays) = overlays.upgrade() else {
+                return;
+            };
+            match overlays.write_snapshot().await {
+                Ok(path) => info!(?path, "Wrote state snapshot"),
+                Err(e) => error!("Failed to write state snapshot: {}", e),
+            }
+        }
+    });
+}
+
+impl Overlays {
+    async fn write_snapshot(&self) -> anyhow::Result<std::path::PathBuf> {
+        let now = time::OffsetDateTime::now_utc();
+        let state = tokio::time::timeout(LOCK_TIMEOUT, self.lock.lock())
+            .await
+            .ok();
+        let mut bases = vec![];
+        for base in self.bases()? {
+            bases.push(BaseSnapshot {
+                family: base.family(),
+                name: base.name(),
+                created: base.created().ok().and_then(|c| c.format(&Rfc3339).ok()),
+                state: state
+                    .as_ref()
+                    .map(|state| self.base_state(state, &base).as_str()),
+                meta: base.read_meta(),
+            });
+        }
+        let snapshot = Snapshot {
+            time: now.format(&Rfc3339)?,
+            args: std::env::args().collect(),
+            state: state
+                .as_ref()
+                .map(|state| PersistedState::from_state(state)),
+            bases,
+            operations: self.watchdog.operations().map(|ops| {
+                ops.into_iter()
+                    .map(|(op, age)| (op, age.as_secs_f64()))
+                    .collect()
+            }),
+        };
+        drop(state);
+        let dir = self.flags.bases.join(SNAPSHOT_DIR);
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join(format!("{}.json", now.unix_timestamp_nanos()));
+        // Never leave a partial snapshot behind
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(&snapshot)?)?;
+        std::fs::rename(&tmp, &path)?;
+        Ok(path)
+    }
+}
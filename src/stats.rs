@@ -0,0 +1,146 @@
+//! Cached volume usage statistics.
+//!
+//! `statvfs` on a mountpoint is cheap, but knowing how much a volume actually wrote requires walking
+//! its data directory. Results are cached per volume for a short TTL, and walks happen in the
+//! background, so that kubelet's periodic stats calls are answered from memory in the steady state.
+use std::collections::{HashMap, HashSet};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tracing::*;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VolumeStats {
+    pub total_bytes: i64,
+    pub available_bytes: i64,
+    /// Bytes written to the volume. Until the first background walk completes, this is the usage
+    /// of the underlying filesystem.
+    pub used_bytes: i64,
+    pub total_inodes: i64,
+    pub available_inodes: i64,
+    /// Inodes (files, directories, whiteouts) written to the volume, with the same fallback as
+    /// `used_bytes`.
+    pub used_inodes: i64,
+}
+
+#[derive(Default)]
+struct Entry {
+    /// Directory where the volume writes its data (upper directory for overlays)
+    data_dir: Option<PathBuf>,
+    stats: Option<(Instant, VolumeStats)>,
+    /// Result of the last walk of `data_dir`
+    usage: Option<(Instant, Usage)>,
+    walking: bool,
+}
+
+pub struct StatsCache {
+    ttl: Duration,
+    entries: Arc<Mutex<HashMap<String, Entry>>>,
+}
+impl StatsCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Default::default(),
+        }
+    }
+    /// Record the data directory of a published volume.
+    pub fn register(&self, id: &str, data_dir: PathBuf) {
+        self.entries
+            .lock()
+            .unwrap()
+            .entry(id.into())
+            .or_default()
+            .data_dir = Some(data_dir);
+    }
+    pub fn forget(&self, id: &str) {
+        self.entries.lock().unwrap().remove(id);
+    }
+    /// Return the statistics for a volume, refreshing them if they are older than the TTL.
+    /// The expensive usage computation is scheduled in the background and never awaited.
+    pub fn get(&self, id: &str, mountpoint: &Path) -> anyhow::Result<VolumeStats> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.entry(id.into()).or_default();
+        if let Some((t, stats)) = entry.stats {
+            if t.elapsed() < self.ttl {
+                return Ok(stats);
+            }
+        }
+        let fs = nix::sys::statvfs::statvfs(mountpoint)?;
+        let block = fs.fragment_size() as i64;
+        let mut stats = VolumeStats {
+            total_bytes: fs.blocks() as i64 * block,
+            available_bytes: fs.blocks_available() as i64 * block,
+            used_bytes: (fs.blocks() - fs.blocks_free()) as i64 * block,
+            total_inodes: fs.files() as i64,
+            available_inodes: fs.files_available() as i64,
+            used_inodes: (fs.files() - fs.files_free()) as i64,
+        };
+        if let Some((_, usage)) = entry.usage {
+            stats.used_bytes = usage.bytes;
+            stats.used_inodes = usage.inodes;
+        }
+        let usage_stale = entry.usage.map_or(true, |(t, _)| t.elapsed() >= self.ttl);
+        if let (true, false, Some(data_dir)) = (usage_stale, entry.walking, &entry.data_dir) {
+            entry.walking = true;
+            self.spawn_walk(id.into(), data_dir.clone());
+        }
+        entry.stats = Some((Instant::now(), stats));
+        Ok(stats)
+    }
+    fn spawn_walk(&self, id: String, data_dir: PathBuf) {
+        let entries = self.entries.clone();
+        tokio::task::spawn_blocking(move || {
+            let start = Instant::now();
+            let usage = Usage::of(&data_dir);
+            debug!(id, ?data_dir, ?usage, elapsed = ?start.elapsed(), "Computed volume usage");
+            // The volume might have been unpublished in the meantime
+            if let Some(entry) = entries.lock().unwrap().get_mut(&id) {
+                entry.walking = false;
+                entry.usage = Some((Instant::now(), usage));
+            }
+        });
+    }
+}
+
+/// Resources used by a directory tree
+#[derive(Debug, Clone, Copy, Default)]
+struct Usage {
+    bytes: i64,
+    inodes: i64,
+}
+impl Usage {
+    fn of(path: &Path) -> Self {
+        Self::walk(path, &mut Default::default())
+    }
+    /// Hardlinked files are only counted once, based on the `(device, inode)` pairs in `seen`.
+    fn walk(path: &Path, seen: &mut HashSet<(u64, u64)>) -> Self {
+        let Ok(meta) = std::fs::symlink_metadata(path) else {
+            return Self::default();
+        };
+        if !meta.is_dir() && meta.nlink() > 1 && !seen.insert((meta.dev(), meta.ino())) {
+            return Self::default();
+        }
+        let mut usage = Self {
+            bytes: meta.blocks() as i64 * 512,
+            inodes: 1,
+        };
+        if meta.is_dir() {
+            if let Ok(entries) = std::fs::read_dir(path) {
+                for entry in entries.filter_map(Result::ok) {
+                    let child = Self::walk(&entry.path(), seen);
+                    usage.bytes += child.bytes;
+                    usage.inodes += child.inodes;
+                }
+            }
+        }
+        usage
+    }
+}
+
+/// Disk space used by a directory tree, in bytes (similar to `du -s`).
+pub(crate) fn disk_usage(path: &Path) -> i64 {
+    Usage::of(path).bytes
+}
@@ -0,0 +1,76 @@
+//! Minimal HTML status page, showing the bases and the published volumes of the node.
+use std::fmt::Write;
+
+use crate::Overlays;
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+pub(crate) async fn render(overlays: &Overlays) -> anyhow::Result<String> {
+    let summary = overlays.summary().await?;
+    let volumes: Vec<_> = {
+        let mapping = overlays.lock.lock().await;
+        let mut volumes: Vec<_> = mapping
+            .volumes
+            .iter()
+            .map(|(id, context)| (id.clone(), context.clone(), mapping.layers.contains_key(id)))
+            .collect();
+        volumes.sort_by(|a, b| a.0.cmp(&b.0));
+        volumes
+    };
+    let mut html = String::new();
+    writeln!(
+        html,
+        "<!DOCTYPE html><html><head><title>overlayfs-csi {node}</title></head><body>\
+         <h1>overlayfs-csi on {node}</h1><p>Updated {updated}</p>",
+        node = escape(&summary.node),
+        updated = escape(&summary.updated)
+    )?;
+    writeln!(
+        html,
+        "<h2>Bases</h2><table border=\"1\"><tr><th>Family</th><th>Base</th><th>Age (s)</th>\
+         <th>Size (bytes)</th><th>State</th><th>Volumes</th></tr>"
+    )?;
+    for (family, bases) in &summary.families {
+        for base in bases {
+            writeln!(
+                html,
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                escape(family),
+                escape(&base.name),
+                base.age_s.map_or_else(|| "?".into(), |a| a.to_string()),
+                base.size_bytes,
+                base.state,
+                base.volumes
+            )?;
+        }
+    }
+    writeln!(
+        html,
+        "</table><h2>Volumes ({})</h2><table border=\"1\"><tr><th>Volume</th><th>Family</th>\
+         <th>Access</th><th>Overlay</th><th>Pod</th></tr>",
+        volumes.len()
+    )?;
+    for (id, context, overlay) in &volumes {
+        let pod = context
+            .pod
+            .as_ref()
+            .map(|p| format!("{}/{}", p.namespace, p.name))
+            .unwrap_or_default();
+        writeln!(
+            html,
+            "<tr><td>{}</td><td>{}</td><td>{:?}</td><td>{}</td><td>{}</td></tr>",
+            escape(id),
+            escape(&context.family),
+            context.access,
+            if *overlay { "yes" } else { "no" },
+            escape(&pod)
+        )?;
+    }
+    writeln!(html, "</table></body></html>")?;
+    Ok(html)
+}
@@ -0,0 +1,98 @@
+//! Summary of the node state (bases and volumes), published in a ConfigMap so that operators
+//! without shell access to the nodes can inspect the cache with kubectl.
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use k8s_openapi::api::core::v1::ConfigMap;
+use kube::api::{ObjectMeta, Patch, PatchParams};
+use kube::Api;
+use serde::Serialize;
+use tracing::*;
+
+use crate::Overlays;
+
+/// Key of the summary in the ConfigMap
+const SUMMARY_KEY: &str = "summary.json";
+/// Delay before publishing, grouping bursts of changes into a single update
+const DEBOUNCE: Duration = Duration::from_secs(5);
+/// Interval at which the summary is refreshed without changes, to keep the ages accurate
+const REFRESH: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Serialize)]
+pub struct BaseSummary {
+    pub name: String,
+    pub age_s: Option<i64>,
+    pub size_bytes: u64,
+    pub state: &'static str,
+    /// Number of published volumes using the base
+    pub volumes: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NodeSummary {
+    pub node: String,
+    pub updated: String,
+    pub volumes: usize,
+    /// Bases per family
+    pub families: BTreeMap<String, Vec<BaseSummary>>,
+}
+
+impl Overlays {
+    pub async fn summary(&self) -> anyhow::Result<NodeSummary> {
+        let now = self.clock.now();
+        let mapping = self.lock.lock().await;
+        let mut families: BTreeMap<_, Vec<_>> = BTreeMap::new();
+        for base in self.bases()? {
+            families
+                .entry(base.family())
+                .or_default()
+                .push(BaseSummary {
+                    name: base.name(),
+                    age_s: base.created().ok().map(|c| (now - c).whole_seconds()),
+                    size_bytes: base.size(),
+                    state: self.base_state(&mapping, &base).as_str(),
+                    volumes: mapping.bases.get(&base).map_or(0, |v| v.len()),
+                });
+        }
+        Ok(NodeSummary {
+            node: self.flags.node.clone(),
+            updated: now.format(&time::format_description::well_known::Rfc3339)?,
+            volumes: mapping.volumes.len(),
+            families,
+        })
+    }
+}
+
+async fn publish(api: &Api<ConfigMap>, name: &str, overlays: &Overlays) -> anyhow::Result<()> {
+    let summary = serde_json::to_string_pretty(&overlays.summary().await?)?;
+    let configmap = ConfigMap {
+        metadata: ObjectMeta {
+            name: Some(name.into()),
+            ..Default::default()
+        },
+        data: Some([(SUMMARY_KEY.to_string(), summary)].into()),
+        ..Default::default()
+    };
+    api.patch(
+        name,
+        &PatchParams::apply("overlayfs-csi").force(),
+        &Patch::Apply(&configmap),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Publish the summary in the background, after each change of the state.
+pub fn spawn_publisher(api: Api<ConfigMap>, name: String, overlays: Arc<Overlays>) {
+    tokio::spawn(async move {
+        let mut changed = overlays.state_changed.subscribe();
+        loop {
+            if let Err(e) = publish(&api, &name, &overlays).await {
+                warn!(name, "Failed to publish the state summary: {}", e);
+            }
+            let _ = tokio::time::timeout(REFRESH, changed.changed()).await;
+            tokio::time::sleep(DEBOUNCE).await;
+        }
+    });
+}
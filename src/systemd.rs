@@ -0,0 +1,50 @@
+//! Integration with systemd, when the driver runs as a host service rather than in a DaemonSet:
+//! socket activation and readiness notification.
+use std::os::fd::RawFd;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+use tracing::*;
+
+/// First file descriptor passed by systemd
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Listening socket passed by systemd (see `sd_listen_fds(3)`), if any. The environment variables
+/// are cleared, so that the socket is only taken once.
+pub fn listen_fd() -> anyhow::Result<Option<RawFd>> {
+    let (Ok(pid), Ok(fds)) = (std::env::var("LISTEN_PID"), std::env::var("LISTEN_FDS")) else {
+        return Ok(None);
+    };
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+    if pid.parse::<u32>()? != std::process::id() {
+        debug!(pid, "Ignoring sockets passed to another process");
+        return Ok(None);
+    }
+    match fds.parse::<i32>()? {
+        0 => Ok(None),
+        1 => Ok(Some(LISTEN_FDS_START)),
+        n => anyhow::bail!("Expected a single socket from systemd, got {}", n),
+    }
+}
+
+/// Send a state change, e.g. `READY=1`, to the service manager (see `sd_notify(3)`). This is a
+/// no-op when not running under systemd with `Type=notify`.
+pub fn notify(state: &str) {
+    let Some(socket) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let result = (|| {
+        let addr = match socket.as_bytes().strip_prefix(b"@") {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(&socket)?,
+        };
+        UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr)?;
+        std::io::Result::Ok(())
+    })();
+    if let Err(e) = result {
+        warn!(state, ?socket, "Failed to notify systemd: {}", e);
+    }
+}
@@ -0,0 +1,425 @@
+//! Overlay logic driven without root nor cluster, through [`MockMounter`] and [`MockPodApi`], and
+//! lifecycle of the bases driven by a [`MockClock`] rather than by waiting.
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use time::OffsetDateTime;
+use tokio_util::sync::CancellationToken;
+
+use crate::base::Base;
+use crate::clock::MockClock;
+use crate::context::VolumeContext;
+use crate::mount::{MockMounter, MountCall, Mounter};
+use crate::pods::MockPodApi;
+use crate::{OverlayFlags, Overlays, OverlaysBuilder};
+
+/// 2023-11-14T22:13:20Z
+const START: i64 = 1_700_000_000;
+
+fn at(offset_s: i64) -> OffsetDateTime {
+    OffsetDateTime::from_unix_timestamp(START + offset_s).unwrap()
+}
+
+/// Directories of a node, shared by the drivers started on it
+struct Env {
+    dir: tempfile::TempDir,
+    clock: Arc<MockClock>,
+    mounter: Arc<MockMounter>,
+    pods: Arc<MockPodApi>,
+}
+impl Env {
+    fn new() -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("bases")).unwrap();
+        std::fs::create_dir(dir.path().join("pods")).unwrap();
+        Self {
+            dir,
+            clock: Arc::new(MockClock::new(at(0))),
+            mounter: Default::default(),
+            pods: Default::default(),
+        }
+    }
+    /// Flags expiring the bases after an hour
+    fn flags(&self) -> OverlayFlags {
+        OverlayFlags {
+            node: "node".into(),
+            namespace: "overlayfs-csi".into(),
+            bases: self.dir.path().join("bases"),
+            pods: self.dir.path().join("pods"),
+            max_age_s: 3600,
+            ..Default::default()
+        }
+    }
+    fn retention_policy(&self, rules: &str) -> OverlayFlags {
+        let path = self.dir.path().join("retention.yaml");
+        std::fs::write(&path, rules).unwrap();
+        OverlayFlags {
+            retention_policy: Some(path),
+            ..self.flags()
+        }
+    }
+    async fn overlays(&self, flags: OverlayFlags) -> Arc<Overlays> {
+        OverlaysBuilder::from_flags(flags)
+            .bases_host(self.dir.path().join("bases"))
+            .pod_api(self.pods.clone())
+            .mounter(self.mounter.clone())
+            .clock(self.clock.clone())
+            .cleanup_interval(None)
+            .build()
+            .await
+            .unwrap()
+    }
+    fn set(&self, offset_s: i64) {
+        self.clock.set(at(offset_s));
+    }
+    fn target(&self, id: &str) -> PathBuf {
+        self.dir.path().join("targets").join(id)
+    }
+}
+
+fn base(overlays: &Overlays, family: &str, name: &str, created: OffsetDateTime) -> Base {
+    let base = Base(overlays.flags.bases.join(family).join(name));
+    std::fs::create_dir_all(&base.0).unwrap();
+    base.write_time(created).unwrap();
+    base
+}
+
+#[tokio::test]
+async fn expiry() {
+    let env = Env::new();
+    let overlays = env.overlays(env.flags()).await;
+    let base = base(&overlays, "f", "a", at(0));
+    assert!(overlays.base_valid(&base));
+    env.set(3599);
+    assert!(overlays.base_valid(&base));
+    env.set(3600);
+    assert!(!overlays.base_valid(&base));
+    assert!(overlays.base_deletable(&base));
+    overlays.cleanup().await.unwrap();
+    assert!(!base.0.exists());
+}
+
+#[tokio::test]
+async fn grace_period() {
+    let env = Env::new();
+    let overlays = env
+        .overlays(OverlayFlags {
+            hard_max_age_s: Some(7200),
+            ..env.flags()
+        })
+        .await;
+    let base = base(&overlays, "f", "a", at(0));
+    env.clock.advance(Duration::from_secs(3600));
+    assert!(!overlays.base_valid(&base));
+    assert!(!overlays.base_deletable(&base));
+    overlays.cleanup().await.unwrap();
+    assert!(base.0.exists());
+    env.clock.advance(Duration::from_secs(3600));
+    assert!(overlays.base_deletable(&base));
+    overlays.cleanup().await.unwrap();
+    assert!(!base.0.exists());
+}
+
+#[tokio::test]
+async fn base_in_the_future() {
+    let env = Env::new();
+    let overlays = env.overlays(env.flags()).await;
+    let base = base(&overlays, "f", "a", at(60));
+    assert!(!overlays.base_valid(&base));
+    assert!(overlays.base_deletable(&base));
+    // Once the clock catches up, the base is like any other
+    env.set(60);
+    assert!(overlays.base_valid(&base));
+}
+
+#[tokio::test]
+async fn keep_newest() {
+    let env = Env::new();
+    let overlays = env
+        .overlays(env.retention_policy("- keepNewest: { count: 1 }\n"))
+        .await;
+    let old = base(&overlays, "f", "a", at(0));
+    let new = base(&overlays, "f", "b", at(10));
+    env.set(7200);
+    assert!(!overlays.base_valid(&old));
+    assert!(!overlays.base_valid(&new));
+    assert!(overlays.base_deletable(&old));
+    assert!(!overlays.base_deletable(&new));
+    overlays.cleanup().await.unwrap();
+    assert!(!old.0.exists());
+    assert!(new.0.exists());
+}
+
+#[tokio::test]
+async fn max_generations() {
+    let env = Env::new();
+    let overlays = env
+        .overlays(env.retention_policy("- maxGenerations: { family: f, count: 1 }\n"))
+        .await;
+    let old = base(&overlays, "f", "a", at(0));
+    let new = base(&overlays, "f", "b", at(10));
+    let other = base(&overlays, "g", "a", at(0));
+    env.set(20);
+    assert!(!overlays.base_valid(&old));
+    assert!(overlays.base_deletable(&old));
+    assert!(overlays.base_valid(&new));
+    assert!(overlays.base_valid(&other));
+}
+
+#[tokio::test]
+async fn rotation() {
+    let env = Env::new();
+    let hourly = cron::Schedule::from_str("0 0 * * * *").unwrap();
+    let overlays = env
+        .overlays(OverlayFlags {
+            family_rotation: vec![("*".into(), hourly)],
+            max_age_s: 86400,
+            ..env.flags()
+        })
+        .await;
+    // 22:13:20, rotated out at 23:00:00
+    let base = base(&overlays, "f", "a", at(0));
+    env.set(2799);
+    assert!(overlays.base_valid(&base));
+    env.set(2800);
+    assert!(!overlays.base_valid(&base));
+    // Only expiry makes a base deletable
+    assert!(!overlays.base_deletable(&base));
+}
+
+fn context(family: &str) -> VolumeContext {
+    VolumeContext {
+        family: family.into(),
+        ..Default::default()
+    }
+}
+
+async fn mount(env: &Env, overlays: &Overlays, id: &str) {
+    overlays
+        .mount(id, env.target(id), &context("f"), &CancellationToken::new())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn mount_from_scratch() {
+    let env = Env::new();
+    let overlays = env.overlays(env.flags()).await;
+    mount(&env, &overlays, "vol").await;
+    assert_eq!(env.pods.pods().len(), 1);
+    let volume_dir = overlays.find_volume_dir("vol").await.unwrap();
+    assert_eq!(
+        env.mounter.calls(),
+        [MountCall::Bind {
+            source: volume_dir,
+            target: env.target("vol"),
+            read_only: false,
+        }]
+    );
+    // Without the promotion marker, the data is dropped with the data pod
+    overlays.unmount("vol", env.target("vol")).await.unwrap();
+    assert_eq!(
+        env.mounter.calls().last(),
+        Some(&MountCall::Unmount(env.target("vol")))
+    );
+    assert!(env.mounter.mounts().unwrap().is_empty());
+    assert!(env.pods.pods().is_empty());
+    assert!(!env.target("vol").exists());
+    assert_eq!(overlays.bases().unwrap().count(), 0);
+}
+
+#[tokio::test]
+async fn promotion() {
+    let env = Env::new();
+    let overlays = env.overlays(env.flags()).await;
+    mount(&env, &overlays, "vol").await;
+    let volume_dir = overlays.find_volume_dir("vol").await.unwrap();
+    std::fs::write(volume_dir.join("data"), "cache").unwrap();
+    std::fs::write(volume_dir.join(Base::as_base_filename()), "").unwrap();
+    overlays.unmount("vol", env.target("vol")).await.unwrap();
+    let bases: Vec<_> = overlays.bases().unwrap().collect();
+    assert_eq!(bases.len(), 1);
+    assert_eq!(bases[0].family(), "f");
+    assert_eq!(bases[0].created().unwrap(), at(0));
+    assert!(overlays.base_valid(&bases[0]));
+    assert_eq!(
+        std::fs::read_to_string(bases[0].0.join("data")).unwrap(),
+        "cache"
+    );
+    assert!(env.pods.pods().is_empty());
+}
+
+#[tokio::test]
+async fn mount_on_base() {
+    let env = Env::new();
+    let overlays = env.overlays(env.flags()).await;
+    let base = base(&overlays, "f", "a", at(0));
+    mount(&env, &overlays, "vol").await;
+    let volume_dir = overlays.find_volume_dir("vol").await.unwrap();
+    assert_eq!(
+        env.mounter.calls(),
+        [MountCall::Overlay {
+            lower: base.0.clone(),
+            upper: volume_dir.join("upper"),
+            work: volume_dir.join("workdir"),
+            target: env.target("vol"),
+        }]
+    );
+    assert!(overlays.lock.lock().await.bases[&base].contains("vol"));
+    // The base stays valid, so the overlay is not promoted
+    overlays.unmount("vol", env.target("vol")).await.unwrap();
+    assert_eq!(
+        env.mounter.calls().last(),
+        Some(&MountCall::Unmount(env.target("vol")))
+    );
+    assert!(overlays.lock.lock().await.bases[&base].is_empty());
+    assert_eq!(overlays.bases().unwrap().count(), 1);
+    assert!(env.pods.pods().is_empty());
+}
+
+#[tokio::test]
+async fn read_only_view() {
+    let env = Env::new();
+    let overlays = env.overlays(env.flags()).await;
+    let base = base(&overlays, "f", "a", at(0));
+    let context = VolumeContext {
+        access: crate::context::Access::ReadOnly,
+        ..context("f")
+    };
+    overlays
+        .mount(
+            "vol",
+            env.target("vol"),
+            &context,
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        env.mounter.calls(),
+        [MountCall::Bind {
+            source: base.0.clone(),
+            target: env.target("vol"),
+            read_only: true,
+        }]
+    );
+    // No data pod holds the data of a view
+    assert!(env.pods.pods().is_empty());
+    overlays.unmount("vol", env.target("vol")).await.unwrap();
+    assert!(env.mounter.mounts().unwrap().is_empty());
+    assert!(overlays.lock.lock().await.bases[&base].is_empty());
+}
+
+#[tokio::test]
+async fn restore() {
+    let env = Env::new();
+    let overlays = env.overlays(env.flags()).await;
+    let base = base(&overlays, "f", "a", at(0));
+    mount(&env, &overlays, "vol").await;
+    drop(overlays);
+    // The driver restarts while the volume is published
+    let overlays = env.overlays(env.flags()).await;
+    {
+        let mapping = overlays.lock.lock().await;
+        assert_eq!(mapping.volumes["vol"].family, "f");
+        assert!(mapping.bases[&base].contains("vol"));
+    }
+    // The base stays in use until the volume is unpublished
+    env.set(7200);
+    overlays.cleanup().await.unwrap();
+    assert!(base.0.exists());
+    overlays.unmount("vol", env.target("vol")).await.unwrap();
+    assert!(env.mounter.mounts().unwrap().is_empty());
+    assert!(env.pods.pods().is_empty());
+    overlays.cleanup().await.unwrap();
+    assert!(!base.0.exists());
+}
+
+#[tokio::test]
+async fn recover_overlays_without_state() {
+    let env = Env::new();
+    let overlays = env.overlays(env.flags()).await;
+    let base = base(&overlays, "f", "a", at(0));
+    mount(&env, &overlays, "vol").await;
+    drop(overlays);
+    std::fs::remove_file(env.dir.path().join("bases").join(".state.json")).unwrap();
+    // The overlay is found in the mount table
+    let overlays = env.overlays(env.flags()).await;
+    assert!(overlays.lock.lock().await.bases[&base].contains("vol"));
+    overlays.unmount("vol", env.target("vol")).await.unwrap();
+    assert!(env.mounter.mounts().unwrap().is_empty());
+    assert!(env.pods.pods().is_empty());
+}
+
+#[tokio::test]
+async fn expansion() {
+    let env = Env::new();
+    let overlays = env.overlays(env.flags()).await;
+    mount(&env, &overlays, "vol").await;
+    // The emptyDir of the data pod holds the data, and its size limit is immutable
+    let gi = 1 << 30;
+    assert_eq!(
+        overlays.expand_volume("vol", 10 * gi, None).await.unwrap(),
+        10 * gi
+    );
+    let e = overlays
+        .expand_volume("vol", 20 * gi, None)
+        .await
+        .unwrap_err();
+    assert!(e.is::<crate::NotExpandable>());
+    let e = overlays.expand_volume("vol", gi, None).await.unwrap_err();
+    assert!(e.is::<crate::CapacityOutOfRange>());
+    overlays.unmount("vol", env.target("vol")).await.unwrap();
+
+    let volumes_dir = env.dir.path().join("volumes");
+    let overlays = env
+        .overlays(OverlayFlags {
+            volumes_dir: Some(volumes_dir),
+            ..env.flags()
+        })
+        .await;
+    mount(&env, &overlays, "vol").await;
+    assert_eq!(
+        overlays.expand_volume("vol", 20 * gi, None).await.unwrap(),
+        20 * gi
+    );
+    let e = overlays
+        .expand_volume("vol", 30 * gi, Some(10 * gi))
+        .await
+        .unwrap_err();
+    assert!(e.is::<crate::CapacityOutOfRange>());
+    // The new size limit outlives a restart of the driver
+    drop(overlays);
+    let overlays = env
+        .overlays(OverlayFlags {
+            volumes_dir: Some(env.dir.path().join("volumes")),
+            ..env.flags()
+        })
+        .await;
+    let e = overlays
+        .expand_volume("vol", 10 * gi, None)
+        .await
+        .unwrap_err();
+    assert!(e.is::<crate::CapacityOutOfRange>());
+}
+
+#[tokio::test]
+async fn pre_mount_hook_on_retry() {
+    let env = Env::new();
+    let log = env.dir.path().join("hooks.log");
+    let overlays = env
+        .overlays(OverlayFlags {
+            hooks: crate::hooks::HookFlags {
+                hook_pre_mount: Some(format!("echo $VOLUME_ID >> {}", log.display())),
+                ..Default::default()
+            },
+            ..env.flags()
+        })
+        .await;
+    mount(&env, &overlays, "vol").await;
+    // kubelet retries publishing volumes that are already published
+    mount(&env, &overlays, "vol").await;
+    assert_eq!(std::fs::read_to_string(&log).unwrap(), "vol\n");
+}
@@ -0,0 +1,543 @@
+//! gRPC service transferring bases as tar archives, used for replication between nodes and for
+//! exports and imports through the admin CLI.
+//!
+//! Archives are generated deterministically (sorted entries) from a hardlinked snapshot of the
+//! base, so that an interrupted export can be resumed from an offset, and they are verified
+//! against a SHA-256 checksum sent at the end of the stream. Files that the receiver already has,
+//! according to the manifests of the bases (`--base-manifests`), can be left out of the archive.
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::Channel;
+use tracing::*;
+
+use crate::base::{self, Base};
+use crate::manifest::{self, Manifest};
+use crate::Overlays;
+
+pub mod v1 {
+    tonic::include_proto!("overlayfs_csi.transfer.v1");
+}
+use v1::base_transfer_client::BaseTransferClient;
+use v1::import_request::Message;
+
+/// Directory of the bases volume where bases are assembled before being moved into their family
+const PARTIAL_DIR: &str = ".partial";
+/// Directory of the bases volume holding hardlinked snapshots of the bases being exported
+const PINNED_DIR: &str = ".pinned";
+const CHUNK_SIZE: usize = 1 << 20;
+/// Maximum size of the requests accepted from peers, bounding the files skipped in an export
+pub(crate) const MAX_REQUEST_BYTES: usize = 64 << 20;
+
+/// Family or base name that can safely be used as a path component
+pub(crate) fn valid_component(s: &str) -> bool {
+    !s.is_empty() && !s.starts_with('.') && !s.contains('/')
+}
+
+/// Name that is unique within this process
+fn unique(prefix: &str) -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    format!("{}-{}", prefix, COUNTER.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Attach the bearer token expected by the peers to a request.
+pub(crate) fn request<T>(message: T, token: Option<&str>) -> tonic::Request<T> {
+    let mut request = tonic::Request::new(message);
+    if let Some(token) = token {
+        if let Ok(value) = format!("Bearer {}", token).parse() {
+            request.metadata_mut().insert("authorization", value);
+        }
+    }
+    request
+}
+
+/// Whether a bearer token matches the expected one, in constant time for tokens of the same
+/// length.
+pub(crate) fn token_matches(token: &str, expected: &str) -> bool {
+    token.len() == expected.len()
+        && token
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+pub struct TransferService {
+    overlays: Arc<Overlays>,
+    /// Whether `Import` is served
+    import: bool,
+}
+impl TransferService {
+    pub fn new(overlays: Arc<Overlays>) -> Self {
+        let pinned = overlays.flags.bases.join(PINNED_DIR);
+        // Leftovers from before a restart
+        if pinned.exists() {
+            if let Err(e) = std::fs::remove_dir_all(&pinned) {
+                warn!(?pinned, "Failed to remove pinned bases: {}", e);
+            }
+        }
+        Self {
+            overlays,
+            import: true,
+        }
+    }
+    /// Only serve the read-only methods, e.g. to peers, which must not be able to create bases.
+    pub fn without_import(mut self) -> Self {
+        self.import = false;
+        self
+    }
+}
+#[async_trait::async_trait]
+impl v1::base_transfer_server::BaseTransfer for TransferService {
+    async fn list_bases(
+        &self,
+        req: tonic::Request<v1::ListBasesRequest>,
+    ) -> tonic::Result<tonic::Response<v1::ListBasesResponse>> {
+        let req = req.into_inner();
+        if !valid_component(&req.family) {
+            return Err(tonic::Status::invalid_argument("Invalid family"));
+        }
+        let mut bases = vec![];
+        for base in self
+            .overlays
+            .family_bases(&req.family)
+            .filter(|b| self.overlays.base_valid(b))
+        {
+            let Ok(created) = base.created() else {
+                continue;
+            };
+            bases.push(v1::BaseInfo {
+                name: base.name(),
+                created: created.unix_timestamp(),
+                size_bytes: base.size(),
+            });
+        }
+        Ok(tonic::Response::new(v1::ListBasesResponse { bases }))
+    }
+
+    type ExportStream = ReceiverStream<tonic::Result<v1::Chunk>>;
+    async fn export(
+        &self,
+        req: tonic::Request<v1::ExportRequest>,
+    ) -> tonic::Result<tonic::Response<Self::ExportStream>> {
+        let req = req.into_inner();
+        if !valid_component(&req.family) || !valid_component(&req.name) {
+            return Err(tonic::Status::invalid_argument("Invalid family or name"));
+        }
+        let base = Base(self.overlays.flags.bases.join(&req.family).join(&req.name));
+        // Bases past their soft expiry can still be exported
+        if base.created().is_err() {
+            return Err(tonic::Status::not_found("No such base"));
+        }
+        // Snapshot the base, so that it stays consistent even if it gets cleaned up during the
+        // transfer.
+        let pinned = pinned_dir(&self.overlays.flags.bases, &base.name());
+        pin(&base, &pinned).map_err(|e| tonic::Status::internal(e.to_string()))?;
+        if let Err(e) = omit(&pinned, &req.skip) {
+            let _ = std::fs::remove_dir_all(&pinned);
+            return Err(tonic::Status::invalid_argument(e.to_string()));
+        }
+        info!(
+            ?base,
+            req.offset,
+            skipped = req.skip.len(),
+            "Exporting base"
+        );
+        let (tx, rx) = mpsc::channel(4);
+        tokio::spawn(async move {
+            if let Err(e) = send_archive(&pinned, req.offset, &tx).await {
+                warn!(?pinned, "Failed to export base: {}", e);
+                let _ = tx.send(Err(tonic::Status::internal(e.to_string()))).await;
+            }
+            if let Err(e) = std::fs::remove_dir_all(&pinned) {
+                warn!(?pinned, "Failed to unpin base: {}", e);
+            }
+        });
+        Ok(tonic::Response::new(ReceiverStream::new(rx)))
+    }
+
+    type GetManifestStream = ReceiverStream<tonic::Result<v1::Chunk>>;
+    async fn get_manifest(
+        &self,
+        req: tonic::Request<v1::GetManifestRequest>,
+    ) -> tonic::Result<tonic::Response<Self::GetManifestStream>> {
+        let req = req.into_inner();
+        if !valid_component(&req.family) || !valid_component(&req.name) {
+            return Err(tonic::Status::invalid_argument("Invalid family or name"));
+        }
+        let base = Base(self.overlays.flags.bases.join(&req.family).join(&req.name));
+        // Read upfront, as the base may be cleaned up during the transfer
+        let Ok(data) = std::fs::read(base.manifest_file()) else {
+            return Err(tonic::Status::not_found("No manifest for this base"));
+        };
+        let (tx, rx) = mpsc::channel(4);
+        tokio::spawn(async move {
+            let result = async {
+                let (position, sha256) = send_chunks(&mut data.as_slice(), 0, &tx).await?;
+                tx.send(Ok(v1::Chunk {
+                    offset: position,
+                    data: vec![],
+                    sha256,
+                }))
+                .await?;
+                anyhow::Ok(())
+            }
+            .await;
+            if let Err(e) = result {
+                warn!(?base, "Failed to send manifest: {}", e);
+            }
+        });
+        Ok(tonic::Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn import(
+        &self,
+        req: tonic::Request<tonic::Streaming<v1::ImportRequest>>,
+    ) -> tonic::Result<tonic::Response<v1::ImportResponse>> {
+        if !self.import {
+            return Err(tonic::Status::permission_denied(
+                "Bases cannot be imported on this endpoint",
+            ));
+        }
+        let mut stream = req.into_inner();
+        let Some(v1::ImportRequest {
+            message: Some(Message::Header(header)),
+        }) = stream.message().await?
+        else {
+            return Err(tonic::Status::invalid_argument(
+                "The first message must be the header",
+            ));
+        };
+        if !valid_component(&header.family) || !valid_component(&header.name) {
+            return Err(tonic::Status::invalid_argument("Invalid family or name"));
+        }
+        let bases = &self.overlays.flags.bases;
+        let base = Base(bases.join(&header.family).join(&header.name));
+        if base.0.exists() {
+            return Err(tonic::Status::already_exists("The base already exists"));
+        }
+        info!(?base, "Importing base");
+        let partial = partial_dir(bases, &unique("import"));
+        let result = async {
+            receive_archive(&mut stream, &partial).await?;
+            std::fs::create_dir_all(bases.join(&header.family))?;
+            std::fs::rename(&partial, &base.0)?;
+            base.write_meta(&base::BaseMeta {
+                epoch: self.overlays.epochs.get(&header.family),
+                ..Default::default()
+            })
+        }
+        .await;
+        if let Err(e) = result {
+            warn!(?base, "Failed to import base: {}", e);
+            let _ = std::fs::remove_dir_all(&partial);
+            return Err(tonic::Status::internal(e.to_string()));
+        }
+        info!(?base, "Imported base");
+        Ok(tonic::Response::new(v1::ImportResponse {
+            base: header.name,
+        }))
+    }
+}
+
+/// Directory for a new snapshot of the base `name`
+pub(crate) fn pinned_dir(bases: &Path, name: &str) -> PathBuf {
+    bases.join(PINNED_DIR).join(unique(name))
+}
+
+/// Create a hardlinked copy of a base.
+pub(crate) fn pin(base: &Base, pinned: &Path) -> anyhow::Result<()> {
+    std::fs::create_dir_all(pinned.parent().unwrap())?;
+    duct::cmd!("cp", "-al", &base.0, pinned).run()?;
+    Ok(())
+}
+
+/// Remove the regular files at `paths` from a snapshot of a base.
+fn omit(pinned: &Path, paths: &[String]) -> anyhow::Result<()> {
+    for path in paths {
+        let file = manifest::beneath(pinned, path)?;
+        if std::fs::symlink_metadata(&file).map_or(false, |m| m.is_file()) {
+            std::fs::remove_file(file)?;
+        }
+    }
+    Ok(())
+}
+
+/// Send the archive of a directory from `offset`, followed by its checksum.
+async fn send_archive(
+    dir: &Path,
+    offset: u64,
+    tx: &mpsc::Sender<tonic::Result<v1::Chunk>>,
+) -> anyhow::Result<()> {
+    let mut tar = tokio::process::Command::new("tar")
+        .args(["-c", "--sort=name", "--numeric-owner", "-C"])
+        .arg(dir)
+        .arg(".")
+        .stdout(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let mut stdout = tar.stdout.take().unwrap();
+    let (position, sha256) = send_chunks(&mut stdout, offset, tx).await?;
+    let status = tar.wait().await?;
+    anyhow::ensure!(status.success(), "tar failed with {}", status);
+    anyhow::ensure!(
+        offset <= position,
+        "Offset {} is past the end of the archive ({} bytes)",
+        offset,
+        position
+    );
+    tx.send(Ok(v1::Chunk {
+        offset: position,
+        data: vec![],
+        sha256,
+    }))
+    .await?;
+    Ok(())
+}
+
+/// Send the data of `reader` from `offset`, returning its length and checksum.
+async fn send_chunks(
+    reader: &mut (impl AsyncRead + Unpin),
+    offset: u64,
+    tx: &mpsc::Sender<tonic::Result<v1::Chunk>>,
+) -> anyhow::Result<(u64, Vec<u8>)> {
+    let mut hasher = Sha256::new();
+    let mut position = 0u64;
+    let mut buf = vec![0; CHUNK_SIZE];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        let end = position + n as u64;
+        if end > offset {
+            let skip = offset.saturating_sub(position) as usize;
+            tx.send(Ok(v1::Chunk {
+                offset: position + skip as u64,
+                data: buf[skip..n].to_vec(),
+                sha256: vec![],
+            }))
+            .await?;
+        }
+        position = end;
+    }
+    Ok((position, hasher.finalize().to_vec()))
+}
+
+/// Extract a streamed archive into `dst`, verifying its checksum.
+async fn receive_archive(
+    stream: &mut tonic::Streaming<v1::ImportRequest>,
+    dst: &Path,
+) -> anyhow::Result<()> {
+    std::fs::create_dir_all(dst)?;
+    let mut tar = tokio::process::Command::new("tar")
+        .arg("-x")
+        .arg("-C")
+        .arg(dst)
+        .stdin(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let mut stdin = tar.stdin.take().unwrap();
+    let mut hasher = Sha256::new();
+    let mut position = 0u64;
+    while let Some(req) = stream.message().await? {
+        let Some(Message::Chunk(chunk)) = req.message else {
+            anyhow::bail!("Expected a chunk");
+        };
+        anyhow::ensure!(
+            chunk.offset == position,
+            "Unexpected chunk at offset {}, expected {}",
+            chunk.offset,
+            position
+        );
+        if !chunk.sha256.is_empty() {
+            drop(stdin);
+            let status = tar.wait().await?;
+            anyhow::ensure!(status.success(), "tar failed with {}", status);
+            anyhow::ensure!(
+                hasher.finalize().as_slice() == chunk.sha256.as_slice(),
+                "Checksum mismatch"
+            );
+            return Ok(());
+        }
+        hasher.update(&chunk.data);
+        stdin.write_all(&chunk.data).await?;
+        position += chunk.data.len() as u64;
+    }
+    anyhow::bail!("Archive ended at offset {} without checksum", position)
+}
+
+/// Download the archive of a base into `archive`, resuming after the data it already contains,
+/// and verify its checksum. The files at `skip` are left out of the archive.
+pub(crate) async fn download(
+    client: &mut BaseTransferClient<Channel>,
+    family: &str,
+    name: &str,
+    archive: &Path,
+    token: Option<&str>,
+    skip: &[String],
+) -> anyhow::Result<()> {
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(archive)
+        .await?;
+    let offset = file.metadata().await?.len();
+    let mut position = offset;
+    let req = v1::ExportRequest {
+        family: family.into(),
+        name: name.into(),
+        offset,
+        skip: skip.to_vec(),
+    };
+    let mut stream = client.export(request(req, token)).await?.into_inner();
+    while let Some(chunk) = stream.message().await? {
+        anyhow::ensure!(
+            chunk.offset == position,
+            "Unexpected chunk at offset {}, expected {}",
+            chunk.offset,
+            position
+        );
+        if !chunk.sha256.is_empty() {
+            file.flush().await?;
+            drop(file);
+            let archive = archive.to_owned();
+            let digest = tokio::task::spawn_blocking(move || sha256(&archive)).await??;
+            anyhow::ensure!(digest == chunk.sha256, "Checksum mismatch");
+            return Ok(());
+        }
+        file.write_all(&chunk.data).await?;
+        position += chunk.data.len() as u64;
+    }
+    anyhow::bail!("Archive ended at offset {} without checksum", position)
+}
+
+/// Download the manifest of a base, if it has one.
+pub(crate) async fn download_manifest(
+    client: &mut BaseTransferClient<Channel>,
+    family: &str,
+    name: &str,
+    token: Option<&str>,
+) -> anyhow::Result<Option<Manifest>> {
+    let req = v1::GetManifestRequest {
+        family: family.into(),
+        name: name.into(),
+    };
+    let mut stream = match client.get_manifest(request(req, token)).await {
+        Ok(response) => response.into_inner(),
+        // Also from drivers predating manifests
+        Err(status)
+            if matches!(
+                status.code(),
+                tonic::Code::NotFound | tonic::Code::Unimplemented
+            ) =>
+        {
+            return Ok(None)
+        }
+        Err(status) => return Err(status.into()),
+    };
+    let mut data = vec![];
+    let mut hasher = Sha256::new();
+    while let Some(chunk) = stream.message().await? {
+        anyhow::ensure!(
+            chunk.offset == data.len() as u64,
+            "Unexpected chunk at offset {}, expected {}",
+            chunk.offset,
+            data.len()
+        );
+        if !chunk.sha256.is_empty() {
+            anyhow::ensure!(
+                hasher.finalize().as_slice() == chunk.sha256.as_slice(),
+                "Checksum mismatch"
+            );
+            return Ok(Some(serde_json::from_slice(&data)?));
+        }
+        hasher.update(&chunk.data);
+        data.extend_from_slice(&chunk.data);
+    }
+    anyhow::bail!("Manifest ended at offset {} without checksum", data.len())
+}
+
+/// Upload an archive as a new base, returning its name.
+#[cfg(feature = "admin-cli")]
+pub(crate) async fn upload(
+    client: &mut BaseTransferClient<Channel>,
+    family: &str,
+    name: &str,
+    archive: &Path,
+) -> anyhow::Result<String> {
+    let mut file = tokio::fs::File::open(archive).await?;
+    let (tx, rx) = mpsc::channel(4);
+    tx.send(v1::ImportRequest {
+        message: Some(Message::Header(v1::ImportHeader {
+            family: family.into(),
+            name: name.into(),
+        })),
+    })
+    .await?;
+    let sender = tokio::spawn(async move {
+        let mut hasher = Sha256::new();
+        let mut position = 0u64;
+        let mut buf = vec![0; CHUNK_SIZE];
+        loop {
+            let n = file.read(&mut buf).await?;
+            let chunk = if n == 0 {
+                v1::Chunk {
+                    offset: position,
+                    data: vec![],
+                    sha256: hasher.clone().finalize().to_vec(),
+                }
+            } else {
+                hasher.update(&buf[..n]);
+                v1::Chunk {
+                    offset: position,
+                    data: buf[..n].to_vec(),
+                    sha256: vec![],
+                }
+            };
+            position += n as u64;
+            let req = v1::ImportRequest {
+                message: Some(Message::Chunk(chunk)),
+            };
+            // The server went away, its response carries the error
+            if tx.send(req).await.is_err() || n == 0 {
+                return anyhow::Ok(());
+            }
+        }
+    });
+    let response = client.import(ReceiverStream::new(rx)).await;
+    sender.await??;
+    Ok(response?.into_inner().base)
+}
+
+/// Extract an archive into a new directory.
+pub(crate) async fn extract(archive: &Path, dst: &Path) -> anyhow::Result<()> {
+    std::fs::create_dir_all(dst)?;
+    let status = tokio::process::Command::new("tar")
+        .arg("-x")
+        .arg("-f")
+        .arg(archive)
+        .arg("-C")
+        .arg(dst)
+        .status()
+        .await?;
+    anyhow::ensure!(status.success(), "tar failed with {}", status);
+    Ok(())
+}
+
+fn sha256(path: &Path) -> anyhow::Result<Vec<u8>> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize().to_vec())
+}
+
+/// Partial directory used for the transfer `id`
+pub(crate) fn partial_dir(bases: &Path, id: &str) -> PathBuf {
+    bases.join(PARTIAL_DIR).join(crate::encoding::encode(id))
+}
@@ -0,0 +1,119 @@
+//! Listener for vsock endpoints, used when the driver runs in a VM-isolated node agent (e.g. Kata
+//! Containers) and kubelet reaches it over vsock rather than a shared UNIX socket. As any guest or
+//! host peer can connect, only the CSI services are served on vsock endpoints.
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use futures::Stream;
+use nix::sys::socket::{self, AddressFamily, SockFlag, SockType, VsockAddr};
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Context identifier accepting connections from any peer
+pub const CID_ANY: u32 = u32::MAX;
+
+/// Stream of the connections accepted on a vsock port
+pub struct VsockIncoming(AsyncFd<OwnedFd>);
+
+impl VsockIncoming {
+    pub fn bind(cid: u32, port: u32) -> io::Result<Self> {
+        let fd = socket::socket(
+            AddressFamily::Vsock,
+            SockType::Stream,
+            SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC,
+            None,
+        )?;
+        socket::bind(fd.as_raw_fd(), &VsockAddr::new(cid, port))?;
+        socket::listen(&fd, 128)?;
+        Ok(Self(AsyncFd::new(fd)?))
+    }
+}
+
+impl Stream for VsockIncoming {
+    type Item = io::Result<VsockStream>;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let mut guard = ready!(self.0.poll_read_ready(cx))?;
+            let accepted = guard.try_io(|fd| {
+                let flags = SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC;
+                Ok(socket::accept4(fd.as_raw_fd(), flags)?)
+            });
+            if let Ok(accepted) = accepted {
+                return Poll::Ready(Some(accepted.and_then(VsockStream::new)));
+            }
+        }
+    }
+}
+
+/// Accepted vsock connection
+pub struct VsockStream {
+    fd: AsyncFd<OwnedFd>,
+    peer: Option<VsockAddr>,
+}
+
+impl VsockStream {
+    fn new(fd: i32) -> io::Result<Self> {
+        // SAFETY: the descriptor was just returned by accept4, and is owned by nothing else
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let peer = socket::getpeername(fd.as_raw_fd()).ok();
+        Ok(Self {
+            fd: AsyncFd::new(fd)?,
+            peer,
+        })
+    }
+}
+
+impl AsyncRead for VsockStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            let mut guard = ready!(self.fd.poll_read_ready(cx))?;
+            let unfilled = buf.initialize_unfilled();
+            match guard.try_io(|fd| Ok(nix::unistd::read(fd.as_raw_fd(), unfilled)?)) {
+                Ok(Ok(n)) => {
+                    buf.advance(n);
+                    return Poll::Ready(Ok(()));
+                }
+                Ok(Err(e)) => return Poll::Ready(Err(e)),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for VsockStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            let mut guard = ready!(self.fd.poll_write_ready(cx))?;
+            match guard.try_io(|fd| Ok(nix::unistd::write(fd.as_raw_fd(), buf)?)) {
+                Ok(result) => return Poll::Ready(result),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(socket::shutdown(
+            self.fd.as_raw_fd(),
+            socket::Shutdown::Write,
+        )?))
+    }
+}
+
+impl tonic::transport::server::Connected for VsockStream {
+    type ConnectInfo = Option<VsockAddr>;
+    fn connect_info(&self) -> Self::ConnectInfo {
+        self.peer
+    }
+}
@@ -0,0 +1,137 @@
+//! Node labels advertising the families with a valid base on the node, so that workloads can
+//! prefer the nodes where their cache is warm, with a preferred node affinity.
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use k8s_openapi::api::core::v1::Node;
+use kube::api::{ObjectMeta, Patch, PatchParams};
+use kube::Api;
+use tracing::*;
+
+use crate::Overlays;
+
+/// Prefix of the labels, followed by the family
+pub const LABEL_PREFIX: &str = "warm.overlayfs.csi.k8s.io/";
+/// Field manager owning the labels, so that applying drops those of families gone cold
+const FIELD_MANAGER: &str = "overlayfs-csi-warm";
+/// Delay before labeling, grouping bursts of changes into a single update
+const DEBOUNCE: Duration = Duration::from_secs(5);
+/// Interval at which the labels are refreshed without changes, as bases age out
+const REFRESH: Duration = Duration::from_secs(60);
+
+/// Label of a family, if it forms a valid label name. As `@` is not allowed in label names,
+/// namespace-scoped families (`{family}@{namespace}`) are labeled under a prefix qualified by their
+/// namespace, `{namespace}.warm.overlayfs.csi.k8s.io/`.
+fn label(family: &str) -> Option<String> {
+    let (name, prefix) = match family.split_once('@') {
+        Some((name, namespace)) => {
+            let valid_namespace = !namespace.is_empty()
+                && namespace.len() <= 63
+                && namespace
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+                && !namespace.starts_with('-')
+                && !namespace.ends_with('-');
+            if !valid_namespace {
+                return None;
+            }
+            (name, format!("{}.{}", namespace, LABEL_PREFIX))
+        }
+        None => (family, LABEL_PREFIX.to_string()),
+    };
+    let valid = name.len() <= 63
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+        && name.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && name.ends_with(|c: char| c.is_ascii_alphanumeric());
+    valid.then(|| format!("{}{}", prefix, name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::label;
+
+    #[test]
+    fn labels() {
+        assert_eq!(
+            label("datasets").as_deref(),
+            Some("warm.overlayfs.csi.k8s.io/datasets")
+        );
+        assert_eq!(
+            label("datasets@team-a").as_deref(),
+            Some("team-a.warm.overlayfs.csi.k8s.io/datasets")
+        );
+        // Distinct from the scoped family above
+        assert_eq!(
+            label("datasets.team-a").as_deref(),
+            Some("warm.overlayfs.csi.k8s.io/datasets.team-a")
+        );
+        assert_eq!(label("_datasets"), None);
+        assert_eq!(label(&"a".repeat(64)), None);
+        assert_eq!(label("datasets@Team"), None);
+        assert_eq!(label("datasets@"), None);
+    }
+}
+
+impl Overlays {
+    /// Families with a valid base on the node
+    pub fn warm_families(&self) -> anyhow::Result<Vec<String>> {
+        let mut families: Vec<_> = self
+            .bases()?
+            .filter(|b| self.base_valid(b))
+            .map(|b| b.family())
+            .collect();
+        families.sort();
+        families.dedup();
+        Ok(families)
+    }
+}
+
+async fn apply(api: &Api<Node>, node: &str, overlays: &Overlays) -> anyhow::Result<()> {
+    let labels: BTreeMap<_, _> = overlays
+        .warm_families()?
+        .iter()
+        .filter_map(|family| {
+            let label = label(family);
+            if label.is_none() {
+                debug!(family, "Family cannot be a label");
+            }
+            label
+        })
+        .map(|label| (label, "true".to_string()))
+        .collect();
+    let patch = Node {
+        metadata: ObjectMeta {
+            name: Some(node.into()),
+            labels: Some(labels),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    api.patch(
+        node,
+        &PatchParams::apply(FIELD_MANAGER).force(),
+        &Patch::Apply(&patch),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Label the node in the background, after each change of the state.
+pub fn spawn_labeler(api: Api<Node>, node: String, overlays: Arc<Overlays>) {
+    tokio::spawn(async move {
+        let mut changed = overlays.state_changed.subscribe();
+        loop {
+            if let Err(e) = apply(&api, &node, &overlays).await {
+                warn!(
+                    node,
+                    "Failed to label the node with the warm families: {}", e
+                );
+            }
+            let _ = tokio::time::timeout(REFRESH, changed.changed()).await;
+            tokio::time::sleep(DEBOUNCE).await;
+        }
+    });
+}
@@ -0,0 +1,159 @@
+//! Detection of stalls: the state lock held for too long, or CSI calls that do not complete.
+//!
+//! Once a stall is detected, diagnostic state is logged, and the driver reports itself unhealthy
+//! (via the CSI probe and `/healthz`), so that it gets restarted.
+use std::collections::HashMap;
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::panic::Location;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use tokio::sync::{Mutex, MutexGuard};
+use tracing::*;
+
+/// Mutex recording where and since when it is held.
+#[derive(Default)]
+pub(crate) struct TrackedMutex<T> {
+    inner: Mutex<T>,
+    holder: std::sync::Mutex<Option<(&'static Location<'static>, Instant)>>,
+    waiting: AtomicUsize,
+}
+
+impl<T> TrackedMutex<T> {
+    #[track_caller]
+    pub(crate) fn lock(&self) -> impl Future<Output = TrackedGuard<'_, T>> {
+        let location = Location::caller();
+        async move {
+            let waiting = Waiting::new(&self.waiting);
+            let guard = self.inner.lock().await;
+            drop(waiting);
+            *self.holder.lock().unwrap() = Some((location, Instant::now()));
+            TrackedGuard {
+                guard,
+                holder: &self.holder,
+            }
+        }
+    }
+    /// Lock without waiting nor tracking, e.g. from a panic hook
+    pub(crate) fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.inner.try_lock().ok()
+    }
+    /// Current holder and for how long it has held the lock, and the number of waiters
+    fn holder(&self) -> (Option<(&'static Location<'static>, Duration)>, usize) {
+        let holder = self.holder.lock().unwrap().map(|(l, t)| (l, t.elapsed()));
+        (holder, self.waiting.load(Ordering::Relaxed))
+    }
+}
+
+/// Counts a waiter, also when the waiting future is dropped
+struct Waiting<'a>(&'a AtomicUsize);
+impl<'a> Waiting<'a> {
+    fn new(count: &'a AtomicUsize) -> Self {
+        count.fetch_add(1, Ordering::Relaxed);
+        Self(count)
+    }
+}
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub(crate) struct TrackedGuard<'a, T> {
+    guard: MutexGuard<'a, T>,
+    holder: &'a std::sync::Mutex<Option<(&'static Location<'static>, Instant)>>,
+}
+impl<T> Deref for TrackedGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+impl<T> DerefMut for TrackedGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+impl<T> Drop for TrackedGuard<'_, T> {
+    fn drop(&mut self) {
+        *self.holder.lock().unwrap() = None;
+    }
+}
+
+/// Operations in flight, and the health of the driver
+pub(crate) struct Watchdog {
+    ops: std::sync::Mutex<HashMap<u64, (String, Instant)>>,
+    next_op: AtomicU64,
+    healthy: AtomicBool,
+}
+impl Default for Watchdog {
+    fn default() -> Self {
+        Self {
+            ops: Default::default(),
+            next_op: Default::default(),
+            healthy: AtomicBool::new(true),
+        }
+    }
+}
+
+/// Operation in flight, until dropped
+pub(crate) struct Operation<'a> {
+    watchdog: &'a Watchdog,
+    id: u64,
+}
+impl Drop for Operation<'_> {
+    fn drop(&mut self) {
+        self.watchdog.ops.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl Watchdog {
+    pub(crate) fn track(&self, description: String) -> Operation<'_> {
+        let id = self.next_op.fetch_add(1, Ordering::Relaxed);
+        self.ops
+            .lock()
+            .unwrap()
+            .insert(id, (description, Instant::now()));
+        Operation { watchdog: self, id }
+    }
+    /// Operations in flight and their age, unless the list is being modified (e.g. by the
+    /// panicking thread)
+    pub(crate) fn operations(&self) -> Option<Vec<(String, Duration)>> {
+        let ops = self.ops.try_lock().ok()?;
+        Some(
+            ops.values()
+                .map(|(op, since)| (op.clone(), since.elapsed()))
+                .collect(),
+        )
+    }
+    pub(crate) fn healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+    /// Check for stalls beyond `threshold`. Once unhealthy, the driver stays so until restarted.
+    pub(crate) fn check<T>(&self, lock: &TrackedMutex<T>, threshold: Duration) {
+        let (holder, waiting) = lock.holder();
+        let lock_stalled = holder.map_or(false, |(_, held)| held > threshold);
+        let ops: Vec<_> = self
+            .ops
+            .lock()
+            .unwrap()
+            .values()
+            .map(|(op, since)| (op.clone(), since.elapsed()))
+            .collect();
+        let ops_stalled = ops.iter().any(|(_, age)| *age > threshold);
+        if !lock_stalled && !ops_stalled {
+            return;
+        }
+        error!(
+            ?threshold,
+            holder = ?holder.map(|(l, held)| (l.to_string(), held)),
+            waiting,
+            "Stall detected, marking the driver unhealthy"
+        );
+        for (op, age) in ops {
+            error!(%op, ?age, "Operation in flight");
+        }
+        self.healthy.store(false, Ordering::Relaxed);
+    }
+}
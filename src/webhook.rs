@@ -0,0 +1,123 @@
+//! JSON notifications of base events to an external URL.
+//!
+//! Requires the `http-client` feature.
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+#[cfg(feature = "http-client")]
+use std::time::Duration;
+
+use serde::Serialize;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tracing::*;
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BaseEvent {
+    /// A volume was transformed into a base
+    Promoted,
+    /// A base is too old to be used for new volumes
+    Expired,
+    /// A base was removed from disk
+    Deleted,
+}
+
+#[derive(Serialize)]
+struct Payload {
+    event: BaseEvent,
+    node: String,
+    /// Name of the base directory
+    base: String,
+    volume_id: Option<String>,
+    time: String,
+}
+
+pub struct Webhook {
+    url: Option<String>,
+    node: String,
+    #[cfg(feature = "http-client")]
+    retries: u32,
+    #[cfg(feature = "http-client")]
+    client: reqwest::Client,
+    /// Bases for which an expiry was already sent
+    expired: Mutex<HashSet<PathBuf>>,
+}
+impl Webhook {
+    pub fn new(url: Option<String>, node: String, retries: u32) -> Self {
+        #[cfg(not(feature = "http-client"))]
+        let _ = retries;
+        Self {
+            url,
+            node,
+            #[cfg(feature = "http-client")]
+            retries,
+            #[cfg(feature = "http-client")]
+            client: Default::default(),
+            expired: Default::default(),
+        }
+    }
+    /// Send a notification in the background, retrying with exponential backoff.
+    pub fn notify(&self, event: BaseEvent, base: &Path, volume_id: Option<&str>) {
+        let Some(url) = self.url.clone() else {
+            return;
+        };
+        match event {
+            BaseEvent::Expired => {
+                if !self.expired.lock().unwrap().insert(base.into()) {
+                    return;
+                }
+            }
+            BaseEvent::Deleted => {
+                self.expired.lock().unwrap().remove(base);
+            }
+            BaseEvent::Promoted => {}
+        }
+        let payload = Payload {
+            event,
+            node: self.node.clone(),
+            base: base
+                .file_name()
+                .map_or_else(Default::default, |n| n.to_string_lossy().into_owned()),
+            volume_id: volume_id.map(String::from),
+            time: OffsetDateTime::now_utc()
+                .format(&Rfc3339)
+                .unwrap_or_default(),
+        };
+        self.send(url, payload);
+    }
+    #[cfg(feature = "http-client")]
+    fn send(&self, url: String, payload: Payload) {
+        let client = self.client.clone();
+        let retries = self.retries;
+        tokio::spawn(async move {
+            let mut delay = Duration::from_secs(1);
+            for attempt in 0..=retries {
+                let result = client
+                    .post(&url)
+                    .json(&payload)
+                    .timeout(Duration::from_secs(10))
+                    .send()
+                    .await
+                    .and_then(|r| r.error_for_status());
+                match result {
+                    Ok(_) => {
+                        debug!(?payload.event, payload.base, "Sent webhook notification");
+                        return;
+                    }
+                    Err(e) => {
+                        warn!(attempt, ?payload.event, "Failed to send webhook notification: {}", e);
+                    }
+                }
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            error!(?payload.event, payload.base, "Giving up on webhook notification");
+        });
+    }
+    #[cfg(not(feature = "http-client"))]
+    fn send(&self, url: String, payload: Payload) {
+        // Rejected when building the driver
+        warn!(url, ?payload.event, "Webhooks require the `http-client` feature");
+    }
+}
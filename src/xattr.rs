@@ -0,0 +1,45 @@
+//! Reading extended attributes without following symbolic links.
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use nix::errno::Errno;
+
+/// Value of the extended attribute `name` of `path`, or `None` if it is not set.
+pub(crate) fn lgetxattr(path: &Path, name: &str) -> std::io::Result<Option<Vec<u8>>> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let name = CString::new(name)?;
+    loop {
+        // SAFETY: Both strings are valid and NUL-terminated, and a zero size only queries the
+        // length of the value.
+        let len =
+            unsafe { nix::libc::lgetxattr(path.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0) };
+        if len < 0 {
+            return match Errno::last() {
+                Errno::ENODATA => Ok(None),
+                e => Err(e.into()),
+            };
+        }
+        let mut value = vec![0u8; len as usize];
+        // SAFETY: Both strings are valid and NUL-terminated, and the buffer is writable for its
+        // length.
+        let r = unsafe {
+            nix::libc::lgetxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_mut_ptr().cast(),
+                value.len(),
+            )
+        };
+        if r >= 0 {
+            value.truncate(r as usize);
+            return Ok(Some(value));
+        }
+        match Errno::last() {
+            Errno::ENODATA => return Ok(None),
+            // The value grew in the meantime
+            Errno::ERANGE => continue,
+            e => return Err(e.into()),
+        }
+    }
+}